@@ -0,0 +1,271 @@
+use crate::{
+    matrix::Matrix,
+    matrix_3d::{Point, gamma},
+};
+
+/// Axis-aligned bounding box. An empty box has `min` at +infinity and `max`
+/// at -infinity so that growing it by any point yields that point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Aabb::empty()
+    }
+}
+
+impl Aabb {
+    pub fn new(min: Point, max: Point) -> Self {
+        Aabb { min, max }
+    }
+
+    pub fn empty() -> Self {
+        Aabb {
+            min: Matrix([[f32::INFINITY, f32::INFINITY, f32::INFINITY, 1.]]),
+            max: Matrix([[f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY, 1.]]),
+        }
+    }
+
+    pub fn from_points(points: &[Point]) -> Self {
+        let mut aabb = Aabb::empty();
+        for point in points.iter() {
+            aabb.grow(*point);
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x() > self.max.x() || self.min.y() > self.max.y() || self.min.z() > self.max.z()
+    }
+
+    pub fn grow(&mut self, point: Point) {
+        for i in 0..3 {
+            self.min[0][i] = self.min[0][i].min(point[0][i]);
+            self.max[0][i] = self.max[0][i].max(point[0][i]);
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        let mut out = self;
+        for i in 0..3 {
+            out.min[0][i] = self.min[0][i].min(other.min[0][i]);
+            out.max[0][i] = self.max[0][i].max(other.max[0][i]);
+        }
+        out
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        (0..3).all(|i| point[0][i] >= self.min[0][i] && point[0][i] <= self.max[0][i])
+    }
+
+    /// Edge lengths as a direction (w = 0).
+    pub fn size(&self) -> Matrix<1, 4> {
+        if self.is_empty() {
+            return Matrix::default();
+        }
+        let mut size = self.max - self.min;
+        size[0][3] = 0.;
+        size
+    }
+
+    pub fn center(&self) -> Point {
+        let mut center = (self.min + self.max) * 0.5;
+        center[0][3] = 1.;
+        center
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let size = self.size();
+        2. * (size.x() * size.y() + size.y() * size.z() + size.z() * size.x())
+    }
+
+    /// Index of the longest axis, used to pick split planes.
+    pub fn longest_axis(&self) -> usize {
+        let size = self.size();
+        if size.x() >= size.y() && size.x() >= size.z() {
+            0
+        } else if size.y() >= size.z() {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn corners(&self) -> [Point; 8] {
+        let mut corners = [self.min; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            for axis in 0..3 {
+                if i & (1 << axis) != 0 {
+                    corner[0][axis] = self.max[0][axis];
+                }
+            }
+        }
+        corners
+    }
+
+    /// Bounds of this box after transforming it by `mat`. The result is
+    /// generally looser than the bounds of the transformed contents.
+    pub fn transform(&self, mat: Matrix<4, 4>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+
+        // Arvo's method: each output axis takes the extreme of every matrix
+        // term independently, which avoids transforming all eight corners.
+        let mut out = Aabb::new(
+            Matrix([[mat[3][0], mat[3][1], mat[3][2], 1.]]),
+            Matrix([[mat[3][0], mat[3][1], mat[3][2], 1.]]),
+        );
+
+        for i in 0..3 {
+            for j in 0..3 {
+                let a = mat[i][j] * self.min[0][i];
+                let b = mat[i][j] * self.max[0][i];
+                out.min[0][j] += a.min(b);
+                out.max[0][j] += a.max(b);
+            }
+        }
+
+        out
+    }
+
+    /// Slab test against a ray with precomputed `1 / direction`. Zero
+    /// direction components give infinite inverses; the NaNs that produces
+    /// for rays lying in a slab plane are ignored by `max`/`min`.
+    /// Returns the parametric entry and exit distances clipped to `[0, t_max]`.
+    pub fn ray_intersects(
+        &self,
+        origin: Point,
+        inverse_direction: Matrix<1, 4>,
+        t_max: f32,
+    ) -> Option<(f32, f32)> {
+        let mut t0: f32 = 0.;
+        let mut t1 = t_max;
+
+        for i in 0..3 {
+            let mut near = (self.min[0][i] - origin[0][i]) * inverse_direction[0][i];
+            let mut far = (self.max[0][i] - origin[0][i]) * inverse_direction[0][i];
+
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+
+            // Widen the exit so rounding can't turn a grazing hit into a miss.
+            far *= 1. + 2. * gamma(3);
+
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+
+            if t0 > t1 {
+                return None;
+            }
+        }
+
+        Some((t0, t1))
+    }
+}
+
+pub fn inverse_direction(direction: Matrix<1, 4>) -> Matrix<1, 4> {
+    Matrix([[
+        1. / direction.x(),
+        1. / direction.y(),
+        1. / direction.z(),
+        0.,
+    ]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix_3d::{cube, rotate_y, translate};
+
+    #[test]
+    fn test_grow_and_union() {
+        let mut a = Aabb::empty();
+        assert!(a.is_empty());
+
+        a.grow(Matrix([[1., 2., 3., 1.]]));
+        a.grow(Matrix([[-1., 0., 5., 1.]]));
+        assert_eq!(a.min, Matrix([[-1., 0., 3., 1.]]));
+        assert_eq!(a.max, Matrix([[1., 2., 5., 1.]]));
+
+        let b = Aabb::new(Matrix([[0., -3., 0., 1.]]), Matrix([[0.5, 0.5, 0.5, 1.]]));
+        let c = a.union(b);
+        assert_eq!(c.min, Matrix([[-1., -3., 0., 1.]]));
+        assert_eq!(c.max, Matrix([[1., 2., 5., 1.]]));
+
+        assert_eq!(Aabb::empty().union(b), b);
+    }
+
+    #[test]
+    fn test_measurements() {
+        let aabb = Aabb::new(Matrix([[0., 0., 0., 1.]]), Matrix([[1., 2., 3., 1.]]));
+
+        assert_eq!(aabb.surface_area(), 22.);
+        assert_eq!(aabb.center(), Matrix([[0.5, 1., 1.5, 1.]]));
+        assert_eq!(aabb.longest_axis(), 2);
+        assert_eq!(Aabb::empty().surface_area(), 0.);
+    }
+
+    #[test]
+    fn test_transform() {
+        let bounds = cube().bounds();
+        assert_eq!(bounds.min.round(5), Matrix([[-0.5, -0.5, -0.5, 1.]]));
+        assert_eq!(bounds.max.round(5), Matrix([[0.5, 0.5, 0.5, 1.]]));
+
+        let mat = rotate_y(0.6)(translate(3., 1., -2.));
+        let transformed = bounds.transform(mat);
+
+        let mut expanded = transformed;
+        expanded.grow(transformed.min - Matrix([[1e-5, 1e-5, 1e-5, 0.]]));
+        expanded.grow(transformed.max + Matrix([[1e-5, 1e-5, 1e-5, 0.]]));
+
+        for corner in bounds.corners() {
+            assert!(expanded.contains(corner(mat)));
+        }
+
+        let exact = cube().apply(mat).bounds();
+        assert_eq!(transformed.min.round(5), exact.min.round(5));
+        assert_eq!(transformed.max.round(5), exact.max.round(5));
+    }
+
+    #[test]
+    fn test_ray_slab() {
+        let aabb = Aabb::new(Matrix([[-1., -1., -1., 1.]]), Matrix([[1., 1., 1., 1.]]));
+        let origin = Matrix([[0., 0., -5., 1.]]);
+
+        let direction = Matrix([[0., 0., 1., 0.]]);
+        let (t0, t1) = aabb
+            .ray_intersects(origin, inverse_direction(direction), f32::INFINITY)
+            .unwrap();
+        assert_eq!(t0, 4.);
+        assert!((t1 - 6.).abs() < 1e-5);
+
+        let away = Matrix([[0., 0., -1., 0.]]);
+        assert!(
+            aabb.ray_intersects(origin, inverse_direction(away), f32::INFINITY)
+                .is_none()
+        );
+
+        assert!(
+            aabb.ray_intersects(origin, inverse_direction(direction), 3.)
+                .is_none()
+        );
+
+        // Ray travelling exactly along a face: 0 * inf produces NaN.
+        let grazing = Matrix([[1., 0., -5., 1.]]);
+        assert!(
+            aabb.ray_intersects(grazing, inverse_direction(direction), f32::INFINITY)
+                .is_some()
+        );
+
+        let outside = Matrix([[2., 0., -5., 1.]]);
+        assert!(
+            aabb.ray_intersects(outside, inverse_direction(direction), f32::INFINITY)
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{bitmap::FloatImage, matrix::Matrix};
+
+/// Which buffer a frame shows.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AovKind {
+    #[default]
+    Color,
+    Depth,
+    Normal,
+    Albedo,
+    ObjectId,
+    Barycentric,
+    HitCount,
+}
+
+/// Auxiliary buffers written alongside the color of a traced frame. All but
+/// the object ID and hit count describe the primary hit, averaged over the
+/// samples that hit something.
+#[derive(Clone, Debug)]
+pub struct Aovs {
+    pub width: u32,
+    pub height: u32,
+    /// Distance along the camera ray, or infinity where every sample missed.
+    pub depth: Vec<f32>,
+    /// World space, not renormalized after averaging.
+    pub normal: FloatImage,
+    pub albedo: FloatImage,
+    /// Index of the model hit by the first sample, among the frame's models.
+    pub object_id: Vec<Option<u32>>,
+    /// Weights of the triangle's three vertices.
+    pub barycentric: FloatImage,
+    /// Surfaces hit by all the pixel's rays, reflections included.
+    pub hit_count: Vec<u32>,
+}
+
+/// What one camera sample saw at its primary hit.
+#[derive(Clone, Copy, Debug)]
+pub struct AovSample {
+    pub depth: f32,
+    pub normal: Matrix<1, 4>,
+    pub albedo: Matrix<1, 4>,
+    pub object_id: u32,
+    pub barycentric: Matrix<1, 4>,
+}
+
+impl Aovs {
+    pub fn new(width: u32, height: u32) -> Aovs {
+        let len = width as usize * height as usize;
+        Aovs {
+            width,
+            height,
+            depth: vec![f32::INFINITY; len],
+            normal: FloatImage::new(width, height),
+            albedo: FloatImage::new(width, height),
+            object_id: vec![None; len],
+            barycentric: FloatImage::new(width, height),
+            hit_count: vec![0; len],
+        }
+    }
+
+    /// Stores a pixel's samples. `None` marks a sample that missed.
+    pub fn set(&mut self, x: u32, y: u32, samples: &[Option<AovSample>], hits: u32) {
+        let i = (y * self.width + x) as usize;
+        self.hit_count[i] = hits;
+        self.object_id[i] = samples.first().copied().flatten().map(|s| s.object_id);
+
+        let hit: Vec<AovSample> = samples.iter().flatten().copied().collect();
+        if hit.is_empty() {
+            return;
+        }
+
+        let n = hit.len() as f32;
+        let average = |f: fn(&AovSample) -> Matrix<1, 4>| {
+            hit.iter().fold(Matrix::default(), |s, h| s + f(h)) / n
+        };
+        self.depth[i] = hit.iter().map(|h| h.depth).sum::<f32>() / n;
+        self.normal.set(x, y, average(|h| h.normal));
+        self.albedo.set(x, y, average(|h| h.albedo));
+        self.barycentric.set(x, y, average(|h| h.barycentric));
+    }
+
+    /// Maps a buffer to displayable colors. `Color` has no buffer here and
+    /// gives black.
+    pub fn visualize(&self, kind: AovKind) -> FloatImage {
+        let mut image = FloatImage::new(self.width, self.height);
+        let opaque = |c: Matrix<1, 4>| Matrix([[c.x(), c.y(), c.z(), 1.]]);
+
+        // Depth is scaled between the nearest and furthest hit, near bright.
+        let finite = self.depth.iter().copied().filter(|d| d.is_finite());
+        let near = finite.clone().fold(f32::INFINITY, f32::min);
+        let far = finite.fold(0., f32::max);
+        let max_hits = self.hit_count.iter().copied().max().unwrap_or(0).max(1);
+
+        for (i, pixel) in image.pixels.iter_mut().enumerate() {
+            let (x, y) = (i as u32 % self.width, i as u32 / self.width);
+            *pixel = match kind {
+                AovKind::Color => Matrix([[0., 0., 0., 1.]]),
+                AovKind::Depth if self.depth[i].is_finite() => {
+                    let v = 1. - (self.depth[i] - near) / (far - near).max(f32::EPSILON);
+                    Matrix([[v, v, v, 1.]])
+                }
+                AovKind::Depth => Matrix([[0., 0., 0., 1.]]),
+                AovKind::Normal if self.depth[i].is_finite() => {
+                    opaque(self.normal.get(x, y) * 0.5 + 0.5)
+                }
+                AovKind::Normal => Matrix([[0., 0., 0., 1.]]),
+                AovKind::Albedo => opaque(self.albedo.get(x, y)),
+                AovKind::ObjectId => self.object_id[i].map_or(Matrix([[0., 0., 0., 1.]]), id_color),
+                AovKind::Barycentric => opaque(self.barycentric.get(x, y)),
+                AovKind::HitCount => heat(self.hit_count[i] as f32 / max_hits as f32),
+            };
+        }
+
+        image
+    }
+}
+
+/// A distinct, saturated color per ID, spreading hues by the golden ratio.
+pub fn id_color(id: u32) -> Matrix<1, 4> {
+    let hue = (id as f32 * 0.618_034).fract() * 6.;
+    let channel = |offset: f32| (((hue + offset) % 6. - 3.).abs() - 1.).clamp(0., 1.);
+    Matrix([[channel(0.), channel(4.), channel(2.), 1.]])
+}
+
+/// Black through blue, green and yellow to red as `t` goes from 0 to 1.
+pub fn heat(t: f32) -> Matrix<1, 4> {
+    const STOPS: [[f32; 3]; 5] = [
+        [0., 0., 0.],
+        [0., 0., 1.],
+        [0., 1., 0.],
+        [1., 1., 0.],
+        [1., 0., 0.],
+    ];
+
+    let x = t.clamp(0., 1.) * (STOPS.len() - 1) as f32;
+    let i = (x as usize).min(STOPS.len() - 2);
+    let f = x - i as f32;
+    let (a, b) = (STOPS[i], STOPS[i + 1]);
+    Matrix([[
+        a[0] + (b[0] - a[0]) * f,
+        a[1] + (b[1] - a[1]) * f,
+        a[2] + (b[2] - a[2]) * f,
+        1.,
+    ]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(depth: f32, object_id: u32) -> AovSample {
+        AovSample {
+            depth,
+            normal: Matrix([[0., 0., -1., 0.]]),
+            albedo: Matrix([[1., 0.5, 0., 1.]]),
+            object_id,
+            barycentric: Matrix([[0.2, 0.3, 0.5, 0.]]),
+        }
+    }
+
+    #[test]
+    fn test_set() {
+        let mut aovs = Aovs::new(2, 1);
+        aovs.set(0, 0, &[Some(sample(4., 2)), None, Some(sample(6., 1))], 5);
+        aovs.set(1, 0, &[None, Some(sample(3., 0))], 1);
+
+        assert_eq!(aovs.depth, vec![5., 3.]);
+        assert_eq!(aovs.object_id, vec![Some(2), None]);
+        assert_eq!(aovs.hit_count, vec![5, 1]);
+        assert_eq!(aovs.albedo.get(0, 0), Matrix([[1., 0.5, 0., 1.]]));
+    }
+
+    #[test]
+    fn test_visualize() {
+        let mut aovs = Aovs::new(3, 1);
+        aovs.set(0, 0, &[Some(sample(2., 0))], 1);
+        aovs.set(1, 0, &[Some(sample(4., 1))], 3);
+
+        let depth = aovs.visualize(AovKind::Depth);
+        assert_eq!(depth.get(0, 0), Matrix([[1., 1., 1., 1.]]));
+        assert_eq!(depth.get(1, 0), Matrix([[0., 0., 0., 1.]]));
+        assert_eq!(depth.get(2, 0), Matrix([[0., 0., 0., 1.]]));
+
+        let normal = aovs.visualize(AovKind::Normal);
+        assert_eq!(normal.get(0, 0), Matrix([[0.5, 0.5, 0., 1.]]));
+
+        let ids = aovs.visualize(AovKind::ObjectId);
+        assert_ne!(ids.get(0, 0), ids.get(1, 0));
+        assert_eq!(ids.get(2, 0), Matrix([[0., 0., 0., 1.]]));
+
+        let heat_map = aovs.visualize(AovKind::HitCount);
+        assert_eq!(heat_map.get(1, 0), Matrix([[1., 0., 0., 1.]]));
+        assert_eq!(heat_map.get(2, 0), Matrix([[0., 0., 0., 1.]]));
+    }
+
+    #[test]
+    fn test_id_colors() {
+        let colors: Vec<_> = (0..8).map(id_color).collect();
+        for (i, a) in colors.iter().enumerate() {
+            for b in colors[i + 1..].iter() {
+                assert!((*a - *b).abs().0[0].iter().sum::<f32>() > 0.1);
+            }
+        }
+    }
+}
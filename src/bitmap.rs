@@ -2,17 +2,21 @@ use wasm_bindgen::Clamped;
 use web_sys::ImageData;
 
 use crate::{
-    inside_triangle,
+    blend::{BlendMode, premultiply, unpremultiply},
+    depth::DepthBuffer,
+    isosurface::IndexedMesh,
     matrix::Matrix,
-    matrix_3d::{Triangle, screen},
+    matrix_3d::{Point, Triangle},
+    raster::{Sampling, scan},
+    shader::{Shader, Vertex, vertices},
 };
 
 #[derive(Clone, Copy)]
 pub struct Color {
-    r: u8,
-    g: u8,
-    b: u8,
-    a: u8,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
 }
 
 impl Color {
@@ -57,29 +61,134 @@ impl Bitmap {
     }
 
     pub fn render_trig(&mut self, trig: Triangle, view_projection: Matrix<4, 4>, color: Color) {
-        let p0 = trig.0(view_projection);
-        let p1 = trig.1(view_projection);
-        let p2 = trig.2(view_projection);
-
-        let s0 = screen(p0, self.width as f32, self.height as f32);
-        let s1 = screen(p1, self.width as f32, self.height as f32);
-        let s2 = screen(p2, self.width as f32, self.height as f32);
-
-        let min_x = s0.x().min(s1.x()).min(s2.x()) as usize;
-        let max_x = s0.x().max(s1.x()).max(s2.x()) as usize;
-        let min_y = s0.y().min(s1.y()).min(s2.y()) as usize;
-        let max_y = s0.y().max(s1.y()).max(s2.y()) as usize;
-
-        for x in min_x..max_x {
-            for y in min_y..max_y {
-                let p = Matrix([[x as f32 + 0.5, y as f32 + 0.5]]);
-                if inside_triangle(s0, s1, s2, p) {
-                    self.rows[y][x] = color;
-                }
-            }
+        self.render_trig_shaded(trig, view_projection, |_| color);
+    }
+
+    /// Like `render_trig`, but colors each pixel by `shade` of the point on
+    /// the triangle under its centre, interpolated in the triangle's own
+    /// space.
+    pub fn render_trig_shaded(
+        &mut self,
+        trig: Triangle,
+        view_projection: Matrix<4, 4>,
+        shade: impl Fn(Point) -> Color,
+    ) {
+        let shader = Shaded {
+            view_projection,
+            shade,
+        };
+        self.render_shader(&shader, [&trig.0, &trig.1, &trig.2]);
+    }
+
+    /// Runs `shader` over one triangle. Varyings are divided by each
+    /// vertex's clip w before the screen space barycentrics weight them,
+    /// so they stay right under perspective. Fragments are composited over
+    /// the pixels beneath.
+    pub fn render_shader<S: Shader>(&mut self, shader: &S, vertices: [&S::Vertex; 3]) {
+        self.render_shader_blended(shader, vertices, BlendMode::Over, None);
+    }
+
+    /// Like `render_shader`, compositing with `mode` and dropping fragments
+    /// further away than `depth` holds for their pixel.
+    pub fn render_shader_blended<S: Shader>(
+        &mut self,
+        shader: &S,
+        vertices: [&S::Vertex; 3],
+        mode: BlendMode,
+        depth: Option<&DepthBuffer>,
+    ) {
+        let [a, b, c] = vertices.map(|vertex| shader.vertex(vertex));
+        self.fill(shader, [a.0, b.0, c.0], [a.1, b.1, c.1], mode, depth);
+    }
+
+    /// Like `render_shader`, dropping fragments further away than `depth`
+    /// holds for their pixel and keeping the depth of the rest, so opaque
+    /// triangles hide each other whatever order they're drawn in.
+    pub fn render_shader_depth<S: Shader>(
+        &mut self,
+        shader: &S,
+        vertices: [&S::Vertex; 3],
+        depth: &mut DepthBuffer,
+    ) {
+        let [a, b, c] = vertices.map(|vertex| shader.vertex(vertex));
+        let mut fragments = vec![];
+        scan(
+            self.width,
+            self.height,
+            shader,
+            [a.0, b.0, c.0],
+            [a.1, b.1, c.1],
+            &Sampling::Center,
+            |x, y, _, z| z <= depth.get(x as i32, y as i32),
+            |fragment| fragments.push(fragment),
+        );
+
+        let width = depth.width as usize;
+        for fragment in fragments.iter() {
+            depth.depth[fragment.y * width + fragment.x] = fragment.depth[0];
+            self.composite(
+                fragment.x as i32,
+                fragment.y as i32,
+                fragment.color,
+                BlendMode::Over,
+            );
+        }
+    }
+
+    /// Runs `shader` over every triangle of `mesh`, with the vertex stage
+    /// run once per shared vertex.
+    pub fn render_mesh<S: Shader<Vertex = Vertex>>(&mut self, shader: &S, mesh: &IndexedMesh) {
+        let outputs: Vec<_> = vertices(mesh)
+            .iter()
+            .map(|vertex| shader.vertex(vertex))
+            .collect();
+
+        for indices in mesh.indices.iter() {
+            let [a, b, c] = indices.map(|i| outputs[i as usize]);
+            self.fill(
+                shader,
+                [a.0, b.0, c.0],
+                [a.1, b.1, c.1],
+                BlendMode::Over,
+                None,
+            );
         }
     }
 
+    /// Composites `color` onto pixel `(x, y)` with `mode`. Pixels off the
+    /// bitmap are ignored.
+    pub fn composite(&mut self, x: i32, y: i32, color: Color, mode: BlendMode) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let pixel = &mut self.rows[y as usize][x as usize];
+        // Opaque colors cover whatever is beneath exactly.
+        *pixel = match (mode, color.a) {
+            (BlendMode::Over, 255) => color,
+            _ => unpremultiply(mode.blend(premultiply(color), premultiply(*pixel))),
+        };
+    }
+
+    fn fill<S: Shader>(
+        &mut self,
+        shader: &S,
+        clip: [Matrix<1, 4>; 3],
+        varyings: [S::Varying; 3],
+        mode: BlendMode,
+        depth: Option<&DepthBuffer>,
+    ) {
+        scan(
+            self.width,
+            self.height,
+            shader,
+            clip,
+            varyings,
+            &Sampling::Center,
+            |x, y, _, z| depth.is_none_or(|depth| z <= depth.get(x as i32, y as i32)),
+            |fragment| self.composite(fragment.x as i32, fragment.y as i32, fragment.color, mode),
+        );
+    }
+
     pub fn to_image_data(&self) -> ImageData {
         let mut buf: Vec<u8> = Vec::new();
 
@@ -96,3 +205,287 @@ impl Bitmap {
             .expect("failed to create image data")
     }
 }
+
+/// Transparent fragments gathered in any order, for weighted blended
+/// order-independent transparency (McGuire and Bavoil 2013). Instead of
+/// sorting, each pixel keeps a sum of premultiplied colors weighted to
+/// favour the nearer ones, and how much of what's behind still shows
+/// through. `resolve` composites their weighted average over a bitmap.
+pub struct WeightedBlend {
+    pub width: u32,
+    pub height: u32,
+    pub accumulated: Vec<Matrix<1, 4>>,
+    pub revealage: Vec<f32>,
+}
+
+impl WeightedBlend {
+    pub fn new(width: u32, height: u32) -> WeightedBlend {
+        let size = width as usize * height as usize;
+        WeightedBlend {
+            width,
+            height,
+            accumulated: vec![Matrix::default(); size],
+            revealage: vec![1.; size],
+        }
+    }
+
+    /// Adds `color` at pixel `(x, y)`, `distance` away from the camera.
+    pub fn add(&mut self, x: u32, y: u32, color: Color, distance: f32) {
+        let color = premultiply(color);
+        let alpha = color.w();
+        // Equation 7 of the paper, for scenes a few units across.
+        let z = distance.abs();
+        let weight =
+            alpha * (10. / (1e-5 + (z / 5.).powi(2) + (z / 200.).powi(6))).clamp(1e-2, 3e3);
+
+        let i = (y * self.width + x) as usize;
+        self.accumulated[i] = self.accumulated[i] + color * weight;
+        self.revealage[i] *= 1. - alpha;
+    }
+
+    /// Runs `shader` over one triangle like `Bitmap::render_shader_blended`,
+    /// adding its fragments instead of compositing them.
+    pub fn render_shader<S: Shader>(
+        &mut self,
+        shader: &S,
+        vertices: [&S::Vertex; 3],
+        depth: Option<&DepthBuffer>,
+    ) {
+        let [a, b, c] = vertices.map(|vertex| shader.vertex(vertex));
+        scan(
+            self.width,
+            self.height,
+            shader,
+            [a.0, b.0, c.0],
+            [a.1, b.1, c.1],
+            &Sampling::Center,
+            |x, y, _, z| depth.is_none_or(|depth| z <= depth.get(x as i32, y as i32)),
+            |fragment| {
+                self.add(
+                    fragment.x as u32,
+                    fragment.y as u32,
+                    fragment.color,
+                    fragment.w,
+                )
+            },
+        );
+    }
+
+    /// Composites the average of each pixel's fragments over `bmp`,
+    /// covering it as much as all of them together would.
+    pub fn resolve(&self, bmp: &mut Bitmap) {
+        for y in 0..self.height.min(bmp.height) {
+            for x in 0..self.width.min(bmp.width) {
+                let i = (y * self.width + x) as usize;
+                let coverage = 1. - self.revealage[i];
+                if coverage <= 0. {
+                    continue;
+                }
+                let sum = self.accumulated[i];
+                let mut average = sum * (coverage / sum.w().max(1e-5));
+                average[0][3] = coverage;
+
+                let pixel = &mut bmp.rows[y as usize][x as usize];
+                *pixel = unpremultiply(BlendMode::Over.blend(average, premultiply(*pixel)));
+            }
+        }
+    }
+}
+
+/// Shader behind `render_trig_shaded`, passing the triangle's own points
+/// through to a closure.
+struct Shaded<F> {
+    view_projection: Matrix<4, 4>,
+    shade: F,
+}
+
+impl<F: Fn(Point) -> Color> Shader for Shaded<F> {
+    type Vertex = Point;
+    type Varying = Point;
+
+    fn vertex(&self, vertex: &Point) -> (Matrix<1, 4>, Point) {
+        (vertex(self.view_projection), *vertex)
+    }
+
+    fn fragment(&self, point: Point) -> Option<Color> {
+        Some((self.shade)(point))
+    }
+}
+
+/// An RGBA image with a float per channel, for sources with more precision
+/// or range than `Bitmap` can hold. Pixels are stored row by row.
+#[derive(Clone, Debug)]
+pub struct FloatImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Matrix<1, 4>>,
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32) -> FloatImage {
+        FloatImage {
+            width,
+            height,
+            pixels: vec![Matrix::default(); width as usize * height as usize],
+        }
+    }
+
+    pub fn from_bitmap(bmp: &Bitmap) -> FloatImage {
+        let pixels = bmp
+            .rows
+            .iter()
+            .flat_map(|row| row.iter())
+            .map(|c| Matrix([[c.r as f32, c.g as f32, c.b as f32, c.a as f32]]) / 255.)
+            .collect();
+
+        FloatImage {
+            width: bmp.width,
+            height: bmp.height,
+            pixels,
+        }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Matrix<1, 4> {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, color: Matrix<1, 4>) {
+        self.pixels[(y * self.width + x) as usize] = color;
+    }
+
+    /// Clamps to 0..1 and rounds to the nearest 8-bit value.
+    pub fn to_bitmap(&self) -> Bitmap {
+        let mut bmp = Bitmap::new(self.width, self.height);
+
+        for (y, row) in bmp.rows.iter_mut().enumerate() {
+            for (x, color) in row.iter_mut().enumerate() {
+                let c = self.get(x as u32, y as u32);
+                let channel = |v: f32| (v.clamp(0., 1.) * 255.).round() as u8;
+                *color = Color::new(
+                    channel(c.x()),
+                    channel(c.y()),
+                    channel(c.z()),
+                    channel(c.w()),
+                );
+            }
+        }
+
+        bmp
+    }
+
+    /// Clamps to 0..1 and truncates to 8 bits, as `Matrix::to_color` does,
+    /// which is how traced frames have always been stored.
+    pub fn to_bitmap_truncated(&self) -> Bitmap {
+        let mut bmp = Bitmap::new(self.width, self.height);
+
+        for (y, row) in bmp.rows.iter_mut().enumerate() {
+            for (x, color) in row.iter_mut().enumerate() {
+                *color = self.get(x as u32, y as u32).to_color();
+            }
+        }
+
+        bmp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::Solid;
+
+    /// Clip space triangle covering the whole of a small bitmap at `z`.
+    fn covering(z: f32) -> [Point; 3] {
+        [
+            Matrix([[-3., -1., z, 1.]]),
+            Matrix([[1., -1., z, 1.]]),
+            Matrix([[1., 3., z, 1.]]),
+        ]
+    }
+
+    fn rgba(color: Color) -> [u8; 4] {
+        [color.r, color.g, color.b, color.a]
+    }
+
+    #[test]
+    fn test_blended_triangles() {
+        let [a, b, c] = covering(0.);
+        let red = Solid(Color::new(255, 0, 0, 255));
+        let green = Solid(Color::new(0, 255, 0, 128));
+
+        let mut bmp = Bitmap::new(4, 4);
+        bmp.render_shader(&red, [&a, &b, &c]);
+        bmp.render_shader(&green, [&a, &b, &c]);
+        assert_eq!(rgba(bmp.rows[1][1]), [127, 128, 0, 255]);
+
+        // Adding saturates, and fragments behind the depth buffer are
+        // dropped.
+        bmp.render_shader_blended(&red, [&a, &b, &c], BlendMode::Add, None);
+        assert_eq!(rgba(bmp.rows[1][1]), [255, 128, 0, 255]);
+        let mut depth = DepthBuffer::new(4, 4);
+        depth.depth.fill(-0.5);
+        bmp.render_shader_blended(&green, [&a, &b, &c], BlendMode::Over, Some(&depth));
+        assert_eq!(rgba(bmp.rows[1][1]), [255, 128, 0, 255]);
+
+        // Over an empty bitmap the color stays and only alpha drops.
+        let mut bmp = Bitmap::new(4, 4);
+        bmp.render_shader(&green, [&a, &b, &c]);
+        assert_eq!(rgba(bmp.rows[1][1]), [0, 255, 0, 128]);
+    }
+
+    #[test]
+    fn test_depth_tested_triangles() {
+        let near = covering(-0.5);
+        let far = covering(0.5);
+        let red = Solid(Color::new(255, 0, 0, 255));
+        let green = Solid(Color::new(0, 255, 0, 255));
+
+        // The nearer triangle stays in front when the further one is
+        // drawn after it, and the depth keeps the nearer of the two.
+        let mut bmp = Bitmap::new(4, 4);
+        let mut depth = DepthBuffer::new(4, 4);
+        bmp.render_shader_depth(&red, [&near[0], &near[1], &near[2]], &mut depth);
+        bmp.render_shader_depth(&green, [&far[0], &far[1], &far[2]], &mut depth);
+        assert_eq!(rgba(bmp.rows[1][1]), [255, 0, 0, 255]);
+        assert_eq!(depth.get(1, 1), -0.5);
+    }
+
+    #[test]
+    fn test_weighted_blend() {
+        // The same pixels, one layer much further from the camera.
+        let near = covering(0.);
+        let far = covering(0.5).map(|p| p * 20.);
+        let cyan = Solid(Color::new(0, 255, 255, 128));
+        let magenta = Solid(Color::new(255, 0, 255, 128));
+
+        for (front, back) in [(&cyan, &magenta), (&magenta, &cyan)] {
+            // The result doesn't depend on the order fragments arrive in.
+            let mut results = vec![];
+            for order in [[(front, near), (back, far)], [(back, far), (front, near)]] {
+                let mut oit = WeightedBlend::new(4, 4);
+                for (shader, [a, b, c]) in order {
+                    oit.render_shader(shader, [&a, &b, &c], None);
+                }
+                let mut bmp = Bitmap::new(4, 4);
+                for row in bmp.rows.iter_mut() {
+                    row.fill(Color::new(0, 0, 0, 255));
+                }
+                oit.resolve(&mut bmp);
+                results.push(rgba(bmp.rows[1][1]));
+            }
+            assert_eq!(results[0], results[1]);
+
+            // Three quarters covered, almost all by the nearer layer.
+            let [r, g, b, a] = results[0];
+            let expected = rgba(front.0);
+            assert_eq!(a, 255);
+            assert!(b > 180 && b < 200, "{:?}", results[0]);
+            for (channel, full) in [(r, expected[0]), (g, expected[1])] {
+                if full == 0 {
+                    assert!(channel < 5, "{:?}", results[0]);
+                } else {
+                    assert!(channel > 180, "{:?}", results[0]);
+                }
+            }
+        }
+    }
+}
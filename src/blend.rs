@@ -0,0 +1,123 @@
+use crate::{bitmap::Color, matrix::Matrix};
+
+/// How a color is composited onto what's already in a pixel. Colors are
+/// blended premultiplied by their alpha, and the result covers the pixel
+/// as source over destination does.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BlendMode {
+    /// Porter–Duff source over destination.
+    #[default]
+    Over,
+    /// Sum of both, saturating at white, as for glows and light.
+    Add,
+    /// Product of both where they overlap, which only darkens.
+    Multiply,
+    /// Inverse of the product of the inverses, which only lightens.
+    Screen,
+}
+
+impl BlendMode {
+    /// Composites premultiplied `source` onto premultiplied `destination`.
+    pub fn blend(self, source: Matrix<1, 4>, destination: Matrix<1, 4>) -> Matrix<1, 4> {
+        let (sa, da) = (source.w(), destination.w());
+        let alpha = sa + da - sa * da;
+
+        let mut out = Matrix::default();
+        for i in 0..3 {
+            let (s, d) = (source[0][i], destination[0][i]);
+            out[0][i] = match self {
+                BlendMode::Over => s + d * (1. - sa),
+                BlendMode::Add => (s + d).min(1.),
+                // The blended color where both are present, either one
+                // alone where only it is.
+                BlendMode::Multiply => s * d + s * (1. - da) + d * (1. - sa),
+                BlendMode::Screen => s + d - s * d,
+            };
+        }
+        out[0][3] = match self {
+            BlendMode::Add => (sa + da).min(1.),
+            _ => alpha,
+        };
+        out
+    }
+}
+
+/// `color` with its channels scaled by its alpha, as floats from 0 to 1.
+pub fn premultiply(color: Color) -> Matrix<1, 4> {
+    let alpha = color.a as f32 / 255.;
+    Matrix([[
+        color.r as f32 / 255. * alpha,
+        color.g as f32 / 255. * alpha,
+        color.b as f32 / 255. * alpha,
+        alpha,
+    ]])
+}
+
+/// Inverse of `premultiply`. Colors too transparent to keep any alpha
+/// come back transparent black.
+pub fn unpremultiply(color: Matrix<1, 4>) -> Color {
+    let alpha = color.w().clamp(0., 1.);
+    if (alpha * 255.).round() == 0. {
+        return Color::new(0, 0, 0, 0);
+    }
+    let channel = |v: f32| ((v / alpha).clamp(0., 1.) * 255.).round() as u8;
+    Color::new(
+        channel(color.x()),
+        channel(color.y()),
+        channel(color.z()),
+        (alpha * 255.).round() as u8,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Matrix<1, 4>, b: [f32; 4]) -> bool {
+        (0..4).all(|i| (a[0][i] - b[i]).abs() < 1e-5)
+    }
+
+    #[test]
+    fn test_blend_modes() {
+        let red = Matrix([[0.5, 0., 0., 0.5]]);
+        let grey = Matrix([[0.5, 0.5, 0.5, 1.]]);
+        let clear = Matrix::default();
+
+        assert!(close(
+            BlendMode::Over.blend(red, grey),
+            [0.75, 0.25, 0.25, 1.]
+        ));
+        assert!(close(BlendMode::Add.blend(red, grey), [1., 0.5, 0.5, 1.]));
+        assert!(close(
+            BlendMode::Multiply.blend(red, grey),
+            [0.5, 0.25, 0.25, 1.]
+        ));
+        assert!(close(
+            BlendMode::Screen.blend(red, grey),
+            [0.75, 0.5, 0.5, 1.]
+        ));
+
+        // Nothing underneath leaves every mode with the source as is, and an
+        // opaque source covers the destination.
+        for mode in [
+            BlendMode::Over,
+            BlendMode::Add,
+            BlendMode::Multiply,
+            BlendMode::Screen,
+        ] {
+            assert!(close(mode.blend(red, clear), [0.5, 0., 0., 0.5]));
+            assert!(close(mode.blend(clear, grey), [0.5, 0.5, 0.5, 1.]));
+        }
+        assert!(close(BlendMode::Over.blend(grey, red), [0.5, 0.5, 0.5, 1.]));
+    }
+
+    #[test]
+    fn test_premultiply() {
+        let color = Color::new(200, 100, 0, 128);
+        let back = unpremultiply(premultiply(color));
+        assert_eq!((back.r, back.g, back.b, back.a), (200, 100, 0, 128));
+        assert_eq!(unpremultiply(Matrix::default()).a, 0);
+        let faint = unpremultiply(Matrix([[1e-4, 1e-4, 1e-4, 1e-4]]));
+        assert_eq!((faint.r, faint.a), (0, 0));
+    }
+}
@@ -0,0 +1,455 @@
+use core::f32;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    matrix::Matrix,
+    matrix_3d::{Point, Point2D, look_at, orthographic, perspective, unproject_ray},
+    sampling::{concentric_disc, regular_polygon},
+};
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProjectionKind {
+    Perspective,
+    Orthographic,
+    Isometric,
+    Dimetric,
+    Fisheye,
+    Equirectangular,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// `fov` is the vertical field of view in radians.
+    Perspective { fov: f32, near: f32, far: f32 },
+    /// `height` is the vertical extent of the view volume in world units.
+    Orthographic { height: f32, near: f32, far: f32 },
+    /// Equidistant fisheye: the distance from the image center is
+    /// proportional to the angle from the view axis. `fov` spans the
+    /// diameter of the image circle, which touches the top and bottom edges.
+    Fisheye { fov: f32 },
+    /// Full 360° by 180° panorama mapping longitude to x and latitude to y.
+    Equirectangular,
+}
+
+impl Projection {
+    /// Projection matrix, for projections that can be expressed as one.
+    pub fn matrix(&self, aspect: f32) -> Option<Matrix<4, 4>> {
+        match *self {
+            Projection::Perspective { fov, near, far } => Some(perspective(fov, aspect, near, far)),
+            Projection::Orthographic { height, near, far } => {
+                let half_height = height / 2.;
+                let half_width = half_height * aspect;
+                Some(orthographic(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    near,
+                    far,
+                ))
+            }
+            Projection::Fisheye { .. } | Projection::Equirectangular => None,
+        }
+    }
+
+    /// The projection at `aspect`, with its matrix inverted once for all
+    /// the rays through it.
+    pub fn unprojection(&self, aspect: f32) -> Unprojection {
+        Unprojection {
+            projection: *self,
+            aspect,
+            inverse: self.matrix(aspect).map(|matrix| matrix.inv()),
+        }
+    }
+
+    /// Camera space ray through `ndc`, or `None` for points outside the
+    /// projection's image, like the corners of a fisheye image circle.
+    /// `unprojection` is cheaper for more than one ray.
+    pub fn ray(&self, ndc: Point2D, aspect: f32) -> Option<(Point, Matrix<1, 4>)> {
+        self.unprojection(aspect).ray(ndc)
+    }
+}
+
+/// A projection set up for generating rays, see `Projection::unprojection`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unprojection {
+    pub projection: Projection,
+    pub aspect: f32,
+    /// Inverse of the projection matrix, for projections with one.
+    pub inverse: Option<Matrix<4, 4>>,
+}
+
+impl Unprojection {
+    /// Camera space ray through `ndc`, as `Projection::ray`.
+    pub fn ray(&self, ndc: Point2D) -> Option<(Point, Matrix<1, 4>)> {
+        let origin = Matrix([[0., 0., 0., 1.]]);
+        let aspect = self.aspect;
+
+        match self.projection {
+            Projection::Perspective { .. } | Projection::Orthographic { .. } => {
+                Some(unproject_ray(ndc, self.inverse?))
+            }
+            Projection::Fisheye { fov } => {
+                let x = ndc.x() * aspect;
+                let y = ndc.y();
+                let r = (x * x + y * y).sqrt();
+
+                if r > 1. {
+                    return None;
+                }
+
+                let theta = r * fov / 2.;
+                let (sin, cos) = theta.sin_cos();
+                let direction = if r > 0. {
+                    Matrix([[sin * x / r, sin * y / r, -cos, 0.]])
+                } else {
+                    Matrix([[0., 0., -1., 0.]])
+                };
+
+                Some((origin, direction))
+            }
+            Projection::Equirectangular => {
+                let longitude = ndc.x() * f32::consts::PI;
+                let latitude = ndc.y() * f32::consts::PI / 2.;
+
+                let (sin_lon, cos_lon) = longitude.sin_cos();
+                let (sin_lat, cos_lat) = latitude.sin_cos();
+
+                Some((
+                    origin,
+                    Matrix([[sin_lon * cos_lat, sin_lat, -cos_lon * cos_lat, 0.]]),
+                ))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aperture {
+    Disc,
+    /// Straight-bladed diaphragm, which gives polygonal bokeh.
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+}
+
+/// Thin lens in front of the projection's pinhole. Points on the plane
+/// `focal_distance` in front of the camera stay sharp.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lens {
+    pub radius: f32,
+    pub focal_distance: f32,
+    pub aperture: Aperture,
+}
+
+impl Lens {
+    /// Point on the lens for a sample in the unit square, in camera space.
+    pub fn sample(&self, u: Point2D) -> Point2D {
+        let p = match self.aperture {
+            Aperture::Disc => concentric_disc(u),
+            Aperture::Polygon { blades, rotation } => regular_polygon(u, blades, rotation),
+        };
+        p * self.radius
+    }
+
+    /// Moves a camera space pinhole ray onto the lens, keeping the point where
+    /// it crosses the focal plane fixed.
+    pub fn refocus(
+        &self,
+        origin: Point,
+        direction: Matrix<1, 4>,
+        u: Point2D,
+    ) -> (Point, Matrix<1, 4>) {
+        // Rays that never reach the focal plane, like the back half of a
+        // panorama, stay pinhole rays.
+        if direction.z() >= 0. {
+            return (origin, direction);
+        }
+
+        let t = (-self.focal_distance - origin.z()) / direction.z();
+        let focus = origin + direction * t;
+
+        let lens = self.sample(u);
+        let origin = origin + Matrix([[lens.x(), lens.y(), 0., 0.]]);
+
+        (origin, (focus - origin).normalize())
+    }
+}
+
+/// Interval over which a frame is exposed, in the same units as the frame time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+}
+
+impl Shutter {
+    /// Time for a sample in [0, 1).
+    pub fn sample(&self, u: f32) -> f32 {
+        self.open + (self.close - self.open) * u
+    }
+}
+
+pub struct Camera {
+    /// Camera-to-world transform, see `look_at`.
+    pub transform: Matrix<4, 4>,
+    pub projection: Projection,
+    /// Pinhole camera when `None`.
+    pub lens: Option<Lens>,
+    pub shutter: Shutter,
+}
+
+impl Camera {
+    pub fn new(transform: Matrix<4, 4>, projection: Projection) -> Self {
+        Camera {
+            transform,
+            projection,
+            lens: None,
+            shutter: Shutter {
+                open: 0.,
+                close: 0.,
+            },
+        }
+    }
+
+    /// Orthographic camera looking down the diagonal of the axes, so the
+    /// x, y and z axes are foreshortened equally.
+    pub fn isometric(target: Point, distance: f32, height: f32) -> Self {
+        // Elevation of atan(1 / sqrt(2)), about 35.26°.
+        let elevation = (1. / 2f32.sqrt()).atan();
+        Camera::orbit_orthographic(target, distance, height, elevation)
+    }
+
+    /// The 2:1 pixel art projection, where lines along the ground axes rise
+    /// one pixel for every two across.
+    pub fn dimetric(target: Point, distance: f32, height: f32) -> Self {
+        // Seen from 45° around, ground lines slope by the sine of the
+        // elevation, so 30°.
+        let elevation = 0.5f32.asin();
+        Camera::orbit_orthographic(target, distance, height, elevation)
+    }
+
+    fn orbit_orthographic(target: Point, distance: f32, height: f32, elevation: f32) -> Self {
+        let azimuth = f32::consts::PI / 4.;
+        let offset = Matrix([[
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+            0.,
+        ]]);
+
+        Camera::new(
+            look_at(
+                target + offset * distance,
+                target,
+                Matrix([[0., 1., 0., 0.]]),
+            ),
+            Projection::Orthographic {
+                height,
+                near: 0.1,
+                far: distance * 2.,
+            },
+        )
+    }
+
+    pub fn view(&self) -> Matrix<4, 4> {
+        self.transform.inv()
+    }
+
+    pub fn view_projection(&self, aspect: f32) -> Option<Matrix<4, 4>> {
+        Some(self.view()(self.projection.matrix(aspect)?))
+    }
+
+    /// World space ray through `ndc`, with `unprojection` set up once per
+    /// frame from `self.projection`. `lens_sample` in the unit square picks
+    /// the point on the lens and is ignored by pinhole cameras.
+    pub fn generate_ray(
+        &self,
+        unprojection: &Unprojection,
+        ndc: Point2D,
+        lens_sample: Point2D,
+    ) -> Option<(Point, Matrix<1, 4>)> {
+        let (mut origin, mut direction) = unprojection.ray(ndc)?;
+
+        if let Some(lens) = self.lens {
+            (origin, direction) = lens.refocus(origin, direction, lens_sample);
+        }
+
+        Some((origin(self.transform), direction(self.transform)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix_3d::{rotate_y, translate};
+
+    fn assert_close(a: Matrix<1, 4>, b: Matrix<1, 4>) {
+        assert_eq!(a.round(4), b.round(4));
+    }
+
+    #[test]
+    fn test_look_at() {
+        let eye = Matrix([[0., 0., -5., 1.]]);
+        let transform = look_at(eye, Matrix([[0., 0., 0., 1.]]), Matrix([[0., 1., 0., 0.]]));
+        let expected = rotate_y(f32::consts::PI)(translate(0., 0., -5.));
+
+        assert_eq!(transform.round(5), expected.round(5));
+    }
+
+    #[test]
+    fn test_perspective_rays() {
+        let projection = Projection::Perspective {
+            fov: f32::consts::PI / 2.,
+            near: 0.1,
+            far: 100.,
+        };
+
+        let (_, center) = projection.ray(Matrix([[0., 0.]]), 2.).unwrap();
+        assert_close(center, Matrix([[0., 0., -1., 0.]]));
+
+        // With a 90° vertical fov the top edge is 45° up.
+        let (_, top) = projection.ray(Matrix([[0., 1.]]), 2.).unwrap();
+        assert_close(top, Matrix([[0., 1., -1., 0.]]).normalize());
+
+        let (_, right) = projection.ray(Matrix([[1., 0.]]), 2.).unwrap();
+        assert_close(right, Matrix([[2., 0., -1., 0.]]).normalize());
+    }
+
+    #[test]
+    fn test_orthographic_rays() {
+        let projection = Projection::Orthographic {
+            height: 4.,
+            near: 0.1,
+            far: 100.,
+        };
+
+        let (origin, direction) = projection.ray(Matrix([[1., 1.]]), 1.5).unwrap();
+        assert_close(direction, Matrix([[0., 0., -1., 0.]]));
+        assert_close(origin, Matrix([[3., 2., -0.1, 1.]]));
+    }
+
+    #[test]
+    fn test_fisheye_rays() {
+        let projection = Projection::Fisheye {
+            fov: f32::consts::PI,
+        };
+
+        let (_, center) = projection.ray(Matrix([[0., 0.]]), 1.).unwrap();
+        assert_close(center, Matrix([[0., 0., -1., 0.]]));
+
+        // The edge of a 180° image circle looks sideways.
+        let (_, edge) = projection.ray(Matrix([[0., 1.]]), 1.).unwrap();
+        assert_close(edge, Matrix([[0., 1., 0., 0.]]));
+
+        let (_, half) = projection.ray(Matrix([[0.5, 0.]]), 1.).unwrap();
+        assert_close(half, Matrix([[1., 0., -1., 0.]]).normalize());
+
+        assert!(projection.ray(Matrix([[1., 1.]]), 1.).is_none());
+        assert!(projection.matrix(1.).is_none());
+    }
+
+    #[test]
+    fn test_equirectangular_rays() {
+        let projection = Projection::Equirectangular;
+
+        let (_, forward) = projection.ray(Matrix([[0., 0.]]), 2.).unwrap();
+        assert_close(forward, Matrix([[0., 0., -1., 0.]]));
+
+        let (_, behind) = projection.ray(Matrix([[1., 0.]]), 2.).unwrap();
+        assert_close(behind, Matrix([[0., 0., 1., 0.]]));
+
+        let (_, right) = projection.ray(Matrix([[0.5, 0.]]), 2.).unwrap();
+        assert_close(right, Matrix([[1., 0., 0., 0.]]));
+
+        let (_, up) = projection.ray(Matrix([[0.3, 1.]]), 2.).unwrap();
+        assert_close(up, Matrix([[0., 1., 0., 0.]]));
+    }
+
+    #[test]
+    fn test_isometric() {
+        let camera = Camera::isometric(Matrix([[0., 0., 0., 1.]]), 10., 4.);
+        let view_projection = camera.view_projection(1.).unwrap();
+
+        // Unit steps along each axis project to equal screen lengths.
+        let origin = Matrix([[0., 0., 0., 1.]])(view_projection);
+        let length = |p: Matrix<1, 4>| {
+            let d = p(view_projection) - origin;
+            (d.x() * d.x() + d.y() * d.y()).sqrt()
+        };
+
+        let x = length(Matrix([[1., 0., 0., 1.]]));
+        let y = length(Matrix([[0., 1., 0., 1.]]));
+        let z = length(Matrix([[0., 0., 1., 1.]]));
+
+        assert!((x - y).abs() < 1e-5);
+        assert!((y - z).abs() < 1e-5);
+
+        let unprojection = camera.projection.unprojection(1.);
+        let (_, direction) = camera
+            .generate_ray(&unprojection, Matrix([[0., 0.]]), Matrix([[0.5, 0.5]]))
+            .unwrap();
+        assert_close(direction, Matrix([[-1., -1., 1., 0.]]).normalize());
+    }
+
+    #[test]
+    fn test_dimetric() {
+        let camera = Camera::dimetric(Matrix([[0., 0., 0., 1.]]), 10., 4.);
+        let view_projection = camera.view_projection(1.).unwrap();
+
+        // Both ground axes rise one unit for every two across.
+        let origin = Matrix([[0., 0., 0., 1.]])(view_projection);
+        for axis in [Matrix([[1., 0., 0., 1.]]), Matrix([[0., 0., 1., 1.]])] {
+            let d = axis(view_projection) - origin;
+            assert!((d.y().abs() / d.x().abs() - 0.5).abs() < 1e-5, "{d:?}");
+        }
+    }
+
+    #[test]
+    fn test_thin_lens_focus() {
+        let mut camera = Camera::new(
+            Matrix::identity(),
+            Projection::Perspective {
+                fov: f32::consts::PI / 2.,
+                near: 0.1,
+                far: 100.,
+            },
+        );
+        camera.lens = Some(Lens {
+            radius: 0.5,
+            focal_distance: 4.,
+            aperture: Aperture::Polygon {
+                blades: 5,
+                rotation: 0.,
+            },
+        });
+
+        let ndc = Matrix([[0.3, -0.2]]);
+        let pinhole = Camera::new(camera.transform, camera.projection);
+        let unprojection = camera.projection.unprojection(1.5);
+        let (o, d) = pinhole
+            .generate_ray(&unprojection, ndc, Matrix([[0.5, 0.5]]))
+            .unwrap();
+        let focus = o + d * ((-4. - o.z()) / d.z());
+
+        // Every lens sample converges on the same point of the focal plane.
+        for u in [[0.1, 0.9], [0.7, 0.2], [0.5, 0.5], [0.99, 0.01]] {
+            let (origin, direction) = camera
+                .generate_ray(&unprojection, ndc, Matrix([u]))
+                .unwrap();
+            let t = (-4. - origin.z()) / direction.z();
+
+            assert_close(origin + direction * t, focus);
+            assert!((origin.z() - o.z()).abs() < 1e-6);
+        }
+
+        // Without a focal plane to converge on, rays are left alone.
+        let lens = camera.lens.unwrap();
+        let backwards = Matrix([[0., 0., 1., 0.]]);
+        let (_, direction) = lens.refocus(o, backwards, Matrix([[0.1, 0.1]]));
+        assert_eq!(direction, backwards);
+    }
+}
@@ -0,0 +1,1237 @@
+use std::fmt;
+
+use crate::{
+    bitmap::{Bitmap, FloatImage},
+    deflate::{InflateError, crc32, crc32_update, zlib_decompress},
+    encode::{ImageFormat, paeth},
+    matrix::Matrix,
+};
+
+/// Images with more pixels are rejected before anything is allocated for them.
+pub const MAX_PIXELS: u64 = 1 << 26;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    /// The data doesn't start with the signature of a format we can read.
+    UnknownFormat,
+    /// The file ended before the image was complete.
+    UnexpectedEof,
+    /// A value the format doesn't allow.
+    Malformed(&'static str),
+    /// Valid for the format, but not something these decoders handle.
+    Unsupported(&'static str),
+    /// Zero sized, or larger than `MAX_PIXELS`.
+    InvalidDimensions {
+        width: u64,
+        height: u64,
+    },
+    /// A PNG chunk whose CRC doesn't match.
+    Checksum,
+    Inflate(InflateError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownFormat => f.write_str("unknown image format"),
+            DecodeError::UnexpectedEof => f.write_str("unexpected end of image data"),
+            DecodeError::Malformed(what) => write!(f, "malformed image: {what}"),
+            DecodeError::Unsupported(what) => write!(f, "unsupported image: {what}"),
+            DecodeError::InvalidDimensions { width, height } => {
+                write!(f, "invalid image dimensions {width}x{height}")
+            }
+            DecodeError::Checksum => f.write_str("PNG chunk CRC mismatch"),
+            DecodeError::Inflate(error) => write!(f, "invalid compressed data: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Inflate(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<InflateError> for DecodeError {
+    fn from(error: InflateError) -> Self {
+        DecodeError::Inflate(error)
+    }
+}
+
+/// Guesses the format from the leading bytes. TGA has no signature, so it is
+/// never detected.
+pub fn detect(data: &[u8]) -> Option<ImageFormat> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some(ImageFormat::Png),
+        [b'B', b'M', ..] => Some(ImageFormat::Bmp),
+        [b'P', b'1'..=b'6', ..] => Some(ImageFormat::Ppm),
+        _ => None,
+    }
+}
+
+/// Decodes a PNG, PPM/PGM/PBM, BMP or Radiance HDR file. Channel values are
+/// kept as stored, scaled to 0..1 for the 8 and 16 bit formats.
+pub fn decode(data: &[u8]) -> Result<FloatImage, DecodeError> {
+    if data.starts_with(b"#?") {
+        return decode_hdr(data);
+    }
+
+    match detect(data) {
+        Some(ImageFormat::Png) => decode_png(data),
+        Some(ImageFormat::Ppm) => decode_ppm(data),
+        Some(ImageFormat::Bmp) => decode_bmp(data),
+        _ => Err(DecodeError::UnknownFormat),
+    }
+}
+
+pub fn decode_bitmap(data: &[u8]) -> Result<Bitmap, DecodeError> {
+    Ok(decode(data)?.to_bitmap())
+}
+
+fn check_dimensions(width: u64, height: u64) -> Result<(u32, u32), DecodeError> {
+    if width == 0 || height == 0 || width.saturating_mul(height) > MAX_PIXELS {
+        return Err(DecodeError::InvalidDimensions { width, height });
+    }
+    Ok((width as u32, height as u32))
+}
+
+fn bytes<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N], DecodeError> {
+    data.get(offset..offset.saturating_add(N))
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(DecodeError::UnexpectedEof)
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], DecodeError> {
+    data.get(offset..offset.saturating_add(len))
+        .ok_or(DecodeError::UnexpectedEof)
+}
+
+struct PngHeader {
+    width: u32,
+    height: u32,
+    depth: usize,
+    color_type: u8,
+    interlaced: bool,
+}
+
+impl PngHeader {
+    fn parse(body: &[u8]) -> Result<Self, DecodeError> {
+        if body.len() != 13 {
+            return Err(DecodeError::Malformed("IHDR length"));
+        }
+
+        let width = u32::from_be_bytes(bytes(body, 0)?);
+        let height = u32::from_be_bytes(bytes(body, 4)?);
+        let (width, height) = check_dimensions(width as u64, height as u64)?;
+
+        let depth = body[8];
+        let color_type = body[9];
+        let valid = match color_type {
+            0 => matches!(depth, 1 | 2 | 4 | 8 | 16),
+            3 => matches!(depth, 1 | 2 | 4 | 8),
+            2 | 4 | 6 => matches!(depth, 8 | 16),
+            _ => false,
+        };
+        if !valid {
+            return Err(DecodeError::Malformed("color type and bit depth"));
+        }
+        if body[10] != 0 || body[11] != 0 {
+            return Err(DecodeError::Malformed("compression or filter method"));
+        }
+        if body[12] > 1 {
+            return Err(DecodeError::Malformed("interlace method"));
+        }
+
+        Ok(PngHeader {
+            width,
+            height,
+            depth: depth as usize,
+            color_type,
+            interlaced: body[12] == 1,
+        })
+    }
+
+    fn channels(&self) -> usize {
+        match self.color_type {
+            0 | 3 => 1,
+            2 => 3,
+            4 => 2,
+            _ => 4,
+        }
+    }
+
+    fn row_bytes(&self, width: u32) -> usize {
+        (width as usize * self.channels() * self.depth).div_ceil(8)
+    }
+
+    /// Each pass as (x0, y0, dx, dy, width, height). Images without
+    /// interlacing are a single pass.
+    fn passes(&self) -> Vec<(u32, u32, u32, u32, u32, u32)> {
+        const ADAM7: [(u32, u32, u32, u32); 7] = [
+            (0, 0, 8, 8),
+            (4, 0, 8, 8),
+            (0, 4, 4, 8),
+            (2, 0, 4, 4),
+            (0, 2, 2, 4),
+            (1, 0, 2, 2),
+            (0, 1, 1, 2),
+        ];
+
+        if !self.interlaced {
+            return vec![(0, 0, 1, 1, self.width, self.height)];
+        }
+
+        ADAM7
+            .iter()
+            .map(|(x0, y0, dx, dy)| {
+                let width = self.width.saturating_sub(*x0).div_ceil(*dx);
+                let height = self.height.saturating_sub(*y0).div_ceil(*dy);
+                (*x0, *y0, *dx, *dy, width, height)
+            })
+            .filter(|pass| pass.4 > 0 && pass.5 > 0)
+            .collect()
+    }
+}
+
+/// Reverses `png_filter` in place, given the already unfiltered row above.
+pub fn png_unfilter(
+    filter: u8,
+    row: &mut [u8],
+    previous: &[u8],
+    bpp: usize,
+) -> Result<(), DecodeError> {
+    if filter > 4 {
+        return Err(DecodeError::Malformed("filter type"));
+    }
+
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = previous[i];
+        let c = if i >= bpp { previous[i - bpp] } else { 0 };
+
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        };
+
+        row[i] = row[i].wrapping_add(predicted);
+    }
+
+    Ok(())
+}
+
+fn png_pixel(
+    header: &PngHeader,
+    row: &[u8],
+    x: usize,
+    palette: &[[u8; 3]],
+    transparency: &[u8],
+) -> Result<Matrix<1, 4>, DecodeError> {
+    let depth = header.depth;
+    let max = ((1u32 << depth) - 1) as f32;
+
+    let sample = |i: usize| -> u16 {
+        match depth {
+            16 => u16::from_be_bytes([row[i * 2], row[i * 2 + 1]]),
+            8 => row[i] as u16,
+            _ => {
+                // Packed samples start at the most significant bit.
+                let bit = i * depth;
+                let shift = 8 - depth - bit % 8;
+                ((row[bit / 8] >> shift) & ((1 << depth) - 1) as u8) as u16
+            }
+        }
+    };
+    // tRNS holds 16-bit samples regardless of the bit depth.
+    let key = |i: usize| {
+        transparency
+            .get(i * 2..i * 2 + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    };
+
+    let base = x * header.channels();
+    let color = match header.color_type {
+        0 => {
+            let v = sample(base);
+            let alpha = if key(0) == Some(v) { 0. } else { 1. };
+            let v = v as f32 / max;
+            Matrix([[v, v, v, alpha]])
+        }
+        2 => {
+            let rgb = [sample(base), sample(base + 1), sample(base + 2)];
+            let opaque = (0..3).any(|i| key(i) != Some(rgb[i]));
+            let [r, g, b] = rgb.map(|v| v as f32 / max);
+            Matrix([[r, g, b, if opaque { 1. } else { 0. }]])
+        }
+        3 => {
+            let index = sample(base) as usize;
+            let [r, g, b] = *palette
+                .get(index)
+                .ok_or(DecodeError::Malformed("palette index out of range"))?;
+            let a = transparency.get(index).copied().unwrap_or(255);
+            Matrix([[r as f32, g as f32, b as f32, a as f32]]) / 255.
+        }
+        4 => {
+            let v = sample(base) as f32 / max;
+            Matrix([[v, v, v, sample(base + 1) as f32 / max]])
+        }
+        _ => {
+            Matrix([[
+                sample(base) as f32,
+                sample(base + 1) as f32,
+                sample(base + 2) as f32,
+                sample(base + 3) as f32,
+            ]]) / max
+        }
+    };
+
+    Ok(color)
+}
+
+/// Every standard color type and bit depth, with or without Adam7
+/// interlacing. tRNS transparency is applied; gamma and color space chunks
+/// are ignored.
+pub fn decode_png(data: &[u8]) -> Result<FloatImage, DecodeError> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return Err(DecodeError::Malformed("PNG signature"));
+    }
+
+    let mut header: Option<PngHeader> = None;
+    let mut palette: Vec<[u8; 3]> = Vec::new();
+    let mut transparency: Vec<u8> = Vec::new();
+    let mut compressed: Vec<u8> = Vec::new();
+
+    let mut offset = PNG_SIGNATURE.len();
+    loop {
+        let len = u32::from_be_bytes(bytes(data, offset)?) as usize;
+        let kind: [u8; 4] = bytes(data, offset + 4)?;
+        let body = slice(data, offset + 8, len)?;
+        let crc = u32::from_be_bytes(bytes(data, offset + 8 + len)?);
+        offset += 12 + len;
+
+        if crc != crc32_update(crc32(&kind), body) {
+            return Err(DecodeError::Checksum);
+        }
+        if header.is_none() && &kind != b"IHDR" {
+            return Err(DecodeError::Malformed("IHDR must come first"));
+        }
+
+        match &kind {
+            b"IHDR" if header.is_some() => return Err(DecodeError::Malformed("duplicate IHDR")),
+            b"IHDR" => header = Some(PngHeader::parse(body)?),
+            b"PLTE" => {
+                if len == 0 || !len.is_multiple_of(3) || len > 256 * 3 {
+                    return Err(DecodeError::Malformed("palette length"));
+                }
+                palette = body.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+            }
+            b"tRNS" => transparency = body.to_vec(),
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            // Ancillary chunks have a lowercase first letter and are safe to skip.
+            _ if kind[0].is_ascii_lowercase() => {}
+            _ => return Err(DecodeError::Unsupported("unknown critical chunk")),
+        }
+    }
+
+    let header = header.ok_or(DecodeError::Malformed("missing IHDR"))?;
+    if header.color_type == 3 && palette.is_empty() {
+        return Err(DecodeError::Malformed("missing palette"));
+    }
+
+    let passes = header.passes();
+    let expected: usize = passes
+        .iter()
+        .map(|pass| pass.5 as usize * (1 + header.row_bytes(pass.4)))
+        .sum();
+
+    let raw = zlib_decompress(&compressed, expected)?;
+    if raw.len() != expected {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let bpp = (header.channels() * header.depth / 8).max(1);
+    let mut image = FloatImage::new(header.width, header.height);
+    let mut rows = raw.as_slice();
+
+    for (x0, y0, dx, dy, width, height) in passes {
+        let row_bytes = header.row_bytes(width);
+        let mut previous = vec![0; row_bytes];
+
+        for y in 0..height {
+            let (filter, rest) = rows.split_first().ok_or(DecodeError::UnexpectedEof)?;
+            let mut row = rest[..row_bytes].to_vec();
+            rows = &rest[row_bytes..];
+
+            png_unfilter(*filter, &mut row, &previous, bpp)?;
+
+            for x in 0..width {
+                let color = png_pixel(&header, &row, x as usize, &palette, &transparency)?;
+                image.set(x0 + x * dx, y0 + y * dy, color);
+            }
+
+            previous = row;
+        }
+    }
+
+    Ok(image)
+}
+
+/// Netpbm header and ASCII raster reader. Whitespace separates tokens and
+/// `#` starts a comment that runs to the end of the line.
+struct Tokens<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl Tokens<'_> {
+    fn skip(&mut self) {
+        while let Some(byte) = self.data.get(self.position) {
+            if *byte == b'#' {
+                while self.data.get(self.position).is_some_and(|b| *b != b'\n') {
+                    self.position += 1;
+                }
+            } else if byte.is_ascii_whitespace() {
+                self.position += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<u32, DecodeError> {
+        self.skip();
+
+        let start = self.position;
+        let mut value: u32 = 0;
+        while let Some(digit) = self.data.get(self.position).filter(|b| b.is_ascii_digit()) {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add((digit - b'0') as u32))
+                .ok_or(DecodeError::Malformed("number too large"))?;
+            self.position += 1;
+        }
+
+        if self.position == start {
+            return match self.data.get(self.position) {
+                Some(_) => Err(DecodeError::Malformed("expected a number")),
+                None => Err(DecodeError::UnexpectedEof),
+            };
+        }
+        Ok(value)
+    }
+
+    /// Plain PBM pixels are single digits that need no separator.
+    fn bit(&mut self) -> Result<u32, DecodeError> {
+        self.skip();
+
+        let bit = match self.data.get(self.position) {
+            Some(b'0') => 0,
+            Some(b'1') => 1,
+            Some(_) => return Err(DecodeError::Malformed("expected 0 or 1")),
+            None => return Err(DecodeError::UnexpectedEof),
+        };
+        self.position += 1;
+        Ok(bit)
+    }
+}
+
+/// PBM, PGM and PPM in both plain (P1-P3) and raw (P4-P6) forms, with up to
+/// 16 bits per sample.
+pub fn decode_ppm(data: &[u8]) -> Result<FloatImage, DecodeError> {
+    let kind = match data {
+        [b'P', kind @ b'1'..=b'6', ..] => kind - b'0',
+        _ => return Err(DecodeError::Malformed("netpbm magic number")),
+    };
+
+    let mut tokens = Tokens { data, position: 2 };
+    let width = tokens.number()?;
+    let height = tokens.number()?;
+    let bitmap = kind == 1 || kind == 4;
+    let max = if bitmap { 1 } else { tokens.number()? };
+    if max == 0 || max > 65535 {
+        return Err(DecodeError::Malformed("maximum value"));
+    }
+
+    let (width, height) = check_dimensions(width as u64, height as u64)?;
+    let channels = if kind == 3 || kind == 6 { 3 } else { 1 };
+    let samples = width as usize * height as usize * channels;
+
+    let values: Vec<u32> = match kind {
+        1..=3 => {
+            // Every sample takes at least one byte, so a short file fails
+            // before the image is allocated.
+            if data.len() - tokens.position < samples {
+                return Err(DecodeError::UnexpectedEof);
+            }
+
+            let mut values = Vec::with_capacity(samples);
+            for _ in 0..samples {
+                let value = if bitmap {
+                    tokens.bit()?
+                } else {
+                    tokens.number()?
+                };
+                if value > max {
+                    return Err(DecodeError::Malformed("sample above maximum value"));
+                }
+                values.push(value);
+            }
+            values
+        }
+        _ => {
+            // A single whitespace byte separates the header from the raster.
+            if !data
+                .get(tokens.position)
+                .is_some_and(u8::is_ascii_whitespace)
+            {
+                return Err(DecodeError::Malformed("header terminator"));
+            }
+            let raster = &data[tokens.position + 1..];
+
+            if bitmap {
+                let row_bytes = (width as usize).div_ceil(8);
+                let raster = slice(raster, 0, row_bytes * height as usize)?;
+                (0..samples)
+                    .map(|i| {
+                        let (y, x) = (i / width as usize, i % width as usize);
+                        (raster[y * row_bytes + x / 8] >> (7 - x % 8)) as u32 & 1
+                    })
+                    .collect()
+            } else if max < 256 {
+                slice(raster, 0, samples)?
+                    .iter()
+                    .map(|v| *v as u32)
+                    .collect()
+            } else {
+                slice(raster, 0, samples * 2)?
+                    .chunks(2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]) as u32)
+                    .collect()
+            }
+        }
+    };
+
+    if values.iter().any(|v| *v > max) {
+        return Err(DecodeError::Malformed("sample above maximum value"));
+    }
+
+    let mut image = FloatImage::new(width, height);
+    for (pixel, samples) in image.pixels.iter_mut().zip(values.chunks(channels)) {
+        let v = |i: usize| samples[i] as f32 / max as f32;
+        *pixel = match (bitmap, channels) {
+            // In PBM 1 is black.
+            (true, _) => Matrix([[1. - v(0), 1. - v(0), 1. - v(0), 1.]]),
+            (false, 1) => Matrix([[v(0), v(0), v(0), 1.]]),
+            _ => Matrix([[v(0), v(1), v(2), 1.]]),
+        };
+    }
+
+    Ok(image)
+}
+
+fn bmp_channel(pixel: u32, mask: u32) -> Option<f32> {
+    if mask == 0 {
+        return None;
+    }
+    let shift = mask.trailing_zeros();
+    let max = (mask >> shift) as f32;
+    Some(((pixel & mask) >> shift) as f32 / max)
+}
+
+/// Uncompressed BMPs with 1, 2, 4 or 8 bit palettes, or 16, 24 and 32 bit
+/// pixels with default or explicit channel masks. Rows may be stored either
+/// way up.
+pub fn decode_bmp(data: &[u8]) -> Result<FloatImage, DecodeError> {
+    if !data.starts_with(b"BM") {
+        return Err(DecodeError::Malformed("BMP signature"));
+    }
+
+    let offset = u32::from_le_bytes(bytes(data, 10)?) as usize;
+    let header_size = u32::from_le_bytes(bytes(data, 14)?) as usize;
+
+    let (width, height, bpp, compression, colors_used) = match header_size {
+        12 => (
+            u16::from_le_bytes(bytes(data, 18)?) as i64,
+            u16::from_le_bytes(bytes(data, 20)?) as i64,
+            u16::from_le_bytes(bytes(data, 24)?),
+            0,
+            0,
+        ),
+        40.. => (
+            i32::from_le_bytes(bytes(data, 18)?) as i64,
+            i32::from_le_bytes(bytes(data, 22)?) as i64,
+            u16::from_le_bytes(bytes(data, 28)?),
+            u32::from_le_bytes(bytes(data, 30)?),
+            u32::from_le_bytes(bytes(data, 46)?),
+        ),
+        _ => return Err(DecodeError::Unsupported("BMP header size")),
+    };
+
+    // A negative height means the rows are stored top-down.
+    let top_down = height < 0;
+    let (width, height) = check_dimensions(width.max(0) as u64, height.unsigned_abs())?;
+
+    let masks: [u32; 4] = match (compression, bpp) {
+        (0, 1 | 2 | 4 | 8) => [0; 4],
+        (0, 16) => [0x7c00, 0x03e0, 0x001f, 0],
+        (0, 24 | 32) => [0xff0000, 0x00ff00, 0x0000ff, 0],
+        // With BI_BITFIELDS the masks sit right after the 40 byte header,
+        // whether or not a longer header has fields for them.
+        (3 | 6, 16 | 32) => {
+            let mask = |i: usize| -> Result<u32, DecodeError> {
+                Ok(u32::from_le_bytes(bytes(data, 54 + i * 4)?))
+            };
+            let alpha = if compression == 6 || header_size >= 56 {
+                mask(3)?
+            } else {
+                0
+            };
+            [mask(0)?, mask(1)?, mask(2)?, alpha]
+        }
+        (1 | 2, _) => return Err(DecodeError::Unsupported("RLE compressed BMP")),
+        _ => return Err(DecodeError::Unsupported("BMP bit depth or compression")),
+    };
+    for mask in masks.iter().filter(|mask| **mask != 0) {
+        // Masks must be a single run of bits.
+        if (mask >> mask.trailing_zeros()).trailing_ones() != mask.count_ones() {
+            return Err(DecodeError::Malformed("channel mask"));
+        }
+    }
+
+    let mut palette = Vec::new();
+    if bpp <= 8 {
+        let entry_size = if header_size == 12 { 3 } else { 4 };
+        let count = match colors_used {
+            0 => 1 << bpp,
+            n => n.min(256) as usize,
+        };
+        for i in 0..count {
+            let [b, g, r] = bytes(data, 14 + header_size + i * entry_size)?;
+            palette.push(Matrix([[r as f32, g as f32, b as f32, 255.]]) / 255.);
+        }
+    }
+
+    let stride = (width as usize * bpp as usize).div_ceil(32) * 4;
+    let raster = slice(data, offset, stride * height as usize)?;
+
+    let mut image = FloatImage::new(width, height);
+    for (i, row) in raster.chunks(stride).enumerate() {
+        let y = if top_down { i } else { height as usize - 1 - i };
+
+        for x in 0..width as usize {
+            let color = match bpp {
+                1 | 2 | 4 | 8 => {
+                    let bit = x * bpp as usize;
+                    let shift = 8 - bpp as usize - bit % 8;
+                    let index = (row[bit / 8] >> shift) as usize & ((1 << bpp) - 1);
+                    *palette
+                        .get(index)
+                        .ok_or(DecodeError::Malformed("palette index out of range"))?
+                }
+                _ => {
+                    let pixel = match bpp {
+                        16 => u16::from_le_bytes([row[x * 2], row[x * 2 + 1]]) as u32,
+                        24 => u32::from_le_bytes([row[x * 3], row[x * 3 + 1], row[x * 3 + 2], 0]),
+                        _ => u32::from_le_bytes(bytes(row, x * 4)?),
+                    };
+                    let channel = |i: usize| bmp_channel(pixel, masks[i]).unwrap_or(0.);
+                    let alpha = bmp_channel(pixel, masks[3]).unwrap_or(1.);
+                    Matrix([[channel(0), channel(1), channel(2), alpha]])
+                }
+            };
+            image.set(x as u32, y as u32, color);
+        }
+    }
+
+    Ok(image)
+}
+
+/// One scanline of RGBE pixels, either flat or in the run length encoding
+/// Radiance writes for widths from 8 to 32767.
+fn hdr_scanline(
+    data: &[u8],
+    position: &mut usize,
+    width: usize,
+) -> Result<Vec<[u8; 4]>, DecodeError> {
+    let rle = (8..0x8000).contains(&width)
+        && data.get(*position..*position + 2) == Some(&[2, 2])
+        && data.get(*position + 2).is_some_and(|b| b & 0x80 == 0);
+
+    if !rle {
+        let bytes = slice(data, *position, width * 4)?;
+        *position += width * 4;
+        return Ok(bytes.chunks(4).map(|c| [c[0], c[1], c[2], c[3]]).collect());
+    }
+
+    let [_, _, high, low] = bytes(data, *position)?;
+    if ((high as usize) << 8 | low as usize) != width {
+        return Err(DecodeError::Malformed("scanline width"));
+    }
+    *position += 4;
+
+    // Each channel is stored separately as a mix of runs and literal spans.
+    let mut pixels = vec![[0; 4]; width];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let [count] = bytes(data, *position)?;
+            *position += 1;
+
+            if count > 128 {
+                let count = count as usize - 128;
+                let [value] = bytes(data, *position)?;
+                *position += 1;
+                if x + count > width {
+                    return Err(DecodeError::Malformed("run past end of scanline"));
+                }
+                for pixel in pixels[x..x + count].iter_mut() {
+                    pixel[channel] = value;
+                }
+                x += count;
+            } else {
+                let count = count as usize;
+                if count == 0 || x + count > width {
+                    return Err(DecodeError::Malformed("run past end of scanline"));
+                }
+                for (pixel, value) in pixels[x..x + count]
+                    .iter_mut()
+                    .zip(slice(data, *position, count)?)
+                {
+                    pixel[channel] = *value;
+                }
+                *position += count;
+                x += count;
+            }
+        }
+    }
+
+    Ok(pixels)
+}
+
+/// Radiance RGBE (`.hdr`) images, flat or run length encoded. Values are
+/// linear and unbounded.
+pub fn decode_hdr(data: &[u8]) -> Result<FloatImage, DecodeError> {
+    let mut lines = data.split(|b| *b == b'\n');
+    let mut position = 0;
+    let mut next_line = || {
+        let line = lines.next()?;
+        position += line.len() + 1;
+        Some(line)
+    };
+
+    let magic = next_line().ok_or(DecodeError::UnexpectedEof)?;
+    if magic != b"#?RADIANCE" && magic != b"#?RGBE" {
+        return Err(DecodeError::Malformed("Radiance signature"));
+    }
+
+    // Header variables run until an empty line.
+    loop {
+        let line = next_line().ok_or(DecodeError::UnexpectedEof)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix(b"FORMAT=")
+            && format != b"32-bit_rle_rgbe"
+        {
+            return Err(DecodeError::Unsupported("Radiance pixel format"));
+        }
+    }
+
+    let resolution = next_line().ok_or(DecodeError::UnexpectedEof)?;
+    let resolution =
+        std::str::from_utf8(resolution).map_err(|_| DecodeError::Malformed("resolution string"))?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+    let (flip, height, width) = match fields[..] {
+        ["-Y", height, "+X", width] => (false, height, width),
+        ["+Y", height, "+X", width] => (true, height, width),
+        [_, _, _, _] => return Err(DecodeError::Unsupported("Radiance image orientation")),
+        _ => return Err(DecodeError::Malformed("resolution string")),
+    };
+    let parse = |v: &str| {
+        v.parse::<u64>()
+            .map_err(|_| DecodeError::Malformed("resolution string"))
+    };
+    let (width, height) = check_dimensions(parse(width)?, parse(height)?)?;
+
+    // A run covers at most 127 pixels of one channel in two bytes, so even
+    // a fully run length encoded image needs a byte per 16 pixels. Checking
+    // a looser bound catches absurd sizes before allocating.
+    if data.len().saturating_sub(position) < width as usize * height as usize / 32 {
+        return Err(DecodeError::UnexpectedEof);
+    }
+
+    let mut image = FloatImage::new(width, height);
+    for row in 0..height {
+        let pixels = hdr_scanline(data, &mut position, width as usize)?;
+        let y = if flip { height - 1 - row } else { row };
+
+        for (x, [r, g, b, e]) in pixels.into_iter().enumerate() {
+            let color = if e == 0 {
+                Matrix([[0., 0., 0., 1.]])
+            } else {
+                let scale = 2f32.powi(e as i32 - 136);
+                Matrix([[
+                    (r as f32 + 0.5) * scale,
+                    (g as f32 + 0.5) * scale,
+                    (b as f32 + 0.5) * scale,
+                    1.,
+                ]])
+            };
+            image.set(x as u32, y, color);
+        }
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitmap::Color,
+        deflate::Compression,
+        encode::{encode_bmp, encode_png, encode_ppm},
+        sampling::Rng,
+    };
+
+    fn gradient(width: u32, height: u32) -> Bitmap {
+        let mut bmp = Bitmap::new(width, height);
+        for (y, row) in bmp.rows.iter_mut().enumerate() {
+            for (x, color) in row.iter_mut().enumerate() {
+                *color = Color::new((x * 40) as u8, (y * 60) as u8, (x * y) as u8, 200 + x as u8);
+            }
+        }
+        bmp
+    }
+
+    fn assert_same(a: &Bitmap, b: &Bitmap, alpha: bool) {
+        assert_eq!((a.width, a.height), (b.width, b.height));
+        for (row_a, row_b) in a.rows.iter().zip(b.rows.iter()) {
+            for (a, b) in row_a.iter().zip(row_b.iter()) {
+                assert_eq!((a.r, a.g, a.b), (b.r, b.g, b.b));
+                if alpha {
+                    assert_eq!(a.a, b.a);
+                }
+            }
+        }
+    }
+
+    /// Builds a PNG from samples, unfiltered, so every color type, depth and
+    /// interlacing combination can be produced.
+    fn png(
+        width: u32,
+        height: u32,
+        depth: usize,
+        color_type: u8,
+        interlaced: bool,
+        extra: &[(&[u8; 4], Vec<u8>)],
+        sample: impl Fn(u32, u32) -> Vec<u16>,
+    ) -> Vec<u8> {
+        let header = PngHeader {
+            width,
+            height,
+            depth,
+            color_type,
+            interlaced,
+        };
+
+        let mut raw = Vec::new();
+        for (x0, y0, dx, dy, w, h) in header.passes() {
+            for y in 0..h {
+                let mut bits = crate::deflate::BitWriter::new();
+                for x in 0..w {
+                    for v in sample(x0 + x * dx, y0 + y * dy) {
+                        bits.write_code(v as u32, depth as u32);
+                    }
+                }
+                raw.push(0);
+                // write_code packs from the top bit, which after reversing
+                // each byte gives PNG's big-endian sample order.
+                raw.extend(bits.finish().iter().map(|b| b.reverse_bits()));
+            }
+        }
+
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&width.to_be_bytes());
+        ihdr.extend_from_slice(&height.to_be_bytes());
+        ihdr.extend_from_slice(&[depth as u8, color_type, 0, 0, interlaced as u8]);
+
+        let mut chunks = vec![(b"IHDR", ihdr)];
+        chunks.extend(extra.iter().map(|(kind, body)| (*kind, body.clone())));
+        chunks.push((
+            b"IDAT",
+            crate::deflate::zlib_compress(&raw, Compression::Compressed),
+        ));
+        chunks.push((b"IEND", Vec::new()));
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        for (kind, body) in chunks {
+            out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            out.extend_from_slice(kind);
+            out.extend_from_slice(&body);
+            out.extend_from_slice(&crc32_update(crc32(kind), &body).to_be_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn test_png_round_trip() {
+        let bmp = gradient(7, 5);
+        for compression in [Compression::Stored, Compression::Compressed] {
+            let decoded = decode_bitmap(&encode_png(&bmp, compression)).unwrap();
+            assert_same(&bmp, &decoded, true);
+        }
+    }
+
+    #[test]
+    fn test_png_color_types() {
+        for interlaced in [false, true] {
+            for (color_type, depths) in [
+                (0, &[1, 2, 4, 8, 16][..]),
+                (2, &[8, 16]),
+                (4, &[8, 16]),
+                (6, &[8, 16]),
+            ] {
+                for depth in depths {
+                    let max = (1u32 << depth) - 1;
+                    let value = |x: u32, y: u32| ((x * 3 + y * 5) % (max + 1)) as u16;
+                    let channels = PngHeader {
+                        width: 1,
+                        height: 1,
+                        depth: *depth,
+                        color_type,
+                        interlaced,
+                    }
+                    .channels();
+
+                    let data = png(11, 9, *depth, color_type, interlaced, &[], |x, y| {
+                        (0..channels as u32).map(|c| value(x + c, y)).collect()
+                    });
+                    let image = decode(&data).unwrap();
+
+                    for y in 0..9 {
+                        for x in 0..11 {
+                            let v = |c: u32| value(x + c, y) as f32 / max as f32;
+                            let expected = match color_type {
+                                0 => [v(0), v(0), v(0), 1.],
+                                2 => [v(0), v(1), v(2), 1.],
+                                4 => [v(0), v(0), v(0), v(1)],
+                                _ => [v(0), v(1), v(2), v(3)],
+                            };
+                            assert_eq!(image.get(x, y).0[0], expected, "{color_type} {depth}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_png_palette() {
+        let palette = vec![255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let transparency = vec![128];
+
+        for depth in [1, 2, 4, 8] {
+            let data = png(
+                5,
+                2,
+                depth,
+                3,
+                false,
+                &[(b"PLTE", palette.clone()), (b"tRNS", transparency.clone())],
+                |x, y| vec![((x + y) % 2) as u16],
+            );
+            let bmp = decode_bitmap(&data).unwrap();
+            let c = bmp.rows[0][0];
+            assert_eq!((c.r, c.g, c.b, c.a), (255, 0, 0, 128));
+            let c = bmp.rows[1][0];
+            assert_eq!((c.r, c.g, c.b, c.a), (0, 255, 0, 255));
+        }
+
+        // Index 2 is past the end of a two entry palette.
+        let data = png(
+            2,
+            1,
+            8,
+            3,
+            false,
+            &[(b"PLTE", palette[..6].to_vec())],
+            |x, _| vec![x as u16 * 2],
+        );
+        assert_eq!(
+            decode(&data).err(),
+            Some(DecodeError::Malformed("palette index out of range"))
+        );
+
+        let data = png(2, 1, 8, 3, false, &[], |_, _| vec![0]);
+        assert_eq!(
+            decode(&data).err(),
+            Some(DecodeError::Malformed("missing palette"))
+        );
+    }
+
+    #[test]
+    fn test_png_transparency_key() {
+        let key = vec![0, 1, 0, 2, 0, 3];
+        let data = png(2, 1, 16, 2, false, &[(b"tRNS", key)], |x, _| {
+            vec![1, 2, 3 + x as u16]
+        });
+        let image = decode(&data).unwrap();
+        assert_eq!(image.get(0, 0).w(), 0.);
+        assert_eq!(image.get(1, 0).w(), 1.);
+    }
+
+    #[test]
+    fn test_ppm() {
+        let bmp = gradient(6, 4);
+        assert_same(&bmp, &decode_bitmap(&encode_ppm(&bmp)).unwrap(), false);
+
+        let plain = b"P3\n# a comment\n2 1\n15\n15 0 0  0 15 0\n";
+        let image = decode(plain).unwrap();
+        assert_eq!(image.get(0, 0).0[0], [1., 0., 0., 1.]);
+        assert_eq!(image.get(1, 0).0[0], [0., 1., 0., 1.]);
+
+        let gray = b"P2 2 1 65535 0 65535";
+        assert_eq!(decode(gray).unwrap().get(1, 0).0[0], [1., 1., 1., 1.]);
+
+        let mut gray16 = b"P5 2 1 1000\n".to_vec();
+        gray16.extend_from_slice(&[0x01, 0xf4, 0x03, 0xe8]);
+        assert_eq!(decode(&gray16).unwrap().get(0, 0).x(), 0.5);
+
+        // Plain PBM digits need no separators, and 1 is black.
+        let image = decode(b"P1 3 1 101").unwrap();
+        assert_eq!(image.get(0, 0).x(), 0.);
+        assert_eq!(image.get(1, 0).x(), 1.);
+
+        let image = decode(b"P4 10 1\n\xa0\x40").unwrap();
+        let xs: Vec<f32> = (0..10).map(|x| image.get(x, 0).x()).collect();
+        assert_eq!(xs, [0., 1., 0., 1., 1., 1., 1., 1., 1., 0.]);
+
+        assert_eq!(
+            decode(b"P2 1 1 10 11").err(),
+            Some(DecodeError::Malformed("sample above maximum value"))
+        );
+        assert_eq!(
+            decode(b"P6 1 1 255\n\x00").err(),
+            Some(DecodeError::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn test_bmp() {
+        let bmp = gradient(5, 3);
+        assert_same(&bmp, &decode_bitmap(&encode_bmp(&bmp)).unwrap(), false);
+
+        // A 2x2 top-down 1-bit image with a two color palette.
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&62u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&(-2i32).to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        data.extend_from_slice(&[0, 0, 255, 0, 255, 0, 0, 0]);
+        data.extend_from_slice(&[0b1000_0000, 0, 0, 0, 0b0100_0000, 0, 0, 0]);
+
+        let bmp = decode_bitmap(&data).unwrap();
+        let rgb = |x: usize, y: usize| {
+            let c = bmp.rows[y][x];
+            (c.r, c.g, c.b)
+        };
+        assert_eq!(rgb(0, 0), (0, 0, 255));
+        assert_eq!(rgb(1, 0), (255, 0, 0));
+        assert_eq!(rgb(0, 1), (255, 0, 0));
+        assert_eq!(rgb(1, 1), (0, 0, 255));
+
+        // 32-bit BI_BITFIELDS with alpha in the low byte.
+        let mut data = b"BM".to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&70u32.to_le_bytes());
+        data.extend_from_slice(&56u32.to_le_bytes());
+        data.extend_from_slice(&1i32.to_le_bytes());
+        data.extend_from_slice(&1i32.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&32u16.to_le_bytes());
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(&[0; 20]);
+        for mask in [0xff000000u32, 0xff0000, 0xff00, 0xff] {
+            data.extend_from_slice(&mask.to_le_bytes());
+        }
+        data.extend_from_slice(&0x10203040u32.to_le_bytes());
+
+        let c = decode_bitmap(&data).unwrap().rows[0][0];
+        assert_eq!((c.r, c.g, c.b, c.a), (0x10, 0x20, 0x30, 0x40));
+
+        // A channel may take all 32 bits, but not a broken run of them.
+        let masked = |masks: [u32; 4], pixel: u32| {
+            let mut data = data[..54].to_vec();
+            for mask in masks {
+                data.extend_from_slice(&mask.to_le_bytes());
+            }
+            data.extend_from_slice(&pixel.to_le_bytes());
+            decode_bitmap(&data)
+        };
+        let c = masked([0xffffffff, 0, 0, 0], 0xffffffff).unwrap().rows[0][0];
+        assert_eq!((c.r, c.g, c.b, c.a), (255, 0, 0, 255));
+        assert_eq!(
+            masked([0xff00ff00, 0, 0, 0], 0).err(),
+            Some(DecodeError::Malformed("channel mask"))
+        );
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(
+            decode(b"P6 100000 100000 255\n").err(),
+            Some(DecodeError::InvalidDimensions {
+                width: 100000,
+                height: 100000
+            })
+        );
+        assert_eq!(
+            decode(b"P5 0 1 255\n").err(),
+            Some(DecodeError::InvalidDimensions {
+                width: 0,
+                height: 1
+            })
+        );
+        assert_eq!(decode(b"GIF89a").err(), Some(DecodeError::UnknownFormat));
+    }
+
+    #[test]
+    fn test_malformed_never_panics() {
+        let bmp = gradient(9, 7);
+        let files = [
+            encode_png(&bmp, Compression::Compressed),
+            png(9, 7, 2, 0, true, &[], |x, y| vec![((x + y) % 4) as u16]),
+            encode_ppm(&bmp),
+            b"P3 2 2 255 1 2 3 4 5 6 7 8 9 10 11 12".to_vec(),
+            encode_bmp(&bmp),
+        ];
+
+        for file in files.iter() {
+            assert!(decode(file).is_ok());
+
+            // A plain PPM cut between samples is still a valid file.
+            for len in 0..file.len() {
+                let result = decode(&file[..len]);
+                assert!(result.is_err() || file.starts_with(b"P3"));
+            }
+
+            let mut rng = Rng::new(7);
+            for _ in 0..500 {
+                let mut corrupt = file.clone();
+                for _ in 0..1 + rng.next_below(4) {
+                    let i = rng.next_below(corrupt.len() as u32) as usize;
+                    corrupt[i] = rng.next_u32() as u8;
+                }
+                let _ = decode(&corrupt);
+            }
+        }
+
+        // Corrupt PNG chunks are caught by their CRC.
+        let mut corrupt = files[0].clone();
+        corrupt[40] ^= 1;
+        assert_eq!(decode(&corrupt).err(), Some(DecodeError::Checksum));
+    }
+
+    /// RGBE encoding of `image`, run length encoded when `rle` is set.
+    fn hdr(
+        width: usize,
+        height: usize,
+        rle: bool,
+        pixel: impl Fn(usize, usize) -> [u8; 4],
+    ) -> Vec<u8> {
+        let mut out =
+            format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {height} +X {width}\n").into_bytes();
+
+        for y in 0..height {
+            let row: Vec<[u8; 4]> = (0..width).map(|x| pixel(x, y)).collect();
+            if !rle {
+                out.extend(row.iter().flatten());
+                continue;
+            }
+
+            out.extend_from_slice(&[2, 2, (width >> 8) as u8, width as u8]);
+            for channel in 0..4 {
+                let values: Vec<u8> = row.iter().map(|p| p[channel]).collect();
+                let mut x = 0;
+                while x < width {
+                    let run = values[x..]
+                        .iter()
+                        .take_while(|v| **v == values[x])
+                        .count()
+                        .min(127);
+                    if run >= 3 {
+                        out.extend_from_slice(&[128 + run as u8, values[x]]);
+                        x += run;
+                    } else {
+                        let count = (width - x).min(2);
+                        out.push(count as u8);
+                        out.extend_from_slice(&values[x..x + count]);
+                        x += count;
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_hdr() {
+        let pixel = |x: usize, y: usize| {
+            if x < 6 {
+                [128, 64, 32, 129]
+            } else {
+                [(x * 10) as u8, (y * 20) as u8, 200, 128 + y as u8]
+            }
+        };
+
+        for rle in [false, true] {
+            let image = decode(&hdr(12, 3, rle, pixel)).unwrap();
+            assert_eq!((image.width, image.height), (12, 3));
+
+            // 128.5 * 2^(129 - 136) is just over one.
+            let c = image.get(2, 1);
+            assert_eq!(c.0[0], [128.5 / 128., 64.5 / 128., 32.5 / 128., 1.]);
+
+            let c = image.get(9, 2);
+            assert_eq!(c.x(), 90.5 * 2f32.powi(130 - 136));
+            assert_eq!(c.y(), 40.5 * 2f32.powi(130 - 136));
+        }
+
+        let flipped = b"#?RGBE\n\n+Y 2 +X 1\n\x80\x80\x80\x81\x00\x00\x00\x00";
+        let image = decode(flipped).unwrap();
+        assert_eq!(image.get(0, 0).x(), 0.);
+        assert!(image.get(0, 1).x() > 1.);
+
+        assert_eq!(
+            decode(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n\0\0\0\0").err(),
+            Some(DecodeError::Unsupported("Radiance pixel format"))
+        );
+
+        let file = hdr(12, 3, true, pixel);
+        for len in 0..file.len() {
+            assert!(decode(&file[..len]).is_err());
+        }
+        let mut rng = Rng::new(3);
+        for _ in 0..500 {
+            let mut corrupt = file.clone();
+            let i = rng.next_below(corrupt.len() as u32) as usize;
+            corrupt[i] = rng.next_u32() as u8;
+            let _ = decode(&corrupt);
+        }
+    }
+}
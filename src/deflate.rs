@@ -0,0 +1,741 @@
+use std::fmt;
+
+/// Length symbol 257 + i covers lengths `LENGTH_BASE[i]..` with
+/// `LENGTH_EXTRA[i]` extra bits.
+pub const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+pub const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    /// Uncompressed blocks. Fast and predictable in size.
+    Stored,
+    /// LZ77 matching coded with the fixed Huffman tables.
+    Compressed,
+}
+
+/// Packs bits least significant first, as deflate requires.
+pub struct BitWriter {
+    pub bytes: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        BitWriter::new()
+    }
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        BitWriter {
+            bytes: Vec::new(),
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    pub fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits;
+
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are defined most significant bit first, so they go into
+    /// the stream reversed.
+    pub fn write_code(&mut self, code: u32, bits: u32) {
+        self.write(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    pub fn align(&mut self) {
+        if self.count > 0 {
+            self.write(0, 8 - self.count);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+/// Code and length of a literal/length symbol in the fixed Huffman table.
+fn fixed_literal_code(symbol: u16) -> (u32, u32) {
+    match symbol {
+        0..=143 => (0b00110000 + symbol as u32, 8),
+        144..=255 => (0b110010000 + (symbol as u32 - 144), 9),
+        256..=279 => (symbol as u32 - 256, 7),
+        _ => (0b11000000 + (symbol as u32 - 280), 8),
+    }
+}
+
+fn write_length(writer: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASE
+        .iter()
+        .rposition(|base| *base as usize <= length)
+        .unwrap();
+    let (code, bits) = fixed_literal_code(257 + index as u16);
+
+    writer.write_code(code, bits);
+    writer.write(
+        (length - LENGTH_BASE[index] as usize) as u32,
+        LENGTH_EXTRA[index] as u32,
+    );
+}
+
+fn write_distance(writer: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASE
+        .iter()
+        .rposition(|base| *base as usize <= distance)
+        .unwrap();
+
+    writer.write_code(index as u32, 5);
+    writer.write(
+        (distance - DISTANCE_BASE[index] as usize) as u32,
+        DISTANCE_EXTRA[index] as u32,
+    );
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Raw deflate stream (RFC 1951).
+pub fn deflate(data: &[u8], compression: Compression) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    match compression {
+        Compression::Stored => {
+            let mut chunks = data.chunks(65535).peekable();
+
+            if chunks.peek().is_none() {
+                writer.write(1, 1);
+                writer.write(0, 2);
+                writer.align();
+                writer.bytes.extend_from_slice(&[0, 0, 0xff, 0xff]);
+            }
+
+            while let Some(chunk) = chunks.next() {
+                writer.write(chunks.peek().is_none() as u32, 1);
+                writer.write(0, 2);
+                writer.align();
+
+                let len = chunk.len() as u16;
+                writer.bytes.extend_from_slice(&len.to_le_bytes());
+                writer.bytes.extend_from_slice(&(!len).to_le_bytes());
+                writer.bytes.extend_from_slice(chunk);
+            }
+        }
+        Compression::Compressed => {
+            // A single fixed Huffman block. Matches are found through hash
+            // chains over the previous 32 KiB.
+            writer.write(1, 1);
+            writer.write(1, 2);
+
+            let mut head = vec![usize::MAX; 1 << HASH_BITS];
+            let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+            let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+                if i + MIN_MATCH <= data.len() {
+                    let h = hash(data, i);
+                    prev[i % WINDOW_SIZE] = head[h];
+                    head[h] = i;
+                }
+            };
+
+            let mut i = 0;
+            while i < data.len() {
+                let mut best_length = 0;
+                let mut best_distance = 0;
+
+                if i + MIN_MATCH <= data.len() {
+                    let max_length = MAX_MATCH.min(data.len() - i);
+                    let mut candidate = head[hash(data, i)];
+                    let mut chain = 0;
+
+                    while candidate != usize::MAX
+                        && i - candidate <= WINDOW_SIZE
+                        && chain < MAX_CHAIN
+                    {
+                        let length = data[candidate..]
+                            .iter()
+                            .zip(data[i..i + max_length].iter())
+                            .take_while(|(a, b)| a == b)
+                            .count();
+
+                        if length > best_length {
+                            best_length = length;
+                            best_distance = i - candidate;
+                            if length == max_length {
+                                break;
+                            }
+                        }
+
+                        let next = prev[candidate % WINDOW_SIZE];
+                        if next == usize::MAX || next >= candidate {
+                            break;
+                        }
+                        candidate = next;
+                        chain += 1;
+                    }
+                }
+
+                if best_length >= MIN_MATCH {
+                    write_length(&mut writer, best_length);
+                    write_distance(&mut writer, best_distance);
+
+                    for j in i..i + best_length {
+                        insert(j, &mut head, &mut prev);
+                    }
+                    i += best_length;
+                } else {
+                    let (code, bits) = fixed_literal_code(data[i] as u16);
+                    writer.write_code(code, bits);
+
+                    insert(i, &mut head, &mut prev);
+                    i += 1;
+                }
+            }
+
+            let (code, bits) = fixed_literal_code(256);
+            writer.write_code(code, bits);
+        }
+    }
+
+    writer.finish()
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+
+    // 5552 is the largest run that cannot overflow `b` before reducing.
+    for chunk in data.chunks(5552) {
+        for byte in chunk.iter() {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+
+    table
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// CRC-32 as used by PNG and zip, continued from a previous `crc`. Start with 0.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut c = !crc;
+    for byte in data.iter() {
+        c = CRC32_TABLE[((c ^ *byte as u32) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Deflate stream wrapped in a zlib header and Adler-32 trailer (RFC 1950).
+pub fn zlib_compress(data: &[u8], compression: Compression) -> Vec<u8> {
+    let level = match compression {
+        Compression::Stored => 0,
+        Compression::Compressed => 1,
+    };
+
+    // Deflate with a 32 KiB window, and the check bits that make the header
+    // a multiple of 31.
+    let cmf: u16 = 0x78;
+    let mut flg: u16 = level << 6;
+    flg += 31 - ((cmf << 8) + flg) % 31;
+
+    let mut out = vec![cmf as u8, flg as u8];
+    out.extend(deflate(data, compression));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InflateError {
+    /// The stream ended in the middle of a block.
+    UnexpectedEof,
+    /// Block type 3, which is reserved.
+    InvalidBlockType,
+    /// A stored block whose length doesn't match its complement.
+    StoredLength,
+    /// Code lengths that don't describe a usable Huffman code.
+    InvalidCodeLengths,
+    /// A code that isn't assigned, or a reserved length or distance symbol.
+    InvalidSymbol,
+    /// A match that reaches back before the start of the output.
+    InvalidDistance,
+    /// More output than the caller allowed.
+    OutputTooLarge,
+    /// A zlib header that isn't deflate, or asks for a preset dictionary.
+    InvalidHeader,
+    /// The Adler-32 trailer doesn't match the output.
+    Checksum,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            InflateError::UnexpectedEof => "unexpected end of deflate stream",
+            InflateError::InvalidBlockType => "invalid deflate block type",
+            InflateError::StoredLength => "stored block length mismatch",
+            InflateError::InvalidCodeLengths => "invalid Huffman code lengths",
+            InflateError::InvalidSymbol => "invalid Huffman symbol",
+            InflateError::InvalidDistance => "match distance too far back",
+            InflateError::OutputTooLarge => "decompressed data too large",
+            InflateError::InvalidHeader => "invalid zlib header",
+            InflateError::Checksum => "Adler-32 mismatch",
+        };
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for InflateError {}
+
+/// Reads bits least significant first, the inverse of `BitWriter`.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    pub fn read(&mut self, bits: u32) -> Result<u32, InflateError> {
+        while self.count < bits {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or(InflateError::UnexpectedEof)?;
+            self.buffer |= (byte as u64) << self.count;
+            self.position += 1;
+            self.count += 8;
+        }
+
+        let value = (self.buffer & ((1 << bits) - 1)) as u32;
+        self.buffer >>= bits;
+        self.count -= bits;
+        Ok(value)
+    }
+
+    /// Skips to the next byte boundary and hands out the next `len` bytes.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], InflateError> {
+        // Whole bytes still in the buffer haven't been consumed yet.
+        self.position -= (self.count / 8) as usize;
+        self.buffer = 0;
+        self.count = 0;
+
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or(InflateError::UnexpectedEof)?;
+        self.position += len;
+        Ok(bytes)
+    }
+}
+
+/// Canonical Huffman code, stored as the number of codes of each length
+/// and the symbols sorted by code.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0; 16];
+        for length in lengths.iter() {
+            counts[*length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Reject over-subscribed codes. Incomplete ones are allowed, since
+        // a distance code with a single symbol is legal.
+        let mut left: i32 = 1;
+        for count in counts[1..].iter() {
+            left = (left << 1) - *count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCodeLengths);
+            }
+        }
+
+        let mut offsets = [0; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbols[offsets[*length as usize] as usize] = symbol as u16;
+                offsets[*length as usize] += 1;
+            }
+        }
+
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+
+        for count in self.counts[1..].iter() {
+            code |= reader.read(1)? as i32;
+            let count = *count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+
+        Err(InflateError::InvalidSymbol)
+    }
+}
+
+fn fixed_tables() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+
+    (
+        Huffman::new(&lengths).unwrap(),
+        Huffman::new(&[5; 30]).unwrap(),
+    )
+}
+
+fn dynamic_tables(reader: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    const ORDER: [usize; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+
+    let literals = reader.read(5)? as usize + 257;
+    let distances = reader.read(5)? as usize + 1;
+    let code_lengths = reader.read(4)? as usize + 4;
+
+    if literals > 286 || distances > 30 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    let mut lengths = [0; 19];
+    for i in ORDER.iter().take(code_lengths) {
+        lengths[*i] = reader.read(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(reader)?;
+
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + reader.read(2)? as usize),
+            17 => (0, 3 + reader.read(3)? as usize),
+            18 => (0, 11 + reader.read(7)? as usize),
+            _ => return Err(InflateError::InvalidCodeLengths),
+        };
+
+        if i + repeat > lengths.len() {
+            return Err(InflateError::InvalidCodeLengths);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+
+    // Without an end of block code the block could never finish.
+    if lengths[256] == 0 {
+        return Err(InflateError::InvalidCodeLengths);
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+/// Decodes a raw deflate stream (RFC 1951), producing at most `limit` bytes.
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    inflate_from(&mut BitReader::new(data), limit)
+}
+
+fn inflate_from(reader: &mut BitReader, limit: usize) -> Result<Vec<u8>, InflateError> {
+    let mut out = Vec::new();
+
+    loop {
+        let last = reader.read(1)? == 1;
+
+        match reader.read(2)? {
+            0 => {
+                let header = reader.read_bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let complement = u16::from_le_bytes([header[2], header[3]]);
+                if len != !complement {
+                    return Err(InflateError::StoredLength);
+                }
+                if out.len() + len as usize > limit {
+                    return Err(InflateError::OutputTooLarge);
+                }
+                out.extend_from_slice(reader.read_bytes(len as usize)?);
+            }
+            block_type @ (1 | 2) => {
+                let (literal_code, distance_code) = if block_type == 1 {
+                    fixed_tables()
+                } else {
+                    dynamic_tables(reader)?
+                };
+
+                loop {
+                    let symbol = literal_code.decode(reader)? as usize;
+
+                    if symbol < 256 {
+                        if out.len() >= limit {
+                            return Err(InflateError::OutputTooLarge);
+                        }
+                        out.push(symbol as u8);
+                        continue;
+                    }
+                    if symbol == 256 {
+                        break;
+                    }
+
+                    let index = symbol - 257;
+                    if index >= LENGTH_BASE.len() {
+                        return Err(InflateError::InvalidSymbol);
+                    }
+                    let length = LENGTH_BASE[index] as usize
+                        + reader.read(LENGTH_EXTRA[index] as u32)? as usize;
+
+                    let index = distance_code.decode(reader)? as usize;
+                    if index >= DISTANCE_BASE.len() {
+                        return Err(InflateError::InvalidSymbol);
+                    }
+                    let distance = DISTANCE_BASE[index] as usize
+                        + reader.read(DISTANCE_EXTRA[index] as u32)? as usize;
+
+                    if distance > out.len() {
+                        return Err(InflateError::InvalidDistance);
+                    }
+                    if out.len() + length > limit {
+                        return Err(InflateError::OutputTooLarge);
+                    }
+
+                    // Byte by byte, since a match may overlap its own output.
+                    let start = out.len() - distance;
+                    for i in 0..length {
+                        out.push(out[start + i]);
+                    }
+                }
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+/// Decodes a zlib stream and checks its Adler-32 trailer.
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let [cmf, flg, ..] = *data else {
+        return Err(InflateError::UnexpectedEof);
+    };
+
+    let method = cmf & 0x0f;
+    let window = cmf >> 4;
+    let dictionary = flg & 0x20 != 0;
+    if method != 8
+        || window > 7
+        || dictionary
+        || !(cmf as u16 * 256 + flg as u16).is_multiple_of(31)
+    {
+        return Err(InflateError::InvalidHeader);
+    }
+
+    let mut reader = BitReader::new(&data[2..]);
+    let out = inflate_from(&mut reader, limit)?;
+
+    let trailer = reader.read_bytes(4)?;
+    if u32::from_be_bytes(trailer.try_into().unwrap()) != adler32(&out) {
+        return Err(InflateError::Checksum);
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(&[]), 1);
+    }
+
+    #[test]
+    fn test_stored() {
+        let out = deflate(b"abc", Compression::Stored);
+        assert_eq!(out, vec![0b001, 3, 0, 0xfc, 0xff, b'a', b'b', b'c']);
+
+        assert_eq!(deflate(&[], Compression::Stored), vec![1, 0, 0, 0xff, 0xff]);
+
+        // Split into blocks of at most 65535 bytes.
+        let data = vec![7; 70000];
+        let out = deflate(&data, Compression::Stored);
+        assert_eq!(out.len(), 70000 + 2 * 5);
+        assert_eq!(out[0], 0);
+        assert_eq!(out[5 + 65535], 1);
+    }
+
+    #[test]
+    fn test_compressed_shrinks() {
+        let data: Vec<u8> = (0..10000).map(|i| (i % 64) as u8).collect();
+        let out = deflate(&data, Compression::Compressed);
+        assert!(out.len() < data.len() / 10);
+
+        let header = zlib_compress(&data, Compression::Compressed);
+        assert_eq!(((header[0] as u16) << 8 | header[1] as u16) % 31, 0);
+    }
+
+    #[test]
+    fn test_inflate_round_trip() {
+        let data: Vec<u8> = (0..100000u64).map(|i| (i * i % 251) as u8).collect();
+
+        for compression in [Compression::Stored, Compression::Compressed] {
+            let compressed = zlib_compress(&data, compression);
+            assert_eq!(zlib_decompress(&compressed, data.len()), Ok(data.clone()));
+            assert_eq!(
+                zlib_decompress(&compressed, data.len() - 1),
+                Err(InflateError::OutputTooLarge)
+            );
+        }
+
+        assert_eq!(
+            inflate(&deflate(&[], Compression::Compressed), 0),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_inflate_dynamic() {
+        // zlib at level 9 picks a dynamic Huffman block for this input.
+        let compressed = [
+            0x78, 0xda, 0x15, 0x88, 0x41, 0x01, 0x00, 0x30, 0x10, 0x82, 0xaa, 0x58, 0x8d, 0xc3,
+            0xfe, 0x19, 0xe6, 0xe0, 0x05, 0x70, 0x34, 0x15, 0x8a, 0x92, 0x63, 0x63, 0x38, 0x49,
+            0x7f, 0xec, 0xc9, 0x03, 0x26, 0x38, 0x0e, 0x42,
+        ];
+        assert_eq!(compressed[2] >> 1 & 3, 2);
+
+        let out = zlib_decompress(&compressed, 1000).unwrap();
+        assert_eq!(out, b"aabad dcaadacca baabaaaacacaa dabaa baca");
+    }
+
+    #[test]
+    fn test_inflate_malformed() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i * 7 % 13) as u8).collect();
+        let compressed = zlib_compress(&data, Compression::Compressed);
+
+        // Every truncation fails cleanly.
+        for len in 0..compressed.len() {
+            assert!(zlib_decompress(&compressed[..len], data.len()).is_err());
+        }
+
+        let mut corrupt = compressed.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            zlib_decompress(&corrupt, data.len()),
+            Err(InflateError::Checksum)
+        );
+
+        // A distance before the start of the output.
+        let mut writer = BitWriter::new();
+        writer.write(1, 1);
+        writer.write(1, 2);
+        write_length(&mut writer, 3);
+        write_distance(&mut writer, 1);
+        assert_eq!(
+            inflate(&writer.finish(), 100),
+            Err(InflateError::InvalidDistance)
+        );
+
+        assert_eq!(inflate(&[0b111], 100), Err(InflateError::InvalidBlockType));
+        assert_eq!(
+            inflate(&[1, 3, 0, 0, 0], 100),
+            Err(InflateError::StoredLength)
+        );
+        assert_eq!(
+            zlib_decompress(&[0x78, 0x00], 100),
+            Err(InflateError::InvalidHeader)
+        );
+    }
+}
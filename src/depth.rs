@@ -0,0 +1,173 @@
+use crate::{
+    cross_product,
+    matrix::Matrix,
+    matrix_3d::{Mesh, Triangle, screen},
+};
+
+/// Allowance in normalized device depth before something counts as
+/// behind a surface, for lines and points lying on it.
+const BIAS: f32 = 1e-4;
+
+/// Normalized device depth of the nearest surface under each pixel centre,
+/// from -1 on the near plane to 1 on the far plane, for hidden surface
+/// tests where painter's order isn't enough. Uncovered pixels are infinitely
+/// far away.
+pub struct DepthBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub depth: Vec<f32>,
+}
+
+impl DepthBuffer {
+    pub fn new(width: u32, height: u32) -> DepthBuffer {
+        DepthBuffer {
+            width,
+            height,
+            depth: vec![f32::INFINITY; width as usize * height as usize],
+        }
+    }
+
+    /// Depth at pixel `(x, y)`, infinite off the buffer.
+    pub fn get(&self, x: i32, y: i32) -> f32 {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return f32::INFINITY;
+        }
+        self.depth[(y as u32 * self.width + x as u32) as usize]
+    }
+
+    /// Whether something at `depth` on pixel `(x, y)` is in front of the
+    /// surfaces there. The pixel's four neighbours count too, since lines
+    /// along an edge can stray half a pixel from where its triangles were
+    /// sampled.
+    pub fn visible(&self, x: i32, y: i32, depth: f32) -> bool {
+        let nearest = [(0, 0), (1, 0), (-1, 0), (0, 1), (0, -1)]
+            .iter()
+            .map(|(dx, dy)| self.get(x + dx, y + dy))
+            .fold(f32::NEG_INFINITY, f32::max);
+        depth <= nearest + BIAS
+    }
+
+    /// Keeps the nearer of the stored depth and `trig`'s, whichever way
+    /// the triangle faces. Triangles reaching behind the camera are
+    /// skipped, as the rasterizer does.
+    pub fn render_trig(&mut self, trig: Triangle, view_projection: Matrix<4, 4>) {
+        self.render_trig_offset(trig, view_projection, 0., 0.);
+    }
+
+    /// Like `render_trig`, pushing the triangle back by `slope` times its
+    /// steepest change in depth per pixel plus `constant`, as
+    /// glPolygonOffset does. Shadow maps use it to keep surfaces from
+    /// shadowing themselves.
+    pub fn render_trig_offset(
+        &mut self,
+        trig: Triangle,
+        view_projection: Matrix<4, 4>,
+        slope: f32,
+        constant: f32,
+    ) {
+        let clip = [trig.0, trig.1, trig.2].map(|p| p(view_projection));
+        if clip.iter().any(|p| p.w() <= 0.) {
+            return;
+        }
+
+        let [s0, s1, s2] = clip.map(|p| screen(p, self.width as f32, self.height as f32));
+        let [z0, z1, z2] = clip.map(|p| p.z() / p.w());
+
+        let area = cross_product(s0, s1, s2);
+        if area == 0. {
+            return;
+        }
+
+        let dz_dx =
+            (z0 * (s1.y() - s2.y()) + z1 * (s2.y() - s0.y()) + z2 * (s0.y() - s1.y())) / area;
+        let dz_dy =
+            (z0 * (s2.x() - s1.x()) + z1 * (s0.x() - s2.x()) + z2 * (s1.x() - s0.x())) / area;
+        let offset = slope * dz_dx.abs().max(dz_dy.abs()) + constant;
+
+        let min_x = s0.x().min(s1.x()).min(s2.x()).max(0.) as usize;
+        let max_x =
+            (s0.x().max(s1.x()).max(s2.x()).ceil().max(0.) as usize).min(self.width as usize);
+        let min_y = s0.y().min(s1.y()).min(s2.y()).max(0.) as usize;
+        let max_y =
+            (s0.y().max(s1.y()).max(s2.y()).ceil().max(0.) as usize).min(self.height as usize);
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let p = Matrix([[x as f32 + 0.5, y as f32 + 0.5]]);
+                // Normalized device depth is affine in screen space, so the
+                // plain screen barycentrics interpolate it.
+                let b0 = cross_product(s1, s2, p) / area;
+                let b1 = cross_product(s2, s0, p) / area;
+                let b2 = cross_product(s0, s1, p) / area;
+                if b0 < 0. || b1 < 0. || b2 < 0. {
+                    continue;
+                }
+
+                let depth = &mut self.depth[y * self.width as usize + x];
+                *depth = depth.min(z0 * b0 + z1 * b1 + z2 * b2 + offset);
+            }
+        }
+    }
+
+    pub fn render_mesh(&mut self, mesh: &Mesh, view_projection: Matrix<4, 4>) {
+        for trig in mesh.0.iter() {
+            self.render_trig(*trig, view_projection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        RenderSettings,
+        matrix_3d::{cube, rotate_y, translate},
+    };
+
+    #[test]
+    fn test_depth_buffer() {
+        let view_projection = RenderSettings::default()
+            .camera(0.)
+            .view_projection(1.)
+            .unwrap();
+        let mut buffer = DepthBuffer::new(32, 32);
+
+        // A cube further back, then one in front of it.
+        buffer.render_mesh(&cube().apply(translate(0., 0., 2.)), view_projection);
+        let far = buffer.get(16, 16);
+        assert!(far > -1. && far < 1.);
+        buffer.render_mesh(&cube().apply(translate(0., 0., -1.)), view_projection);
+        let near = buffer.get(16, 16);
+        assert!(near < far);
+
+        // Drawing the far one again changes nothing.
+        buffer.render_mesh(&cube().apply(translate(0., 0., 2.)), view_projection);
+        assert_eq!(buffer.get(16, 16), near);
+
+        assert_eq!(buffer.get(0, 0), f32::INFINITY);
+        assert_eq!(buffer.get(-1, 40), f32::INFINITY);
+        assert!(buffer.visible(16, 16, near));
+        assert!(!buffer.visible(16, 16, far));
+        assert!(buffer.visible(0, 0, 1.));
+
+        // A face tilted away from the camera is pushed back further than
+        // one facing it.
+        let mut offset = DepthBuffer::new(32, 32);
+        let facing = cube().apply(translate(0., 0., -1.));
+        offset.render_trig_offset(facing.0[0], view_projection, 1., 0.);
+        offset.render_trig_offset(facing.0[1], view_projection, 1., 0.);
+        let mut plain = DepthBuffer::new(32, 32);
+        plain.render_trig(facing.0[0], view_projection);
+        plain.render_trig(facing.0[1], view_projection);
+        let pushed = offset.get(16, 16) - plain.get(16, 16);
+        assert!((0. ..1e-3).contains(&pushed));
+        let tilted = cube().apply(rotate_y(1.2)).apply(translate(0., 0., -1.));
+        let mut offset = DepthBuffer::new(32, 32);
+        offset.render_mesh(&tilted, view_projection);
+        let mut steep = DepthBuffer::new(32, 32);
+        for trig in tilted.0.iter() {
+            steep.render_trig_offset(*trig, view_projection, 1., 0.);
+        }
+        assert!(steep.get(16, 16) - offset.get(16, 16) > pushed);
+    }
+}
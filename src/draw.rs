@@ -0,0 +1,517 @@
+use crate::{
+    aabb::Aabb,
+    bitmap::{Bitmap, Color},
+    depth::DepthBuffer,
+    matrix::Matrix,
+    matrix_3d::{Mesh, Point, Point2D, screen},
+};
+
+/// Drawing primitives on top of the triangle filler. Coordinates are in
+/// pixels with pixel `(x, y)` covering `x..x + 1` and `y..y + 1`.
+impl Bitmap {
+    /// Sets a pixel, ignoring coordinates off the bitmap.
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: Color) {
+        if x >= 0 && y >= 0 && x < self.width as i32 && y < self.height as i32 {
+            self.rows[y as usize][x as usize] = color;
+        }
+    }
+
+    /// Lays `color` over a pixel as if it covered `coverage` of it.
+    fn blend_pixel(&mut self, x: i32, y: i32, color: Color, coverage: f32) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        let alpha = coverage.clamp(0., 1.) * color.a as f32 / 255.;
+        let under = &mut self.rows[y as usize][x as usize];
+        let mix = |a: u8, b: u8| (a as f32 * alpha + b as f32 * (1. - alpha)).round() as u8;
+        *under = Color::new(
+            mix(color.r, under.r),
+            mix(color.g, under.g),
+            mix(color.b, under.b),
+            (255. * alpha + under.a as f32 * (1. - alpha)).round() as u8,
+        );
+    }
+
+    /// Bresenham line through the pixels containing `a` and `b`.
+    pub fn draw_line(&mut self, a: Point2D, b: Point2D, color: Color) {
+        let Some((a, b)) = self.clip_line(a, b) else {
+            return;
+        };
+        bresenham(a, b, |x, y| self.set_pixel(x, y, color));
+    }
+
+    /// Xiaolin Wu's antialiased line, shading the two pixels either side of
+    /// the exact line by how close their centres are to it.
+    pub fn draw_line_aa(&mut self, a: Point2D, b: Point2D, color: Color) {
+        let Some((a, b)) = self.clip_line(a, b) else {
+            return;
+        };
+        // Shift so integers fall on pixel centres.
+        let (mut x0, mut y0) = (a.x() - 0.5, a.y() - 0.5);
+        let (mut x1, mut y1) = (b.x() - 0.5, b.y() - 0.5);
+
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+        if steep {
+            (x0, y0, x1, y1) = (y0, x0, y1, x1);
+        }
+        if x0 > x1 {
+            (x0, y0, x1, y1) = (x1, y1, x0, y0);
+        }
+        let gradient = if x1 == x0 { 1. } else { (y1 - y0) / (x1 - x0) };
+
+        let mut plot = |x: f32, y: f32, coverage: f32| {
+            if steep {
+                self.blend_pixel(y as i32, x as i32, color, coverage)
+            } else {
+                self.blend_pixel(x as i32, y as i32, color, coverage)
+            }
+        };
+
+        // The end pixels are weighted by how much of them the line spans.
+        let mut ends = [0.; 2];
+        for (i, (x, y, gap)) in [(x0, y0, 1. - fract(x0 + 0.5)), (x1, y1, fract(x1 + 0.5))]
+            .into_iter()
+            .enumerate()
+        {
+            let end = x.round();
+            let y = y + gradient * (end - x);
+            plot(end, y.floor(), (1. - fract(y)) * gap);
+            plot(end, y.floor() + 1., fract(y) * gap);
+            ends[i] = end;
+        }
+
+        let mut y = y0 + gradient * (ends[0] - x0) + gradient;
+        let mut x = ends[0] + 1.;
+        while x < ends[1] {
+            plot(x, y.floor(), 1. - fract(y));
+            plot(x, y.floor() + 1., fract(y));
+            y += gradient;
+            x += 1.;
+        }
+    }
+
+    /// Antialiased line `width` pixels wide with round caps.
+    pub fn draw_thick_line(&mut self, a: Point2D, b: Point2D, width: f32, color: Color) {
+        let radius = width / 2.;
+        let min_x = (a.x().min(b.x()) - radius - 1.).floor().max(0.) as i32;
+        let max_x = (a.x().max(b.x()) + radius + 1.)
+            .ceil()
+            .min(self.width as f32) as i32;
+        let min_y = (a.y().min(b.y()) - radius - 1.).floor().max(0.) as i32;
+        let max_y = (a.y().max(b.y()) + radius + 1.)
+            .ceil()
+            .min(self.height as f32) as i32;
+
+        let along = b - a;
+        let length = along.x() * along.x() + along.y() * along.y();
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let p = Matrix([[x as f32 + 0.5, y as f32 + 0.5]]) - a;
+                let t = if length == 0. {
+                    0.
+                } else {
+                    ((p.x() * along.x() + p.y() * along.y()) / length).clamp(0., 1.)
+                };
+                let d = p - along * t;
+                let distance = (d.x() * d.x() + d.y() * d.y()).sqrt();
+                let coverage = radius + 0.5 - distance;
+                if coverage > 0. {
+                    self.blend_pixel(x, y, color, coverage);
+                }
+            }
+        }
+    }
+
+    /// Midpoint circle outline around the pixel containing `center`.
+    pub fn draw_circle(&mut self, center: Point2D, radius: f32, color: Color) {
+        let (cx, cy) = (center.x().floor() as i32, center.y().floor() as i32);
+        let mut x = radius.round() as i32;
+        let mut y = 0;
+        let mut error = 1 - x;
+
+        while x >= y {
+            for (dx, dy) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                self.set_pixel(cx + dx, cy + dy, color);
+            }
+
+            y += 1;
+            if error < 0 {
+                error += 2 * y + 1;
+            } else {
+                x -= 1;
+                error += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Round dot `size` pixels across, or the single pixel containing `p`
+    /// for sizes up to one.
+    pub fn draw_point(&mut self, p: Point2D, size: f32, color: Color) {
+        if size <= 1. {
+            self.set_pixel(p.x().floor() as i32, p.y().floor() as i32, color);
+        } else {
+            self.draw_thick_line(p, p, size, color);
+        }
+    }
+
+    /// World space line through `view_projection`, cut off at the near
+    /// plane. With `depth`, pixels behind the surfaces in it are left out.
+    pub fn draw_line_3d(
+        &mut self,
+        a: Point,
+        b: Point,
+        view_projection: Matrix<4, 4>,
+        color: Color,
+        depth: Option<&DepthBuffer>,
+    ) {
+        let Some((a, b)) = clip_near(a(view_projection), b(view_projection)) else {
+            return;
+        };
+        let (width, height) = (self.width as f32, self.height as f32);
+        let (sa, sb) = (screen(a, width, height), screen(b, width, height));
+        let (za, zb) = (a.z() / a.w(), b.z() / b.w());
+
+        let Some((ca, cb)) = self.clip_line(sa, sb) else {
+            return;
+        };
+        let along = sb - sa;
+        let length = along.x() * along.x() + along.y() * along.y();
+
+        bresenham(ca, cb, |x, y| {
+            if let Some(depth) = depth {
+                // Like the depth buffer, normalized device depth runs
+                // straight across the screen.
+                let p = Matrix([[x as f32 + 0.5, y as f32 + 0.5]]) - sa;
+                let t = if length == 0. {
+                    0.
+                } else {
+                    ((p.x() * along.x() + p.y() * along.y()) / length).clamp(0., 1.)
+                };
+                if !depth.visible(x, y, za + (zb - za) * t) {
+                    return;
+                }
+            }
+            self.set_pixel(x, y, color);
+        });
+    }
+
+    /// World space point through `view_projection`, drawn as `draw_point`
+    /// does unless it's behind the camera or, with `depth`, a surface.
+    pub fn draw_point_3d(
+        &mut self,
+        p: Point,
+        view_projection: Matrix<4, 4>,
+        size: f32,
+        color: Color,
+        depth: Option<&DepthBuffer>,
+    ) {
+        let clip = p(view_projection);
+        if clip.z() < -clip.w() {
+            return;
+        }
+        let s = screen(clip, self.width as f32, self.height as f32);
+        if let Some(depth) = depth
+            && !depth.visible(
+                s.x().floor() as i32,
+                s.y().floor() as i32,
+                clip.z() / clip.w(),
+            )
+        {
+            return;
+        }
+        self.draw_point(s, size, color);
+    }
+
+    /// Edges of every triangle in `mesh`. Pass a depth buffer holding the
+    /// mesh, and whatever else is in the scene, to remove hidden lines.
+    pub fn draw_wireframe(
+        &mut self,
+        mesh: &Mesh,
+        view_projection: Matrix<4, 4>,
+        color: Color,
+        depth: Option<&DepthBuffer>,
+    ) {
+        for trig in mesh.0.iter() {
+            for (a, b) in [(trig.0, trig.1), (trig.1, trig.2), (trig.2, trig.0)] {
+                self.draw_line_3d(a, b, view_projection, color, depth);
+            }
+        }
+    }
+
+    /// Face normals `length` long from the middle of each triangle.
+    pub fn draw_normals(
+        &mut self,
+        mesh: &Mesh,
+        view_projection: Matrix<4, 4>,
+        length: f32,
+        color: Color,
+    ) {
+        for trig in mesh.0.iter() {
+            let center = (trig.0 + trig.1 + trig.2) / 3.;
+            let tip = center + trig.normal() * length;
+            self.draw_line_3d(center, tip, view_projection, color, None);
+        }
+    }
+
+    /// The twelve edges of `aabb`.
+    pub fn draw_aabb(&mut self, aabb: &Aabb, view_projection: Matrix<4, 4>, color: Color) {
+        if aabb.is_empty() {
+            return;
+        }
+        let corners = aabb.corners();
+        for (i, corner) in corners.iter().enumerate() {
+            for axis in 0..3 {
+                let j = i | 1 << axis;
+                if j != i {
+                    self.draw_line_3d(*corner, corners[j], view_projection, color, None);
+                }
+            }
+        }
+    }
+
+    /// Sun symbol at a light's `position`, with a unit line along the
+    /// `direction` it shines in for directional and spot lights.
+    pub fn draw_light(
+        &mut self,
+        position: Point,
+        direction: Option<Matrix<1, 4>>,
+        view_projection: Matrix<4, 4>,
+        color: Color,
+    ) {
+        if let Some(direction) = direction {
+            self.draw_line_3d(
+                position,
+                position + direction.normalize(),
+                view_projection,
+                color,
+                None,
+            );
+        }
+
+        let clip = position(view_projection);
+        if clip.z() < -clip.w() {
+            return;
+        }
+        let center = screen(clip, self.width as f32, self.height as f32);
+        self.draw_circle(center, 3., color);
+        for i in 0..8 {
+            let angle = i as f32 * std::f32::consts::FRAC_PI_4;
+            let ray = Matrix([[angle.cos(), angle.sin()]]);
+            self.draw_line(center + ray * 5., center + ray * 7., color);
+        }
+    }
+
+    /// `a` to `b` cut to the bitmap by Liang–Barsky, so lines running far
+    /// off screen don't walk every pixel on the way.
+    fn clip_line(&self, a: Point2D, b: Point2D) -> Option<(Point2D, Point2D)> {
+        let d = b - a;
+        let (mut t0, mut t1) = (0_f32, 1_f32);
+        for (p, q) in [
+            (-d.x(), a.x()),
+            (d.x(), self.width as f32 - a.x()),
+            (-d.y(), a.y()),
+            (d.y(), self.height as f32 - a.y()),
+        ] {
+            if p == 0. {
+                if q < 0. {
+                    return None;
+                }
+            } else {
+                let t = q / p;
+                if p < 0. {
+                    t0 = t0.max(t);
+                } else {
+                    t1 = t1.min(t);
+                }
+            }
+        }
+        (t0 <= t1).then(|| (a + d * t0, a + d * t1))
+    }
+}
+
+/// Calls `plot` for each pixel of the line between the pixels holding `a`
+/// and `b`.
+fn bresenham(a: Point2D, b: Point2D, mut plot: impl FnMut(i32, i32)) {
+    let (mut x, mut y) = (a.x().floor() as i32, a.y().floor() as i32);
+    let (x1, y1) = (b.x().floor() as i32, b.y().floor() as i32);
+    let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+    let (sx, sy) = ((x1 - x).signum(), (y1 - y).signum());
+    let mut error = dx + dy;
+
+    loop {
+        plot(x, y);
+        if x == x1 && y == y1 {
+            break;
+        }
+        let e2 = 2 * error;
+        if e2 >= dy {
+            error += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            error += dx;
+            y += sy;
+        }
+    }
+}
+
+/// Cuts the clip space segment `a` to `b` at the near plane, `z = -w`.
+fn clip_near(a: Matrix<1, 4>, b: Matrix<1, 4>) -> Option<(Matrix<1, 4>, Matrix<1, 4>)> {
+    let (da, db) = (a.z() + a.w(), b.z() + b.w());
+    match (da >= 0., db >= 0.) {
+        (true, true) => Some((a, b)),
+        (false, false) => None,
+        (true, false) => Some((a, a + (b - a) * (da / (da - db)))),
+        (false, true) => Some((a + (b - a) * (da / (da - db)), b)),
+    }
+}
+
+fn fract(v: f32) -> f32 {
+    v - v.floor()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        RenderSettings,
+        matrix_3d::{cube, translate},
+    };
+
+    const WHITE: Color = Color {
+        r: 255,
+        g: 255,
+        b: 255,
+        a: 255,
+    };
+
+    fn point(x: f32, y: f32) -> Point2D {
+        Matrix([[x, y]])
+    }
+
+    fn lit(bmp: &Bitmap) -> Vec<(i32, i32)> {
+        let mut out = vec![];
+        for (y, row) in bmp.rows.iter().enumerate() {
+            for (x, c) in row.iter().enumerate() {
+                if c.a > 0 {
+                    out.push((x as i32, y as i32));
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut bmp = Bitmap::new(16, 16);
+        bmp.draw_line(point(1.5, 1.5), point(12.5, 5.5), WHITE);
+        let pixels = lit(&bmp);
+        // One pixel per column, ends included, and 8-connected.
+        assert_eq!(pixels.len(), 12);
+        assert!(pixels.contains(&(1, 1)) && pixels.contains(&(12, 5)));
+        let mut columns: Vec<i32> = pixels.iter().map(|p| p.0).collect();
+        columns.sort();
+        assert_eq!(columns, (1..=12).collect::<Vec<_>>());
+
+        // Lines far outside are clipped rather than walked.
+        let mut bmp = Bitmap::new(16, 16);
+        bmp.draw_line(point(-1e7, 8.5), point(1e7, 8.5), WHITE);
+        assert_eq!(lit(&bmp).len(), 16);
+        bmp.draw_line(point(-5., -5.), point(-1., 20.), WHITE);
+        assert_eq!(lit(&bmp).len(), 16);
+
+        // Wu's weights split each column between two pixels.
+        let mut bmp = Bitmap::new(16, 16);
+        bmp.draw_line_aa(point(2.5, 2.5), point(12.5, 7.5), WHITE);
+        for x in 4..11 {
+            let column: u32 = (0..16).map(|y| bmp.rows[y][x].r as u32).sum();
+            assert!((250..=260).contains(&column), "{x}: {column}");
+        }
+        // Exactly on pixel centres horizontally, it's a solid line, with the
+        // ends covering half their pixels.
+        let mut bmp = Bitmap::new(16, 16);
+        bmp.draw_line_aa(point(2.5, 4.5), point(12.5, 4.5), WHITE);
+        assert!((3..=11).all(|x| bmp.rows[4][x].r == 255));
+        assert_eq!(bmp.rows[4][2].r, 128);
+        assert_eq!(bmp.rows[4][12].r, 128);
+        assert_eq!(lit(&bmp).len(), 11);
+    }
+
+    #[test]
+    fn test_shapes() {
+        let mut bmp = Bitmap::new(32, 32);
+        bmp.draw_circle(point(16.5, 16.5), 10., WHITE);
+        for (x, y) in lit(&bmp) {
+            let r = (((x - 16) * (x - 16) + (y - 16) * (y - 16)) as f32).sqrt();
+            assert!((r - 10.).abs() < 0.75, "{x}, {y}");
+        }
+        assert!(bmp.rows[16][26].a > 0 && bmp.rows[6][16].a > 0);
+
+        let mut bmp = Bitmap::new(32, 32);
+        bmp.draw_thick_line(point(4., 16.3), point(28., 16.3), 6., WHITE);
+        assert_eq!(bmp.rows[16][16].r, 255);
+        assert_eq!(bmp.rows[14][16].r, 255);
+        assert_eq!(bmp.rows[12][16].a, 0);
+        // Soft edge.
+        let edge = bmp.rows[13][16].r;
+        assert!(edge > 0 && edge < 255);
+
+        let mut bmp = Bitmap::new(8, 8);
+        bmp.draw_point(point(3.2, 4.7), 1., WHITE);
+        assert_eq!(lit(&bmp), vec![(3, 4)]);
+        bmp.draw_point(point(4., 4.), 4., WHITE);
+        assert_eq!(bmp.rows[4][4].r, 255);
+        assert_eq!(bmp.rows[0][0].a, 0);
+    }
+
+    #[test]
+    fn test_hidden_lines() {
+        let view_projection = RenderSettings::default()
+            .camera(0.)
+            .view_projection(1.)
+            .unwrap();
+        // Close to the camera, so the back face is well inside the front.
+        let mesh = cube().apply(translate(0., 0., -3.));
+
+        let mut all = Bitmap::new(96, 96);
+        all.draw_wireframe(&mesh, view_projection, WHITE, None);
+
+        let mut depth = DepthBuffer::new(96, 96);
+        depth.render_mesh(&mesh, view_projection);
+        let mut hidden = Bitmap::new(96, 96);
+        hidden.draw_wireframe(&mesh, view_projection, WHITE, Some(&depth));
+
+        // The back face is inset from the front one. Its edges show without
+        // depth, and are hidden with it; the front outline shows either way.
+        let (all, hidden) = (lit(&all), lit(&hidden));
+        assert!(hidden.len() < all.len());
+        assert!(hidden.iter().all(|p| all.contains(p)));
+        let back = screen(Matrix([[0.5, 0.5, -2.5, 1.]])(view_projection), 96., 96.);
+        let corner = (back.x().floor() as i32, back.y().floor() as i32);
+        assert!(all.contains(&corner));
+        assert!(!hidden.contains(&corner));
+        let front = screen(Matrix([[0.5, 0.5, -3.5, 1.]])(view_projection), 96., 96.);
+        let corner = (front.x().floor() as i32, front.y().floor() as i32);
+        assert!(hidden.contains(&corner));
+
+        // Lines through the camera are cut at the near plane.
+        let mut bmp = Bitmap::new(48, 48);
+        bmp.draw_line_3d(
+            Matrix([[0., 0., -10., 1.]]),
+            Matrix([[0., 1., 10., 1.]]),
+            view_projection,
+            WHITE,
+            None,
+        );
+        assert!(!lit(&bmp).is_empty());
+    }
+}
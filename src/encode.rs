@@ -0,0 +1,295 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    bitmap::Bitmap,
+    deflate::{Compression, crc32, crc32_update, zlib_compress},
+};
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Ppm,
+    Bmp,
+    Tga,
+}
+
+pub fn encode(bmp: &Bitmap, format: ImageFormat) -> Vec<u8> {
+    match format {
+        ImageFormat::Png => encode_png(bmp, Compression::Compressed),
+        ImageFormat::Ppm => encode_ppm(bmp),
+        ImageFormat::Bmp => encode_bmp(bmp),
+        ImageFormat::Tga => encode_tga(bmp),
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32_update(crc32(kind), data).to_be_bytes());
+}
+
+pub fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Applies PNG filter `filter` to `row`, given the unfiltered row above.
+pub fn png_filter(filter: u8, row: &[u8], previous: &[u8], bpp: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(row.len());
+
+    for i in 0..row.len() {
+        let a = if i >= bpp { row[i - bpp] } else { 0 };
+        let b = previous[i];
+        let c = if i >= bpp { previous[i - bpp] } else { 0 };
+
+        let predicted = match filter {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => paeth(a, b, c),
+        };
+
+        out.push(row[i].wrapping_sub(predicted));
+    }
+
+    out
+}
+
+/// 8-bit RGBA PNG. When compressing, each row uses whichever filter gives the
+/// smallest sum of absolute residuals, the usual heuristic from libpng.
+pub fn encode_png(bmp: &Bitmap, compression: Compression) -> Vec<u8> {
+    let mut out = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&bmp.width.to_be_bytes());
+    header.extend_from_slice(&bmp.height.to_be_bytes());
+    // Bit depth, color type 6 (RGBA), compression, filter and interlace methods.
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    png_chunk(&mut out, b"IHDR", &header);
+
+    let mut raw = Vec::new();
+    let mut previous = vec![0; bmp.width as usize * 4];
+
+    for row in bmp.rows.iter() {
+        let row: Vec<u8> = row.iter().flat_map(|c| [c.r, c.g, c.b, c.a]).collect();
+
+        let (filter, filtered) = match compression {
+            Compression::Stored => (0, row.clone()),
+            Compression::Compressed => (0..5)
+                .map(|filter| (filter, png_filter(filter, &row, &previous, 4)))
+                .min_by_key(|(_, filtered)| {
+                    filtered
+                        .iter()
+                        .map(|v| (*v as i8).unsigned_abs() as u32)
+                        .sum::<u32>()
+                })
+                .unwrap(),
+        };
+
+        raw.push(filter);
+        raw.extend(filtered);
+        previous = row;
+    }
+
+    png_chunk(&mut out, b"IDAT", &zlib_compress(&raw, compression));
+    png_chunk(&mut out, b"IEND", &[]);
+
+    out
+}
+
+/// Binary PPM (P6). PPM has no alpha channel, so alpha is dropped.
+pub fn encode_ppm(bmp: &Bitmap) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", bmp.width, bmp.height).into_bytes();
+
+    for row in bmp.rows.iter() {
+        for color in row.iter() {
+            out.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+    }
+
+    out
+}
+
+/// 24-bit uncompressed BMP. Rows are stored bottom-up and padded to four
+/// bytes; alpha is dropped.
+pub fn encode_bmp(bmp: &Bitmap) -> Vec<u8> {
+    let row_size = (bmp.width as usize * 3).div_ceil(4) * 4;
+    let pixels_size = row_size * bmp.height as usize;
+    let offset = 14 + 40;
+
+    let mut out = Vec::with_capacity(offset + pixels_size);
+
+    // BITMAPFILEHEADER
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&((offset + pixels_size) as u32).to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&(offset as u32).to_le_bytes());
+
+    // BITMAPINFOHEADER
+    out.extend_from_slice(&40u32.to_le_bytes());
+    out.extend_from_slice(&(bmp.width as i32).to_le_bytes());
+    out.extend_from_slice(&(bmp.height as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&24u16.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(pixels_size as u32).to_le_bytes());
+    // 2835 pixels per meter is 72 DPI.
+    out.extend_from_slice(&2835i32.to_le_bytes());
+    out.extend_from_slice(&2835i32.to_le_bytes());
+    out.extend_from_slice(&[0; 8]);
+
+    for row in bmp.rows.iter().rev() {
+        let start = out.len();
+        for color in row.iter() {
+            out.extend_from_slice(&[color.b, color.g, color.r]);
+        }
+        out.resize(start + row_size, 0);
+    }
+
+    out
+}
+
+/// 32-bit uncompressed true-color TGA with alpha, stored top-down.
+pub fn encode_tga(bmp: &Bitmap) -> Vec<u8> {
+    let mut out = vec![
+        0, // No image ID.
+        0, // No color map.
+        2, // Uncompressed true-color.
+        0, 0, 0, 0, 0, // Color map specification.
+        0, 0, 0, 0, // X and Y origin.
+    ];
+
+    out.extend_from_slice(&(bmp.width as u16).to_le_bytes());
+    out.extend_from_slice(&(bmp.height as u16).to_le_bytes());
+    out.push(32);
+    // Eight alpha bits, and bit 5 for a top-left origin.
+    out.push(0x28);
+
+    for row in bmp.rows.iter() {
+        for color in row.iter() {
+            out.extend_from_slice(&[color.b, color.g, color.r, color.a]);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitmap::Color;
+
+    fn checkerboard() -> Bitmap {
+        let mut bmp = Bitmap::new(3, 2);
+        for (y, row) in bmp.rows.iter_mut().enumerate() {
+            for (x, color) in row.iter_mut().enumerate() {
+                *color = Color::new(x as u8 * 100, y as u8 * 200, 7, 255 - x as u8);
+            }
+        }
+        bmp
+    }
+
+    #[test]
+    fn test_png_structure() {
+        for compression in [Compression::Stored, Compression::Compressed] {
+            let png = encode_png(&checkerboard(), compression);
+
+            assert_eq!(
+                &png[..8],
+                &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']
+            );
+
+            // Walk the chunks and check every CRC.
+            let mut i = 8;
+            let mut kinds = Vec::new();
+            while i < png.len() {
+                let len = u32::from_be_bytes(png[i..i + 4].try_into().unwrap()) as usize;
+                let kind = &png[i + 4..i + 8];
+                let crc = u32::from_be_bytes(png[i + 8 + len..i + 12 + len].try_into().unwrap());
+
+                assert_eq!(crc, crc32(&png[i + 4..i + 8 + len]));
+                kinds.push(kind.to_vec());
+                i += 12 + len;
+            }
+
+            assert_eq!(
+                kinds,
+                vec![b"IHDR".to_vec(), b"IDAT".to_vec(), b"IEND".to_vec()]
+            );
+            assert_eq!(&png[16..20], &3u32.to_be_bytes());
+            assert_eq!(&png[20..24], &2u32.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn test_png_filters() {
+        let row = [10, 20, 30, 40, 50, 60];
+        let previous = [5, 5, 5, 5, 5, 5];
+
+        assert_eq!(png_filter(0, &row, &previous, 3), row.to_vec());
+        assert_eq!(
+            png_filter(1, &row, &previous, 3),
+            vec![10, 20, 30, 30, 30, 30]
+        );
+        assert_eq!(
+            png_filter(2, &row, &previous, 3),
+            vec![5, 15, 25, 35, 45, 55]
+        );
+        assert_eq!(
+            png_filter(3, &row, &previous, 3),
+            vec![8, 18, 28, 33, 38, 43]
+        );
+        assert_eq!(paeth(10, 5, 5), 10);
+        assert_eq!(paeth(5, 10, 5), 10);
+    }
+
+    #[test]
+    fn test_ppm() {
+        let ppm = encode_ppm(&checkerboard());
+        let header = b"P6\n3 2\n255\n";
+
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(ppm.len(), header.len() + 3 * 2 * 3);
+        assert_eq!(&ppm[header.len() + 3..header.len() + 6], &[100, 0, 7]);
+    }
+
+    #[test]
+    fn test_bmp() {
+        let bmp = encode_bmp(&checkerboard());
+
+        // 3 pixels of 3 bytes are padded to 12 bytes per row.
+        assert_eq!(bmp.len(), 54 + 12 * 2);
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(
+            u32::from_le_bytes(bmp[2..6].try_into().unwrap()),
+            bmp.len() as u32
+        );
+
+        // Bottom-up: the first stored row is y = 1.
+        assert_eq!(&bmp[54..57], &[7, 200, 0]);
+        assert_eq!(&bmp[63..66], &[0, 0, 0]);
+    }
+
+    #[test]
+    fn test_tga() {
+        let tga = encode_tga(&checkerboard());
+
+        assert_eq!(tga.len(), 18 + 3 * 2 * 4);
+        assert_eq!(tga[2], 2);
+        assert_eq!(&tga[12..16], &[3, 0, 2, 0]);
+        assert_eq!(&tga[18..22], &[7, 0, 0, 255]);
+    }
+}
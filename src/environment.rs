@@ -0,0 +1,445 @@
+use core::f32;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    bitmap::FloatImage,
+    decode::decode,
+    matrix::Matrix,
+    matrix_3d::Point2D,
+    sampling::{Distribution2D, uniform_sphere},
+};
+
+/// Position in an equirectangular map for a world direction. The center of
+/// the map lies along +z, the default view direction, with +y at the top.
+pub fn direction_to_uv(direction: Matrix<1, 4>) -> Point2D {
+    let d = Matrix([[direction.x(), direction.y(), direction.z(), 0.]]).normalize();
+    let longitude = (-d.x()).atan2(d.z());
+    let latitude = d.y().clamp(-1., 1.).asin();
+
+    Matrix([[
+        0.5 + longitude / (2. * f32::consts::PI),
+        0.5 - latitude / f32::consts::PI,
+    ]])
+}
+
+pub fn uv_to_direction(uv: Point2D) -> Matrix<1, 4> {
+    let longitude = (uv.x() - 0.5) * 2. * f32::consts::PI;
+    let latitude = (0.5 - uv.y()) * f32::consts::PI;
+
+    let (sin_lon, cos_lon) = longitude.sin_cos();
+    let (sin_lat, cos_lat) = latitude.sin_cos();
+
+    Matrix([[-sin_lon * cos_lat, sin_lat, cos_lon * cos_lat, 0.]])
+}
+
+/// Rec. 709 luminance of a linear color.
+pub fn luminance(color: Matrix<1, 4>) -> f32 {
+    0.2126 * color.x() + 0.7152 * color.y() + 0.0722 * color.z()
+}
+
+/// Preetham et al.'s analytic daylight model (1999). Radiance is relative,
+/// with the zenith at a luminance of one.
+#[derive(Clone, Copy, Debug)]
+pub struct Sky {
+    sun: Matrix<1, 4>,
+    sun_theta: f32,
+    /// Perez coefficients A to E for luminance and the x and y chromaticities.
+    perez: [[f32; 5]; 3],
+    zenith: [f32; 3],
+}
+
+impl Sky {
+    /// `turbidity` ranges from about 2 for a clear sky to 10 for haze.
+    pub fn new(sun: Matrix<1, 4>, turbidity: f32) -> Self {
+        let t = turbidity;
+        let sun = Matrix([[sun.x(), sun.y().max(0.), sun.z(), 0.]]).normalize();
+        let theta = sun.y().clamp(0., 1.).acos();
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let (t2, th2, th3) = (t * t, theta * theta, theta * theta * theta);
+        let chromaticity = |c: [[f32; 4]; 3]| {
+            let row = |r: [f32; 4]| r[0] * th3 + r[1] * th2 + r[2] * theta + r[3];
+            t2 * row(c[0]) + t * row(c[1]) + row(c[2])
+        };
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut sky = Sky {
+            sun,
+            sun_theta: theta,
+            perez,
+            zenith: [1., x, y],
+        };
+
+        // Scale the Perez distributions so they pass through the zenith values.
+        for i in 0..3 {
+            sky.zenith[i] /= sky.perez(i, 0., theta);
+        }
+
+        sky
+    }
+
+    fn perez(&self, i: usize, theta: f32, gamma: f32) -> f32 {
+        let [a, b, c, d, e] = self.perez[i];
+        (1. + a * (b / theta.cos().max(0.01)).exp())
+            * (1. + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+    }
+
+    pub fn radiance(&self, direction: Matrix<1, 4>) -> Matrix<1, 4> {
+        let d = Matrix([[direction.x(), direction.y(), direction.z(), 0.]]).normalize();
+
+        // Below the horizon, a dim ground reflecting the sky at the horizon.
+        let (d, ground) = if d.y() < 0. {
+            let horizon = Matrix([[d.x(), 0., d.z(), 0.]]);
+            let horizon = if horizon.x() == 0. && horizon.z() == 0. {
+                Matrix([[1., 0., 0., 0.]])
+            } else {
+                horizon.normalize()
+            };
+            (horizon, 0.3)
+        } else {
+            (d, 1.)
+        };
+
+        let theta = d.y().clamp(0., 1.).acos();
+        let gamma = d.dot(self.sun.transpose()).x().clamp(-1., 1.).acos();
+
+        let [luminance, x, y] =
+            [0, 1, 2].map(|i| self.zenith[i] * self.perez(i, theta, gamma.max(0.)));
+        let luminance = luminance * ground;
+
+        // xyY to XYZ, then to linear sRGB.
+        let big_x = x / y * luminance;
+        let big_z = (1. - x - y) / y * luminance;
+
+        Matrix([[
+            (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.),
+            (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.),
+            (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.),
+            1.,
+        ]])
+    }
+
+    /// The sun's angle from the zenith, in radians.
+    pub fn sun_theta(&self) -> f32 {
+        self.sun_theta
+    }
+}
+
+enum Source {
+    Constant(Matrix<1, 4>),
+    Map(FloatImage),
+    Sky(Sky),
+}
+
+/// Light arriving from infinitely far away, seen by rays that miss every
+/// model. Maps and skies are importance sampled through a luminance
+/// weighted distribution over their equirectangular layout.
+#[wasm_bindgen]
+pub struct Environment {
+    source: Source,
+    distribution: Option<Distribution2D>,
+    /// Multiplies the radiance of every source.
+    pub intensity: f32,
+}
+
+impl Default for Environment {
+    fn default() -> Self {
+        Environment::constant(0., 0., 0.)
+    }
+}
+
+#[wasm_bindgen]
+impl Environment {
+    pub fn constant(r: f32, g: f32, b: f32) -> Environment {
+        Environment {
+            source: Source::Constant(Matrix([[r, g, b, 1.]])),
+            distribution: None,
+            intensity: 1.,
+        }
+    }
+
+    /// Preetham sky with the sun at `elevation` above the horizon and
+    /// `azimuth` around it, both in radians. An azimuth of zero puts the sun
+    /// straight ahead, along +z.
+    pub fn sky(elevation: f32, azimuth: f32, turbidity: f32) -> Environment {
+        let (sin_el, cos_el) = elevation.sin_cos();
+        let (sin_az, cos_az) = azimuth.sin_cos();
+        let sky = Sky::new(
+            Matrix([[sin_az * cos_el, sin_el, cos_az * cos_el, 0.]]),
+            turbidity,
+        );
+
+        // The sky is smooth, so a coarse grid is enough to guide sampling.
+        let (width, height) = (128, 64);
+        let distribution =
+            Environment::distribution(width, height, |uv| sky.radiance(uv_to_direction(uv)));
+
+        Environment {
+            source: Source::Sky(sky),
+            distribution: Some(distribution),
+            // The horizon and the sky around the sun are several times
+            // brighter than the zenith. Scaling keeps them displayable.
+            intensity: 0.25,
+        }
+    }
+
+    /// Loads an equirectangular map from any format `decode` reads, usually
+    /// a Radiance `.hdr` file.
+    pub fn load(data: &[u8]) -> Result<Environment, JsValue> {
+        let image = decode(data).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(Environment::from_image(image))
+    }
+}
+
+impl Environment {
+    pub fn from_image(image: FloatImage) -> Environment {
+        let distribution = Environment::distribution(image.width, image.height, |uv| {
+            let x = (uv.x() * image.width as f32) as u32;
+            let y = (uv.y() * image.height as f32) as u32;
+            image.get(x, y)
+        });
+
+        Environment {
+            source: Source::Map(image),
+            distribution: Some(distribution),
+            intensity: 1.,
+        }
+    }
+
+    /// Luminance at each cell center, weighted by the solid angle the cell
+    /// covers, which shrinks towards the poles.
+    fn distribution(
+        width: u32,
+        height: u32,
+        radiance: impl Fn(Point2D) -> Matrix<1, 4>,
+    ) -> Distribution2D {
+        let mut func = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            let v = (y as f32 + 0.5) / height as f32;
+            let sin_theta = (v * f32::consts::PI).sin();
+            for x in 0..width {
+                let u = (x as f32 + 0.5) / width as f32;
+                func.push(luminance(radiance(Matrix([[u, v]]))) * sin_theta);
+            }
+        }
+
+        Distribution2D::new(&func, width as usize, height as usize)
+    }
+
+    pub fn radiance(&self, direction: Matrix<1, 4>) -> Matrix<1, 4> {
+        let color = match &self.source {
+            Source::Constant(color) => *color,
+            Source::Sky(sky) => sky.radiance(direction),
+            Source::Map(image) => {
+                let uv = direction_to_uv(direction);
+                let x = ((uv.x() * image.width as f32) as u32).min(image.width - 1);
+                let y = ((uv.y() * image.height as f32) as u32).min(image.height - 1);
+                image.get(x, y)
+            }
+        };
+
+        let mut color = color * self.intensity;
+        color[0][3] = 1.;
+        color
+    }
+
+    /// Picks a direction towards the environment, favouring bright regions.
+    /// Returns the direction, the radiance from it and the solid angle pdf.
+    pub fn sample(&self, u: Point2D) -> (Matrix<1, 4>, Matrix<1, 4>, f32) {
+        let Some(distribution) = &self.distribution else {
+            let direction = uniform_sphere(u);
+            return (
+                direction,
+                self.radiance(direction),
+                1. / (4. * f32::consts::PI),
+            );
+        };
+
+        let (uv, pdf) = distribution.sample(u);
+        let direction = uv_to_direction(uv);
+        (
+            direction,
+            self.radiance(direction),
+            Environment::solid_angle_pdf(uv, pdf),
+        )
+    }
+
+    /// Solid angle density with which `sample` returns `direction`.
+    pub fn pdf(&self, direction: Matrix<1, 4>) -> f32 {
+        match &self.distribution {
+            None => 1. / (4. * f32::consts::PI),
+            Some(distribution) => {
+                let uv = direction_to_uv(direction);
+                Environment::solid_angle_pdf(uv, distribution.pdf(uv))
+            }
+        }
+    }
+
+    fn solid_angle_pdf(uv: Point2D, pdf: f32) -> f32 {
+        let sin_theta = (uv.y() * f32::consts::PI).sin();
+        if sin_theta <= 0. {
+            return 0.;
+        }
+        pdf / (2. * f32::consts::PI * f32::consts::PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampling::Rng;
+
+    fn gradient_map() -> Environment {
+        let mut image = FloatImage::new(32, 16);
+        for y in 0..16 {
+            for x in 0..32 {
+                let v = if (10..12).contains(&x) && y == 5 {
+                    50.
+                } else {
+                    x as f32 / 32.
+                };
+                image.set(x, y, Matrix([[v, v * 0.5, 0.2, 1.]]));
+            }
+        }
+        Environment::from_image(image)
+    }
+
+    #[test]
+    fn test_uv_mapping() {
+        let forward = direction_to_uv(Matrix([[0., 0., 1., 0.]]));
+        assert_eq!(forward, Matrix([[0.5, 0.5]]));
+        assert_eq!(direction_to_uv(Matrix([[0., 1., 0., 0.]])).y(), 0.);
+
+        let mut rng = Rng::new(4);
+        for _ in 0..100 {
+            let uv = rng.next_2d();
+            let back = direction_to_uv(uv_to_direction(uv));
+            assert!((back - uv).abs().x() < 1e-4 && (back - uv).abs().y() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_sampling_matches_pdf() {
+        for environment in [
+            gradient_map(),
+            Environment::sky(0.4, 1., 3.),
+            Environment::constant(1., 1., 1.),
+        ] {
+            let mut rng = Rng::new(8);
+            let mut importance = 0.;
+            let mut uniform = 0.;
+            let mut mismatched = 0;
+            let n = 20000;
+
+            for _ in 0..n {
+                let (direction, radiance, pdf) = environment.sample(rng.next_2d());
+                // Samples on a cell boundary can round into the neighbour.
+                if (pdf - environment.pdf(direction)).abs() > pdf * 1e-3 {
+                    mismatched += 1;
+                }
+                importance += luminance(radiance) / pdf / n as f32;
+
+                let direction = uniform_sphere(rng.next_2d());
+                let radiance = environment.radiance(direction);
+                uniform += luminance(radiance) * 4. * f32::consts::PI / n as f32;
+            }
+
+            assert!(mismatched < n / 1000, "{mismatched}");
+
+            // Both estimate the same integral of luminance over the sphere.
+            assert!(
+                (importance - uniform).abs() < uniform * 0.05,
+                "{importance} {uniform}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_bright_regions_sampled_more() {
+        let environment = gradient_map();
+        let mut rng = Rng::new(1);
+
+        let hot = (0..10000)
+            .filter(|_| {
+                let (direction, _, _) = environment.sample(rng.next_2d());
+                let uv = direction_to_uv(direction);
+                (uv.x() * 32.) as u32 / 2 == 5 && (uv.y() * 16.) as u32 == 5
+            })
+            .count();
+
+        // Two of 512 cells hold over a third of the energy.
+        assert!(hot > 3000, "{hot}");
+    }
+
+    #[test]
+    fn test_sky() {
+        let sky = Sky::new(Matrix([[0., 0.5, 1., 0.]]), 3.);
+        let toward_sun = sky.radiance(Matrix([[0., 0.55, 1., 0.]]));
+        let away = sky.radiance(Matrix([[0., 0.55, -1., 0.]]));
+        let ground = sky.radiance(Matrix([[0., -0.5, -1., 0.]]));
+        let zenith = sky.radiance(Matrix([[0., 1., 0., 0.]]));
+
+        assert!(luminance(toward_sun) > luminance(away));
+        assert!(luminance(ground) < luminance(away));
+        assert!((luminance(zenith) - 1.).abs() < 0.05);
+        // A clear sky is blue away from the sun.
+        assert!(away.z() > away.x());
+    }
+
+    #[test]
+    fn test_equirectangular_camera_sees_map() {
+        // The equirectangular camera looks along +z from the default view,
+        // so it shows the map the right way round.
+        let settings = crate::RenderSettings {
+            projection: crate::camera::ProjectionKind::Equirectangular,
+            ..Default::default()
+        };
+        let camera = settings.camera(0.);
+        let environment = gradient_map();
+
+        let (_, direction) = camera
+            .generate_ray(
+                &camera.projection.unprojection(2.),
+                Matrix([[0.5, 0.]]),
+                Matrix([[0.5, 0.5]]),
+            )
+            .unwrap();
+        let uv = direction_to_uv(direction);
+        assert!((uv.x() - 0.75).abs() < 1e-4);
+        assert!((uv.y() - 0.5).abs() < 1e-4);
+        assert!(environment.radiance(direction).x() > 0.7);
+    }
+}
@@ -0,0 +1,223 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    aabb::Aabb,
+    camera::Shutter,
+    matrix::Matrix,
+    matrix_3d::{Model, Point},
+};
+
+/// Plane in the form `dot(normal, p) + distance = 0`, with the normal pointing
+/// towards the inside of the frustum.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plane {
+    pub normal: Matrix<1, 4>,
+    pub distance: f32,
+}
+
+impl Plane {
+    pub fn new(a: f32, b: f32, c: f32, d: f32) -> Self {
+        let len = (a * a + b * b + c * c).sqrt();
+        Plane {
+            normal: Matrix([[a / len, b / len, c / len, 0.]]),
+            distance: d / len,
+        }
+    }
+
+    pub fn signed_distance(&self, point: Point) -> f32 {
+        self.normal.x() * point.x()
+            + self.normal.y() * point.y()
+            + self.normal.z() * point.z()
+            + self.distance
+    }
+}
+
+pub struct Frustum {
+    /// Left, right, bottom, top, near and far, in that order.
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// Extracts the clip planes of a view-projection matrix (Gribb and
+    /// Hartmann). Points are row vectors here, so the planes come from the
+    /// columns of the matrix rather than its rows.
+    pub fn from_matrix(view_projection: Matrix<4, 4>) -> Self {
+        let m = view_projection;
+        let column = |i: usize| [m[0][i], m[1][i], m[2][i], m[3][i]];
+        let plane = |a: [f32; 4], b: [f32; 4], sign: f32| {
+            Plane::new(
+                a[0] + sign * b[0],
+                a[1] + sign * b[1],
+                a[2] + sign * b[2],
+                a[3] + sign * b[3],
+            )
+        };
+
+        let (x, y, z, w) = (column(0), column(1), column(2), column(3));
+
+        Frustum {
+            planes: [
+                plane(w, x, 1.),
+                plane(w, x, -1.),
+                plane(w, y, 1.),
+                plane(w, y, -1.),
+                plane(w, z, 1.),
+                plane(w, z, -1.),
+            ],
+        }
+    }
+
+    pub fn contains_point(&self, point: Point) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.signed_distance(point) >= 0.)
+    }
+
+    pub fn intersects_sphere(&self, center: Point, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.signed_distance(center) >= -radius)
+    }
+
+    /// Conservative box test: a box is only rejected when it lies entirely
+    /// behind one plane, so some boxes near the corners are kept needlessly.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        if aabb.is_empty() {
+            return false;
+        }
+
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane normal.
+            let mut positive = aabb.min;
+            for i in 0..3 {
+                if plane.normal[0][i] >= 0. {
+                    positive[0][i] = aabb.max[0][i];
+                }
+            }
+            plane.signed_distance(positive) >= 0.
+        })
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CullStats {
+    pub tested: u32,
+    pub culled: u32,
+}
+
+/// Moves every model that may be visible while the shutter is open to the
+/// front of `models` and returns where each of those was before, so
+/// `&models[..visible.len()]` holds only the visible ones and `visible[i]`
+/// is the original index of `models[i]`.
+pub fn cull_models(
+    frustum: &Frustum,
+    models: &mut [Model],
+    shutter: Shutter,
+) -> (Vec<usize>, CullStats) {
+    let mut order: Vec<usize> = (0..models.len()).collect();
+    let mut visible = 0;
+
+    for i in 0..models.len() {
+        let bounds = models[i].motion_bounds(shutter.open, shutter.close);
+
+        if frustum.intersects_aabb(&bounds) {
+            models.swap(i, visible);
+            order.swap(i, visible);
+            visible += 1;
+        }
+    }
+
+    let stats = CullStats {
+        tested: models.len() as u32,
+        culled: (models.len() - visible) as u32,
+    };
+
+    order.truncate(visible);
+    (order, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32;
+
+    use super::*;
+    use crate::matrix_3d::{cube, perspective, rotate_y, translate};
+
+    fn frustum() -> Frustum {
+        // Camera at z = -5 looking down +z.
+        let camera = rotate_y(f32::consts::PI)(translate(0., 0., -5.));
+        let projection = perspective(f32::consts::PI / 2., 1., 0.1, 100.);
+        Frustum::from_matrix(camera.inv()(projection))
+    }
+
+    #[test]
+    fn test_points() {
+        let frustum = frustum();
+
+        assert!(frustum.contains_point(Matrix([[0., 0., 0., 1.]])));
+        assert!(frustum.contains_point(Matrix([[4., 4., 0., 1.]])));
+        assert!(!frustum.contains_point(Matrix([[6., 0., 0., 1.]])));
+        assert!(!frustum.contains_point(Matrix([[0., 0., -6., 1.]])));
+        assert!(!frustum.contains_point(Matrix([[0., 0., 96., 1.]])));
+    }
+
+    #[test]
+    fn test_spheres() {
+        let frustum = frustum();
+
+        assert!(frustum.intersects_sphere(Matrix([[0., 0., 0., 1.]]), 1.));
+        assert!(frustum.intersects_sphere(Matrix([[0., 0., -6., 1.]]), 1.5));
+        assert!(!frustum.intersects_sphere(Matrix([[0., 0., -7., 1.]]), 1.5));
+        assert!(!frustum.intersects_sphere(Matrix([[10., 0., 0., 1.]]), 1.));
+    }
+
+    #[test]
+    fn test_aabbs() {
+        let frustum = frustum();
+
+        assert!(frustum.intersects_aabb(&cube().bounds()));
+        assert!(!frustum.intersects_aabb(&cube().apply(translate(0., 0., -10.)).bounds()));
+        assert!(!frustum.intersects_aabb(&cube().apply(translate(0., 20., 5.)).bounds()));
+        assert!(!frustum.intersects_aabb(&Aabb::empty()));
+
+        // Straddling the right plane.
+        assert!(frustum.intersects_aabb(&cube().apply(translate(5.2, 0., 0.)).bounds()));
+    }
+
+    #[test]
+    fn test_cull_models() {
+        let frustum = frustum();
+        let model = |x: f32| Model {
+            color: Matrix([[1., 1., 1., 1.]]),
+            reflect: 0.,
+            mesh: cube().apply(translate(x, 0., 0.)),
+            ..Default::default()
+        };
+
+        let shutter = Shutter {
+            open: 0.,
+            close: 0.,
+        };
+
+        let mut models = vec![model(-50.), model(0.), model(50.), model(2.)];
+        let (visible, stats) = cull_models(&frustum, &mut models, shutter);
+
+        assert_eq!(visible, vec![1, 3]);
+        assert_eq!(
+            stats,
+            CullStats {
+                tested: 4,
+                culled: 2
+            }
+        );
+        assert!(
+            models[..visible.len()]
+                .iter()
+                .all(|m| m.bounds().center().x().abs() < 5.)
+        );
+        for (model, &i) in models.iter().zip(visible.iter()) {
+            assert!((model.bounds().center().x() - [-50., 0., 50., 2.][i]).abs() < 1e-5);
+        }
+    }
+}
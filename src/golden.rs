@@ -0,0 +1,601 @@
+use crate::{
+    RenderSettings, SceneKind,
+    bitmap::Bitmap,
+    camera::ProjectionKind,
+    environment::Environment,
+    isosurface::{ScalarGrid, marching_cubes},
+    matrix::Matrix,
+    matrix_3d::translate,
+    post::PostChain,
+    raster::AntialiasKind,
+    rasterize,
+    sdf::Sdf,
+    shader::{Flat, Gouraud, Phong, Toon, Transforms},
+    trace, trace_image,
+};
+
+/// Reference scenes with a checked-in image under `tests/golden`.
+pub const SCENES: [&str; 26] = [
+    "trace",
+    "raster",
+    "orthographic",
+    "fisheye",
+    "equirectangular",
+    "depth_of_field",
+    "motion_blur",
+    "sky",
+    "path_traced",
+    "post_processed",
+    "denoised",
+    "sdf",
+    "voxels",
+    "voxels_raster",
+    "terrain",
+    "textured",
+    "textured_raster",
+    "shaders",
+    "wireframe",
+    "overlays",
+    "shadows",
+    "shadows_raster",
+    "glass_raster",
+    "glass_oit",
+    "msaa_raster",
+    "coverage_raster",
+];
+
+/// Renders one of `SCENES` headlessly at a fixed size and time.
+pub fn render_scene(name: &str) -> Option<Bitmap> {
+    let (width, height) = (96., 64.);
+    let black = Environment::default();
+    let settings = |projection| RenderSettings {
+        projection,
+        ..Default::default()
+    };
+    let traced = |settings: RenderSettings, t: f32| trace(width, height, t, &settings, &black).0;
+
+    let bmp = match name {
+        "trace" => traced(settings(ProjectionKind::Perspective), 0.),
+        "raster" => rasterize(width, height, 0., &settings(ProjectionKind::Perspective))?.0,
+        "orthographic" => traced(settings(ProjectionKind::Orthographic), 0.),
+        "fisheye" => traced(settings(ProjectionKind::Fisheye), 0.),
+        "equirectangular" => traced(settings(ProjectionKind::Equirectangular), 0.),
+        "depth_of_field" => {
+            let settings = RenderSettings {
+                aperture: 0.4,
+                focal_distance: 4.,
+                samples: 4,
+                ..Default::default()
+            };
+            traced(settings, 0.)
+        }
+        "motion_blur" => {
+            let settings = RenderSettings {
+                shutter: 3000.,
+                samples: 4,
+                ..Default::default()
+            };
+            traced(settings, 5000.)
+        }
+        "sky" => {
+            let sky = Environment::sky(0.5, 0.8, 3.);
+            trace(
+                width,
+                height,
+                0.,
+                &settings(ProjectionKind::Perspective),
+                &sky,
+            )
+            .0
+        }
+        "path_traced" => {
+            let sky = Environment::sky(0.5, 0.8, 3.);
+            let settings = RenderSettings {
+                path_tracing: true,
+                samples: 4,
+                ..Default::default()
+            };
+            trace(width, height, 0., &settings, &sky).0
+        }
+        "post_processed" => {
+            let mut post = PostChain::new();
+            post.add_fxaa();
+            post.add_bloom(0.6, 0.8, 2.);
+            post.add_chromatic_aberration(0.01);
+            post.add_vignette(0.6, 0.4);
+            post.add_grain(0.02);
+
+            let settings = settings(ProjectionKind::Perspective);
+            let (mut image, _) = trace_image(width, height, 0., &settings, &black);
+            post.apply(&mut image, 0);
+            image.to_bitmap_truncated()
+        }
+        "denoised" => {
+            let sky = Environment::sky(0.5, 0.8, 3.);
+            let settings = RenderSettings {
+                path_tracing: true,
+                samples: 4,
+                denoise: true,
+                ..Default::default()
+            };
+            trace(width, height, 0., &settings, &sky).0
+        }
+        "sdf" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Sdf,
+                ..Default::default()
+            };
+            traced(settings, 0.)
+        }
+        "voxels" => {
+            let sky = Environment::sky(0.5, 0.8, 3.);
+            let settings = RenderSettings {
+                scene: SceneKind::Voxels,
+                ..Default::default()
+            };
+            trace(width, height, 0., &settings, &sky).0
+        }
+        "voxels_raster" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Voxels,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "terrain" => {
+            let sky = Environment::sky(0.5, 0.8, 3.);
+            let settings = RenderSettings {
+                scene: SceneKind::Terrain,
+                path_tracing: true,
+                samples: 4,
+                denoise: true,
+                ..Default::default()
+            };
+            trace(width, height, 0., &settings, &sky).0
+        }
+        "textured" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Textured,
+                ..Default::default()
+            };
+            traced(settings, 0.)
+        }
+        "textured_raster" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Textured,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "shaders" => {
+            let view_projection = RenderSettings::default()
+                .camera(0.)
+                .view_projection(width / height)?;
+            let sphere = marching_cubes(&ScalarGrid::from_sdf(&Sdf::sphere(1.4), 0.1), 0.);
+            let color = Matrix([[0.9, 0.4, 0.2, 1.]]);
+            let light = Matrix([[-0.4, 0.6, -0.7, 0.]]).normalize();
+            let at = |x| Transforms::new(translate(x, 0., 0.), view_projection);
+
+            // Flat, Gouraud, Phong and toon from left to right.
+            let mut bmp = Bitmap::new(width as u32, height as u32);
+            let flat = Flat {
+                transforms: at(4.5),
+                color: color.to_color(),
+            };
+            bmp.render_mesh(&flat, &sphere);
+            let gouraud = Gouraud {
+                transforms: at(1.5),
+                color,
+                light,
+            };
+            bmp.render_mesh(&gouraud, &sphere);
+            let phong = Phong {
+                transforms: at(-1.5),
+                color,
+                light,
+                eye: Matrix([[0., 0., -5., 1.]]),
+                shininess: 24.,
+            };
+            bmp.render_mesh(&phong, &sphere);
+            let toon = Toon {
+                transforms: at(-4.5),
+                color,
+                light,
+                bands: 3,
+            };
+            bmp.render_mesh(&toon, &sphere);
+            bmp
+        }
+        "wireframe" => {
+            let settings = RenderSettings {
+                wireframe: true,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "overlays" => {
+            let settings = RenderSettings {
+                overlays: true,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "shadows" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Shadows,
+                ..Default::default()
+            };
+            traced(settings, 0.)
+        }
+        "shadows_raster" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Shadows,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "glass_raster" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Glass,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "glass_oit" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Glass,
+                oit: true,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "msaa_raster" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Shadows,
+                antialias: AntialiasKind::Msaa4,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        "coverage_raster" => {
+            let settings = RenderSettings {
+                scene: SceneKind::Shadows,
+                antialias: AntialiasKind::Coverage,
+                ..Default::default()
+            };
+            rasterize(width, height, 0., &settings)?.0
+        }
+        _ => return None,
+    };
+
+    Some(bmp)
+}
+
+/// How far an image may drift from its reference before the test fails.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    /// Largest per channel difference for a pixel to still count as matching.
+    pub channel: u8,
+    /// Fraction of pixels allowed to exceed `channel`.
+    pub mismatched: f32,
+    pub min_psnr: f32,
+    pub min_ssim: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            channel: 2,
+            mismatched: 0.002,
+            min_psnr: 40.,
+            min_ssim: 0.98,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Comparison {
+    pub mismatched: f32,
+    pub max_difference: u8,
+    pub psnr: f32,
+    pub ssim: f32,
+}
+
+impl Comparison {
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        self.mismatched <= tolerance.mismatched
+            && self.psnr >= tolerance.min_psnr
+            && self.ssim >= tolerance.min_ssim
+    }
+}
+
+fn rgb(bmp: &Bitmap) -> impl Iterator<Item = [u8; 3]> + '_ {
+    bmp.rows
+        .iter()
+        .flat_map(|row| row.iter())
+        .map(|c| [c.r, c.g, c.b])
+}
+
+fn max_difference(a: [u8; 3], b: [u8; 3]) -> u8 {
+    (0..3).map(|i| a[i].abs_diff(b[i])).max().unwrap()
+}
+
+/// Peak signal to noise ratio over the color channels, in decibels.
+/// Identical images give infinity.
+pub fn psnr(a: &Bitmap, b: &Bitmap) -> f32 {
+    let mut sum = 0.;
+    let mut count = 0;
+
+    for (a, b) in rgb(a).zip(rgb(b)) {
+        for i in 0..3 {
+            sum += (a[i] as f64 - b[i] as f64).powi(2);
+            count += 1;
+        }
+    }
+
+    let mse = sum / count as f64;
+    (10. * (255f64.powi(2) / mse).log10()) as f32
+}
+
+fn luma(bmp: &Bitmap) -> Vec<f64> {
+    rgb(bmp)
+        .map(|[r, g, b]| 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64)
+        .collect()
+}
+
+/// Mean structural similarity of the luma, over 8x8 windows placed every
+/// four pixels. 1 means identical.
+pub fn ssim(a: &Bitmap, b: &Bitmap) -> f32 {
+    const WINDOW: usize = 8;
+    const STEP: usize = 4;
+    let c1 = (0.01 * 255f64).powi(2);
+    let c2 = (0.03 * 255f64).powi(2);
+
+    let width = a.width as usize;
+    let height = a.height as usize;
+    let (la, lb) = (luma(a), luma(b));
+
+    let window = WINDOW.min(width).min(height);
+    let mut total = 0.;
+    let mut count = 0;
+
+    for y in (0..=height - window).step_by(STEP) {
+        for x in (0..=width - window).step_by(STEP) {
+            let pixels =
+                || (y..y + window).flat_map(|y| (x..x + window).map(move |x| y * width + x));
+            let n = (window * window) as f64;
+
+            let mean_a = pixels().map(|i| la[i]).sum::<f64>() / n;
+            let mean_b = pixels().map(|i| lb[i]).sum::<f64>() / n;
+
+            let mut var_a = 0.;
+            let mut var_b = 0.;
+            let mut covariance = 0.;
+            for i in pixels() {
+                var_a += (la[i] - mean_a).powi(2);
+                var_b += (lb[i] - mean_b).powi(2);
+                covariance += (la[i] - mean_a) * (lb[i] - mean_b);
+            }
+            var_a /= n - 1.;
+            var_b /= n - 1.;
+            covariance /= n - 1.;
+
+            total += ((2. * mean_a * mean_b + c1) * (2. * covariance + c2))
+                / ((mean_a.powi(2) + mean_b.powi(2) + c1) * (var_a + var_b + c2));
+            count += 1;
+        }
+    }
+
+    (total / count as f64) as f32
+}
+
+/// Pixels within `channel` of each other are drawn as a dimmed copy of
+/// `expected`, the rest in red, brighter the larger the difference.
+pub fn diff_image(expected: &Bitmap, actual: &Bitmap, channel: u8) -> Bitmap {
+    let mut diff = Bitmap::new(expected.width, expected.height);
+
+    for (y, row) in diff.rows.iter_mut().enumerate() {
+        for (x, color) in row.iter_mut().enumerate() {
+            let e = expected.rows[y][x];
+            let a = actual.rows[y][x];
+            let difference = max_difference([e.r, e.g, e.b], [a.r, a.g, a.b]);
+
+            *color = if difference > channel {
+                crate::bitmap::Color::new(128 + difference / 2, 0, 0, 255)
+            } else {
+                let v = ((e.r as u32 + e.g as u32 + e.b as u32) / 12) as u8;
+                crate::bitmap::Color::new(v, v, v, 255)
+            };
+        }
+    }
+
+    diff
+}
+
+/// Compares images of the same size.
+pub fn compare(expected: &Bitmap, actual: &Bitmap, channel: u8) -> Comparison {
+    let differences: Vec<u8> = rgb(expected)
+        .zip(rgb(actual))
+        .map(|(e, a)| max_difference(e, a))
+        .collect();
+
+    Comparison {
+        mismatched: differences.iter().filter(|d| **d > channel).count() as f32
+            / differences.len() as f32,
+        max_difference: differences.iter().copied().max().unwrap_or(0),
+        psnr: psnr(expected, actual),
+        ssim: ssim(expected, actual),
+    }
+}
+
+/// Compares `actual` with `tests/golden/<name>.png`. On failure the expected,
+/// actual and diff images are written to `target/golden`. Setting
+/// `BLESS_GOLDEN=1` writes `actual` as the new reference instead.
+#[cfg(test)]
+pub fn assert_golden(name: &str, actual: &Bitmap, tolerance: Tolerance) {
+    use crate::{decode::decode_bitmap, deflate::Compression, encode::encode_png};
+    use std::{fs, path::Path};
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let reference = root.join("tests/golden").join(format!("{name}.png"));
+
+    if std::env::var_os("BLESS_GOLDEN").is_some_and(|v| v == "1") {
+        fs::create_dir_all(reference.parent().unwrap()).unwrap();
+        fs::write(&reference, encode_png(actual, Compression::Compressed)).unwrap();
+        return;
+    }
+
+    let write_artifacts = |expected: Option<&Bitmap>| {
+        let out = root.join("target/golden");
+        fs::create_dir_all(&out).unwrap();
+        let write = |suffix: &str, bmp: &Bitmap| {
+            let path = out.join(format!("{name}-{suffix}.png"));
+            fs::write(path, encode_png(bmp, Compression::Compressed)).unwrap();
+        };
+
+        write("actual", actual);
+        if let Some(expected) = expected {
+            write("expected", expected);
+            write("diff", &diff_image(expected, actual, tolerance.channel));
+        }
+        out
+    };
+
+    let Ok(data) = fs::read(&reference) else {
+        let out = write_artifacts(None);
+        panic!(
+            "missing reference {}, actual image written to {}. Run with BLESS_GOLDEN=1 to accept it.",
+            reference.display(),
+            out.display()
+        );
+    };
+    let expected = decode_bitmap(&data).unwrap();
+
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        let out = write_artifacts(None);
+        panic!(
+            "{name}: expected {}x{}, got {}x{}. Images written to {}",
+            expected.width,
+            expected.height,
+            actual.width,
+            actual.height,
+            out.display()
+        );
+    }
+
+    let comparison = compare(&expected, actual, tolerance.channel);
+    if !comparison.passes(&tolerance) {
+        let out = write_artifacts(Some(&expected));
+        panic!(
+            "{name}: {comparison:?} exceeds {tolerance:?}. Images written to {}",
+            out.display()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitmap::Color,
+        blend::{BlendMode, premultiply, unpremultiply},
+        sampling::Rng,
+    };
+
+    fn noise(seed: u64, amplitude: u32) -> (Bitmap, Bitmap) {
+        let mut rng = Rng::new(seed);
+        let mut a = Bitmap::new(32, 24);
+        let mut b = Bitmap::new(32, 24);
+
+        for y in 0..24 {
+            for x in 0..32 {
+                let v = (x * 8) as u8;
+                a.rows[y][x] = Color::new(v, v, 255 - v, 255);
+                let n = rng.next_below(amplitude * 2 + 1) as i32 - amplitude as i32;
+                let w = (v as i32 + n).clamp(0, 255) as u8;
+                b.rows[y][x] = Color::new(w, v, 255 - v, 255);
+            }
+        }
+
+        (a, b)
+    }
+
+    #[test]
+    fn test_metrics() {
+        let (a, _) = noise(1, 0);
+        assert_eq!(psnr(&a, &a), f32::INFINITY);
+        assert!((ssim(&a, &a) - 1.).abs() < 1e-6);
+
+        let (a, slight) = noise(1, 2);
+        let (_, heavy) = noise(1, 60);
+        assert!(psnr(&a, &slight) > 45.);
+        assert!(psnr(&a, &heavy) < 30.);
+        assert!(ssim(&a, &slight) > ssim(&a, &heavy));
+
+        let comparison = compare(&a, &slight, 2);
+        assert_eq!(comparison.mismatched, 0.);
+        assert!(comparison.passes(&Tolerance::default()));
+        assert!(!compare(&a, &heavy, 2).passes(&Tolerance::default()));
+    }
+
+    #[test]
+    fn test_diff_image() {
+        let (a, mut b) = noise(1, 0);
+        b.rows[3][4] = Color::new(0, 255, 0, 255);
+
+        let diff = diff_image(&a, &b, 2);
+        assert!(diff.rows[3][4].r > 128);
+        assert_eq!(diff.rows[3][4].g, 0);
+        assert_eq!(diff.rows[0][0].r, diff.rows[0][0].g);
+    }
+
+    #[test]
+    fn test_scenes() {
+        for name in SCENES {
+            let bmp = render_scene(name).unwrap();
+            assert_golden(name, &bmp, Tolerance::default());
+        }
+
+        assert!(render_scene("missing").is_none());
+    }
+
+    #[test]
+    fn test_shadows_match_trace() {
+        // Shadow maps stand in for the tracer's shadow rays, so both
+        // renderers should agree apart from edges and faceting.
+        let traced = render_scene("shadows").unwrap();
+        let rasterized = render_scene("shadows_raster").unwrap();
+        let comparison = compare(&traced, &rasterized, 24);
+        assert!(comparison.mismatched < 0.01, "{comparison:?}");
+        assert!(comparison.psnr > 35., "{comparison:?}");
+        assert!(comparison.ssim > 0.98, "{comparison:?}");
+    }
+
+    #[test]
+    fn test_antialiasing_matches_supersampling() {
+        // Smoothed edges should land nearer a supersampled trace than
+        // edges cut at pixel centres do.
+        let settings = RenderSettings {
+            scene: SceneKind::Shadows,
+            samples: 8,
+            ..Default::default()
+        };
+        let reference = trace(96., 64., 0., &settings, &Environment::default()).0;
+        // The rasterizer leaves the background clear and edges partly
+        // transparent, so compare what shows over the tracer's black.
+        let over_black = |name| {
+            let mut bmp = render_scene(name).unwrap();
+            for color in bmp.rows.iter_mut().flatten() {
+                *color = unpremultiply(
+                    BlendMode::Over.blend(premultiply(*color), Matrix([[0., 0., 0., 1.]])),
+                );
+            }
+            bmp
+        };
+        let baseline = psnr(&reference, &over_black("shadows_raster"));
+        for name in ["msaa_raster", "coverage_raster"] {
+            let smoothed = psnr(&reference, &over_black(name));
+            assert!(smoothed > baseline + 2., "{name}: {smoothed} vs {baseline}");
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::collections::HashMap;
+
+use crate::{
+    aabb::Aabb,
+    matrix::Matrix,
+    matrix_3d::{Mesh, Point, Point2D, Triangle},
+    sdf::Sdf,
+};
+
+/// Scalar field sampled on a regular grid, such as simulation output.
+/// Sample `(x, y, z)` sits at `origin + (x, y, z) * spacing` and is stored
+/// with x varying fastest.
+#[derive(Clone, Debug)]
+pub struct ScalarGrid {
+    pub size: [usize; 3],
+    pub origin: Point,
+    pub spacing: Matrix<1, 4>,
+    pub values: Vec<f32>,
+}
+
+impl ScalarGrid {
+    pub fn new(size: [usize; 3], origin: Point, spacing: Matrix<1, 4>, values: Vec<f32>) -> Self {
+        assert_eq!(values.len(), size[0] * size[1] * size[2]);
+        ScalarGrid {
+            size,
+            origin,
+            spacing,
+            values,
+        }
+    }
+
+    /// Samples `field` at `size` points along each axis, corners included.
+    pub fn sample(bounds: Aabb, size: [usize; 3], field: impl Fn(Point) -> f32) -> Self {
+        let extent = bounds.size();
+        let mut spacing = Matrix::default();
+        for i in 0..3 {
+            spacing[0][i] = extent[0][i] / (size[i].max(2) - 1) as f32;
+        }
+
+        let mut values = Vec::with_capacity(size[0] * size[1] * size[2]);
+        for z in 0..size[2] {
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    values.push(field(
+                        bounds.min + Matrix([[x as f32, y as f32, z as f32, 0.]]) * spacing,
+                    ));
+                }
+            }
+        }
+
+        ScalarGrid::new(size, bounds.min, spacing, values)
+    }
+
+    /// Samples a signed distance field over its bounds padded by two cells,
+    /// so the surface closes inside the grid. Unbounded fields need
+    /// `sample` with explicit bounds instead.
+    pub fn from_sdf(sdf: &Sdf, cell: f32) -> Self {
+        let bounds = sdf.bounds();
+        let pad = Matrix([[2., 2., 2., 0.]]) * cell;
+        let extent = bounds.size() + pad * 2.;
+        let size = [0, 1, 2].map(|i| (extent[0][i] / cell).ceil() as usize + 1);
+
+        let min = bounds.min - pad;
+        let max = min + Matrix([[size[0] as f32, size[1] as f32, size[2] as f32, 0.]]) * cell
+            - Matrix([[cell, cell, cell, 0.]]);
+        ScalarGrid::sample(Aabb::new(min, max), size, |p| sdf.distance(p))
+    }
+
+    fn index(&self, [x, y, z]: [usize; 3]) -> usize {
+        (z * self.size[1] + y) * self.size[0] + x
+    }
+
+    pub fn get(&self, at: [usize; 3]) -> f32 {
+        self.values[self.index(at)]
+    }
+
+    pub fn position(&self, [x, y, z]: [usize; 3]) -> Point {
+        self.origin + Matrix([[x as f32, y as f32, z as f32, 0.]]) * self.spacing
+    }
+
+    /// Central differences, one-sided on the grid's faces.
+    pub fn gradient(&self, at: [usize; 3]) -> Matrix<1, 4> {
+        let mut gradient = Matrix::default();
+
+        for axis in 0..3 {
+            if self.size[axis] < 2 {
+                continue;
+            }
+            let (mut lo, mut hi) = (at, at);
+            lo[axis] = at[axis].saturating_sub(1);
+            hi[axis] = (at[axis] + 1).min(self.size[axis] - 1);
+
+            let run = (hi[axis] - lo[axis]) as f32 * self.spacing[0][axis];
+            gradient[0][axis] = (self.get(hi) - self.get(lo)) / run;
+        }
+
+        gradient
+    }
+}
+
+/// Triangles over a shared vertex list, with a normal per vertex.
+#[derive(Clone, Debug, Default)]
+pub struct IndexedMesh {
+    pub positions: Vec<Point>,
+    pub normals: Vec<Matrix<1, 4>>,
+    /// Texture coordinates per vertex, empty when the mesh has none.
+    pub uvs: Vec<Point2D>,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl IndexedMesh {
+    /// Flat shaded copy of `mesh` with texture coordinates from `uv` at
+    /// each position, as a planar or box projection would give.
+    pub fn from_mesh(mesh: &Mesh, uv: impl Fn(Point) -> Point2D) -> IndexedMesh {
+        let mut out = IndexedMesh::default();
+        for trig in mesh.0.iter() {
+            let first = out.positions.len() as u32;
+            for p in [trig.0, trig.1, trig.2] {
+                out.positions.push(p);
+                out.normals.push(trig.normal());
+                out.uvs.push(uv(p));
+            }
+            out.indices.push([first, first + 1, first + 2]);
+        }
+        out
+    }
+
+    /// Unshared triangles for the ray tracer and rasterizer.
+    pub fn to_mesh(&self) -> Mesh {
+        Mesh(
+            self.indices
+                .iter()
+                .map(|[a, b, c]| {
+                    Triangle(
+                        self.positions[*a as usize],
+                        self.positions[*b as usize],
+                        self.positions[*c as usize],
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Corner `c` of a cell is offset by `(c & 1, c >> 1 & 1, c >> 2 & 1)`.
+const fn corner(c: usize) -> [usize; 3] {
+    [c & 1, c >> 1 & 1, c >> 2 & 1]
+}
+
+/// Corners of each cell face, counter-clockwise seen from outside the cell.
+const FACES: [[usize; 4]; 6] = [
+    [0, 4, 6, 2],
+    [1, 3, 7, 5],
+    [0, 1, 5, 4],
+    [2, 6, 7, 3],
+    [0, 2, 3, 1],
+    [4, 5, 7, 6],
+];
+
+/// Extracts the surface where the field equals `iso` with marching cubes,
+/// values below `iso` counting as inside. Rather than looking polygons up in
+/// a case table, each cell's are traced from the segments the surface cuts
+/// across its faces. A face crossed four times is ambiguous, and the
+/// asymptotic decider (Nielsen and Hamann 1991) settles it by the value at
+/// the saddle of the bilinear interpolant. Both cells sharing a face then
+/// agree on its segments, so the surface has no cracks.
+///
+/// Vertices on a grid edge are shared by every cell around it, and their
+/// normals are the field gradient interpolated along the edge. Triangles are
+/// wound counter-clockwise seen from outside.
+pub fn marching_cubes(grid: &ScalarGrid, iso: f32) -> IndexedMesh {
+    let mut mesh = IndexedMesh::default();
+    // Vertex on the edge leaving a grid point along an axis.
+    let mut shared: HashMap<(usize, usize), u32> = HashMap::new();
+    let [sx, sy, sz] = grid.size;
+
+    for z in 0..sz.saturating_sub(1) {
+        for y in 0..sy.saturating_sub(1) {
+            for x in 0..sx.saturating_sub(1) {
+                let at = |c: usize| {
+                    let [dx, dy, dz] = corner(c);
+                    [x + dx, y + dy, z + dz]
+                };
+                let values: [f32; 8] = std::array::from_fn(|c| grid.get(at(c)));
+                let inside = |c: usize| values[c] < iso;
+
+                if (0..8).all(inside) || !(0..8).any(inside) {
+                    continue;
+                }
+
+                let mut vertex = |a: usize, b: usize| {
+                    let (a, b) = (a.min(b), a.max(b));
+                    let axis = (a ^ b).trailing_zeros() as usize;
+                    let key = (grid.index(at(a)), axis);
+
+                    *shared.entry(key).or_insert_with(|| {
+                        let t = (iso - values[a]) / (values[b] - values[a]);
+                        let lerp = |p: Matrix<1, 4>, q: Matrix<1, 4>| p + (q - p) * t;
+
+                        let position = lerp(grid.position(at(a)), grid.position(at(b)));
+                        let normal = lerp(grid.gradient(at(a)), grid.gradient(at(b)));
+                        let length = normal.dot(normal.transpose()).x().sqrt();
+
+                        mesh.positions.push(position);
+                        mesh.normals
+                            .push(if length > 0. { normal / length } else { normal });
+                        mesh.positions.len() as u32 - 1
+                    })
+                };
+
+                // Each segment runs from the edge where the face boundary
+                // enters the inside to the one where it leaves, so a
+                // segment ending on an edge continues on the other face
+                // through that edge.
+                let mut segments: Vec<(u32, u32)> = Vec::new();
+                let mut ambiguous = false;
+
+                for face in FACES {
+                    let edge = |i: usize| (face[i], face[(i + 1) % 4]);
+                    let entering: Vec<usize> = (0..4)
+                        .filter(|&i| !inside(face[i]) && inside(face[(i + 1) % 4]))
+                        .collect();
+                    let leaving: Vec<usize> = (0..4)
+                        .filter(|&i| inside(face[i]) && !inside(face[(i + 1) % 4]))
+                        .collect();
+
+                    let pairs: Vec<(usize, usize)> = match entering.len() {
+                        0 => Vec::new(),
+                        1 => vec![(entering[0], leaving[0])],
+                        _ => {
+                            ambiguous = true;
+
+                            // Corners alternate around the face. The inside
+                            // ones are joined across the middle when the
+                            // saddle between them is inside too.
+                            let [a, b, c, d] = face.map(|c| values[c]);
+                            let denominator = a + c - b - d;
+                            let saddle = if denominator == 0. {
+                                (a + b + c + d) / 4.
+                            } else {
+                                (a * c - b * d) / denominator
+                            };
+
+                            entering
+                                .iter()
+                                .map(|&e| {
+                                    // Cut off the inside corner after the
+                                    // entering edge, or walk past it and the
+                                    // outside corner beyond.
+                                    let l = if saddle < iso {
+                                        (e + 3) % 4
+                                    } else {
+                                        (e + 1) % 4
+                                    };
+                                    (e, l)
+                                })
+                                .collect()
+                        }
+                    };
+
+                    for (e, l) in pairs {
+                        let (e0, e1) = edge(e);
+                        let (l0, l1) = edge(l);
+                        segments.push((vertex(e0, e1), vertex(l0, l1)));
+                    }
+                }
+
+                // Chain the segments into loops and triangulate each.
+                while let Some((start, mut current)) = segments.pop() {
+                    let mut polygon = vec![start];
+                    while current != start {
+                        polygon.push(current);
+                        let i = segments.iter().position(|s| s.0 == current).unwrap();
+                        current = segments.swap_remove(i).1;
+                    }
+
+                    if polygon.len() == 3 || !ambiguous {
+                        // Two corners of a polygon can only share a face
+                        // without a segment between them on an ambiguous
+                        // face, so elsewhere a fan adds no edge the
+                        // neighbouring cell could repeat.
+                        for i in 1..polygon.len() - 1 {
+                            mesh.indices.push([polygon[0], polygon[i], polygon[i + 1]]);
+                        }
+                    } else {
+                        // Fan around the centroid instead.
+                        let n = polygon.len() as f32;
+                        let centre = polygon.iter().fold(Matrix::default(), |sum, &i| {
+                            sum + mesh.positions[i as usize]
+                        }) / n;
+                        let normal = polygon
+                            .iter()
+                            .fold(Matrix::default(), |sum, &i| sum + mesh.normals[i as usize]);
+                        let length = normal.dot(normal.transpose()).x().sqrt();
+
+                        mesh.positions.push(centre);
+                        mesh.normals
+                            .push(if length > 0. { normal / length } else { normal });
+                        let centre = mesh.positions.len() as u32 - 1;
+
+                        for i in 0..polygon.len() {
+                            let next = polygon[(i + 1) % polygon.len()];
+                            mesh.indices.push([centre, polygon[i], next]);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    mesh
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{matrix_3d::length3, sampling::Rng};
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    /// Every edge is used once in each direction, so the surface is closed
+    /// and consistently wound.
+    fn assert_watertight(mesh: &IndexedMesh) {
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for [a, b, c] in mesh.indices.iter().copied() {
+            for edge in [(a, b), (b, c), (c, a)] {
+                *edges.entry(edge).or_default() += 1;
+            }
+        }
+        for (&(a, b), &count) in edges.iter() {
+            assert_eq!(count, 1, "edge {a} {b} used {count} times");
+            assert_eq!(edges.get(&(b, a)), Some(&1), "edge {a} {b} is open");
+        }
+    }
+
+    #[test]
+    fn test_sphere() {
+        let sphere = Sdf::sphere(1.);
+        let grid = ScalarGrid::from_sdf(&sphere, 0.1);
+        let mesh = marching_cubes(&grid, 0.);
+
+        assert!(mesh.indices.len() > 500);
+        assert_watertight(&mesh);
+
+        for (p, n) in mesh.positions.iter().zip(mesh.normals.iter()) {
+            let radial = *p - point(0., 0., 0.);
+            assert!((length3(radial) - 1.).abs() < 0.01);
+            assert!(n.dot(radial.transpose()).x() / length3(radial) > 0.99);
+        }
+
+        // Faces point out, and the volume comes out close to 4/3 pi. Grid
+        // points right on the surface leave some triangles with no area.
+        let mut volume = 0.;
+        for trig in mesh.to_mesh().0.iter() {
+            let to_3d = |p: Point| Matrix([[p.x(), p.y(), p.z()]]);
+            let cross = (to_3d(trig.1) - to_3d(trig.0)).cross(to_3d(trig.2) - to_3d(trig.0));
+            let centre = (trig.0 + trig.1 + trig.2) / 3.;
+            if cross.dot(cross.transpose()).x() > 1e-12 {
+                assert!(trig.normal().dot(centre.transpose()).x() > 0.);
+            }
+
+            volume += to_3d(trig.0)
+                .dot(to_3d(trig.1).cross(to_3d(trig.2)).transpose())
+                .x()
+                / 6.;
+        }
+        assert!(
+            (volume - 4. / 3. * std::f32::consts::PI).abs() < 0.05,
+            "{volume}"
+        );
+    }
+
+    #[test]
+    fn test_shared_vertices() {
+        let grid = ScalarGrid::from_sdf(&Sdf::torus(1., 0.4), 0.1);
+        let mesh = marching_cubes(&grid, 0.);
+        assert_watertight(&mesh);
+
+        // A closed torus has Euler characteristic zero.
+        let edges = mesh.indices.len() * 3 / 2;
+        let euler = mesh.positions.len() as i64 - edges as i64 + mesh.indices.len() as i64;
+        assert_eq!(euler, 0);
+    }
+
+    #[test]
+    fn test_ambiguous_faces() {
+        // Noise inside a border that's always outside hits every
+        // ambiguous configuration, and must still close up.
+        let mut rng = Rng::new(3);
+        for _ in 0..20 {
+            let size = [8, 7, 6];
+            let mut values = Vec::new();
+            for z in 0..size[2] {
+                for y in 0..size[1] {
+                    for x in 0..size[0] {
+                        let border = x == 0
+                            || y == 0
+                            || z == 0
+                            || x == size[0] - 1
+                            || y == size[1] - 1
+                            || z == size[2] - 1;
+                        values.push(if border { 1. } else { rng.next_f32() * 2. - 1. });
+                    }
+                }
+            }
+            let grid = ScalarGrid::new(size, point(0., 0., 0.), Matrix([[1., 1., 1., 0.]]), values);
+            assert_watertight(&marching_cubes(&grid, 0.));
+        }
+    }
+
+    #[test]
+    fn test_sampled_grid() {
+        // A plane through a sampled field, with the gradient along +y.
+        let bounds = Aabb::new(point(-1., -1., -1.), point(1., 1., 1.));
+        let grid = ScalarGrid::sample(bounds, [5, 5, 5], |p| p.y() - 0.25);
+        assert_eq!(grid.get([2, 2, 2]), -0.25);
+        assert_eq!(grid.gradient([0, 4, 0]), Matrix([[0., 1., 0., 0.]]));
+
+        let mesh = marching_cubes(&grid, 0.);
+        assert_eq!(mesh.positions.len(), 25);
+        assert_eq!(mesh.indices.len(), 32);
+        for (p, n) in mesh.positions.iter().zip(mesh.normals.iter()) {
+            assert!((p.y() - 0.25).abs() < 1e-6);
+            assert_eq!(*n, Matrix([[0., 1., 0., 0.]]));
+        }
+
+        // Empty and full grids give nothing.
+        assert!(marching_cubes(&grid, -5.).indices.is_empty());
+        assert!(marching_cubes(&grid, 5.).indices.is_empty());
+    }
+}
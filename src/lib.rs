@@ -1,9 +1,42 @@
 #![feature(unboxed_closures)]
 #![feature(fn_traits)]
+#![feature(portable_simd)]
+#![cfg_attr(test, feature(test))]
 
+#[cfg(test)]
+extern crate test;
+
+pub mod aabb;
+pub mod aov;
 pub mod bitmap;
+pub mod blend;
+pub mod camera;
+pub mod decode;
+pub mod deflate;
+pub mod denoise;
+pub mod depth;
+pub mod draw;
+pub mod encode;
+pub mod environment;
+pub mod frustum;
+pub mod golden;
+pub mod isosurface;
+pub mod light;
+pub mod lut;
 pub mod matrix;
 pub mod matrix_3d;
+pub mod noise;
+pub mod normal_map;
+pub mod packet;
+pub mod post;
+pub mod raster;
+pub mod sampling;
+pub mod sdf;
+pub mod shader;
+pub mod shadow;
+pub mod terrain;
+pub mod texture;
+pub mod voxel;
 use core::{f32, panic};
 
 use matrix::Matrix;
@@ -12,11 +45,34 @@ use wasm_bindgen::{Clamped, prelude::*};
 use web_sys::ImageData;
 
 use crate::{
-    bitmap::Bitmap,
+    aabb::inverse_direction,
+    aov::{AovKind, AovSample, Aovs},
+    bitmap::{Bitmap, Color, FloatImage, WeightedBlend},
+    blend::BlendMode,
+    camera::{Aperture, Camera, Lens, Projection, ProjectionKind, Shutter},
+    denoise::{DenoiseSettings, denoise},
+    depth::DepthBuffer,
+    encode::{ImageFormat, encode},
+    environment::Environment,
+    frustum::{CullStats, Frustum, cull_models},
+    isosurface::{IndexedMesh, ScalarGrid, marching_cubes},
+    light::Light,
     matrix_3d::{
-        Model, Point2D, RaycastHit, Triangle, cube, from_screen, perspective, quad,
-        ray_intersects_triangle, rotate_x, rotate_y, scale, screen, translate,
+        Culling, Mesh, Model, Motion, Placed, Point, Point2D, RaycastHit, Triangle, cube, dot3,
+        from_screen, look_at, quad, rotate_x, rotate_y, scale, translate,
     },
+    noise::{Fractal, simplex, warp},
+    normal_map::{Bump, NormalMap, NormalMappedMesh},
+    packet::RayPacket,
+    post::PostChain,
+    raster::{AntialiasKind, CoverageLayer, MultisampleBuffer},
+    sampling::{Rng, cosine_hemisphere, power_heuristic, stratified_1d, stratified_2d, to_world},
+    sdf::Sdf,
+    shader::{Flat, Shader, Textured, Transforms, Vertex},
+    shadow::{Caster, Lit, ShadowSettings, ShadowedLight},
+    terrain::Heightfield,
+    texture::Texture,
+    voxel::VoxelGrid,
 };
 
 #[wasm_bindgen]
@@ -49,164 +105,1355 @@ fn inside_triangle(screen_0: Point2D, screen_1: Point2D, screen_2: Point2D, p: P
     return true;
 }
 
-#[wasm_bindgen]
-pub fn render(
-    ctx: web_sys::CanvasRenderingContext2d,
-    width: f32,
-    height: f32,
-    t: f32,
-) -> Result<(), JsValue> {
-    let mut bmp = Bitmap::new(width as u32, height as u32);
+/// Closest hit among `models`, with the index of the model hit in `models`.
+fn nearest_hit<'a>(
+    origin: Matrix<1, 4>,
+    direction: Matrix<1, 4>,
+    time: f32,
+    models: &[Placed<'a>],
+) -> Option<(RaycastHit, usize, &'a Model)> {
+    let mut nearest: Option<(RaycastHit, usize, &Model)> = None;
+    let inverse = inverse_direction(direction);
+
+    for (index, placed) in models.iter().enumerate() {
+        if let Some(hit) = placed.nearest_hit(origin, direction, inverse, time, Culling::None)
+            && nearest.as_ref().is_none_or(|(other, ..)| hit.t < other.t)
+        {
+            nearest = Some((hit, index, placed.model));
+        }
+    }
+
+    nearest
+}
+
+#[allow(clippy::too_many_arguments)]
+fn shade(
+    direction: Matrix<1, 4>,
+    time: f32,
+    hit: &RaycastHit,
+    model: &Model,
+    environment: &Environment,
+    models: &[Placed],
+    lights: &[Light],
+    depth: u32,
+    hits: &mut u32,
+) -> Matrix<1, 4> {
+    let mut out = model.color_at(hit) * model.reflect;
+
+    // Scenes with lights are lit by them alone, diffusely, with a shadow
+    // ray to each.
+    if !lights.is_empty() {
+        let normal = if direction.dot(hit.shading_normal.transpose()).x() < 0. {
+            hit.shading_normal
+        } else {
+            -hit.shading_normal
+        };
+        let mut light = Matrix([[light::AMBIENT, light::AMBIENT, light::AMBIENT, 1.]]);
+        for source in lights.iter() {
+            let (to_light, distance, color) = source.incident(hit.position);
+            let cos = to_light.dot(normal.transpose()).x();
+            if cos > 0. && !occluded(hit, to_light, distance, time, models) {
+                light = light + color * cos;
+            }
+        }
+        out = out * light;
+    }
 
-    let aspect_ratio = width / height;
-    let fov = f32::consts::PI / 2.;
+    if out.w() < 1. && depth < 2 {
+        let dot = direction.dot(hit.shading_normal.transpose()).x();
+        let direction_reflected = direction - hit.shading_normal * (2.0 * dot);
 
-    let camera = translate(0., 0., -5.);
+        let origin_reflected = hit.spawn_origin(direction_reflected);
 
-    let background_color = Matrix([[0., 0., 0., 1.]]);
-    let light_position = Matrix([[0., -5., 0., 1.]]);
-    let light_direction = Matrix([[0., -1., -0.1, 0.]]).normalize();
+        let other = raycast_color(
+            origin_reflected,
+            direction_reflected,
+            time,
+            environment,
+            models,
+            lights,
+            depth + 1,
+            hits,
+        );
 
-    let models: Vec<Model> = vec![
+        out + other
+    } else {
+        out
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn raycast_color(
+    origin: Matrix<1, 4>,
+    direction: Matrix<1, 4>,
+    time: f32,
+    environment: &Environment,
+    models: &[Placed],
+    lights: &[Light],
+    depth: u32,
+    hits: &mut u32,
+) -> Matrix<1, 4> {
+    match nearest_hit(origin, direction, time, models) {
+        Some((hit, _, model)) => {
+            *hits += 1;
+            shade(
+                direction,
+                time,
+                &hit,
+                model,
+                environment,
+                models,
+                lights,
+                depth,
+                hits,
+            )
+        }
+        None => environment.radiance(direction),
+    }
+}
+
+/// Whether anything lies between `hit` and a light `distance` away along
+/// `to_light`.
+fn occluded(
+    hit: &RaycastHit,
+    to_light: Matrix<1, 4>,
+    distance: f32,
+    time: f32,
+    models: &[Placed],
+) -> bool {
+    nearest_hit(hit.spawn_origin(to_light), to_light, time, models)
+        .is_some_and(|(blocker, ..)| blocker.t < distance)
+}
+
+/// Unidirectional path tracer lit by the environment and `lights`. A `reflect`
+/// fraction of paths bounce off a model as from a perfect mirror, the rest
+/// scatter diffusely with `color` as albedo. At every diffuse vertex the
+/// environment is sampled directly and combined with the scattered ray
+/// through multiple importance sampling, and each light through a shadow
+/// ray. Surfaces hit after the primary one are added to `hits`.
+#[allow(clippy::too_many_arguments)]
+fn path_trace(
+    mut direction: Matrix<1, 4>,
+    time: f32,
+    primary: Option<(RaycastHit, usize, &Model)>,
+    environment: &Environment,
+    models: &[Placed],
+    lights: &[Light],
+    max_bounces: u32,
+    rng: &mut Rng,
+    hits: &mut u32,
+) -> Matrix<1, 4> {
+    let mut radiance: Matrix<1, 4> = Matrix::default();
+    let mut throughput = Matrix([[1., 1., 1., 1.]]);
+    let mut nearest = primary;
+    // Density of the last diffuse bounce. Camera rays and mirror bounces
+    // can't be produced by light sampling, so they get the full weight.
+    let mut scatter_pdf: Option<f32> = None;
+
+    for bounce in 0..=max_bounces {
+        let Some((hit, _, model)) = nearest else {
+            let weight =
+                scatter_pdf.map_or(1., |pdf| power_heuristic(pdf, environment.pdf(direction)));
+            radiance = radiance + throughput * environment.radiance(direction) * weight;
+            break;
+        };
+
+        // The geometric normal picks the side, the shading normal bends
+        // the light.
+        let facing = direction.dot(hit.normal.transpose()).x() < 0.;
+        let normal = if facing {
+            hit.shading_normal
+        } else {
+            -hit.shading_normal
+        };
+
+        if rng.next_f32() < model.reflect {
+            let dot = direction.dot(normal.transpose()).x();
+            direction = direction - normal * (2. * dot);
+            scatter_pdf = None;
+        } else {
+            let albedo = model.color_at(&hit);
+
+            let (light_direction, light, light_pdf) = environment.sample(rng.next_2d());
+            let cos = light_direction.dot(normal.transpose()).x();
+            if cos > 0.
+                && light_pdf > 0.
+                && nearest_hit(
+                    hit.spawn_origin(light_direction),
+                    light_direction,
+                    time,
+                    models,
+                )
+                .is_none()
+            {
+                let pdf = cos / f32::consts::PI;
+                let weight = power_heuristic(light_pdf, pdf);
+                radiance = radiance + throughput * albedo * light * (pdf / light_pdf * weight);
+            }
+
+            for source in lights.iter() {
+                let (to_light, distance, color) = source.incident(hit.position);
+                let cos = to_light.dot(normal.transpose()).x();
+                if cos > 0. && !occluded(&hit, to_light, distance, time, models) {
+                    radiance = radiance + throughput * albedo * color * cos;
+                }
+            }
+
+            // Cosine sampling cancels the Lambertian cosine and 1 / PI,
+            // leaving just the albedo.
+            let local = cosine_hemisphere(rng.next_2d());
+            direction = to_world(local, normal);
+            scatter_pdf = Some(local.z() / f32::consts::PI);
+            throughput = throughput * albedo;
+        }
+
+        // Russian roulette once paths have had a few bounces to pick up
+        // most of their light.
+        if bounce >= 3 {
+            let survive = throughput
+                .x()
+                .max(throughput.y())
+                .max(throughput.z())
+                .min(0.95);
+            if rng.next_f32() >= survive {
+                break;
+            }
+            throughput = throughput / survive;
+        }
+
+        nearest = nearest_hit(hit.spawn_origin(direction), direction, time, models);
+        *hits += nearest.is_some() as u32;
+    }
+
+    radiance[0][3] = 1.;
+    radiance
+}
+
+/// Which models a frame shows.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SceneKind {
+    /// Three reflective cubes, the middle one spinning.
+    #[default]
+    Cubes,
+    /// Signed distance fields around the spinning cube, over a dimpled floor.
+    Sdf,
+    /// A voxel sphere and a sparse stepped pyramid around the spinning cube.
+    Voxels,
+    /// Ridged, domain warped hills beneath the spinning cube.
+    Terrain,
+    /// Marble, wood and brick cubes on a checkered floor.
+    Textured,
+    /// A cube, a sphere and a pillar on a floor, lit by the sun and a spot
+    /// light.
+    Shadows,
+    /// Tinted panes of glass crossing among the cubes. Only the
+    /// rasterizer sees through them.
+    Glass,
+}
+
+fn scene(kind: SceneKind) -> Vec<Model> {
+    match kind {
+        SceneKind::Cubes => cubes(),
+        SceneKind::Sdf => sdf_scene(),
+        SceneKind::Voxels => voxel_scene(),
+        SceneKind::Terrain => terrain_scene(),
+        SceneKind::Textured => textured_scene(),
+        SceneKind::Shadows => shadow_scene(),
+        SceneKind::Glass => glass_scene(),
+    }
+}
+
+/// Lights shining on a scene. Scenes without any are lit by the
+/// environment.
+fn scene_lights(kind: SceneKind) -> Vec<Light> {
+    match kind {
+        SceneKind::Shadows => vec![
+            Light::Directional {
+                direction: Matrix([[-0.7, -1., 0.3, 0.]]),
+                color: Matrix([[0.8, 0.75, 0.65, 1.]]),
+            },
+            Light::Spot {
+                position: Matrix([[-0.5, 2.5, -3., 1.]]),
+                direction: Matrix([[-0.3, -3.5, 1.8, 0.]]),
+                angle: 0.5,
+                color: Matrix([[0.3, 0.4, 0.7, 1.]]),
+            },
+        ],
+        _ => vec![],
+    }
+}
+
+fn cubes() -> Vec<Model> {
+    vec![
         Model {
             color: Matrix([[1., 0., 0., 1.]]),
             reflect: 0.5,
             mesh: cube().apply(translate(3., 0., 0.)),
+            ..Default::default()
         },
         Model {
             color: Matrix([[0., 1., 0., 1.]]),
             reflect: 0.5,
-            mesh: cube().apply(rotate_y(t / 10000.)),
+            mesh: cube(),
+            motion: Some(Motion(Box::new(|time| rotate_y(time / 10000.)))),
+            ..Default::default()
         },
         Model {
             color: Matrix([[0., 0., 1., 1.]]),
             reflect: 0.5,
             mesh: cube().apply(translate(-3., 0., 0.)),
+            ..Default::default()
+        },
+    ]
+}
+
+fn sdf_scene() -> Vec<Model> {
+    let point = |x, y, z| Matrix([[x, y, z, 1.]]);
+    let implicit = |color, reflect, sdf| Model {
+        color,
+        reflect,
+        mesh: Mesh(Vec::new()),
+        sdf: Some(sdf),
+        ..Default::default()
+    };
+
+    let blob = Sdf::sphere(0.6)
+        .smooth_union(Sdf::torus(0.7, 0.2).transform(rotate_x(0.6)), 0.3)
+        .union(Sdf::capsule(point(0., -0.9, 0.), point(0., 0.9, 0.), 0.15))
+        .transform(translate(3., 0., 0.));
+    let twisted = Sdf::cuboid(0.4, 0.9, 0.4)
+        .twist(1.2)
+        .smooth_subtract(Sdf::sphere(0.5), 0.1)
+        .transform(translate(-3., 0., 0.));
+    let floor = Sdf::plane(Matrix([[0., 1., 0., 0.]]), -1.).subtract(
+        Sdf::sphere(0.3)
+            .repeat(1.5, 0., 1.5)
+            .transform(translate(0., -1., 0.)),
+    );
+
+    let mut models = cubes();
+    models.retain(|model| model.motion.is_some());
+    models.extend([
+        implicit(Matrix([[1., 0.3, 0.2, 1.]]), 0.5, blob),
+        implicit(Matrix([[0.2, 0.4, 1., 1.]]), 0.5, twisted),
+        implicit(Matrix([[0.5, 0.5, 0.5, 1.]]), 0.4, floor),
+    ]);
+    models
+}
+
+fn voxel_scene() -> Vec<Model> {
+    let volume = |voxels: VoxelGrid| Model {
+        color: Matrix([[1., 1., 1., 1.]]),
+        reflect: 0.3,
+        mesh: Mesh(Vec::new()),
+        voxels: Some(voxels),
+        ..Default::default()
+    };
+
+    // A dense sphere banded by height, from the default palette's red and
+    // green ramps.
+    let mut sphere = VoxelGrid::dense([16, 16, 16]);
+    sphere.origin = Matrix([[2., -1., -1., 1.]]);
+    sphere.voxel_size = 0.125;
+    for z in 0..16 {
+        for y in 0..16 {
+            for x in 0..16 {
+                let d = Matrix([[x as f32, y as f32, z as f32, 0.]]) - 7.5;
+                if dot3(d, d) < 64. {
+                    sphere.set([x, y, z], if y % 4 < 2 { 217 } else { 227 });
+                }
+            }
+        }
+    }
+
+    // A stepped pyramid with checkered layers, mostly empty space.
+    let mut pyramid = VoxelGrid::sparse([9, 5, 9]);
+    pyramid.origin = Matrix([[-3.9, -1., -0.9, 1.]]);
+    pyramid.voxel_size = 0.2;
+    for y in 0..5 {
+        for z in y..9 - y {
+            for x in y..9 - y {
+                pyramid.set([x, y, z], if (x + z) % 2 == 0 { 237 } else { 1 });
+            }
+        }
+    }
+
+    let mut models = cubes();
+    models.retain(|model| model.motion.is_some());
+    models.extend([volume(sphere), volume(pyramid)]);
+    models
+}
+
+fn terrain_scene() -> Vec<Model> {
+    let fractal = Fractal::default();
+    let mut terrain = Heightfield::from_fn([129, 129], 0.25, |x, z| {
+        let p = Matrix([[x * 0.08, 0., z * 0.08, 1.]]);
+        let p = warp(p, 0.6, |p| fractal.fbm(simplex, p));
+        fractal.ridged(simplex, p) * 3.
+    });
+    terrain.origin = Matrix([[-16., -3., -2., 1.]]);
+
+    let mut models = cubes();
+    models.retain(|model| model.motion.is_some());
+    models.push(Model {
+        color: Matrix([[0.45, 0.5, 0.35, 1.]]),
+        reflect: 0.1,
+        mesh: Mesh(Vec::new()),
+        terrain: Some(terrain),
+        ..Default::default()
+    });
+    models
+}
+
+fn textured_scene() -> Vec<Model> {
+    let rgb = |r, g, b| Matrix([[r, g, b, 1.]]);
+
+    let mut models = cubes();
+    let textures = [
+        Texture::Marble {
+            a: rgb(0.95, 0.93, 0.9),
+            b: rgb(0.3, 0.3, 0.35),
+            scale: 3.,
+            turbulence: 1.,
+        },
+        Texture::Wood {
+            a: rgb(0.75, 0.55, 0.3),
+            b: rgb(0.35, 0.2, 0.1),
+            rings: 6.,
+            noise: 0.4,
+        },
+        Texture::Brick {
+            brick: rgb(0.6, 0.2, 0.15),
+            mortar: rgb(0.8, 0.8, 0.75),
+            size: Matrix([[0.4, 0.15, 0.4, 0.]]),
+            joint: 0.04,
         },
     ];
+    for (model, texture) in models.iter_mut().zip(textures) {
+        model.texture = Some(texture);
+    }
+    // Mortar sits below the bricks.
+    models[2].bump = Some(Bump {
+        texture: models[2].texture.clone().unwrap(),
+        scale: 0.02,
+        step: 0.02,
+    });
+
+    // Tiles with a dome on each, four to a checker square.
+    let floor = quad()
+        .apply(translate(-0.5, -0.5, 0.))
+        .apply(scale(16., 16., 1.))
+        .apply(rotate_x(f32::consts::PI / 2.))
+        .apply(translate(0., -0.5, 4.));
+    let floor = IndexedMesh::from_mesh(&floor, |p| Matrix([[p.x() * 2., p.z() * 2.]]));
+    let domes = NormalMap::from_height(64, 64, 0.15, |uv| {
+        (uv.x() * f32::consts::PI).sin() * (uv.y() * f32::consts::PI).sin()
+    });
+    models.insert(
+        0,
+        Model {
+            color: rgb(1., 1., 1.),
+            reflect: 0.5,
+            mesh: Mesh(vec![]),
+            texture: Some(Texture::Checker {
+                a: rgb(0.8, 0.8, 0.8),
+                b: rgb(0.2, 0.2, 0.2),
+                size: 1.,
+            }),
+            normal_mapped: Some(NormalMappedMesh::new(floor, domes)),
+            ..Default::default()
+        },
+    );
+    models
+}
+
+fn shadow_scene() -> Vec<Model> {
+    let solid = |color, mesh| Model {
+        color,
+        reflect: 1.,
+        mesh,
+        ..Default::default()
+    };
+
+    // Mirroring turns `cube`'s faces outwards, the way marching cubes
+    // winds the sphere.
+    let floor = quad()
+        .apply(translate(-0.5, -0.5, 0.))
+        .apply(scale(12., 12., 1.))
+        .apply(rotate_x(f32::consts::PI / 2.))
+        .apply(translate(0., -1., 3.));
+    let pillar = cube()
+        .apply(scale(-0.4, 2.4, 0.4))
+        .apply(translate(0.2, 0.2, 0.5));
+    let box_ = cube()
+        .apply(scale(-1., 1., 1.))
+        .apply(rotate_y(0.5))
+        .apply(translate(1.3, -0.5, -1.2));
+    let sphere = marching_cubes(&ScalarGrid::from_sdf(&Sdf::sphere(0.6), 0.1), 0.)
+        .to_mesh()
+        .apply(translate(-1., -0.4, -1.8));
+
+    vec![
+        solid(Matrix([[0.8, 0.8, 0.8, 1.]]), floor),
+        solid(Matrix([[0.3, 0.45, 0.9, 1.]]), pillar),
+        solid(Matrix([[0.9, 0.35, 0.25, 1.]]), box_),
+        solid(Matrix([[0.95, 0.95, 0.95, 1.]]), sphere),
+    ]
+}
+
+fn glass_scene() -> Vec<Model> {
+    let pane = |color, width: f32, height: f32, transform| Model {
+        color,
+        reflect: 1.,
+        mesh: quad()
+            .apply(translate(-0.5, -0.5, 0.))
+            // Mirrored to face the camera.
+            .apply(scale(-width, height, 1.))
+            .apply(transform),
+        ..Default::default()
+    };
+
+    let mut models = cubes();
+    models.extend([
+        pane(
+            Matrix([[1., 0.85, 0.2, 0.5]]),
+            3.,
+            2.5,
+            translate(1.4, 0.2, -1.5),
+        ),
+        pane(
+            Matrix([[0.2, 0.8, 1., 0.4]]),
+            3.,
+            2.,
+            translate(-2.2, -0.3, 1.),
+        ),
+        pane(
+            Matrix([[1., 0.3, 0.8, 0.6]]),
+            2.5,
+            1.5,
+            rotate_y(0.6)(translate(0., -0.6, -2.2)),
+        ),
+    ]);
+    models
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct RenderSettings {
+    pub scene: SceneKind,
+    pub projection: ProjectionKind,
+    /// Field of view in radians for the perspective and fisheye projections.
+    pub fov: f32,
+    /// Height of the view volume in world units for the orthographic projections.
+    pub view_height: f32,
+    /// Rays traced per pixel.
+    pub samples: u32,
+    /// Lens radius in world units. Zero gives a pinhole camera with everything in focus.
+    pub aperture: f32,
+    /// Distance from the camera to the plane in focus.
+    pub focal_distance: f32,
+    /// Number of diaphragm blades, or zero for a round aperture.
+    pub aperture_blades: u32,
+    /// How long the shutter stays open, in the same units as the frame time.
+    /// Zero freezes motion.
+    pub shutter: f32,
+    /// Path trace with diffuse interreflection instead of following mirror
+    /// reflections only.
+    pub path_tracing: bool,
+    /// Longest path in the path tracing mode.
+    pub max_bounces: u32,
+    /// Buffer shown by `render`. Anything but `Color` skips post-processing.
+    pub aov: AovKind,
+    /// Filter the noise out of the traced colors, guided by the AOVs.
+    pub denoise: bool,
+    /// Rasterize triangle edges only, with hidden lines removed.
+    pub wireframe: bool,
+    /// Draw model bounds and face normals over the image.
+    pub overlays: bool,
+    /// Blend transparent triangles with weighted blended order-independent
+    /// transparency instead of sorting them back to front.
+    pub oit: bool,
+    /// How the rasterizer smooths triangle edges.
+    pub antialias: AntialiasKind,
+}
+
+#[wasm_bindgen]
+impl RenderSettings {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        RenderSettings {
+            scene: SceneKind::Cubes,
+            projection: ProjectionKind::Perspective,
+            fov: f32::consts::PI / 2.,
+            view_height: 8.,
+            samples: 1,
+            aperture: 0.,
+            focal_distance: 5.,
+            aperture_blades: 0,
+            shutter: 0.,
+            path_tracing: false,
+            max_bounces: 4,
+            aov: AovKind::Color,
+            denoise: false,
+            wireframe: false,
+            overlays: false,
+            oit: false,
+            antialias: AntialiasKind::None,
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings::new()
+    }
+}
+
+impl RenderSettings {
+    /// Camera for a frame starting at time `t`.
+    pub fn camera(&self, t: f32) -> Camera {
+        let target = Matrix([[0., 0., 0., 1.]]);
+        let up = Matrix([[0., 1., 0., 0.]]);
+
+        // The camera sits at z = -5 looking at the origin.
+        let transform = look_at(Matrix([[0., 0., -5., 1.]]), target, up);
+
+        let mut camera = match self.projection {
+            ProjectionKind::Perspective => Camera::new(
+                transform,
+                Projection::Perspective {
+                    fov: self.fov,
+                    near: 0.1,
+                    far: 100.,
+                },
+            ),
+            ProjectionKind::Orthographic => Camera::new(
+                transform,
+                Projection::Orthographic {
+                    height: self.view_height,
+                    near: 0.1,
+                    far: 100.,
+                },
+            ),
+            ProjectionKind::Isometric => Camera::isometric(target, 10., self.view_height),
+            ProjectionKind::Dimetric => Camera::dimetric(target, 10., self.view_height),
+            ProjectionKind::Fisheye => {
+                Camera::new(transform, Projection::Fisheye { fov: self.fov })
+            }
+            ProjectionKind::Equirectangular => Camera::new(transform, Projection::Equirectangular),
+        };
+
+        camera.shutter = Shutter {
+            open: t,
+            close: t + self.shutter,
+        };
+
+        if self.aperture > 0. {
+            camera.lens = Some(Lens {
+                radius: self.aperture,
+                focal_distance: self.focal_distance,
+                aperture: match self.aperture_blades {
+                    0..3 => Aperture::Disc,
+                    blades => Aperture::Polygon {
+                        blades,
+                        rotation: 0.,
+                    },
+                },
+            });
+        }
+
+        camera
+    }
+}
+
+pub fn trace(
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+    environment: &Environment,
+) -> (Bitmap, CullStats) {
+    let (image, stats) = trace_image(width, height, t, settings, environment);
+    (image.to_bitmap_truncated(), stats)
+}
+
+/// Traces a frame into a float framebuffer, denoised if the settings ask
+/// for it but before any post-processing.
+pub fn trace_image(
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+    environment: &Environment,
+) -> (FloatImage, CullStats) {
+    let (image, _, stats) = trace_aovs(width, height, t, settings, environment);
+    (image, stats)
+}
+
+/// Like `trace_image`, also returning the auxiliary buffers.
+pub fn trace_aovs(
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+    environment: &Environment,
+) -> (FloatImage, Aovs, CullStats) {
+    let mut image = FloatImage::new(width as u32, height as u32);
+    let mut aovs = Aovs::new(image.width, image.height);
+
+    let aspect = width / height;
+    let camera = settings.camera(t);
+    let unprojection = camera.projection.unprojection(aspect);
+
+    // Projections without a matrix have no frustum to cull against. Lens
+    // rays leave the pinhole frustum once past the focal plane, so depth of
+    // field disables culling too.
+    let mut models = scene(settings.scene);
+    let lights = scene_lights(settings.scene);
+    let (visible, stats) = match camera.view_projection(aspect) {
+        Some(view_projection) if camera.lens.is_none() => cull_models(
+            &Frustum::from_matrix(view_projection),
+            &mut models,
+            camera.shutter,
+        ),
+        _ => ((0..models.len()).collect(), CullStats::default()),
+    };
+    let models: Vec<Placed> = models
+        .iter()
+        .map(|model| Placed::new(model, camera.shutter.open, camera.shutter.close))
+        .collect();
+
+    let samples = settings.samples.max(1) as usize;
+
+    // Primary rays are traced as 2x2 packets, which keeps neighbouring rays
+    // coherent. Lanes falling outside the bitmap are masked off. Only models
+    // inside the view frustum can be hit by a primary ray.
+    for tile_y in (0..(height as usize)).step_by(2) {
+        for tile_x in (0..(width as usize)).step_by(2) {
+            let mut pixels = [(0, 0); packet::LANES];
+            let mut pixel_samples: [Vec<Point2D>; packet::LANES] = Default::default();
+            let mut lens_samples: [Vec<Point2D>; packet::LANES] = Default::default();
+            let mut time_samples: [Vec<f32>; packet::LANES] = Default::default();
+            let mut rngs = [Rng::new(0); packet::LANES];
+            let mut colors = [Matrix::<1, 4>::default(); packet::LANES];
+            let mut aov_samples: [Vec<Option<AovSample>>; packet::LANES] = Default::default();
+            let mut hits = [0; packet::LANES];
+
+            for lane in 0..packet::LANES {
+                let screen_x = tile_x + (lane & 1);
+                let screen_y = tile_y + (lane >> 1);
+
+                // Pixel and lens positions are stratified separately and
+                // shuffled independently, so each sample pairs a different
+                // part of the pixel with a different part of the lens.
+                let mut rng = Rng::for_pixel(screen_x, screen_y, 0);
+
+                pixels[lane] = (screen_x, screen_y);
+                pixel_samples[lane] = if samples == 1 {
+                    vec![Matrix([[0.5, 0.5]])]
+                } else {
+                    stratified_2d(&mut rng, samples)
+                };
+                lens_samples[lane] = stratified_2d(&mut rng, samples);
+                time_samples[lane] = stratified_1d(&mut rng, samples);
+                rngs[lane] = rng;
+            }
+
+            for sample in 0..samples {
+                let mut origins = [Matrix::default(); packet::LANES];
+                let mut directions = [Matrix::default(); packet::LANES];
+                let mut times = [0.; packet::LANES];
+                let mut active = [false; packet::LANES];
+
+                for lane in 0..packet::LANES {
+                    let (screen_x, screen_y) = pixels[lane];
+                    times[lane] = camera.shutter.sample(time_samples[lane][sample]);
+                    let offset = pixel_samples[lane][sample];
 
-    for screen_x in 0..(width as usize) {
-        let x = screen_x as f32;
-        for screen_y in 0..(height as usize) {
-            let y = screen_y as f32;
-            let forward = Matrix([[0., 0., 1., 0.]]);
-
-            let pitch = ((y / height) - 0.5) * fov;
-            let yaw = ((x / width) - 0.5) * fov * aspect_ratio;
-
-            let origin = Matrix([[0., 0., 0., 1.]])(camera);
-            let direction = forward(rotate_y(yaw)(rotate_x(pitch)));
-
-            fn raycast_color(
-                origin: Matrix<1, 4>,
-                direction: Matrix<1, 4>,
-                background_color: Matrix<1, 4>,
-                models: &Vec<Model>,
-                depth: u32,
-            ) -> Matrix<1, 4> {
-                let mut hits: Vec<(RaycastHit, &Model)> = Vec::new();
-
-                for model in models.iter() {
-                    for trig in model.mesh.0.iter() {
-                        match ray_intersects_triangle(origin, direction, *trig) {
-                            Some(hit) => {
-                                hits.push((hit, model));
-                            }
-                            None => {}
+                    let ndc = from_screen(
+                        Matrix([[screen_x as f32 + offset.x(), screen_y as f32 + offset.y()]]),
+                        width,
+                        height,
+                    );
+                    let ray = camera.generate_ray(
+                        &unprojection,
+                        Matrix([[ndc.x(), ndc.y()]]),
+                        lens_samples[lane][sample],
+                    );
+
+                    active[lane] =
+                        screen_x < image.width as usize && screen_y < image.height as usize;
+
+                    match ray {
+                        Some((origin, direction)) => {
+                            origins[lane] = origin;
+                            directions[lane] = direction;
                         }
+                        None => active[lane] = false,
                     }
                 }
 
-                let nearest = hits
+                let packet = RayPacket::new(origins, directions, times, active);
+
+                for (lane, nearest) in packet
+                    .nearest_hits(&models[..visible.len()])
                     .iter()
-                    .min_by(|a, b| a.0.t.partial_cmp(&b.0.t).unwrap());
+                    .enumerate()
+                {
+                    if !active[lane] {
+                        continue;
+                    }
 
-                match nearest {
-                    Some((hit, model)) => {
-                        let mut out = model.color * model.reflect;
+                    aov_samples[lane].push(nearest.map(|(hit, index, model)| {
+                        hits[lane] += 1;
+                        let albedo = model.color_at(&hit);
+                        AovSample {
+                            depth: hit.t,
+                            normal: hit.shading_normal,
+                            albedo: Matrix([[albedo.x(), albedo.y(), albedo.z(), 1.]]),
+                            // Culling reorders the models, so ids come from
+                            // where they were in the scene.
+                            object_id: visible[index] as u32,
+                            barycentric: Matrix([[1. - hit.u - hit.v, hit.u, hit.v, 0.]]),
+                        }
+                    }));
+
+                    colors[lane] = colors[lane]
+                        + match nearest {
+                            _ if settings.path_tracing => path_trace(
+                                directions[lane],
+                                times[lane],
+                                *nearest,
+                                environment,
+                                &models,
+                                &lights,
+                                settings.max_bounces,
+                                &mut rngs[lane],
+                                &mut hits[lane],
+                            ),
+                            Some((hit, _, model)) => shade(
+                                directions[lane],
+                                times[lane],
+                                hit,
+                                model,
+                                environment,
+                                &models,
+                                &lights,
+                                0,
+                                &mut hits[lane],
+                            ),
+                            None => environment.radiance(directions[lane]),
+                        };
+                }
+            }
 
-                        if out.w() < 1. && depth < 2 {
-                            let dot = direction.dot(hit.normal.transpose()).x();
-                            let direction_reflected = direction - hit.normal * (2.0 * dot);
+            for (lane, color) in colors.iter().enumerate() {
+                let (screen_x, screen_y) = pixels[lane];
+                if screen_x < image.width as usize && screen_y < image.height as usize {
+                    image.set(screen_x as u32, screen_y as u32, *color / samples as f32);
+                    aovs.set(
+                        screen_x as u32,
+                        screen_y as u32,
+                        &aov_samples[lane],
+                        hits[lane],
+                    );
+                }
+            }
+        }
+    }
 
-                            let origin_reflected = origin + direction * hit.t - hit.normal / 1000.0;
+    if settings.denoise {
+        image = denoise(&image, &aovs, &DenoiseSettings::default());
+    }
 
-                            let other = raycast_color(
-                                origin_reflected,
-                                direction_reflected,
-                                background_color,
-                                models,
-                                depth + 1,
-                            );
+    (image, aovs, stats)
+}
 
-                            out = out + other;
+#[wasm_bindgen]
+pub fn render(
+    ctx: web_sys::CanvasRenderingContext2d,
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+    environment: &Environment,
+    post: &PostChain,
+) -> Result<CullStats, JsValue> {
+    let (mut image, aovs, stats) = trace_aovs(width, height, t, settings, environment);
+    let mut bmp = match settings.aov {
+        AovKind::Color => {
+            post.apply(&mut image, t as u32);
+            image.to_bitmap_truncated()
+        }
+        kind => aovs.visualize(kind).to_bitmap(),
+    };
 
-                            // console_log!("{}", out);
-                            // console_log!("{}", other);
+    if settings.overlays
+        && let Some(view_projection) = settings.camera(t).view_projection(width / height)
+    {
+        draw_overlays(&mut bmp, &scene(settings.scene), view_projection, t);
+    }
 
-                            // panic!();
+    ctx.put_image_data(&bmp.to_image_data(), 0., 0.)?;
 
-                            out
-                        } else {
-                            out
-                        }
+    Ok(stats)
+}
+
+/// Triangles the rasterizer draws for `model`, seen from `eye` in object
+/// space: its mesh, normal mapped mesh, terrain chunks and voxel faces.
+/// Voxel faces carry their palette color.
+fn raster_meshes(model: &Model, eye: Point) -> Vec<(Mesh, Option<Matrix<1, 4>>)> {
+    let mut meshes = vec![(Mesh(model.mesh.0.clone()), None)];
+
+    if let Some(normal_mapped) = &model.normal_mapped {
+        meshes.push((normal_mapped.mesh.to_mesh(), None));
+    }
+
+    if let Some(terrain) = &model.terrain {
+        for mesh in terrain.chunks(16, eye, 8.) {
+            meshes.push((mesh, None));
+        }
+    }
+
+    if let Some(voxels) = &model.voxels {
+        for (index, mesh) in voxels.greedy_mesh() {
+            meshes.push((mesh, Some(voxels.palette[index as usize])));
+        }
+    }
+
+    meshes
+}
+
+/// Debug overlays: each model's bounds and the face normals of its mesh.
+fn draw_overlays(bmp: &mut Bitmap, models: &[Model], view_projection: Matrix<4, 4>, t: f32) {
+    let bounds = Color::new(255, 220, 0, 255);
+    let normals = Color::new(0, 200, 255, 255);
+
+    for model in models.iter() {
+        bmp.draw_aabb(&model.motion_bounds(t, t), view_projection, bounds);
+        let model_view_projection = model.transform_at(t)(view_projection);
+        bmp.draw_normals(&model.mesh, model_view_projection, 0.25, normals);
+    }
+}
+
+/// Where `rasterize` fills triangles, for the antialiasing chosen.
+enum Target {
+    /// Straight into the bitmap, by pixel centres, depth tested against
+    /// everything drawn so far this frame.
+    Pixels(DepthBuffer),
+    /// Into samples resolved at the end.
+    Multisample(MultisampleBuffer),
+    /// Into a layer composited once every opaque mesh is in it.
+    Coverage(CoverageLayer),
+}
+
+impl Target {
+    fn new(antialias: AntialiasKind, width: u32, height: u32) -> Target {
+        match (antialias, antialias.samples()) {
+            (_, Some(samples)) => {
+                Target::Multisample(MultisampleBuffer::new(width, height, samples))
+            }
+            (AntialiasKind::Coverage, _) => Target::Coverage(CoverageLayer::new(width, height)),
+            _ => Target::Pixels(DepthBuffer::new(width, height)),
+        }
+    }
+
+    fn fill<S: Shader>(&mut self, bmp: &mut Bitmap, shader: &S, vertices: &[S::Vertex; 3]) {
+        let [a, b, c] = vertices;
+        match self {
+            Target::Pixels(depth) => bmp.render_shader_depth(shader, [a, b, c], depth),
+            Target::Multisample(buffer) => buffer.render_shader(shader, [a, b, c]),
+            Target::Coverage(layer) => layer.render_shader(shader, [a, b, c]),
+        }
+    }
+
+    /// Finishes drawing the opaque meshes, before anything transparent
+    /// goes over them. Coverage is resolved for all of them at once, so
+    /// meshes hide each other whatever order they're drawn in.
+    fn end_opaque(&mut self, bmp: &mut Bitmap) {
+        if let Target::Coverage(layer) = self {
+            layer.resolve(bmp);
+        }
+    }
+}
+
+/// `trig`'s corners with its face normal.
+fn trig_vertices(trig: &Triangle) -> [Vertex; 3] {
+    let normal = trig.normal();
+    [trig.0, trig.1, trig.2].map(|position| Vertex { position, normal })
+}
+
+/// Returns `None` when the projection can't be expressed as a matrix.
+pub fn rasterize(
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+) -> Option<(Bitmap, CullStats)> {
+    let mut bmp = Bitmap::new(width as u32, height as u32);
+
+    let camera = settings.camera(t);
+    let view_projection = camera.view_projection(width / height)?;
+
+    // The rasterizer shows a single instant, the moment the shutter opens.
+    let instant = Shutter { open: t, close: t };
+
+    let mut models = scene(settings.scene);
+    let lights = scene_lights(settings.scene);
+    let (visible, stats) =
+        cull_models(&Frustum::from_matrix(view_projection), &mut models, instant);
+
+    // Everything drawn of each model, in object space, with the color of
+    // meshes that don't take the model's own.
+    let meshes: Vec<_> = models[..visible.len()]
+        .iter()
+        .map(|model| {
+            let eye = Matrix([[0., 0., 0., 1.]])(camera.transform)(model.transform_at(t).inv());
+            (
+                model,
+                model.transform_at(t)(view_projection),
+                raster_meshes(model, eye),
+            )
+        })
+        .collect();
+    // See-through models are drawn after everything else, apart from in
+    // wireframes where nothing is filled.
+    let (transparent, meshes): (Vec<_>, Vec<_>) = meshes
+        .into_iter()
+        .partition(|(model, _, _)| !settings.wireframe && model.color.w() < 1.);
+
+    let mut target = Target::new(settings.antialias, bmp.width, bmp.height);
+
+    if settings.wireframe {
+        // Lines are hidden by every model, not just their own.
+        let mut depth = DepthBuffer::new(bmp.width, bmp.height);
+        for (_, model_view_projection, meshes) in meshes.iter() {
+            for (mesh, _) in meshes.iter() {
+                depth.render_mesh(mesh, *model_view_projection);
+            }
+        }
+        for (model, model_view_projection, meshes) in meshes.iter() {
+            for (mesh, color) in meshes.iter() {
+                let color = color.unwrap_or(model.color).to_color();
+                bmp.draw_wireframe(mesh, *model_view_projection, color, Some(&depth));
+            }
+        }
+    } else if !lights.is_empty() {
+        // Every model casts shadows, including those out of view.
+        let eye = Matrix([[0., 0., 0., 1.]])(camera.transform);
+        let casters: Vec<_> = models
+            .iter()
+            .flat_map(|model| {
+                let transform = model.transform_at(t);
+                raster_meshes(model, eye(transform.inv()))
+                    .into_iter()
+                    .map(move |(mesh, _)| (mesh, transform))
+            })
+            .collect();
+        let casters: Vec<Caster> = casters
+            .iter()
+            .map(|(mesh, transform)| (mesh, *transform))
+            .collect();
+        let near = match camera.projection {
+            Projection::Perspective { near, .. } | Projection::Orthographic { near, .. } => near,
+            _ => 0.1,
+        };
+        let shadow_settings = ShadowSettings::default();
+        let lights: Vec<_> = lights
+            .iter()
+            .map(|light| {
+                ShadowedLight::render(
+                    *light,
+                    camera.view(),
+                    view_projection,
+                    near,
+                    &casters,
+                    &shadow_settings,
+                )
+            })
+            .collect();
+
+        for (model, _, meshes) in meshes.iter() {
+            let transforms = Transforms::new(model.transform_at(t), view_projection);
+            for (mesh, color) in meshes.iter() {
+                let shader = Lit {
+                    transforms,
+                    color: color.unwrap_or(model.color),
+                    texture: model.texture.as_ref(),
+                    lights: &lights,
+                    eye,
+                };
+                for trig in mesh.0.iter() {
+                    target.fill(&mut bmp, &shader, &trig_vertices(trig));
+                }
+            }
+        }
+    } else {
+        for (model, _, meshes) in meshes.iter() {
+            let transforms = Transforms::new(model.transform_at(t), view_projection);
+            for (mesh, color) in meshes.iter() {
+                let flat = Flat {
+                    transforms,
+                    color: color.unwrap_or(model.color).to_color(),
+                };
+                // Meshes with colors of their own aren't textured.
+                let textured = match (color, &model.texture) {
+                    (None, Some(texture)) => Some(Textured {
+                        transforms,
+                        texture,
+                    }),
+                    _ => None,
+                };
+                for trig in mesh.0.iter() {
+                    let vertices = trig_vertices(trig);
+                    match &textured {
+                        Some(textured) => target.fill(&mut bmp, textured, &vertices),
+                        None => target.fill(&mut bmp, &flat, &vertices),
                     }
-                    None => background_color,
                 }
             }
+        }
+    }
+
+    target.end_opaque(&mut bmp);
+
+    let mut oit = None;
+    if !transparent.is_empty() {
+        // Opaque surfaces hide what's behind them, but transparent ones
+        // don't hide each other.
+        let depth = match &mut target {
+            Target::Multisample(buffer) => buffer.depth_buffer(),
+            // Opaque meshes are done with the frame's depth by now.
+            Target::Pixels(depth) => std::mem::replace(depth, DepthBuffer::new(0, 0)),
+            Target::Coverage(_) => {
+                let mut depth = DepthBuffer::new(bmp.width, bmp.height);
+                for (_, model_view_projection, meshes) in meshes.iter() {
+                    for (mesh, _) in meshes.iter() {
+                        depth.render_mesh(mesh, *model_view_projection);
+                    }
+                }
+                depth
+            }
+        };
+
+        // Every transparent triangle, furthest from the camera first.
+        let mut shaders = vec![];
+        let mut trigs = vec![];
+        for (model, _, meshes) in transparent.iter() {
+            let transforms = Transforms::new(model.transform_at(t), view_projection);
+            for (mesh, color) in meshes.iter() {
+                for trig in mesh.0.iter() {
+                    let centroid = transforms.clip((trig.0 + trig.1 + trig.2) / 3.);
+                    trigs.push((centroid.z() / centroid.w(), *trig, shaders.len()));
+                }
+                shaders.push(Flat {
+                    transforms,
+                    color: color.unwrap_or(model.color).to_color(),
+                });
+            }
+        }
+        trigs.sort_by(|a, b| b.0.total_cmp(&a.0));
 
-            bmp.rows[screen_y][screen_x] =
-                raycast_color(origin, direction, background_color, &models, 0).to_color();
+        // Multisampled transparency is sorted into the samples, weighted
+        // blending happens after the resolve.
+        oit = settings
+            .oit
+            .then(|| WeightedBlend::new(bmp.width, bmp.height));
+        for (_, trig, shader) in trigs.iter() {
+            let shader = &shaders[*shader];
+            let [a, b, c] = trig_vertices(trig);
+            match (&mut oit, &mut target) {
+                (Some(oit), _) => oit.render_shader(shader, [&a, &b, &c], Some(&depth)),
+                (None, Target::Multisample(buffer)) => {
+                    buffer.render_shader_blended(shader, [&a, &b, &c], BlendMode::Over)
+                }
+                (None, _) => {
+                    bmp.render_shader_blended(shader, [&a, &b, &c], BlendMode::Over, Some(&depth))
+                }
+            }
         }
     }
 
+    if let Target::Multisample(buffer) = &target {
+        buffer.resolve(&mut bmp);
+    }
+    if let Some(oit) = oit {
+        oit.resolve(&mut bmp);
+    }
+
+    if settings.overlays {
+        draw_overlays(&mut bmp, &models[..visible.len()], view_projection, t);
+    }
+
+    Some((bmp, stats))
+}
+
+/// Traces a frame like `render` and returns it encoded as an image file, so
+/// it can be downloaded from the page.
+#[wasm_bindgen]
+pub fn export_image(
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+    environment: &Environment,
+    post: &PostChain,
+    format: ImageFormat,
+) -> Vec<u8> {
+    let (mut image, _) = trace_image(width, height, t, settings, environment);
+    post.apply(&mut image, t as u32);
+    encode(&image.to_bitmap_truncated(), format)
+}
+
+#[wasm_bindgen]
+pub fn render_raster(
+    ctx: web_sys::CanvasRenderingContext2d,
+    width: f32,
+    height: f32,
+    t: f32,
+    settings: &RenderSettings,
+) -> Result<CullStats, JsValue> {
+    let (bmp, stats) = rasterize(width, height, t, settings)
+        .ok_or_else(|| JsValue::from_str("projection can't be rasterized"))?;
+
     ctx.put_image_data(&bmp.to_image_data(), 0., 0.)?;
 
-    Ok(())
+    Ok(stats)
 }
 
-// #[wasm_bindgen]
-// pub fn render(
-//     ctx: web_sys::CanvasRenderingContext2d,
-//     width: f32,
-//     height: f32,
-//     t: f32,
-// ) -> Result<(), JsValue> {
-//     let mut bmp = Bitmap::new(width as u32, height as u32);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-//     let projection = perspective(f32::consts::PI / 2., width / height, 0., 100.);
+    #[test]
+    fn test_path_trace_furnace() {
+        // A convex object under a uniform white environment reflects exactly
+        // its albedo, however the light is sampled.
+        for (albedo, reflect) in [(1., 0.), (0.5, 0.), (0.5, 0.5)] {
+            let model = Model {
+                color: Matrix([[albedo, albedo, albedo, 1.]]),
+                reflect,
+                mesh: cube(),
+                ..Default::default()
+            };
+            let models = [Placed::new(&model, 0., 0.)];
 
-//     let camera = translate(0., 0., -5.);
-//     let view = camera.inv();
-//     let view_projection = view(projection);
+            for environment in [
+                Environment::constant(1., 1., 1.),
+                Environment::from_image(bitmap::FloatImage {
+                    width: 2,
+                    height: 1,
+                    pixels: vec![Matrix([[1., 1., 1., 1.]]); 2],
+                }),
+            ] {
+                let origin = Matrix([[0.3, 0.2, -5., 1.]]);
+                let direction = Matrix([[0., 0., 1., 0.]]);
+                let primary = nearest_hit(origin, direction, 0., &models);
+                assert!(primary.is_some());
 
-//     let model = Model {
-//         material: 0,
-//         mesh: cube().apply(rotate_y(t / 1000.)(rotate_x(t / 2000.))),
-//     };
+                let mut rng = Rng::new(3);
+                let n = 4000;
+                let mut sum = 0.;
+                for _ in 0..n {
+                    let color = path_trace(
+                        direction,
+                        0.,
+                        primary,
+                        &environment,
+                        &models,
+                        &[],
+                        4,
+                        &mut rng,
+                        &mut 0,
+                    );
+                    sum += color.x() / n as f32;
+                }
+
+                let expected = albedo * (1. - reflect) + reflect;
+                assert!((sum - expected).abs() < 0.03, "{albedo} {reflect}: {sum}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_trace_aovs() {
+        let settings = RenderSettings {
+            samples: 2,
+            ..Default::default()
+        };
+        let (image, aovs, _) = trace_aovs(48., 32., 0., &settings, &Environment::default());
 
-//     for trig in model.mesh.0.iter() {
-//         bmp.render_trig(
-//             *trig,
-//             view_projection,
-//             Color {
-//                 r: 255,
-//                 g: 0,
-//                 b: 0,
-//                 a: 255,
-//             },
-//         );
-//     }
+        // Every buffer agrees on which pixels hit something.
+        let hit = |i: usize| aovs.depth[i].is_finite();
+        assert!((0..image.pixels.len()).any(hit));
+        for i in 0..image.pixels.len() {
+            let (x, y) = (i as u32 % aovs.width, i as u32 / aovs.width);
+            if hit(i) {
+                assert!(aovs.depth[i] > 3. && aovs.depth[i] < 6.);
+                assert!(aovs.hit_count[i] >= 1);
+                assert_eq!(aovs.albedo.get(x, y).w(), 1.);
+            } else {
+                assert_eq!(aovs.hit_count[i], 0);
+                assert_eq!(aovs.object_id[i], None);
+            }
+        }
 
-//     // let x = width / 2.;
-//     // let y = height / 2.;
+        let ids: std::collections::HashSet<_> = aovs.object_id.iter().flatten().collect();
+        assert_eq!(ids.len(), 3);
 
-//     // let point = from_screen(Matrix([[x, y]]), width, height)(view_projection.inv());
+        // Zoomed in on the middle cube the others are culled, which moves it
+        // to the front of the models, but it keeps its id.
+        let settings = RenderSettings {
+            fov: 0.2,
+            ..Default::default()
+        };
+        let (_, aovs, stats) = trace_aovs(16., 16., 0., &settings, &Environment::default());
+        assert_eq!(stats.culled, 2);
+        let ids: std::collections::HashSet<_> = aovs.object_id.iter().flatten().collect();
+        assert_eq!(ids, [1].iter().collect());
+    }
 
-//     // let mut point = Matrix([[0.5, 0.5]])(view_projection);
-//     // point.0[0][3] = 1.;
+    #[test]
+    fn test_target_hides_far_meshes() {
+        use crate::shader::Solid;
 
-//     ctx.put_image_data(&bmp.to_image_data(), 0., 0.)?;
+        // Two meshes over the whole bitmap, each split along its diagonal.
+        let halves = |z: f32| {
+            let corner = |x: f32, y: f32| Matrix([[x, y, z, 1.]]);
+            [
+                [corner(-1., -1.), corner(1., -1.), corner(1., 1.)],
+                [corner(-1., -1.), corner(1., 1.), corner(-1., 1.)],
+            ]
+        };
+        let near = (Solid(Color::new(255, 0, 0, 255)), halves(0.));
+        let far = (Solid(Color::new(0, 255, 0, 255)), halves(0.5));
 
-//     Ok(())
-// }
+        // The further mesh drawn last stays hidden, however the target
+        // antialiases.
+        for antialias in [
+            AntialiasKind::None,
+            AntialiasKind::Msaa4,
+            AntialiasKind::Coverage,
+        ] {
+            let mut bmp = Bitmap::new(4, 4);
+            let mut target = Target::new(antialias, 4, 4);
+            for (shader, halves) in [&near, &far] {
+                for vertices in halves {
+                    target.fill(&mut bmp, shader, vertices);
+                }
+            }
+            target.end_opaque(&mut bmp);
+            if let Target::Multisample(buffer) = &target {
+                buffer.resolve(&mut bmp);
+            }
+            assert!(
+                bmp.rows
+                    .iter()
+                    .flatten()
+                    .all(|color| (color.r, color.g, color.b, color.a) == (255, 0, 0, 255)),
+                "{antialias:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    matrix::Matrix,
+    matrix_3d::{Point, dot3, length3, look_at, orthographic, perspective, unit3},
+};
+
+/// Light reaching surfaces that no light shines on directly, as a fraction
+/// of their color.
+pub const AMBIENT: f32 = 0.1;
+
+/// Punctual light source. `color` is what a white diffuse surface facing
+/// the light reflects, so a surface is lit by `color * cos` of the angle
+/// the light arrives at. Neither kind fades with distance.
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    /// Parallel light shining along `direction`, as from the sun.
+    Directional {
+        direction: Matrix<1, 4>,
+        color: Matrix<1, 4>,
+    },
+    /// Light at `position` shining along `direction` within `angle` radians
+    /// of it, fading out over the outer fifth of the cone.
+    Spot {
+        position: Point,
+        direction: Matrix<1, 4>,
+        angle: f32,
+        color: Matrix<1, 4>,
+    },
+}
+
+impl Light {
+    /// Unit direction from `p` towards the light, how far away the light
+    /// is, and the color it lights `p` with before the cosine term.
+    pub fn incident(&self, p: Point) -> (Matrix<1, 4>, f32, Matrix<1, 4>) {
+        match *self {
+            Light::Directional { direction, color } => (-unit3(direction), f32::INFINITY, color),
+            Light::Spot {
+                position,
+                direction,
+                angle,
+                color,
+            } => {
+                let to_light = position - p;
+                let distance = length3(to_light);
+                // At the light itself there's no direction for it to come
+                // from.
+                if distance == 0. {
+                    return (Matrix::default(), 0., Matrix::default());
+                }
+                let to_light = to_light / distance;
+                let cos = -dot3(to_light, unit3(direction));
+                let (outer, inner) = (angle.cos(), (angle * 0.8).cos());
+                let t = ((cos - outer) / (inner - outer)).clamp(0., 1.);
+                (to_light, distance, color * (t * t * (3. - 2. * t)))
+            }
+        }
+    }
+
+    /// World to clip space for a spot light's shadow map, a square
+    /// perspective covering the cone from `near` to `far`.
+    pub fn spot_view_projection(&self, near: f32, far: f32) -> Option<Matrix<4, 4>> {
+        let Light::Spot {
+            position,
+            direction,
+            angle,
+            ..
+        } = *self
+        else {
+            return None;
+        };
+        let view = look_at(position, position + direction, up(direction)).inv();
+        let fov = (angle * 2.2).min(3.);
+        Some(view(perspective(fov, 1., near, far)))
+    }
+
+    /// World to clip space for a directional light's shadow map, an
+    /// orthographic box along the light around the world space `receivers`,
+    /// reaching back towards the light far enough to take in `casters`.
+    pub fn directional_view_projection(
+        &self,
+        receivers: &[Point],
+        casters: &[Point],
+    ) -> Option<Matrix<4, 4>> {
+        let Light::Directional { direction, .. } = *self else {
+            return None;
+        };
+        let view = look_at(
+            Matrix([[0., 0., 0., 1.]]),
+            Matrix([[0., 0., 0., 1.]]) + direction,
+            up(direction),
+        )
+        .inv();
+
+        let mut min = Matrix([[f32::INFINITY; 4]]);
+        let mut max = Matrix([[f32::NEG_INFINITY; 4]]);
+        for p in receivers.iter() {
+            let p = p(view);
+            for axis in 0..3 {
+                min[0][axis] = min[0][axis].min(p[0][axis]);
+                max[0][axis] = max[0][axis].max(p[0][axis]);
+            }
+        }
+        if min.x() > max.x() {
+            return None;
+        }
+        for p in casters.iter() {
+            max[0][2] = max[0][2].max(p(view).z());
+        }
+
+        // The light looks down -z, so the nearest point has the largest z.
+        Some(view(orthographic(
+            min.x(),
+            max.x(),
+            min.y(),
+            max.y(),
+            -max.z(),
+            -min.z(),
+        )))
+    }
+}
+
+/// Any up vector not parallel to `direction`, for aiming light cameras.
+fn up(direction: Matrix<1, 4>) -> Matrix<1, 4> {
+    if unit3(direction).y().abs() > 0.99 {
+        Matrix([[0., 0., 1., 0.]])
+    } else {
+        Matrix([[0., 1., 0., 0.]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incident() {
+        let spot = Light::Spot {
+            position: Matrix([[0., 2., 0., 1.]]),
+            direction: Matrix([[0., -1., 0., 0.]]),
+            angle: 0.5,
+            color: Matrix([[1., 1., 1., 1.]]),
+        };
+
+        let (to_light, distance, color) = spot.incident(Matrix([[0., 0., 0., 1.]]));
+        assert_eq!(to_light, Matrix([[0., 1., 0., 0.]]));
+        assert_eq!(distance, 2.);
+        assert_eq!(color, Matrix([[1., 1., 1., 1.]]));
+
+        // Outside the cone, and at the light itself, nothing arrives.
+        let (_, _, color) = spot.incident(Matrix([[2., 0., 0., 1.]]));
+        assert_eq!(color, Matrix::default());
+        let (to_light, distance, color) = spot.incident(Matrix([[0., 2., 0., 1.]]));
+        assert_eq!(
+            (to_light, distance, color),
+            (Matrix::default(), 0., Matrix::default())
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::matrix::Matrix;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CubeError {
+    /// A line that isn't a keyword or three numbers. Lines count from one.
+    Malformed { line: usize },
+    /// A 1D LUT, or a keyword this parser doesn't know.
+    Unsupported(String),
+    /// No `LUT_3D_SIZE`, or a size outside 2..=256.
+    InvalidSize,
+    /// The table doesn't have size³ entries.
+    EntryCount { expected: usize, found: usize },
+}
+
+impl fmt::Display for CubeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CubeError::Malformed { line } => write!(f, "malformed .cube line {line}"),
+            CubeError::Unsupported(what) => write!(f, "unsupported .cube feature: {what}"),
+            CubeError::InvalidSize => f.write_str("missing or invalid LUT_3D_SIZE"),
+            CubeError::EntryCount { expected, found } => {
+                write!(f, "expected {expected} LUT entries, found {found}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CubeError {}
+
+/// 3D color lookup table, applied with trilinear interpolation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut3D {
+    pub size: usize,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    /// Output colors with red varying fastest, then green, then blue.
+    pub table: Vec<[f32; 3]>,
+}
+
+impl Lut3D {
+    /// Maps every color to itself, with `size` entries along each axis, at
+    /// least 2.
+    pub fn identity(size: usize) -> Self {
+        assert!(size >= 2);
+        let step = 1. / (size - 1) as f32;
+        let mut table = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    table.push([r as f32 * step, g as f32 * step, b as f32 * step]);
+                }
+            }
+        }
+
+        Lut3D {
+            size,
+            domain_min: [0.; 3],
+            domain_max: [1.; 3],
+            table,
+        }
+    }
+
+    /// Parses the Adobe/Resolve `.cube` format.
+    pub fn parse_cube(text: &str) -> Result<Self, CubeError> {
+        let mut size = None;
+        let mut domain_min = [0.; 3];
+        let mut domain_max = [1.; 3];
+        let mut table = Vec::new();
+
+        let triple = |fields: &[&str], line: usize| -> Result<[f32; 3], CubeError> {
+            match fields {
+                [r, g, b] => {
+                    let parse =
+                        |v: &str| v.parse::<f32>().map_err(|_| CubeError::Malformed { line });
+                    Ok([parse(r)?, parse(g)?, parse(b)?])
+                }
+                _ => Err(CubeError::Malformed { line }),
+            }
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let number = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[0] {
+                "TITLE" => {}
+                "LUT_3D_SIZE" => {
+                    let n = fields
+                        .get(1)
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .ok_or(CubeError::Malformed { line: number })?;
+                    if !(2..=256).contains(&n) {
+                        return Err(CubeError::InvalidSize);
+                    }
+                    size = Some(n);
+                }
+                "DOMAIN_MIN" => domain_min = triple(&fields[1..], number)?,
+                "DOMAIN_MAX" => domain_max = triple(&fields[1..], number)?,
+                "LUT_1D_SIZE" | "LUT_1D_INPUT_RANGE" => {
+                    return Err(CubeError::Unsupported(fields[0].to_string()));
+                }
+                keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                    return Err(CubeError::Unsupported(keyword.to_string()));
+                }
+                _ => table.push(triple(&fields, number)?),
+            }
+        }
+
+        let size = size.ok_or(CubeError::InvalidSize)?;
+        if table.len() != size * size * size {
+            return Err(CubeError::EntryCount {
+                expected: size * size * size,
+                found: table.len(),
+            });
+        }
+
+        Ok(Lut3D {
+            size,
+            domain_min,
+            domain_max,
+            table,
+        })
+    }
+
+    fn entry(&self, r: usize, g: usize, b: usize) -> [f32; 3] {
+        self.table[(b * self.size + g) * self.size + r]
+    }
+
+    /// Maps a color through the table. Alpha passes through unchanged.
+    pub fn apply(&self, color: Matrix<1, 4>) -> Matrix<1, 4> {
+        let max = (self.size - 1) as f32;
+
+        // Position in grid units, and the cell and offset within it per axis.
+        let cell = |c: usize| {
+            let range = self.domain_max[c] - self.domain_min[c];
+            let x = ((color[0][c] - self.domain_min[c]) / range).clamp(0., 1.) * max;
+            let i = (x as usize).min(self.size - 2);
+            (i, x - i as f32)
+        };
+        let (r, fr) = cell(0);
+        let (g, fg) = cell(1);
+        let (b, fb) = cell(2);
+
+        let mut out = [0.; 3];
+        for (corner, weight) in [
+            ((0, 0, 0), (1. - fr) * (1. - fg) * (1. - fb)),
+            ((1, 0, 0), fr * (1. - fg) * (1. - fb)),
+            ((0, 1, 0), (1. - fr) * fg * (1. - fb)),
+            ((1, 1, 0), fr * fg * (1. - fb)),
+            ((0, 0, 1), (1. - fr) * (1. - fg) * fb),
+            ((1, 0, 1), fr * (1. - fg) * fb),
+            ((0, 1, 1), (1. - fr) * fg * fb),
+            ((1, 1, 1), fr * fg * fb),
+        ] {
+            let entry = self.entry(r + corner.0, g + corner.1, b + corner.2);
+            for c in 0..3 {
+                out[c] += entry[c] * weight;
+            }
+        }
+
+        Matrix([[out[0], out[1], out[2], color.w()]])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let lut = Lut3D::identity(5);
+        for color in [[0.1, 0.5, 0.9], [0., 1., 0.33], [0.77, 0.2, 0.01]] {
+            let out = lut.apply(Matrix([[color[0], color[1], color[2], 0.5]]));
+            for c in 0..3 {
+                assert!((out[0][c] - color[c]).abs() < 1e-5);
+            }
+            assert_eq!(out.w(), 0.5);
+        }
+
+        // Out of range input clamps to the domain.
+        let out = lut.apply(Matrix([[2., -1., 0.5, 1.]]));
+        assert_eq!(out.round(5), Matrix([[1., 0., 0.5, 1.]]));
+    }
+
+    #[test]
+    fn test_parse_cube() {
+        let text = "TITLE \"invert\"\n# comment\nLUT_3D_SIZE 2\n\n\
+            1 1 1\n0 1 1\n1 0 1\n0 0 1\n1 1 0\n0 1 0\n1 0 0\n0 0 0\n";
+        let lut = Lut3D::parse_cube(text).unwrap();
+        assert_eq!(lut.size, 2);
+
+        let out = lut.apply(Matrix([[0.25, 0.5, 1., 1.]]));
+        assert_eq!(out.round(5), Matrix([[0.75, 0.5, 0., 1.]]));
+
+        let text = "LUT_3D_SIZE 2\nDOMAIN_MIN 0 0 0\nDOMAIN_MAX 2 2 2\n\
+            0 0 0\n1 0 0\n0 1 0\n1 1 0\n0 0 1\n1 0 1\n0 1 1\n1 1 1\n";
+        let out = Lut3D::parse_cube(text)
+            .unwrap()
+            .apply(Matrix([[1., 2., 0., 1.]]));
+        assert_eq!(out.round(5), Matrix([[0.5, 1., 0., 1.]]));
+    }
+
+    #[test]
+    fn test_cube_errors() {
+        assert_eq!(
+            Lut3D::parse_cube("LUT_3D_SIZE 2\n0 0 0\n"),
+            Err(CubeError::EntryCount {
+                expected: 8,
+                found: 1
+            })
+        );
+        assert_eq!(
+            Lut3D::parse_cube("LUT_3D_SIZE 2\n0 0\n"),
+            Err(CubeError::Malformed { line: 2 })
+        );
+        assert_eq!(
+            Lut3D::parse_cube("LUT_1D_SIZE 16\n"),
+            Err(CubeError::Unsupported("LUT_1D_SIZE".to_string()))
+        );
+        assert_eq!(Lut3D::parse_cube("0 0 0\n"), Err(CubeError::InvalidSize));
+        assert_eq!(
+            Lut3D::parse_cube("LUT_3D_SIZE 1\n"),
+            Err(CubeError::InvalidSize)
+        );
+    }
+}
@@ -118,6 +118,16 @@ impl<const H: usize, const W: usize> Matrix<H, W> {
         output
     }
 
+    pub fn abs(self) -> Self {
+        let mut output = self;
+        for y in 0..H {
+            for x in 0..W {
+                output[y][x] = self[y][x].abs();
+            }
+        }
+        output
+    }
+
     pub fn round(&self, digits: u32) -> Self {
         let mut copy = self.clone();
         let mult = (10 as u32).pow(digits) as f32;
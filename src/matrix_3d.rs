@@ -1,6 +1,14 @@
 use core::f32;
 
-use crate::matrix::Matrix;
+use crate::{
+    aabb::Aabb,
+    matrix::Matrix,
+    normal_map::{Bump, NormalMappedMesh},
+    sdf::Sdf,
+    terrain::Heightfield,
+    texture::Texture,
+    voxel::VoxelGrid,
+};
 
 pub fn perspective(fov: f32, aspect: f32, near: f32, far: f32) -> Matrix<4, 4> {
     let f = f32::tan(f32::consts::PI * 0.5 - 0.5 * fov);
@@ -14,6 +22,44 @@ pub fn perspective(fov: f32, aspect: f32, near: f32, far: f32) -> Matrix<4, 4> {
     ]);
 }
 
+pub fn orthographic(
+    left: f32,
+    right: f32,
+    bottom: f32,
+    top: f32,
+    near: f32,
+    far: f32,
+) -> Matrix<4, 4> {
+    Matrix([
+        [2. / (right - left), 0., 0., 0.],
+        [0., 2. / (top - bottom), 0., 0.],
+        [0., 0., -2. / (far - near), 0.],
+        [
+            -(right + left) / (right - left),
+            -(top + bottom) / (top - bottom),
+            -(far + near) / (far - near),
+            1.,
+        ],
+    ])
+}
+
+/// Camera-to-world transform for a camera at `eye` looking at `target`.
+/// Like `perspective`, the camera looks down its local -z axis.
+pub fn look_at(eye: Point, target: Point, up: Matrix<1, 4>) -> Matrix<4, 4> {
+    let to_3d = |v: Matrix<1, 4>| Matrix([[v.x(), v.y(), v.z()]]);
+
+    let back = to_3d(eye - target).normalize();
+    let right = to_3d(up).cross(back).normalize();
+    let up = back.cross(right);
+
+    Matrix([
+        [right.x(), right.y(), right.z(), 0.],
+        [up.x(), up.y(), up.z(), 0.],
+        [back.x(), back.y(), back.z(), 0.],
+        [eye.x(), eye.y(), eye.z(), 1.],
+    ])
+}
+
 pub fn rotate_x(radians: f32) -> Matrix<4, 4> {
     let mut out = Matrix::<4, 4>::identity();
     out[1][1] = f32::cos(radians);
@@ -59,10 +105,37 @@ pub fn scale(x: f32, y: f32, z: f32) -> Matrix<4, 4> {
     ]);
 }
 
+/// Dot product of the x, y and z components, for directions held with a w.
+pub fn dot3(a: Matrix<1, 4>, b: Matrix<1, 4>) -> f32 {
+    a.x() * b.x() + a.y() * b.y() + a.z() * b.z()
+}
+
+/// Cross product of the x, y and z components, with w zeroed.
+pub fn cross3(a: Matrix<1, 4>, b: Matrix<1, 4>) -> Matrix<1, 4> {
+    Matrix([[
+        a.y() * b.z() - a.z() * b.y(),
+        a.z() * b.x() - a.x() * b.z(),
+        a.x() * b.y() - a.y() * b.x(),
+        0.,
+    ]])
+}
+
+/// Length of the x, y and z components.
+pub fn length3(v: Matrix<1, 4>) -> f32 {
+    dot3(v, v).sqrt()
+}
+
+/// `v` scaled to length one, with w zeroed. A zero vector stays zero.
+pub fn unit3(v: Matrix<1, 4>) -> Matrix<1, 4> {
+    let length = length3(v);
+    let v = if length > 0. { v / length } else { v };
+    Matrix([[v.x(), v.y(), v.z(), 0.]])
+}
+
 pub fn screen(pos: Matrix<1, 4>, screen_width: f32, screen_height: f32) -> Matrix<1, 2> {
     return Matrix([[
-        ((pos.x() / pos.z() + 1.) * (screen_width)) / 2.,
-        ((1. - pos.y() / pos.z()) * (screen_height)) / 2.,
+        ((pos.x() / pos.w() + 1.) * (screen_width)) / 2.,
+        ((1. - pos.y() / pos.w()) * (screen_height)) / 2.,
     ]]);
 }
 pub fn from_screen(
@@ -83,12 +156,40 @@ pub fn from_screen(
     Matrix([[x, y, z, w]])
 }
 
+/// Ray through a point in normalized device coordinates, found by
+/// unprojecting it onto the near and far clip planes. Returns the point on the
+/// near plane and a normalized direction.
+pub fn unproject_ray(
+    ndc: Matrix<1, 2>,
+    inverse_view_projection: Matrix<4, 4>,
+) -> (Matrix<1, 4>, Matrix<1, 4>) {
+    let near = Matrix([[ndc.x(), ndc.y(), -1., 1.]])(inverse_view_projection);
+    let far = Matrix([[ndc.x(), ndc.y(), 1., 1.]])(inverse_view_projection);
+
+    let near = near / near.w();
+    let far = far / far.w();
+
+    (near, (far - near).normalize())
+}
+
 pub type Point = Matrix<1, 4>;
 pub type Point2D = Matrix<1, 2>;
 
 #[derive(Clone, Copy)]
 pub struct Triangle(pub Point, pub Point, pub Point);
 
+impl Triangle {
+    pub fn normal(&self) -> Matrix<1, 4> {
+        let p0 = Matrix([[self.0.x(), self.0.y(), self.0.z()]]);
+        let p1 = Matrix([[self.1.x(), self.1.y(), self.1.z()]]);
+        let p2 = Matrix([[self.2.x(), self.2.y(), self.2.z()]]);
+
+        let normal = (p1 - p0).cross(p2 - p0).normalize();
+
+        Matrix([[normal.x(), normal.y(), normal.z(), 0.]])
+    }
+}
+
 pub struct Mesh(pub Vec<Triangle>);
 
 impl Mesh {
@@ -109,12 +210,273 @@ impl Mesh {
     pub fn join(&mut self, other: Mesh) {
         self.0.append(&mut other.0.clone());
     }
+
+    pub fn bounds(&self) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for trig in self.0.iter() {
+            aabb.grow(trig.0);
+            aabb.grow(trig.1);
+            aabb.grow(trig.2);
+        }
+        aabb
+    }
 }
 
+/// Object-to-world transform as a function of time, for models that move
+/// while the shutter is open.
+pub struct Motion(pub Box<dyn Fn(f32) -> Matrix<4, 4>>);
+
 pub struct Model {
+    /// In world space for static models, in object space when `motion` is set.
     pub mesh: Mesh,
     pub color: Matrix<1, 4>,
     pub reflect: f32,
+    pub motion: Option<Motion>,
+    /// Implicit surface sphere traced alongside the mesh, in the same space.
+    /// The rasterizer only draws the mesh.
+    pub sdf: Option<Sdf>,
+    /// Voxel volume traced alongside the mesh, in the same space. The
+    /// rasterizer draws its greedy mesh.
+    pub voxels: Option<VoxelGrid>,
+    /// Heightfield traced alongside the mesh, in the same space. The
+    /// rasterizer draws it in level of detail chunks.
+    pub terrain: Option<Heightfield>,
+    /// Replaces `color` with a pattern evaluated in object space.
+    pub texture: Option<Texture>,
+    /// Normal mapped mesh traced alongside the mesh, in the same space. The
+    /// rasterizer draws its triangles.
+    pub normal_mapped: Option<NormalMappedMesh>,
+    /// Perturbs the shading normal of every hit on the model.
+    pub bump: Option<Bump>,
+}
+
+impl Default for Model {
+    /// An empty, static, opaque white model with nothing traced
+    /// alongside its mesh.
+    fn default() -> Model {
+        Model {
+            mesh: Mesh(vec![]),
+            color: Matrix([[1., 1., 1., 1.]]),
+            reflect: 1.,
+            motion: None,
+            sdf: None,
+            voxels: None,
+            terrain: None,
+            texture: None,
+            normal_mapped: None,
+            bump: None,
+        }
+    }
+}
+
+impl Model {
+    pub fn transform_at(&self, time: f32) -> Matrix<4, 4> {
+        match &self.motion {
+            Some(motion) => motion.0(time),
+            None => Matrix::identity(),
+        }
+    }
+
+    /// Surface color at a hit on this model: the hit's own color where it
+    /// has one, as voxels do, then the texture, then `color`.
+    pub fn color_at(&self, hit: &RaycastHit) -> Matrix<1, 4> {
+        if let Some(color) = hit.color {
+            return color;
+        }
+        match &self.texture {
+            Some(texture) => texture.color(hit.object_position),
+            None => self.color,
+        }
+    }
+
+    /// World space bounds at a single instant.
+    pub fn bounds(&self) -> Aabb {
+        self.motion_bounds(0., 0.)
+    }
+
+    /// Bounds swept between `open` and `close`. The transform is sampled at
+    /// fixed steps and the result is padded by half the furthest any corner
+    /// travels in one step, which covers the bulge of curved paths between
+    /// samples as long as no step turns more than half a revolution.
+    pub fn motion_bounds(&self, open: f32, close: f32) -> Aabb {
+        let mut bounds = self.mesh.bounds();
+        if let Some(sdf) = &self.sdf {
+            bounds = bounds.union(sdf.bounds());
+        }
+        if let Some(voxels) = &self.voxels {
+            bounds = bounds.union(voxels.bounds());
+        }
+        if let Some(terrain) = &self.terrain {
+            bounds = bounds.union(terrain.bounds());
+        }
+        if let Some(normal_mapped) = &self.normal_mapped {
+            bounds = bounds.union(normal_mapped.bounds());
+        }
+
+        let Some(motion) = &self.motion else {
+            return bounds;
+        };
+
+        if open == close {
+            return bounds.transform(motion.0(open));
+        }
+
+        let steps = 16;
+        let mut out = Aabb::empty();
+        let mut previous: Option<[Point; 8]> = None;
+        let mut padding: f32 = 0.;
+
+        for i in 0..=steps {
+            let time = open + (close - open) * i as f32 / steps as f32;
+            let transform = motion.0(time);
+
+            out = out.union(bounds.transform(transform));
+
+            let corners = bounds.corners().map(|corner| corner(transform));
+            if let Some(previous) = previous {
+                for (a, b) in corners.iter().zip(previous.iter()) {
+                    padding = padding.max(length3(*a - *b));
+                }
+            }
+            previous = Some(corners);
+        }
+
+        let padding = Matrix([[padding / 2., padding / 2., padding / 2., 0.]]);
+        Aabb::new(out.min - padding, out.max + padding)
+    }
+
+    /// Object to world transform at `time` and its inverse, for moving
+    /// models.
+    pub fn motion_at(&self, time: f32) -> Option<(Matrix<4, 4>, Matrix<4, 4>)> {
+        let transform = self.motion.as_ref()?.0(time);
+        Some((transform, transform.inv()))
+    }
+
+    /// Closest hit against this model at `time`. Moving models are
+    /// intersected in object space, so the mesh is never re-transformed.
+    pub fn nearest_hit(
+        &self,
+        origin: Point,
+        direction: Matrix<1, 4>,
+        time: f32,
+        culling: Culling,
+    ) -> Option<RaycastHit> {
+        self.nearest_hit_moved(origin, direction, self.motion_at(time), culling)
+    }
+
+    /// Closest hit against this model moved by `motion`, a transform and its
+    /// inverse as from `motion_at`.
+    pub fn nearest_hit_moved(
+        &self,
+        origin: Point,
+        direction: Matrix<1, 4>,
+        motion: Option<(Matrix<4, 4>, Matrix<4, 4>)>,
+        culling: Culling,
+    ) -> Option<RaycastHit> {
+        let nearest = |origin: Point, direction: Matrix<1, 4>| {
+            let mut nearest: Option<RaycastHit> = None;
+
+            for trig in self.mesh.0.iter() {
+                if let Some(hit) = ray_intersects_triangle(origin, direction, *trig, culling)
+                    && nearest.is_none_or(|other| hit.t < other.t)
+                {
+                    nearest = Some(hit);
+                }
+            }
+
+            if let Some(sdf) = &self.sdf
+                && let Some(hit) = sdf.nearest_hit(origin, direction)
+                && nearest.is_none_or(|other| hit.t < other.t)
+            {
+                nearest = Some(hit);
+            }
+
+            if let Some(voxels) = &self.voxels
+                && let Some(hit) = voxels.nearest_hit(origin, direction)
+                && nearest.is_none_or(|other| hit.t < other.t)
+            {
+                nearest = Some(hit);
+            }
+
+            if let Some(terrain) = &self.terrain
+                && let Some(hit) = terrain.nearest_hit(origin, direction)
+                && nearest.is_none_or(|other| hit.t < other.t)
+            {
+                nearest = Some(hit);
+            }
+
+            if let Some(normal_mapped) = &self.normal_mapped
+                && let Some(hit) = normal_mapped.nearest_hit(origin, direction)
+                && nearest.is_none_or(|other| hit.t < other.t)
+            {
+                nearest = Some(hit);
+            }
+
+            if let Some(bump) = &self.bump
+                && let Some(hit) = &mut nearest
+            {
+                hit.shading_normal = bump.perturb(hit.shading_normal, hit.position);
+            }
+
+            nearest
+        };
+
+        let Some((transform, inverse)) = motion else {
+            return nearest(origin, direction);
+        };
+
+        let hit = nearest(origin(inverse), direction(inverse))?;
+
+        Some(hit.transform(transform, inverse))
+    }
+}
+
+/// A model as the rays of one frame see it, with what they all need worked
+/// out once rather than for every ray: its bounds over the whole exposure
+/// and, if the shutter catches it at a single instant, where it is then.
+#[derive(Clone, Copy)]
+pub struct Placed<'a> {
+    pub model: &'a Model,
+    /// World space bounds while the shutter is open.
+    pub bounds: Aabb,
+    /// `Model::motion_at` the instant the shutter opens, for moving models
+    /// and shutters that close at the same instant.
+    pub fixed: Option<(Matrix<4, 4>, Matrix<4, 4>)>,
+}
+
+impl<'a> Placed<'a> {
+    pub fn new(model: &'a Model, open: f32, close: f32) -> Placed<'a> {
+        Placed {
+            model,
+            bounds: model.motion_bounds(open, close),
+            fixed: if open == close {
+                model.motion_at(open)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// `Model::motion_at`, without inverting a fixed transform again.
+    pub fn motion_at(&self, time: f32) -> Option<(Matrix<4, 4>, Matrix<4, 4>)> {
+        self.fixed.or_else(|| self.model.motion_at(time))
+    }
+
+    /// `Model::nearest_hit`, for rays that reach the model's bounds. The ray
+    /// comes with `1 / direction` for the slab test.
+    pub fn nearest_hit(
+        &self,
+        origin: Point,
+        direction: Matrix<1, 4>,
+        inverse_direction: Matrix<1, 4>,
+        time: f32,
+        culling: Culling,
+    ) -> Option<RaycastHit> {
+        self.bounds
+            .ray_intersects(origin, inverse_direction, f32::INFINITY)?;
+        self.model
+            .nearest_hit_moved(origin, direction, self.motion_at(time), culling)
+    }
 }
 
 pub fn quad() -> Mesh {
@@ -150,71 +512,247 @@ pub fn cube() -> Mesh {
     mesh
 }
 
+#[derive(Clone, Copy)]
 pub struct RaycastHit {
     pub t: f32,
     pub u: f32,
     pub v: f32,
     pub normal: Matrix<1, 4>,
+    /// Normal that lighting is evaluated with. Starts as `normal` and is
+    /// perturbed by bump and normal maps.
+    pub shading_normal: Matrix<1, 4>,
+    pub position: Point,
+    /// `position` in the model's object space, where its texture is looked
+    /// up. Kept when the hit is moved into world space.
+    pub object_position: Point,
+    /// Conservative absolute error bound on each component of `position`.
+    pub position_error: Matrix<1, 4>,
+    /// Surface color where it varies over the model, as with voxels.
+    /// `None` means the model's own color.
+    pub color: Option<Matrix<1, 4>>,
 }
 
+impl RaycastHit {
+    /// Builds a hit from the barycentric weights of `trig.1` (`u`) and `trig.2` (`v`).
+    pub fn from_barycentric(trig: Triangle, t: f32, u: f32, v: f32) -> Self {
+        let w = 1. - u - v;
+
+        let position = trig.0 * w + trig.1 * u + trig.2 * v;
+        let abs_sum = (trig.0 * w).abs() + (trig.1 * u).abs() + (trig.2 * v).abs();
+
+        let mut position_error = abs_sum * gamma(7);
+        position_error[0][3] = 0.;
+
+        let normal = trig.normal();
+
+        RaycastHit {
+            t,
+            u,
+            v,
+            normal,
+            shading_normal: normal,
+            position,
+            object_position: position,
+            position_error,
+            color: None,
+        }
+    }
+
+    /// Moves an object space hit into world space. `t` is unchanged because
+    /// the ray was transformed by the same affine map.
+    pub fn transform(&self, transform: Matrix<4, 4>, inverse: Matrix<4, 4>) -> Self {
+        let abs = transform.abs();
+
+        // Rounding from the transform itself adds a further error on top of
+        // the carried bound, as in pbrt's Transform::operator().
+        let mut position_error = self.position_error.dot(abs) * (1. + gamma(3))
+            + self.position.abs().dot(abs) * gamma(3);
+        position_error[0][3] = 0.;
+
+        let transform_normal = |normal: Matrix<1, 4>| {
+            let mut normal = normal.dot(inverse.transpose());
+            normal[0][3] = 0.;
+            normal.normalize()
+        };
+
+        RaycastHit {
+            t: self.t,
+            u: self.u,
+            v: self.v,
+            normal: transform_normal(self.normal),
+            shading_normal: transform_normal(self.shading_normal),
+            position: self.position.dot(transform),
+            object_position: self.object_position,
+            position_error,
+            color: self.color,
+        }
+    }
+
+    /// Origin for a ray leaving the surface in `direction`. The hit position
+    /// is pushed along the normal just past its error bound, onto the side the
+    /// new ray travels towards, so it cannot re-intersect the same surface.
+    pub fn spawn_origin(&self, direction: Matrix<1, 4>) -> Point {
+        let error = self.position_error;
+        let n = self.normal;
+
+        let d = n.x().abs() * error.x() + n.y().abs() * error.y() + n.z().abs() * error.z();
+        let mut offset = n * d;
+
+        if direction.dot(n.transpose()).x() < 0. {
+            offset = -offset;
+        }
+
+        let mut origin = self.position + offset;
+
+        for i in 0..3 {
+            if offset[0][i] > 0. {
+                origin[0][i] = origin[0][i].next_up();
+            } else if offset[0][i] < 0. {
+                origin[0][i] = origin[0][i].next_down();
+            }
+        }
+
+        origin[0][3] = 1.;
+        origin
+    }
+}
+
+/// Bound on the relative rounding error accumulated by `n` floating-point operations.
+pub fn gamma(n: u32) -> f32 {
+    let epsilon = f32::EPSILON * 0.5;
+    (n as f32 * epsilon) / (1. - n as f32 * epsilon)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Culling {
+    None,
+    /// Ignore triangles whose winding faces away from the ray.
+    Back,
+}
+
+/// Index of the component with the largest magnitude, and the two that follow it cyclically.
+pub fn dominant_axes(direction: Matrix<1, 4>) -> (usize, usize, usize) {
+    let (x, y, z) = (
+        direction.x().abs(),
+        direction.y().abs(),
+        direction.z().abs(),
+    );
+
+    let kz = if x >= y && x >= z {
+        0
+    } else if y >= z {
+        1
+    } else {
+        2
+    };
+
+    ((kz + 1) % 3, (kz + 2) % 3, kz)
+}
+
+/// Watertight ray/triangle test (Woop, Benthin and Wald 2013). Rays passing
+/// exactly through an edge shared by two triangles always hit one of them.
 pub fn ray_intersects_triangle(
     origin: Matrix<1, 4>,
     direction: Matrix<1, 4>,
     trig: Triangle,
+    culling: Culling,
 ) -> Option<RaycastHit> {
-    let epsilon = 1e-8;
-    let origin = Matrix([[origin.x(), origin.y(), origin.z()]]);
-    let direction = Matrix([[direction.x(), direction.y(), direction.z()]]);
+    let (kx, ky, kz) = dominant_axes(direction);
+
+    let dz = direction[0][kz];
+    let shear_x = -direction[0][kx] / dz;
+    let shear_y = -direction[0][ky] / dz;
+    let shear_z = 1. / dz;
+
+    // Translate into ray space, then permute so z is the dominant axis and
+    // shear so the ray points straight down +z.
+    let transform = |p: Point| {
+        let p = p - origin;
+        let z = p[0][kz];
+        Matrix([[p[0][kx] + shear_x * z, p[0][ky] + shear_y * z, z]])
+    };
 
-    let p0 = Matrix([[trig.0.x(), trig.0.y(), trig.0.z()]]);
-    let p1 = Matrix([[trig.1.x(), trig.1.y(), trig.1.z()]]);
-    let p2 = Matrix([[trig.2.x(), trig.2.y(), trig.2.z()]]);
+    let mut p0 = transform(trig.0);
+    let mut p1 = transform(trig.1);
+    let mut p2 = transform(trig.2);
 
-    let edge1 = p1 - p0;
-    let edge2 = p2 - p0;
+    let mut e0 = p1.x() * p2.y() - p1.y() * p2.x();
+    let mut e1 = p2.x() * p0.y() - p2.y() * p0.x();
+    let mut e2 = p0.x() * p1.y() - p0.y() * p1.x();
 
-    let h = direction.cross(edge2);
-    let a = edge1.dot(h.transpose()).x();
+    // Edge functions that round to exactly zero are ambiguous; redo them in
+    // double precision so the shared edge is claimed consistently.
+    if e0 == 0. || e1 == 0. || e2 == 0. {
+        let edge = |a: Matrix<1, 3>, b: Matrix<1, 3>| {
+            (a.x() as f64 * b.y() as f64 - a.y() as f64 * b.x() as f64) as f32
+        };
+        e0 = edge(p1, p2);
+        e1 = edge(p2, p0);
+        e2 = edge(p0, p1);
+    }
 
-    if a.abs() < epsilon {
+    if (e0 < 0. || e1 < 0. || e2 < 0.) && (e0 > 0. || e1 > 0. || e2 > 0.) {
         return None;
     }
 
-    let f = 1.0 / a;
-    let s = origin - p0;
-    let u = f * s.dot(h.transpose()).x();
+    let det = e0 + e1 + e2;
 
-    if u < 0.0 || u > 1.0 {
+    if det == 0. {
         return None;
     }
 
-    let q = s.cross(edge1);
+    // `det` has the sign of dot(normal, direction) / dz, so the two agreeing
+    // means the ray arrives from behind the triangle.
+    if culling == Culling::Back && (det > 0.) == (dz > 0.) {
+        return None;
+    }
 
-    let v = f * direction.dot(q.transpose()).x();
+    p0[0][2] *= shear_z;
+    p1[0][2] *= shear_z;
+    p2[0][2] *= shear_z;
 
-    if v < 0.0 || v > 1.0 {
+    let t_scaled = e0 * p0.z() + e1 * p1.z() + e2 * p2.z();
+
+    if (det < 0. && t_scaled >= 0.) || (det > 0. && t_scaled <= 0.) {
         return None;
     }
 
-    let t = f * edge2.dot(q.transpose()).x();
+    let inv_det = 1. / det;
+    let t = t_scaled * inv_det;
 
-    if t > epsilon {
-        let normal = edge1.cross(edge2).normalize();
+    // Reject hits whose distance cannot be told apart from zero given the
+    // rounding error accumulated above.
+    let max_x = p0.x().abs().max(p1.x().abs()).max(p2.x().abs());
+    let max_y = p0.y().abs().max(p1.y().abs()).max(p2.y().abs());
+    let max_z = p0.z().abs().max(p1.z().abs()).max(p2.z().abs());
+    let max_e = e0.abs().max(e1.abs()).max(e2.abs());
 
-        return Some(RaycastHit {
-            t: t,
-            u: u,
-            v: v,
-            normal: Matrix([[normal.x(), normal.y(), normal.z(), 0.]]),
-        });
-    } else {
+    let delta_x = gamma(5) * (max_x + max_z);
+    let delta_y = gamma(5) * (max_y + max_z);
+    let delta_z = gamma(3) * max_z;
+    let delta_e = 2. * (gamma(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+    let delta_t =
+        3. * (gamma(3) * max_e * max_z + delta_e * max_z + delta_z * max_e) * inv_det.abs();
+
+    if t <= delta_t {
         return None;
     }
+
+    Some(RaycastHit::from_barycentric(
+        trig,
+        t,
+        e1 * inv_det,
+        e2 * inv_det,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::bitmap::Bitmap;
+    use crate::{
+        aabb::inverse_direction,
+        bitmap::Bitmap,
+        golden::{Tolerance, assert_golden},
+    };
 
     use super::*;
 
@@ -228,6 +766,178 @@ mod tests {
             Matrix([[1., -1.0, 5., 1.]]),
             Matrix([[0.0, 1., 5., 1.]]),
         );
+
+        // The ray passes exactly through the top vertex.
+        let hit = ray_intersects_triangle(ray_origin, ray_direction, triangle, Culling::None)
+            .expect("ray should hit the top vertex");
+        assert_eq!(hit.t, 5.);
+        assert_eq!((hit.u, hit.v), (0., 1.));
+        assert_eq!(hit.position, triangle.2);
+
+        let above = Matrix([[0., 0.3, 1., 0.]]);
+        assert!(ray_intersects_triangle(ray_origin, above, triangle, Culling::None).is_none());
+    }
+
+    #[test]
+    fn test_watertight_shared_edge() {
+        // Two triangles sharing the diagonal of a tilted quad. Rays aimed
+        // exactly at the diagonal must hit at least one of them.
+        let mesh = quad().apply(rotate_x(0.7)(rotate_y(0.3))(translate(0.1, 0.2, 3.)));
+        let a = mesh.0[0];
+        let b = mesh.0[1];
+
+        let origin = Matrix([[0., 0., 0., 1.]]);
+
+        for i in 1..1000 {
+            let s = i as f32 / 1000.;
+            let target = a.1 * (1. - s) + a.2 * s;
+            let direction = target - origin;
+
+            let hit_a = ray_intersects_triangle(origin, direction, a, Culling::None);
+            let hit_b = ray_intersects_triangle(origin, direction, b, Culling::None);
+
+            assert!(hit_a.is_some() || hit_b.is_some(), "leaked at s = {}", s);
+        }
+    }
+
+    #[test]
+    fn test_barycentric_bounds() {
+        let trig = Triangle(
+            Matrix([[0., 0., 5., 1.]]),
+            Matrix([[1., 0., 5., 1.]]),
+            Matrix([[0., 1., 5., 1.]]),
+        );
+        let origin = Matrix([[0., 0., 0., 1.]]);
+
+        // Inside the parallelogram spanned by the edges but outside the triangle.
+        let outside = Matrix([[0.8, 0.8, 5., 0.]]);
+        assert!(ray_intersects_triangle(origin, outside, trig, Culling::None).is_none());
+
+        let inside = Matrix([[0.25, 0.5, 5., 0.]]);
+        let hit = ray_intersects_triangle(origin, inside, trig, Culling::None).unwrap();
+        assert!((hit.t - 1.).abs() < 1e-6);
+        assert!((hit.u - 0.25).abs() < 1e-6);
+        assert!((hit.v - 0.5).abs() < 1e-6);
+        assert_eq!(hit.position.round(5), Matrix([[0.25, 0.5, 5., 1.]]));
+    }
+
+    #[test]
+    fn test_backface_culling() {
+        let trig = Triangle(
+            Matrix([[0., 0., 5., 1.]]),
+            Matrix([[1., 0., 5., 1.]]),
+            Matrix([[0., 1., 5., 1.]]),
+        );
+        // The winding above gives a +z normal, so it faces rays travelling -z.
+        let front = (Matrix([[0.2, 0.2, 10., 1.]]), Matrix([[0., 0., -1., 0.]]));
+        let back = (Matrix([[0.2, 0.2, 0., 1.]]), Matrix([[0., 0., 1., 0.]]));
+
+        assert!(ray_intersects_triangle(front.0, front.1, trig, Culling::Back).is_some());
+        assert!(ray_intersects_triangle(back.0, back.1, trig, Culling::Back).is_none());
+        assert!(ray_intersects_triangle(back.0, back.1, trig, Culling::None).is_some());
+    }
+
+    #[test]
+    fn test_spawn_origin() {
+        let trig = Triangle(
+            Matrix([[-1000., -1000., 1000.3, 1.]]),
+            Matrix([[1000., -1000., 1000.3, 1.]]),
+            Matrix([[0., 1000., 1000.3, 1.]]),
+        );
+        let origin = Matrix([[0.1, 0.3, 0., 1.]]);
+        let direction = Matrix([[0.001, 0.002, 1., 0.]]);
+
+        let hit = ray_intersects_triangle(origin, direction, trig, Culling::None).unwrap();
+
+        let dot = direction.dot(hit.normal.transpose()).x();
+        let reflected = direction - hit.normal * (2. * dot);
+        let spawned = hit.spawn_origin(reflected);
+
+        assert!(spawned.z() < 1000.3);
+        assert!(ray_intersects_triangle(spawned, reflected, trig, Culling::None).is_none());
+
+        // Continuing through the surface spawns on the far side instead.
+        let spawned = hit.spawn_origin(direction);
+        assert!(spawned.z() > 1000.3);
+        assert!(ray_intersects_triangle(spawned, direction, trig, Culling::None).is_none());
+    }
+
+    #[test]
+    fn test_motion() {
+        let model = Model {
+            color: Matrix([[1., 1., 1., 1.]]),
+            reflect: 0.,
+            mesh: cube(),
+            motion: Some(Motion(Box::new(|time| {
+                rotate_y(time)(translate(time * 2., 0., 0.))
+            }))),
+            ..Default::default()
+        };
+
+        let origin = Matrix([[0.3, 0.2, -5., 1.]]);
+        let direction = Matrix([[0.2, 0., 1., 0.]]).normalize();
+
+        for time in [0., 0.25, 0.5, 1.] {
+            let moved = Model {
+                color: model.color,
+                reflect: 0.,
+                mesh: cube().apply(model.transform_at(time)),
+                ..Default::default()
+            };
+
+            let a = model.nearest_hit(origin, direction, time, Culling::None);
+            let b = moved.nearest_hit(origin, direction, time, Culling::None);
+
+            match (a, b) {
+                (Some(a), Some(b)) => {
+                    assert!((a.t - b.t).abs() < 1e-4);
+                    assert_eq!(a.normal.round(4), b.normal.round(4));
+                    assert_eq!(a.position.round(4), b.position.round(4));
+                }
+                (None, None) => {}
+                _ => panic!("moving and pre-transformed models disagree at {}", time),
+            }
+        }
+
+        // Swept bounds cover the model at every instant of the interval.
+        let swept = model.motion_bounds(0., 1.);
+        for i in 0..=100 {
+            let bounds = cube().apply(model.transform_at(i as f32 / 100.)).bounds();
+            assert!(swept.contains(bounds.min) && swept.contains(bounds.max));
+        }
+        assert!(swept.size().x() > 2.);
+
+        // Late in the exposure the model is found where it has moved to,
+        // outside its bounds when the shutter opened.
+        let (origin, forward) = (Matrix([[2., 0., -5., 1.]]), Matrix([[0., 0., 1., 0.]]));
+        let inverse = inverse_direction(forward);
+        let exposed = Placed::new(&model, 0., 1.);
+        assert!(
+            exposed
+                .nearest_hit(origin, forward, inverse, 1., Culling::None)
+                .is_some()
+        );
+        let instant = Placed::new(&model, 0., 0.);
+        assert!(
+            instant
+                .nearest_hit(origin, forward, inverse, 1., Culling::None)
+                .is_none()
+        );
+
+        // A shutter caught at one instant places the model once, and hits
+        // remember where they are on it.
+        let instant = Placed::new(&model, 1., 1.);
+        let hit = instant
+            .nearest_hit(origin, forward, inverse, 1., Culling::None)
+            .unwrap();
+        let (transform, inverse) = model.motion_at(1.).unwrap();
+        assert_eq!(instant.fixed, Some((transform, inverse)));
+        assert_eq!(
+            hit.object_position.round(4),
+            (hit.position)(inverse).round(4)
+        );
+        let p = hit.object_position;
+        assert!((p.x().abs().max(p.y().abs()).max(p.z().abs()) - 0.5).abs() < 1e-4);
     }
 
     #[test]
@@ -253,16 +963,19 @@ mod tests {
                 color: Matrix([[1., 0., 0., 1.]]),
                 reflect: 0.3,
                 mesh: cube().apply(translate(3., 0., 0.)),
+                ..Default::default()
             },
             Model {
                 color: Matrix([[0., 1., 0., 1.]]),
                 reflect: 0.3,
                 mesh: cube().apply(rotate_y(t / 1000.)(rotate_x(t / 2000.))),
+                ..Default::default()
             },
             Model {
                 color: Matrix([[0., 0., 1., 1.]]),
                 reflect: 0.3,
                 mesh: cube().apply(translate(-3., 0., 0.)),
+                ..Default::default()
             },
         ];
 
@@ -289,7 +1002,7 @@ mod tests {
 
                     for model in models.iter() {
                         for trig in model.mesh.0.iter() {
-                            match ray_intersects_triangle(origin, direction, *trig) {
+                            match ray_intersects_triangle(origin, direction, *trig, Culling::None) {
                                 Some(hit) => {
                                     hits.push((hit, model));
                                 }
@@ -331,5 +1044,7 @@ mod tests {
                     raycast_color(origin, direction, background_color, &models, 0).to_color();
             }
         }
+
+        assert_golden("matrix_3d_render", &bmp, Tolerance::default());
     }
 }
@@ -0,0 +1,439 @@
+use crate::{
+    matrix::Matrix,
+    matrix_3d::{Point, Point2D},
+};
+
+/// Shuffle of 0..256, repeated so lookups can add a lattice offset without
+/// wrapping.
+const PERMUTATION: [u8; 512] = permutation();
+
+const fn permutation() -> [u8; 512] {
+    let mut table = [0u8; 512];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = i as u8;
+        i += 1;
+    }
+
+    // Fisher-Yates driven by xorshift, fixed so every build agrees.
+    let mut state: u32 = 0x9e3779b9;
+    let mut i = 255;
+    while i > 0 {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let j = (state % (i as u32 + 1)) as usize;
+        let swap = table[i];
+        table[i] = table[j];
+        table[j] = swap;
+        i -= 1;
+    }
+
+    let mut i = 0;
+    while i < 256 {
+        table[256 + i] = table[i];
+        i += 1;
+    }
+    table
+}
+
+fn hash(x: i32, y: i32, z: i32) -> usize {
+    let a = PERMUTATION[(x & 255) as usize] as usize + (y & 255) as usize;
+    let b = PERMUTATION[a] as usize + (z & 255) as usize;
+    PERMUTATION[b] as usize
+}
+
+/// Dot product with one of the twelve cube edge directions.
+fn gradient(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Improved Perlin noise (Perlin 2002), roughly in -1..1 and zero at every
+/// lattice point.
+pub fn perlin(p: Point) -> f32 {
+    let (fx, fy, fz) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (x, y, z) = (p.x() - fx, p.y() - fy, p.z() - fz);
+    let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+
+    let fade = |t: f32| t * t * t * (t * (t * 6. - 15.) + 10.);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        gradient(
+            hash(ix + dx, iy + dy, iz + dz),
+            x - dx as f32,
+            y - dy as f32,
+            z - dz as f32,
+        )
+    };
+
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+/// 3D simplex noise (Perlin 2001, after Gustavson's reference), roughly in
+/// -1..1. Sums four corners of a tetrahedron instead of eight of a cube, and
+/// has no axis-aligned artifacts.
+pub fn simplex(p: Point) -> f32 {
+    const F3: f32 = 1. / 3.;
+    const G3: f32 = 1. / 6.;
+
+    // Skew into the lattice of tetrahedra and find the containing cell.
+    let s = (p.x() + p.y() + p.z()) * F3;
+    let (i, j, k) = (
+        (p.x() + s).floor(),
+        (p.y() + s).floor(),
+        (p.z() + s).floor(),
+    );
+    let t = (i + j + k) * G3;
+    let x0 = [p.x() - (i - t), p.y() - (j - t), p.z() - (k - t)];
+
+    // Which of the six tetrahedra in the cell, by ordering the offsets.
+    let (first, second) = if x0[0] >= x0[1] {
+        if x0[1] >= x0[2] {
+            ([1, 0, 0], [1, 1, 0])
+        } else if x0[0] >= x0[2] {
+            ([1, 0, 0], [1, 0, 1])
+        } else {
+            ([0, 0, 1], [1, 0, 1])
+        }
+    } else if x0[1] < x0[2] {
+        ([0, 0, 1], [0, 1, 1])
+    } else if x0[0] < x0[2] {
+        ([0, 1, 0], [0, 1, 1])
+    } else {
+        ([0, 1, 0], [1, 1, 0])
+    };
+
+    let (i, j, k) = (i as i32, j as i32, k as i32);
+    let mut total = 0.;
+    for (n, offset) in [[0, 0, 0], first, second, [1, 1, 1]].iter().enumerate() {
+        let d = [
+            x0[0] - offset[0] as f32 + n as f32 * G3,
+            x0[1] - offset[1] as f32 + n as f32 * G3,
+            x0[2] - offset[2] as f32 + n as f32 * G3,
+        ];
+        let falloff = 0.6 - d[0] * d[0] - d[1] * d[1] - d[2] * d[2];
+        if falloff > 0. {
+            let h = hash(i + offset[0], j + offset[1], k + offset[2]) % 12;
+            total += falloff.powi(4) * gradient(h, d[0], d[1], d[2]);
+        }
+    }
+
+    32. * total
+}
+
+/// Value noise: random values at the lattice points, blended smoothly.
+/// Blockier than gradient noise, in -1..1.
+pub fn value(p: Point) -> f32 {
+    let (fx, fy, fz) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+
+    let smooth = |t: f32| t * t * (3. - 2. * t);
+    let (u, v, w) = (smooth(p.x() - fx), smooth(p.y() - fy), smooth(p.z() - fz));
+    let corner = |dx: i32, dy: i32, dz: i32| hash(ix + dx, iy + dy, iz + dz) as f32 / 127.5 - 1.;
+
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), u),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), u),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+/// Cellular noise (Worley 1996): distances to the nearest and second
+/// nearest of a random point in every lattice cell. `F2 - F1` outlines the
+/// cells.
+pub fn worley(p: Point) -> (f32, f32) {
+    let (fx, fy, fz) = (p.x().floor(), p.y().floor(), p.z().floor());
+    let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+    let (mut f1, mut f2) = (f32::INFINITY, f32::INFINITY);
+
+    for dz in -1..=1 {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let (cx, cy, cz) = (ix + dx, iy + dy, iz + dz);
+                // Three more hashes of the cell place its point.
+                let h = hash(cx, cy, cz);
+                let offset = |salt: i32| hash(cx + salt, cy + h as i32, cz) as f32 / 256.;
+                let feature = [
+                    cx as f32 + offset(17),
+                    cy as f32 + offset(59),
+                    cz as f32 + offset(113),
+                ];
+
+                let d = [feature[0] - p.x(), feature[1] - p.y(), feature[2] - p.z()];
+                let distance = (d[0] * d[0] + d[1] * d[1] + d[2] * d[2]).sqrt();
+                if distance < f1 {
+                    (f1, f2) = (distance, f1);
+                } else if distance < f2 {
+                    f2 = distance;
+                }
+            }
+        }
+    }
+
+    (f1, f2)
+}
+
+/// The 2D noises are slices of the 3D ones through z = 0.
+fn slice(p: Point2D) -> Point {
+    Matrix([[p.x(), p.y(), 0., 1.]])
+}
+
+pub fn perlin_2d(p: Point2D) -> f32 {
+    perlin(slice(p))
+}
+
+pub fn simplex_2d(p: Point2D) -> f32 {
+    simplex(slice(p))
+}
+
+pub fn value_2d(p: Point2D) -> f32 {
+    value(slice(p))
+}
+
+pub fn worley_2d(p: Point2D) -> (f32, f32) {
+    worley(slice(p))
+}
+
+/// Octaves for summing noise at rising frequencies.
+#[derive(Clone, Copy, Debug)]
+pub struct Fractal {
+    pub octaves: u32,
+    /// Frequency multiplier between octaves.
+    pub lacunarity: f32,
+    /// Amplitude multiplier between octaves.
+    pub gain: f32,
+}
+
+impl Default for Fractal {
+    fn default() -> Self {
+        Fractal {
+            octaves: 5,
+            lacunarity: 2.,
+            gain: 0.5,
+        }
+    }
+}
+
+impl Fractal {
+    /// Fractional Brownian motion, normalized by the total amplitude so it
+    /// stays in the range of `noise`.
+    pub fn fbm(&self, noise: impl Fn(Point) -> f32, p: Point) -> f32 {
+        let (mut sum, mut total) = (0., 0.);
+        let (mut frequency, mut amplitude) = (1., 1.);
+
+        for _ in 0..self.octaves {
+            sum += noise(scaled(p, frequency)) * amplitude;
+            total += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+
+        sum / total
+    }
+
+    /// Sum of the absolute value of each octave (Perlin 1985), creased
+    /// wherever an octave crosses zero. In 0..1.
+    pub fn turbulence(&self, noise: impl Fn(Point) -> f32, p: Point) -> f32 {
+        self.fbm(|p| noise(p).abs(), p)
+    }
+
+    /// Ridged multifractal (Musgrave): folds each octave into sharp crests
+    /// at its zero crossings, and lets the crests of one octave control how
+    /// much detail the next adds. In 0..1.
+    pub fn ridged(&self, noise: impl Fn(Point) -> f32, p: Point) -> f32 {
+        let (mut sum, mut total) = (0., 0.);
+        let (mut frequency, mut amplitude) = (1., 1.);
+        let mut weight: f32 = 1.;
+
+        for _ in 0..self.octaves {
+            let ridge = 1. - noise(scaled(p, frequency)).abs();
+            let signal = ridge * ridge * weight;
+            sum += signal * amplitude;
+            total += amplitude;
+            weight = (signal * 2.).clamp(0., 1.);
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+
+        sum / total
+    }
+}
+
+/// Domain warping (Quilez): displaces `p` by `field` sampled at three
+/// unrelated offsets, one per axis, before it's looked up again.
+pub fn warp(p: Point, amount: f32, field: impl Fn(Point) -> f32) -> Point {
+    let shifted = |x: f32, y: f32, z: f32| field(p + Matrix([[x, y, z, 0.]]));
+    p + Matrix([[
+        shifted(0., 0., 0.),
+        shifted(5.2, 1.3, 2.8),
+        shifted(1.7, 9.2, 4.1),
+        0.,
+    ]]) * amount
+}
+
+fn scaled(p: Point, frequency: f32) -> Point {
+    Matrix([[p.x() * frequency, p.y() * frequency, p.z() * frequency, 1.]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    fn samples() -> impl Iterator<Item = Point> {
+        (0..4000).map(|i| {
+            let i = i as f32;
+            point(i * 0.137 - 200., (i * 0.731).sin() * 40., i * 0.053)
+        })
+    }
+
+    #[test]
+    fn test_permutation() {
+        let mut seen = [false; 256];
+        for v in PERMUTATION[..256].iter() {
+            seen[*v as usize] = true;
+        }
+        assert!(seen.iter().all(|s| *s));
+        assert_eq!(PERMUTATION[..256], PERMUTATION[256..]);
+    }
+
+    #[test]
+    fn test_perlin() {
+        assert_eq!(perlin(point(3., -7., 12.)), 0.);
+
+        let (mut min, mut max, mut sum) = (f32::MAX, f32::MIN, 0.);
+        for p in samples() {
+            let v = perlin(p);
+            (min, max, sum) = (min.min(v), max.max(v), sum + v);
+
+            // Continuous: a tiny step changes it by a tiny amount.
+            let step = perlin(p + Matrix([[1e-3, 0., 0., 0.]]));
+            assert!((step - v).abs() < 0.01);
+        }
+        assert!(min > -1.1 && max < 1.1);
+        assert!(min < -0.5 && max > 0.5);
+        assert!((sum / 4000.).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_simplex() {
+        let (mut min, mut max, mut sum) = (f32::MAX, f32::MIN, 0.);
+        for p in samples() {
+            let v = simplex(p);
+            (min, max, sum) = (min.min(v), max.max(v), sum + v);
+
+            let step = simplex(p + Matrix([[0., 1e-3, 0., 0.]]));
+            assert!((step - v).abs() < 0.01);
+        }
+        assert!(min > -1.1 && max < 1.1);
+        assert!(min < -0.5 && max > 0.5);
+        assert!((sum / 4000.).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_value() {
+        let (mut min, mut max) = (f32::MAX, f32::MIN);
+        for p in samples() {
+            let v = value(p);
+            (min, max) = (min.min(v), max.max(v));
+            assert!((value(p + Matrix([[1e-3, 0., 0., 0.]])) - v).abs() < 0.01);
+        }
+        assert!((-1. ..=1.).contains(&min) && (-1. ..=1.).contains(&max));
+        assert!(min < -0.5 && max > 0.5);
+
+        // Lattice points take their hashed value.
+        assert_eq!(value(point(4., 2., 9.)), hash(4, 2, 9) as f32 / 127.5 - 1.);
+    }
+
+    #[test]
+    fn test_worley() {
+        for p in samples() {
+            let (f1, f2) = worley(p);
+            assert!(f1 <= f2);
+            // Some point is always in the same cell.
+            assert!(f1 < 3f32.sqrt());
+        }
+
+        // A distance can't change faster than the point moves.
+        for p in samples().take(500) {
+            let (f1, _) = worley(p);
+            let (moved, _) = worley(p + Matrix([[0.01, 0., 0., 0.]]));
+            assert!((moved - f1).abs() <= 0.01 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_2d() {
+        let p = Matrix([[3.7, -1.2]]);
+        assert_eq!(perlin_2d(p), perlin(point(3.7, -1.2, 0.)));
+        assert_eq!(simplex_2d(p), simplex(point(3.7, -1.2, 0.)));
+        assert_eq!(value_2d(p), value(point(3.7, -1.2, 0.)));
+        assert_eq!(worley_2d(p), worley(point(3.7, -1.2, 0.)));
+    }
+
+    #[test]
+    fn test_fractal() {
+        let fractal = Fractal::default();
+        let single = Fractal {
+            octaves: 1,
+            ..fractal
+        };
+
+        for p in samples().take(500) {
+            assert_eq!(single.fbm(perlin, p), perlin(p));
+
+            let fbm = fractal.fbm(perlin, p);
+            assert!(fbm.abs() <= 1.1);
+
+            let ridged = fractal.ridged(simplex, p);
+            assert!((0. ..=1.).contains(&ridged));
+
+            let turbulence = fractal.turbulence(perlin, p);
+            assert!((0. ..=1.1).contains(&turbulence));
+        }
+
+        // Ridges peak where the noise crosses zero.
+        assert_eq!(single.ridged(perlin, point(2., 5., 1.)), 1.);
+
+        let p = point(0.3, 0.2, 0.1);
+        assert_eq!(warp(p, 0., perlin), p);
+        assert_ne!(warp(p, 1., perlin), p);
+    }
+}
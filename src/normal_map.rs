@@ -0,0 +1,394 @@
+use crate::{
+    aabb::Aabb,
+    bitmap::FloatImage,
+    environment::luminance,
+    isosurface::IndexedMesh,
+    matrix::Matrix,
+    matrix_3d::{
+        Culling, Point, Point2D, RaycastHit, Triangle, cross3, dot3, ray_intersects_triangle,
+    },
+    texture::Texture,
+};
+
+/// Per-vertex tangents for a mesh with normals and texture coordinates,
+/// built the way MikkTSpace builds them: every corner adds the direction of
+/// increasing u across its face, projected onto the plane of the vertex
+/// normal, normalized and weighted by the corner's angle. `w` holds the
+/// handedness, so the bitangent is `w * cross(normal, tangent)`. A mesh
+/// without texture coordinates gets some frame at every vertex.
+pub fn generate_tangents(mesh: &IndexedMesh) -> Vec<Matrix<1, 4>> {
+    let count = mesh.positions.len();
+    let mut tangents = vec![Matrix::default(); count];
+    let mut bitangents = vec![Matrix::default(); count];
+    let faces = if mesh.uvs.is_empty() {
+        &[][..]
+    } else {
+        &mesh.indices[..]
+    };
+
+    for face in faces.iter() {
+        let [a, b, c] = face.map(|i| i as usize);
+        let p = [mesh.positions[a], mesh.positions[b], mesh.positions[c]];
+        let uv = [mesh.uvs[a], mesh.uvs[b], mesh.uvs[c]];
+
+        let (e1, e2) = (p[1] - p[0], p[2] - p[0]);
+        let (d1, d2) = (uv[1] - uv[0], uv[2] - uv[0]);
+        let det = d1.x() * d2.y() - d2.x() * d1.y();
+        if det == 0. {
+            continue;
+        }
+        let tangent = (e1 * d2.y() - e2 * d1.y()) / det;
+        let bitangent = (e2 * d1.x() - e1 * d2.x()) / det;
+
+        for corner in 0..3 {
+            let vertex = face[corner] as usize;
+            let next = p[(corner + 1) % 3] - p[corner];
+            let previous = p[(corner + 2) % 3] - p[corner];
+            let angle = dot3(next.normalize(), previous.normalize())
+                .clamp(-1., 1.)
+                .acos();
+
+            let normal = mesh.normals[vertex];
+            tangents[vertex] = tangents[vertex] + project(tangent, normal).normalize() * angle;
+            bitangents[vertex] =
+                bitangents[vertex] + project(bitangent, normal).normalize() * angle;
+        }
+    }
+
+    tangents
+        .iter()
+        .zip(bitangents.iter())
+        .zip(mesh.normals.iter())
+        .map(|((tangent, bitangent), normal)| {
+            let mut tangent = project(*tangent, *normal).normalize();
+            if tangent == Matrix::default() {
+                tangent = perpendicular(*normal);
+            }
+            tangent[0][3] = if dot3(cross3(*normal, tangent), *bitangent) < 0. {
+                -1.
+            } else {
+                1.
+            };
+            tangent
+        })
+        .collect()
+}
+
+/// Tangent space normal map in the usual encoding: x, y and z mapped from
+/// -1..1 to 0..1 in red, green and blue, with green along the bitangent.
+/// Texel `(x, y)` is centered on `((x + 0.5) / width, (y + 0.5) / height)`
+/// and the map repeats outside 0..1.
+pub struct NormalMap(pub FloatImage);
+
+impl NormalMap {
+    /// Normal map of the heights `height(uv)`, scaled by `strength`.
+    pub fn from_height(
+        width: u32,
+        height: u32,
+        strength: f32,
+        field: impl Fn(Point2D) -> f32,
+    ) -> NormalMap {
+        let mut image = FloatImage::new(width, height);
+        let (du, dv) = (1. / width as f32, 1. / height as f32);
+
+        for y in 0..height {
+            for x in 0..width {
+                let u = (x as f32 + 0.5) * du;
+                let v = (y as f32 + 0.5) * dv;
+                let slope_u =
+                    (field(Matrix([[u + du, v]])) - field(Matrix([[u - du, v]]))) / (2. * du);
+                let slope_v =
+                    (field(Matrix([[u, v + dv]])) - field(Matrix([[u, v - dv]]))) / (2. * dv);
+
+                let normal =
+                    Matrix([[-slope_u * strength, -slope_v * strength, 1., 0.]]).normalize();
+                let mut color = normal * 0.5 + 0.5;
+                color[0][3] = 1.;
+                image.set(x, y, color);
+            }
+        }
+
+        NormalMap(image)
+    }
+
+    /// Unit tangent space normal at `uv`, filtered bilinearly.
+    pub fn sample(&self, uv: Point2D) -> Matrix<1, 4> {
+        let image = &self.0;
+        let x = uv.x() * image.width as f32 - 0.5;
+        let y = uv.y() * image.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |x: f32, y: f32| {
+            image.get(
+                (x as i64).rem_euclid(image.width as i64) as u32,
+                (y as i64).rem_euclid(image.height as i64) as u32,
+            )
+        };
+        let top = texel(x0, y0) * (1. - fx) + texel(x0 + 1., y0) * fx;
+        let bottom = texel(x0, y0 + 1.) * (1. - fx) + texel(x0 + 1., y0 + 1.) * fx;
+
+        let mut normal = (top * (1. - fy) + bottom * fy) * 2. - 1.;
+        normal[0][3] = 0.;
+        normal.normalize()
+    }
+
+    /// Shading normal at `uv` on a surface with interpolated `normal` and
+    /// `tangent`, which carries its handedness in `w` as from
+    /// `generate_tangents`.
+    pub fn perturb(
+        &self,
+        normal: Matrix<1, 4>,
+        tangent: Matrix<1, 4>,
+        uv: Point2D,
+    ) -> Matrix<1, 4> {
+        let sign = tangent.w();
+        let mut tangent = project(tangent, normal).normalize();
+        tangent[0][3] = 0.;
+        let bitangent = cross3(normal, tangent) * sign;
+
+        let local = self.sample(uv);
+        (tangent * local.x() + bitangent * local.y() + normal * local.z()).normalize()
+    }
+}
+
+/// Mesh with texture coordinates whose shading normals come from a normal
+/// map. Traced alongside a model's mesh, in the same space. Without texture
+/// coordinates it is shaded with its interpolated normals alone.
+pub struct NormalMappedMesh {
+    pub mesh: IndexedMesh,
+    pub tangents: Vec<Matrix<1, 4>>,
+    pub map: NormalMap,
+}
+
+impl NormalMappedMesh {
+    pub fn new(mesh: IndexedMesh, map: NormalMap) -> NormalMappedMesh {
+        NormalMappedMesh {
+            tangents: generate_tangents(&mesh),
+            mesh,
+            map,
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for p in self.mesh.positions.iter() {
+            aabb.grow(*p);
+        }
+        aabb
+    }
+
+    /// Closest hit, with the normal, tangent and texture coordinates of the
+    /// triangle's corners interpolated to look up the map.
+    pub fn nearest_hit(&self, origin: Point, direction: Matrix<1, 4>) -> Option<RaycastHit> {
+        let mut nearest: Option<(RaycastHit, [usize; 3])> = None;
+
+        for face in self.mesh.indices.iter() {
+            let [a, b, c] = face.map(|i| i as usize);
+            let trig = Triangle(
+                self.mesh.positions[a],
+                self.mesh.positions[b],
+                self.mesh.positions[c],
+            );
+            if let Some(hit) = ray_intersects_triangle(origin, direction, trig, Culling::None)
+                && nearest.is_none_or(|(other, _)| hit.t < other.t)
+            {
+                nearest = Some((hit, [a, b, c]));
+            }
+        }
+
+        let (mut hit, [a, b, c]) = nearest?;
+        let weights = [1. - hit.u - hit.v, hit.u, hit.v];
+        let interpolate = |values: &[Matrix<1, 4>]| {
+            values[a] * weights[0] + values[b] * weights[1] + values[c] * weights[2]
+        };
+
+        let normal = interpolate(&self.mesh.normals).normalize();
+        let mut tangent = interpolate(&self.tangents);
+        // Handedness is constant across a face in any sensible mapping.
+        tangent[0][3] = self.tangents[a].w();
+        let uvs = &self.mesh.uvs;
+        let shading = if uvs.is_empty() {
+            normal
+        } else {
+            let uv = uvs[a] * weights[0] + uvs[b] * weights[1] + uvs[c] * weights[2];
+            self.map.perturb(normal, tangent, uv)
+        };
+        // Keep to the side of the surface the geometric normal is on, which
+        // is what secondary rays are spawned from.
+        hit.shading_normal = if dot3(shading, hit.normal) < 0. {
+            -shading
+        } else {
+            shading
+        };
+        Some(hit)
+    }
+}
+
+/// Bump map reading the luminance of a solid texture as height in object
+/// space units, times `scale`. Heights are differenced `step` apart, which
+/// also bevels the edges of patterns with hard boundaries such as bricks.
+pub struct Bump {
+    pub texture: Texture,
+    pub scale: f32,
+    pub step: f32,
+}
+
+impl Bump {
+    pub fn height(&self, p: Point) -> f32 {
+        luminance(self.texture.color(p)) * self.scale
+    }
+
+    /// Tilts `normal` at `p` away from the direction the height rises in,
+    /// as if the surface were displaced along it.
+    pub fn perturb(&self, normal: Matrix<1, 4>, p: Point) -> Matrix<1, 4> {
+        let mut gradient: Matrix<1, 4> = Matrix::default();
+        for axis in 0..3 {
+            let mut offset: Matrix<1, 4> = Matrix::default();
+            offset[0][axis] = self.step;
+            gradient[0][axis] =
+                (self.height(p + offset) - self.height(p - offset)) / (2. * self.step);
+        }
+
+        (normal - project(gradient, normal)).normalize()
+    }
+}
+
+/// `v` without its component along the unit vector `n`.
+fn project(v: Matrix<1, 4>, n: Matrix<1, 4>) -> Matrix<1, 4> {
+    let mut out = v - n * dot3(v, n);
+    out[0][3] = 0.;
+    out
+}
+
+/// Some unit vector perpendicular to `n`, for vertices whose faces give no
+/// usable tangent.
+fn perpendicular(n: Matrix<1, 4>) -> Matrix<1, 4> {
+    let axis = if n.x().abs() < 0.9 {
+        Matrix([[1., 0., 0., 0.]])
+    } else {
+        Matrix([[0., 1., 0., 0.]])
+    };
+    project(axis, n).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        isosurface::{ScalarGrid, marching_cubes},
+        matrix_3d::quad,
+        sdf::Sdf,
+    };
+
+    fn vector(x: f32, y: f32, z: f32) -> Matrix<1, 4> {
+        Matrix([[x, y, z, 0.]])
+    }
+
+    fn close(a: Matrix<1, 4>, b: Matrix<1, 4>) -> bool {
+        let d = a - b;
+        dot3(d, d) < 1e-6
+    }
+
+    fn plane(uv: impl Fn(Point) -> Point2D) -> IndexedMesh {
+        IndexedMesh::from_mesh(&quad(), uv)
+    }
+
+    #[test]
+    fn test_tangents() {
+        // u along x, v along y on the quad in the z = 0 plane.
+        let mesh = plane(|p| Matrix([[p.x(), p.y()]]));
+        let tangents = generate_tangents(&mesh);
+        let normal = mesh.normals[0];
+        assert!(close(normal, vector(0., 0., 1.)));
+        for tangent in tangents.iter() {
+            assert!(close(*tangent, Matrix([[1., 0., 0., 1.]])));
+            // The bitangent follows v.
+            let bitangent = cross3(normal, *tangent) * tangent.w();
+            assert!(close(bitangent, vector(0., 1., 0.)));
+        }
+
+        // Mirroring u flips the tangent and the handedness, but not the
+        // bitangent.
+        let mirrored = generate_tangents(&plane(|p| Matrix([[-p.x(), p.y()]])));
+        for tangent in mirrored.iter() {
+            assert!(close(*tangent, Matrix([[-1., 0., 0., -1.]])));
+            let bitangent = cross3(normal, *tangent) * tangent.w();
+            assert!(close(bitangent, vector(0., 1., 0.)));
+        }
+
+        // Without usable texture coordinates there is still a frame.
+        let flat = generate_tangents(&plane(|_| Matrix([[0., 0.]])));
+        for tangent in flat.iter() {
+            assert!((dot3(*tangent, *tangent) - 1.).abs() < 1e-5);
+            assert!(dot3(*tangent, normal).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_normal_map() {
+        let flat = NormalMap::from_height(8, 8, 1., |_| 0.);
+        let mesh = NormalMappedMesh::new(plane(|p| Matrix([[p.x(), p.y()]])), flat);
+        let hit = mesh
+            .nearest_hit(Matrix([[0.3, 0.6, -1., 1.]]), vector(0., 0., 1.))
+            .unwrap();
+        assert!(close(hit.shading_normal, hit.normal));
+        assert!((hit.position.x() - 0.3).abs() < 1e-5);
+
+        // Heights rising along u tilt the normal back towards -u, and the
+        // map wraps around.
+        let ramp = NormalMap::from_height(8, 8, 1., |uv| uv.x());
+        let n = ramp.sample(Matrix([[0.5, 0.5]]));
+        assert!(close(n, vector(-1., 0., 1.).normalize()));
+        assert_eq!(ramp.sample(Matrix([[1.5, 0.5]])), n);
+
+        let normal = vector(0., 0., -1.);
+        let tangent = Matrix([[1., 0., 0., 1.]]);
+        let perturbed = ramp.perturb(normal, tangent, Matrix([[0.5, 0.5]]));
+        assert!(close(perturbed, vector(-1., 0., -1.).normalize()));
+    }
+
+    #[test]
+    fn test_without_uvs() {
+        let sphere = marching_cubes(&ScalarGrid::from_sdf(&Sdf::sphere(1.), 0.25), 0.);
+        assert!(sphere.uvs.is_empty());
+
+        for (tangent, normal) in generate_tangents(&sphere).iter().zip(sphere.normals.iter()) {
+            assert!((dot3(*tangent, *tangent) - 1.).abs() < 1e-5);
+            assert!(dot3(*tangent, normal.normalize()).abs() < 1e-5);
+        }
+
+        // With nothing to look the map up by, the normals are left alone.
+        let ramp = NormalMap::from_height(8, 8, 1., |uv| uv.x());
+        let mesh = NormalMappedMesh::new(sphere, ramp);
+        let hit = mesh
+            .nearest_hit(Matrix([[0., 0., -3., 1.]]), vector(0., 0., 1.))
+            .unwrap();
+        assert!((dot3(hit.shading_normal, hit.shading_normal) - 1.).abs() < 1e-5);
+        assert!(dot3(hit.shading_normal, vector(0., 0., -1.)) > 0.95);
+    }
+
+    #[test]
+    fn test_bump() {
+        let bump = Bump {
+            texture: Texture::Stripes {
+                a: Matrix([[1., 1., 1., 1.]]),
+                b: Matrix([[0., 0., 0., 1.]]),
+                width: 1.,
+            },
+            scale: 0.1,
+            step: 0.05,
+        };
+        let normal = vector(0., 1., 0.);
+
+        // Flat inside a stripe, tilted where the height drops at its edge.
+        assert_eq!(bump.perturb(normal, Matrix([[0.5, 0., 0., 1.]])), normal);
+        let edge = bump.perturb(normal, Matrix([[0.99, 0., 0., 1.]]));
+        assert!(edge.x() > 0.5 && edge.y() > 0.);
+        assert!((dot3(edge, edge) - 1.).abs() < 1e-5);
+
+        // The tilt stays in the surface, whichever way it faces.
+        let side = bump.perturb(vector(1., 0., 0.), Matrix([[0.99, 0., 0., 1.]]));
+        assert_eq!(side, vector(1., 0., 0.));
+    }
+}
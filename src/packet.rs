@@ -0,0 +1,607 @@
+use std::{
+    ops,
+    simd::{Select, prelude::*},
+};
+
+use crate::{
+    aabb::inverse_direction,
+    matrix::Matrix,
+    matrix_3d::{
+        Culling, Model, Placed, RaycastHit, Triangle, dominant_axes, gamma, ray_intersects_triangle,
+    },
+};
+
+pub const LANES: usize = 4;
+
+type Lanes = f32x4;
+type LaneMask = mask32x4;
+
+/// Three SIMD registers holding the x, y and z components of one vector per lane.
+#[derive(Clone, Copy)]
+pub struct PacketVector(pub Lanes, pub Lanes, pub Lanes);
+
+impl PacketVector {
+    pub fn splat(v: Matrix<1, 4>) -> Self {
+        PacketVector(
+            Lanes::splat(v.x()),
+            Lanes::splat(v.y()),
+            Lanes::splat(v.z()),
+        )
+    }
+
+    pub fn dot(self, other: PacketVector) -> Lanes {
+        self.0 * other.0 + self.1 * other.1 + self.2 * other.2
+    }
+
+    pub fn cross(self, other: PacketVector) -> PacketVector {
+        PacketVector(
+            self.1 * other.2 - self.2 * other.1,
+            self.2 * other.0 - self.0 * other.2,
+            self.0 * other.1 - self.1 * other.0,
+        )
+    }
+}
+
+impl ops::Sub for PacketVector {
+    type Output = PacketVector;
+    fn sub(self, rhs: Self) -> Self::Output {
+        PacketVector(self.0 - rhs.0, self.1 - rhs.1, self.2 - rhs.2)
+    }
+}
+
+/// A bundle of rays stored as structure-of-arrays so one triangle can be
+/// tested against every lane at once.
+pub struct RayPacket {
+    pub origins: [Matrix<1, 4>; LANES],
+    pub directions: [Matrix<1, 4>; LANES],
+    /// Shutter time of each ray, for models with motion.
+    pub times: [f32; LANES],
+    pub origin: PacketVector,
+    pub direction: PacketVector,
+    pub active: LaneMask,
+    /// Lanes whose dominant direction axis is x and y respectively; the rest are z.
+    dominant: (LaneMask, LaneMask),
+    /// Permuted z component of each direction.
+    direction_z: Lanes,
+    /// Per-lane shear that maps the ray onto the +z axis.
+    shear: PacketVector,
+}
+
+/// Per-lane intersection results for a single triangle.
+pub struct PacketHit {
+    pub mask: LaneMask,
+    pub t: Lanes,
+    pub u: Lanes,
+    pub v: Lanes,
+}
+
+impl RayPacket {
+    pub fn new(
+        origins: [Matrix<1, 4>; LANES],
+        directions: [Matrix<1, 4>; LANES],
+        times: [f32; LANES],
+        active: [bool; LANES],
+    ) -> Self {
+        let lanes = |f: fn(&Matrix<1, 4>) -> f32, v: &[Matrix<1, 4>; LANES]| {
+            Lanes::from_array([f(&v[0]), f(&v[1]), f(&v[2]), f(&v[3])])
+        };
+
+        let direction = PacketVector(
+            lanes(Matrix::x, &directions),
+            lanes(Matrix::y, &directions),
+            lanes(Matrix::z, &directions),
+        );
+
+        let mut dominant_x = [false; LANES];
+        let mut dominant_y = [false; LANES];
+
+        for lane in 0..LANES {
+            let (_, _, kz) = dominant_axes(directions[lane]);
+            dominant_x[lane] = kz == 0;
+            dominant_y[lane] = kz == 1;
+        }
+
+        let mut packet = RayPacket {
+            origins,
+            directions,
+            times,
+            origin: PacketVector(
+                lanes(Matrix::x, &origins),
+                lanes(Matrix::y, &origins),
+                lanes(Matrix::z, &origins),
+            ),
+            direction,
+            active: LaneMask::from_array(active),
+            dominant: (
+                LaneMask::from_array(dominant_x),
+                LaneMask::from_array(dominant_y),
+            ),
+            direction_z: Lanes::splat(0.),
+            shear: PacketVector(Lanes::splat(0.), Lanes::splat(0.), Lanes::splat(0.)),
+        };
+
+        let permuted = packet.permute(direction);
+        packet.direction_z = permuted.2;
+        packet.shear = PacketVector(
+            -permuted.0 / permuted.2,
+            -permuted.1 / permuted.2,
+            Lanes::splat(1.) / permuted.2,
+        );
+
+        packet
+    }
+
+    /// Reorders each lane so its dominant direction axis ends up in z,
+    /// matching `dominant_axes` for single rays.
+    fn permute(&self, v: PacketVector) -> PacketVector {
+        let (dominant_x, dominant_y) = self.dominant;
+
+        PacketVector(
+            dominant_x.select(v.1, dominant_y.select(v.2, v.0)),
+            dominant_x.select(v.2, dominant_y.select(v.0, v.1)),
+            dominant_x.select(v.0, dominant_y.select(v.1, v.2)),
+        )
+    }
+
+    /// A packet is coherent when all active lanes point into the same octant.
+    /// Incoherent packets gain nothing from SIMD traversal.
+    pub fn is_coherent(&self) -> bool {
+        let octant = |d: Lanes| {
+            let negative = d.simd_lt(Lanes::splat(0.)) & self.active;
+            !negative.any() || negative == self.active
+        };
+
+        octant(self.direction.0) && octant(self.direction.1) && octant(self.direction.2)
+    }
+
+    /// The watertight test from `ray_intersects_triangle`, evaluated for all
+    /// lanes against one triangle.
+    pub fn intersect_triangle(&self, trig: Triangle, culling: Culling) -> PacketHit {
+        let zero = Lanes::splat(0.);
+        let gamma = |n| Lanes::splat(gamma(n));
+
+        let transform = |p: Matrix<1, 4>| {
+            let p = self.permute(PacketVector::splat(p) - self.origin);
+            PacketVector(p.0 + self.shear.0 * p.2, p.1 + self.shear.1 * p.2, p.2)
+        };
+
+        let p0 = transform(trig.0);
+        let p1 = transform(trig.1);
+        let p2 = transform(trig.2);
+
+        let e0 = p1.0 * p2.1 - p1.1 * p2.0;
+        let e1 = p2.0 * p0.1 - p2.1 * p0.0;
+        let e2 = p0.0 * p1.1 - p0.1 * p1.0;
+
+        let negative = e0.simd_lt(zero) | e1.simd_lt(zero) | e2.simd_lt(zero);
+        let positive = e0.simd_gt(zero) | e1.simd_gt(zero) | e2.simd_gt(zero);
+
+        let det = e0 + e1 + e2;
+
+        let mut mask = self.active & !(negative & positive) & det.simd_ne(zero);
+
+        if culling == Culling::Back {
+            mask &= det.simd_gt(zero) ^ self.direction_z.simd_gt(zero);
+        }
+
+        let z0 = p0.2 * self.shear.2;
+        let z1 = p1.2 * self.shear.2;
+        let z2 = p2.2 * self.shear.2;
+
+        let t_scaled = e0 * z0 + e1 * z1 + e2 * z2;
+
+        mask &= !((det.simd_lt(zero) & t_scaled.simd_ge(zero))
+            | (det.simd_gt(zero) & t_scaled.simd_le(zero)));
+
+        let inv_det = Lanes::splat(1.) / det;
+        let t = t_scaled * inv_det;
+
+        let max_x = p0.0.abs().simd_max(p1.0.abs()).simd_max(p2.0.abs());
+        let max_y = p0.1.abs().simd_max(p1.1.abs()).simd_max(p2.1.abs());
+        let max_z = z0.abs().simd_max(z1.abs()).simd_max(z2.abs());
+        let max_e = e0.abs().simd_max(e1.abs()).simd_max(e2.abs());
+
+        let delta_x = gamma(5) * (max_x + max_z);
+        let delta_y = gamma(5) * (max_y + max_z);
+        let delta_z = gamma(3) * max_z;
+        let delta_e =
+            Lanes::splat(2.) * (gamma(2) * max_x * max_y + delta_y * max_x + delta_x * max_y);
+        let delta_t = Lanes::splat(3.)
+            * (gamma(3) * max_e * max_z + delta_e * max_z + delta_z * max_e)
+            * inv_det.abs();
+
+        mask &= t.simd_gt(delta_t);
+
+        let mut hit = PacketHit {
+            mask,
+            t,
+            u: e1 * inv_det,
+            v: e2 * inv_det,
+        };
+
+        // Edges that rounded to exactly zero need the double precision
+        // retry, which the scalar path already implements.
+        let ambiguous = self.active & (e0.simd_eq(zero) | e1.simd_eq(zero) | e2.simd_eq(zero));
+
+        if ambiguous.any() {
+            for lane in 0..LANES {
+                if !ambiguous.test(lane) {
+                    continue;
+                }
+
+                let scalar = ray_intersects_triangle(
+                    self.origins[lane],
+                    self.directions[lane],
+                    trig,
+                    culling,
+                );
+
+                hit.mask.set(lane, scalar.is_some());
+
+                if let Some(scalar) = scalar {
+                    hit.t[lane] = scalar.t;
+                    hit.u[lane] = scalar.u;
+                    hit.v[lane] = scalar.v;
+                }
+            }
+        }
+
+        hit
+    }
+
+    /// Finds the closest hit for every active lane. Incoherent packets fall
+    /// back to tracing each lane as a single ray, as do moving models since
+    /// every lane sees them at a different time. Models whose bounds no lane
+    /// reaches are skipped without looking at their triangles. Hits come
+    /// with the index of the model hit in `models`.
+    pub fn nearest_hits<'a>(
+        &self,
+        models: &[Placed<'a>],
+    ) -> [Option<(RaycastHit, usize, &'a Model)>; LANES] {
+        if !self.is_coherent() {
+            return self.nearest_hits_scalar(models);
+        }
+
+        let mut nearest_t = Lanes::splat(f32::INFINITY);
+        let mut nearest: [Option<(RaycastHit, usize, &Model)>; LANES] = [None; LANES];
+        let inverse = self.directions.map(inverse_direction);
+
+        // Moving models, implicit surfaces, voxels, terrain and normal or
+        // bump mapped models are intersected one lane at a time.
+        for (index, placed) in models.iter().enumerate() {
+            let Placed { model, bounds, .. } = *placed;
+            // Lanes whose ray misses the model's bounds can't hit it.
+            let mut reaching = self.active;
+            for (lane, inverse) in inverse.iter().enumerate() {
+                if reaching.test(lane)
+                    && bounds
+                        .ray_intersects(self.origins[lane], *inverse, f32::INFINITY)
+                        .is_none()
+                {
+                    reaching.set(lane, false);
+                }
+            }
+            if !reaching.any() {
+                continue;
+            }
+
+            if model.motion.is_some()
+                || model.sdf.is_some()
+                || model.voxels.is_some()
+                || model.terrain.is_some()
+                || model.normal_mapped.is_some()
+                || model.bump.is_some()
+            {
+                for (lane, slot) in nearest.iter_mut().enumerate() {
+                    if !reaching.test(lane) {
+                        continue;
+                    }
+
+                    if let Some(hit) = model.nearest_hit_moved(
+                        self.origins[lane],
+                        self.directions[lane],
+                        placed.motion_at(self.times[lane]),
+                        Culling::None,
+                    ) && hit.t < nearest_t[lane]
+                    {
+                        nearest_t[lane] = hit.t;
+                        *slot = Some((hit, index, model));
+                    }
+                }
+
+                continue;
+            }
+
+            for trig in model.mesh.0.iter() {
+                let hit = self.intersect_triangle(*trig, Culling::None);
+                let closer = hit.mask & reaching & hit.t.simd_lt(nearest_t);
+
+                if !closer.any() {
+                    continue;
+                }
+
+                nearest_t = closer.select(hit.t, nearest_t);
+
+                for (lane, slot) in nearest.iter_mut().enumerate() {
+                    if closer.test(lane) {
+                        let hit = RaycastHit::from_barycentric(
+                            *trig,
+                            hit.t[lane],
+                            hit.u[lane],
+                            hit.v[lane],
+                        );
+                        *slot = Some((hit, index, model));
+                    }
+                }
+            }
+        }
+
+        nearest
+    }
+
+    pub fn nearest_hits_scalar<'a>(
+        &self,
+        models: &[Placed<'a>],
+    ) -> [Option<(RaycastHit, usize, &'a Model)>; LANES] {
+        let mut out = [None; LANES];
+
+        for (lane, slot) in out.iter_mut().enumerate() {
+            if self.active.test(lane) {
+                *slot = crate::nearest_hit(
+                    self.origins[lane],
+                    self.directions[lane],
+                    self.times[lane],
+                    models,
+                );
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::f32;
+
+    use test::Bencher;
+
+    use super::*;
+    use crate::matrix_3d::{Motion, cube, rotate_x, rotate_y, scale, translate};
+
+    fn scene() -> Vec<Model> {
+        vec![
+            Model {
+                color: Matrix([[1., 0., 0., 1.]]),
+                reflect: 0.5,
+                mesh: cube().apply(translate(1., 0., 0.)),
+                ..Default::default()
+            },
+            Model {
+                color: Matrix([[0., 0., 1., 1.]]),
+                reflect: 0.5,
+                mesh: cube().apply(rotate_y(0.3)(translate(-1., 0., 2.))),
+                ..Default::default()
+            },
+            Model {
+                color: Matrix([[0., 1., 0., 1.]]),
+                reflect: 0.5,
+                mesh: cube(),
+                motion: Some(Motion(Box::new(|time| translate(time, -1., 0.)))),
+                ..Default::default()
+            },
+        ]
+    }
+
+    /// A wall of small cubes, most of which any one packet misses.
+    fn wall() -> Vec<Model> {
+        let mut models = Vec::new();
+        for y in 0..8 {
+            for x in 0..8 {
+                models.push(Model {
+                    mesh: cube().apply(scale(0.5, 0.5, 0.5)).apply(translate(
+                        x as f32 - 3.5,
+                        y as f32 - 3.5,
+                        2.,
+                    )),
+                    ..Default::default()
+                });
+            }
+        }
+        models
+    }
+
+    /// `models` as seen while the shutter is open from 0 to 1.5.
+    fn placed(models: &[Model]) -> Vec<Placed<'_>> {
+        models
+            .iter()
+            .map(|model| Placed::new(model, 0., 1.5))
+            .collect()
+    }
+
+    fn primary_directions(size: usize) -> Vec<Matrix<1, 4>> {
+        let fov = f32::consts::PI / 2.;
+        let forward = Matrix([[0., 0., 1., 0.]]);
+        let mut directions = Vec::new();
+
+        for y in 0..size {
+            for x in 0..size {
+                let pitch = ((y as f32 / size as f32) - 0.5) * fov;
+                let yaw = ((x as f32 / size as f32) - 0.5) * fov;
+                directions.push(forward(rotate_y(yaw)(rotate_x(pitch))));
+            }
+        }
+
+        directions
+    }
+
+    #[test]
+    fn test_packet_matches_scalar() {
+        for scene in [scene(), wall()] {
+            assert_packet_matches_scalar(&placed(&scene));
+        }
+    }
+
+    fn assert_packet_matches_scalar(models: &[Placed]) {
+        let origin = Matrix([[0., 0., -5., 1.]]);
+        let directions = primary_directions(16);
+
+        for chunk in directions.chunks(LANES) {
+            let packet = RayPacket::new(
+                [origin; LANES],
+                [chunk[0], chunk[1], chunk[2], chunk[3]],
+                [0., 0.5, 1., 1.5],
+                [true; LANES],
+            );
+
+            let packet_hits = packet.nearest_hits(models);
+            let scalar_hits = packet.nearest_hits_scalar(models);
+
+            for lane in 0..LANES {
+                match (&packet_hits[lane], &scalar_hits[lane]) {
+                    (Some((a, a_index, _)), Some((b, b_index, _))) => {
+                        assert!((a.t - b.t).abs() < 1e-5);
+                        assert_eq!(a.normal, b.normal);
+                        assert_eq!(a_index, b_index);
+                    }
+                    (None, None) => {}
+                    _ => panic!("packet and scalar disagree on lane {}", lane),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_culling_matches_scalar() {
+        let models = scene();
+        let origin = Matrix([[0., 0., -5., 1.]]);
+        let directions = primary_directions(8);
+
+        for chunk in directions.chunks(LANES) {
+            let directions = [chunk[0], chunk[1], chunk[2], chunk[3]];
+            let packet = RayPacket::new([origin; LANES], directions, [0.; LANES], [true; LANES]);
+
+            for trig in models.iter().flat_map(|model| model.mesh.0.iter()) {
+                let hit = packet.intersect_triangle(*trig, Culling::Back);
+
+                for (lane, direction) in directions.iter().enumerate() {
+                    let scalar = ray_intersects_triangle(origin, *direction, *trig, Culling::Back);
+                    assert_eq!(hit.mask.test(lane), scalar.is_some());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_inactive_lanes() {
+        let scene = scene();
+        let models = placed(&scene);
+        let origin = Matrix([[1., 0., -5., 1.]]);
+        let forward = Matrix([[0., 0., 1., 0.]]);
+
+        let packet = RayPacket::new(
+            [origin; LANES],
+            [forward; LANES],
+            [0.; LANES],
+            [true, false, true, false],
+        );
+        let hits = packet.nearest_hits(&models);
+
+        assert!(hits[0].is_some());
+        assert!(hits[1].is_none());
+        assert!(hits[2].is_some());
+        assert!(hits[3].is_none());
+    }
+
+    #[test]
+    fn test_coherence() {
+        let origin = Matrix([[0., 0., 0., 1.]]);
+        let forward = Matrix([[0., 0., 1., 0.]]);
+        let backward = Matrix([[0., 0., -1., 0.]]);
+
+        let coherent = RayPacket::new(
+            [origin; LANES],
+            [forward; LANES],
+            [0.; LANES],
+            [true; LANES],
+        );
+        assert!(coherent.is_coherent());
+
+        let incoherent = RayPacket::new(
+            [origin; LANES],
+            [forward, backward, forward, forward],
+            [0.; LANES],
+            [true; LANES],
+        );
+        assert!(!incoherent.is_coherent());
+
+        let masked = RayPacket::new(
+            [origin; LANES],
+            [forward, backward, forward, forward],
+            [0.; LANES],
+            [true, false, true, true],
+        );
+        assert!(masked.is_coherent());
+    }
+
+    #[bench]
+    fn bench_primary_scalar(b: &mut Bencher) {
+        let scene = scene();
+        let models = placed(&scene);
+        let origin = Matrix([[0., 0., -5., 1.]]);
+        let directions = primary_directions(32);
+
+        b.iter(|| {
+            directions
+                .iter()
+                .filter(|direction| crate::nearest_hit(origin, **direction, 0., &models).is_some())
+                .count()
+        });
+    }
+
+    #[bench]
+    fn bench_primary_packet(b: &mut Bencher) {
+        let scene = scene();
+        let models = placed(&scene);
+        let origin = Matrix([[0., 0., -5., 1.]]);
+        let directions = primary_directions(32);
+
+        b.iter(|| {
+            directions
+                .chunks(LANES)
+                .map(|chunk| {
+                    let packet = RayPacket::new(
+                        [origin; LANES],
+                        [chunk[0], chunk[1], chunk[2], chunk[3]],
+                        [0.; LANES],
+                        [true; LANES],
+                    );
+                    packet.nearest_hits(&models).iter().flatten().count()
+                })
+                .sum::<usize>()
+        });
+    }
+
+    #[bench]
+    fn bench_primary_packet_wall(b: &mut Bencher) {
+        let scene = wall();
+        let models = placed(&scene);
+        let origin = Matrix([[0., 0., -5., 1.]]);
+        let directions = primary_directions(32);
+
+        b.iter(|| {
+            directions
+                .chunks(LANES)
+                .map(|chunk| {
+                    let packet = RayPacket::new(
+                        [origin; LANES],
+                        [chunk[0], chunk[1], chunk[2], chunk[3]],
+                        [0.; LANES],
+                        [true; LANES],
+                    );
+                    packet.nearest_hits(&models).iter().flatten().count()
+                })
+                .sum::<usize>()
+        });
+    }
+}
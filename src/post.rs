@@ -0,0 +1,613 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    bitmap::FloatImage, environment::luminance, lut::Lut3D, matrix::Matrix, sampling::Rng,
+};
+
+#[derive(Clone, Debug)]
+pub enum Effect {
+    /// Adds a blurred copy of everything brighter than `threshold`. `radius`
+    /// is the standard deviation of the blur in pixels.
+    Bloom {
+        threshold: f32,
+        intensity: f32,
+        radius: f32,
+    },
+    /// Darkens towards the corners. `radius` is where the falloff starts, as
+    /// a fraction of the distance from the centre to a corner.
+    Vignette {
+        strength: f32,
+        radius: f32,
+    },
+    /// Scales red outwards and blue inwards from the centre by `strength`.
+    ChromaticAberration {
+        strength: f32,
+    },
+    /// Lottes' fast approximate antialiasing, applied to the luma edges.
+    Fxaa,
+    /// Unsharp mask against a 3x3 blur.
+    Sharpen {
+        amount: f32,
+    },
+    Lut(Lut3D),
+    /// Monochrome noise, up to `amount` either way, new every frame.
+    Grain {
+        amount: f32,
+    },
+}
+
+/// Effects run in the order they were added.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct PostChain {
+    effects: Vec<Effect>,
+}
+
+#[wasm_bindgen]
+impl PostChain {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> PostChain {
+        PostChain::default()
+    }
+
+    pub fn add_bloom(&mut self, threshold: f32, intensity: f32, radius: f32) {
+        self.effects.push(Effect::Bloom {
+            threshold,
+            intensity,
+            radius,
+        });
+    }
+
+    pub fn add_vignette(&mut self, strength: f32, radius: f32) {
+        self.effects.push(Effect::Vignette { strength, radius });
+    }
+
+    pub fn add_chromatic_aberration(&mut self, strength: f32) {
+        self.effects.push(Effect::ChromaticAberration { strength });
+    }
+
+    pub fn add_fxaa(&mut self) {
+        self.effects.push(Effect::Fxaa);
+    }
+
+    pub fn add_sharpen(&mut self, amount: f32) {
+        self.effects.push(Effect::Sharpen { amount });
+    }
+
+    /// Adds color grading from the text of a `.cube` file.
+    pub fn add_lut(&mut self, cube: &str) -> Result<(), JsValue> {
+        let lut = Lut3D::parse_cube(cube).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.effects.push(Effect::Lut(lut));
+        Ok(())
+    }
+
+    pub fn add_grain(&mut self, amount: f32) {
+        self.effects.push(Effect::Grain { amount });
+    }
+
+    pub fn clear(&mut self) {
+        self.effects.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+}
+
+impl PostChain {
+    pub fn push(&mut self, effect: Effect) {
+        self.effects.push(effect);
+    }
+
+    /// Runs every effect over `image`. `frame` seeds the grain.
+    pub fn apply(&self, image: &mut FloatImage, frame: u32) {
+        for effect in self.effects.iter() {
+            *image = match effect {
+                Effect::Bloom {
+                    threshold,
+                    intensity,
+                    radius,
+                } => bloom(image, *threshold, *intensity, *radius),
+                Effect::Vignette { strength, radius } => vignette(image, *strength, *radius),
+                Effect::ChromaticAberration { strength } => chromatic_aberration(image, *strength),
+                Effect::Fxaa => fxaa(image),
+                Effect::Sharpen { amount } => sharpen(image, *amount),
+                Effect::Lut(lut) => map(image, |_, _, c| lut.apply(c)),
+                Effect::Grain { amount } => grain(image, *amount, frame),
+            };
+        }
+    }
+}
+
+fn map(image: &FloatImage, f: impl Fn(u32, u32, Matrix<1, 4>) -> Matrix<1, 4>) -> FloatImage {
+    let mut out = FloatImage::new(image.width, image.height);
+    for y in 0..image.height {
+        for x in 0..image.width {
+            out.set(x, y, f(x, y, image.get(x, y)));
+        }
+    }
+    out
+}
+
+/// Pixel lookup that clamps to the edge.
+fn texel(image: &FloatImage, x: i32, y: i32) -> Matrix<1, 4> {
+    let x = x.clamp(0, image.width as i32 - 1);
+    let y = y.clamp(0, image.height as i32 - 1);
+    image.get(x as u32, y as u32)
+}
+
+/// Bilinear lookup in pixel units, with pixel centres at integers.
+fn bilinear(image: &FloatImage, x: f32, y: f32) -> Matrix<1, 4> {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+
+    let top = texel(image, x0, y0) * (1. - fx) + texel(image, x0 + 1, y0) * fx;
+    let bottom = texel(image, x0, y0 + 1) * (1. - fx) + texel(image, x0 + 1, y0 + 1) * fx;
+    top * (1. - fy) + bottom * fy
+}
+
+fn with_alpha(color: Matrix<1, 4>, alpha: f32) -> Matrix<1, 4> {
+    Matrix([[color.x(), color.y(), color.z(), alpha]])
+}
+
+/// Separable Gaussian blur with standard deviation `sigma` in pixels. A
+/// `sigma` of zero or less leaves the image as it is.
+pub fn gaussian_blur(image: &FloatImage, sigma: f32) -> FloatImage {
+    if sigma.is_nan() || sigma <= 0. {
+        return image.clone();
+    }
+    let radius = (sigma * 3.).ceil().max(1.) as i32;
+    let mut kernel: Vec<f32> = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2. * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|k| *k /= total);
+
+    let pass = |image: &FloatImage, dx: i32, dy: i32| {
+        map(image, |x, y, _| {
+            kernel
+                .iter()
+                .zip(-radius..=radius)
+                .fold(Matrix::default(), |sum, (k, i)| {
+                    sum + texel(image, x as i32 + i * dx, y as i32 + i * dy) * *k
+                })
+        })
+    };
+
+    pass(&pass(image, 1, 0), 0, 1)
+}
+
+fn bloom(image: &FloatImage, threshold: f32, intensity: f32, radius: f32) -> FloatImage {
+    let bright = map(image, |_, _, c| {
+        let excess = |v: f32| (v - threshold).max(0.);
+        Matrix([[excess(c.x()), excess(c.y()), excess(c.z()), 0.]])
+    });
+    let blurred = gaussian_blur(&bright, radius);
+
+    map(image, |x, y, c| c + blurred.get(x, y) * intensity)
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+fn vignette(image: &FloatImage, strength: f32, radius: f32) -> FloatImage {
+    let cx = (image.width as f32 - 1.) / 2.;
+    let cy = (image.height as f32 - 1.) / 2.;
+    let corner = (cx * cx + cy * cy).sqrt().max(f32::EPSILON);
+
+    map(image, |x, y, c| {
+        let d = ((x as f32 - cx).powi(2) + (y as f32 - cy).powi(2)).sqrt() / corner;
+        let factor = 1. - strength * smoothstep(radius, 1., d);
+        with_alpha(c * factor, c.w())
+    })
+}
+
+fn chromatic_aberration(image: &FloatImage, strength: f32) -> FloatImage {
+    let cx = (image.width as f32 - 1.) / 2.;
+    let cy = (image.height as f32 - 1.) / 2.;
+
+    map(image, |x, y, c| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let channel = |scale: f32| bilinear(image, cx + dx * scale, cy + dy * scale);
+        Matrix([[
+            channel(1. + strength).x(),
+            c.y(),
+            channel(1. - strength).z(),
+            c.w(),
+        ]])
+    })
+}
+
+fn sharpen(image: &FloatImage, amount: f32) -> FloatImage {
+    map(image, |x, y, c| {
+        let (x, y) = (x as i32, y as i32);
+        let mut blur = Matrix::default();
+        for (dy, wy) in [(-1, 1.), (0, 2.), (1, 1.)] {
+            for (dx, wx) in [(-1, 1.), (0, 2.), (1, 1.)] {
+                blur = blur + texel(image, x + dx, y + dy) * (wx * wy / 16.);
+            }
+        }
+        with_alpha(c + (c - blur) * amount, c.w())
+    })
+}
+
+fn grain(image: &FloatImage, amount: f32, frame: u32) -> FloatImage {
+    map(image, |x, y, c| {
+        let noise = (Rng::for_pixel(x as usize, y as usize, frame).next_f32() * 2. - 1.) * amount;
+        with_alpha(c + noise, c.w())
+    })
+}
+
+/// FXAA 3.11 quality preset without the console shortcuts: finds the
+/// dominant luma edge through each pixel, walks along it to both ends, and
+/// resamples across it by how far the pixel is from the nearer end.
+pub fn fxaa(image: &FloatImage) -> FloatImage {
+    const EDGE_THRESHOLD: f32 = 0.125;
+    const EDGE_THRESHOLD_MIN: f32 = 0.0312;
+    const SUBPIXEL_QUALITY: f32 = 0.75;
+    const SEARCH_STEPS: [f32; 12] = [1., 1., 1., 1., 1., 1.5, 2., 2., 2., 2., 4., 8.];
+
+    // Luma is perceptual, so edges are found on clamped, gamma-ish values.
+    let luma = |c: Matrix<1, 4>| luminance(c).clamp(0., 1.).sqrt();
+    let at = |x: i32, y: i32| luma(texel(image, x, y));
+    let between = |x: f32, y: f32| luma(bilinear(image, x, y));
+
+    map(image, |x, y, c| {
+        let (x, y) = (x as i32, y as i32);
+        let center = luma(c);
+        let (north, south) = (at(x, y - 1), at(x, y + 1));
+        let (west, east) = (at(x - 1, y), at(x + 1, y));
+
+        let max = center.max(north).max(south).max(west).max(east);
+        let min = center.min(north).min(south).min(west).min(east);
+        let range = max - min;
+        if range < EDGE_THRESHOLD_MIN.max(max * EDGE_THRESHOLD) {
+            return c;
+        }
+
+        let (north_west, north_east) = (at(x - 1, y - 1), at(x + 1, y - 1));
+        let (south_west, south_east) = (at(x - 1, y + 1), at(x + 1, y + 1));
+
+        let horizontal = ((north + south) - 2. * center).abs() * 2.
+            + ((north_east + south_east) - 2. * east).abs()
+            + ((north_west + south_west) - 2. * west).abs();
+        let vertical = ((east + west) - 2. * center).abs() * 2.
+            + ((north_east + north_west) - 2. * north).abs()
+            + ((south_east + south_west) - 2. * south).abs();
+        let is_horizontal = horizontal >= vertical;
+
+        // Which side of the pixel the edge lies on, and a step across it.
+        let (negative, positive) = if is_horizontal {
+            (north, south)
+        } else {
+            (west, east)
+        };
+        let gradient_negative = (negative - center).abs();
+        let gradient_positive = (positive - center).abs();
+        let (step, side_luma, gradient) = if gradient_negative >= gradient_positive {
+            (-1., negative, gradient_negative)
+        } else {
+            (1., positive, gradient_positive)
+        };
+        let edge_luma = (center + side_luma) / 2.;
+        let gradient_scaled = gradient / 4.;
+
+        // Walk along the edge, half a pixel towards the other side.
+        let (mut ux, mut uy) = (x as f32, y as f32);
+        let (along_x, along_y) = if is_horizontal {
+            uy += step / 2.;
+            (1., 0.)
+        } else {
+            ux += step / 2.;
+            (0., 1.)
+        };
+
+        let walk = |direction: f32| {
+            let mut distance = 0.;
+            let mut delta = 0.;
+            for s in SEARCH_STEPS {
+                distance += s;
+                delta = between(
+                    ux + along_x * distance * direction,
+                    uy + along_y * distance * direction,
+                ) - edge_luma;
+                if delta.abs() >= gradient_scaled {
+                    break;
+                }
+            }
+            (distance, delta)
+        };
+        let (distance_negative, delta_negative) = walk(-1.);
+        let (distance_positive, delta_positive) = walk(1.);
+
+        // Only the nearer end matters, and only if the luma there moves away
+        // from the centre's side of the edge.
+        let (distance, delta) = if distance_negative < distance_positive {
+            (distance_negative, delta_negative)
+        } else {
+            (distance_positive, delta_positive)
+        };
+        let center_below = center < edge_luma;
+        let edge_offset = if (delta < 0.) != center_below {
+            0.5 - distance / (distance_negative + distance_positive)
+        } else {
+            0.
+        };
+
+        // Thin features that the edge walk can't resolve get blended by how
+        // much the pixel differs from its neighbourhood.
+        let average = (2. * (north + south + west + east)
+            + north_west
+            + north_east
+            + south_west
+            + south_east)
+            / 12.;
+        let subpixel = smoothstep(0., 1., ((average - center).abs() / range).clamp(0., 1.));
+        let subpixel_offset = subpixel * subpixel * SUBPIXEL_QUALITY;
+
+        let offset = edge_offset.max(subpixel_offset) * step;
+        let sample = if is_horizontal {
+            bilinear(image, x as f32, y as f32 + offset)
+        } else {
+            bilinear(image, x as f32 + offset, y as f32)
+        };
+        with_alpha(sample, c.w())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(width: u32, height: u32, color: Matrix<1, 4>) -> FloatImage {
+        let mut image = FloatImage::new(width, height);
+        image.pixels.fill(color);
+        image
+    }
+
+    fn apply(effect: Effect, image: &FloatImage) -> FloatImage {
+        let mut chain = PostChain::new();
+        chain.push(effect);
+        let mut image = image.clone();
+        chain.apply(&mut image, 0);
+        image
+    }
+
+    fn close(a: Matrix<1, 4>, b: Matrix<1, 4>) -> bool {
+        (a - b).abs().0[0].iter().all(|d| *d < 1e-4)
+    }
+
+    #[test]
+    fn test_empty_chain() {
+        let mut image = filled(4, 3, Matrix([[0.2, 0.4, 0.6, 1.]]));
+        image.set(1, 1, Matrix([[1., 0., 0., 0.5]]));
+        let before = image.clone();
+
+        PostChain::new().apply(&mut image, 7);
+        assert_eq!(image.pixels, before.pixels);
+    }
+
+    #[test]
+    fn test_bloom() {
+        let gray = Matrix([[0.5, 0.5, 0.5, 1.]]);
+        let mut image = filled(15, 15, gray);
+
+        // Nothing passes the threshold, so nothing changes.
+        let out = apply(
+            Effect::Bloom {
+                threshold: 1.,
+                intensity: 1.,
+                radius: 2.,
+            },
+            &image,
+        );
+        assert!(out.pixels.iter().all(|c| close(*c, gray)));
+
+        image.set(7, 7, Matrix([[10., 10., 10., 1.]]));
+        let out = apply(
+            Effect::Bloom {
+                threshold: 1.,
+                intensity: 1.,
+                radius: 2.,
+            },
+            &image,
+        );
+
+        // Light spreads to the neighbours and falls off with distance.
+        let near = out.get(8, 7).x();
+        let far = out.get(11, 7).x();
+        assert!(near > far && far > 0.5, "{near} {far}");
+        assert!(close(out.get(0, 0), gray));
+        assert_eq!(out.get(8, 7).w(), 1.);
+
+        // The blur itself preserves energy.
+        let blurred = gaussian_blur(&image, 2.);
+        let sum = |image: &FloatImage| image.pixels.iter().map(|c| c.x()).sum::<f32>();
+        assert!((sum(&blurred) - sum(&image)).abs() < 1e-2);
+
+        // A zero radius blooms the bright pixel onto itself alone.
+        let out = apply(
+            Effect::Bloom {
+                threshold: 1.,
+                intensity: 1.,
+                radius: 0.,
+            },
+            &image,
+        );
+        assert_eq!(out.get(7, 7).x(), 19.);
+        assert!(close(out.get(8, 7), gray));
+    }
+
+    #[test]
+    fn test_vignette() {
+        let white = Matrix([[1., 1., 1., 1.]]);
+        let image = filled(21, 11, white);
+        let out = apply(
+            Effect::Vignette {
+                strength: 0.8,
+                radius: 0.3,
+            },
+            &image,
+        );
+
+        assert!(close(out.get(10, 5), white));
+        assert!((out.get(0, 0).x() - 0.2).abs() < 1e-4);
+        assert!(out.get(5, 5).x() > out.get(2, 2).x());
+        assert_eq!(out.get(0, 0).w(), 1.);
+    }
+
+    #[test]
+    fn test_chromatic_aberration() {
+        // A vertical white line right of the centre.
+        let mut image = filled(21, 5, Matrix([[0., 0., 0., 1.]]));
+        for y in 0..5 {
+            image.set(15, y, Matrix([[1., 1., 1., 1.]]));
+        }
+
+        let out = apply(Effect::ChromaticAberration { strength: 0.2 }, &image);
+
+        // Red is sampled further out, so it shows up nearer the centre;
+        // blue is sampled further in, so it shows up further out.
+        let column = |x: u32| out.get(x, 2);
+        assert!(column(15).y() == 1.);
+        assert!(column(14).x() > 0.5 && column(14).z() == 0.);
+        assert!(column(16).z() > 0.5 && column(16).x() == 0.);
+
+        // Nothing moves at the centre.
+        let center = apply(
+            Effect::ChromaticAberration { strength: 0.2 },
+            &filled(5, 5, Matrix([[0.3, 0.6, 0.9, 1.]])),
+        );
+        assert!(close(center.get(2, 2), Matrix([[0.3, 0.6, 0.9, 1.]])));
+    }
+
+    #[test]
+    fn test_fxaa() {
+        // Flat images pass through.
+        let flat = filled(8, 8, Matrix([[0.4, 0.4, 0.4, 1.]]));
+        assert_eq!(apply(Effect::Fxaa, &flat).pixels, flat.pixels);
+
+        // A shallow aliased staircase: white below a line rising one pixel
+        // every four columns.
+        let mut image = filled(32, 16, Matrix([[0., 0., 0., 1.]]));
+        for x in 0..32 {
+            for y in (12 - x / 4).max(0)..16 {
+                image.set(x as u32, y as u32, Matrix([[1., 1., 1., 1.]]));
+            }
+        }
+        let out = apply(Effect::Fxaa, &image);
+
+        // Pixels along the steps pick up intermediate values, while pixels
+        // far from the edge are untouched.
+        let intermediate = out
+            .pixels
+            .iter()
+            .filter(|c| c.x() > 0.05 && c.x() < 0.95)
+            .count();
+        assert!(intermediate > 16, "{intermediate}");
+        assert_eq!(out.get(0, 0), image.get(0, 0));
+        assert_eq!(out.get(31, 15), image.get(31, 15));
+
+        // Antialiasing only blends, so it never leaves the input range.
+        assert!(out.pixels.iter().all(|c| (0. ..=1.).contains(&c.x())));
+    }
+
+    #[test]
+    fn test_sharpen() {
+        let flat = filled(6, 6, Matrix([[0.5, 0.5, 0.5, 1.]]));
+        let out = apply(Effect::Sharpen { amount: 1. }, &flat);
+        assert!(out.pixels.iter().all(|c| close(*c, flat.get(0, 0))));
+
+        // A step edge gains overshoot on both sides.
+        let mut image = filled(8, 4, Matrix([[0.25, 0.25, 0.25, 1.]]));
+        for y in 0..4 {
+            for x in 4..8 {
+                image.set(x, y, Matrix([[0.75, 0.75, 0.75, 1.]]));
+            }
+        }
+        let out = apply(Effect::Sharpen { amount: 1. }, &image);
+        assert!(out.get(3, 1).x() < 0.25);
+        assert!(out.get(4, 1).x() > 0.75);
+        assert!(close(out.get(0, 1), image.get(0, 1)));
+    }
+
+    #[test]
+    fn test_lut() {
+        let mut image = FloatImage::new(4, 4);
+        for (i, pixel) in image.pixels.iter_mut().enumerate() {
+            let v = i as f32 / 15.;
+            *pixel = Matrix([[v, 1. - v, v * v, 1.]]);
+        }
+
+        let out = apply(Effect::Lut(Lut3D::identity(17)), &image);
+        for (a, b) in out.pixels.iter().zip(image.pixels.iter()) {
+            assert!(close(*a, *b));
+        }
+    }
+
+    #[test]
+    fn test_grain() {
+        let gray = Matrix([[0.5, 0.5, 0.5, 1.]]);
+        let image = filled(64, 64, gray);
+        let grain = |frame| {
+            let mut chain = PostChain::new();
+            chain.add_grain(0.1);
+            let mut image = image.clone();
+            chain.apply(&mut image, frame);
+            image
+        };
+
+        let out = grain(1);
+        let mean = out.pixels.iter().map(|c| c.x()).sum::<f32>() / out.pixels.len() as f32;
+        assert!((mean - 0.5).abs() < 0.01, "{mean}");
+        assert!(out.pixels.iter().all(|c| (c.x() - 0.5).abs() <= 0.1));
+        assert!(out.pixels.iter().all(|c| c.x() == c.y() && c.w() == 1.));
+
+        // The same frame gives the same noise, another frame different noise.
+        assert_eq!(grain(1).pixels, out.pixels);
+        assert_ne!(grain(2).pixels, out.pixels);
+    }
+
+    #[test]
+    fn test_chain_order() {
+        // Grading first clamps the bright pixel to a half that's no longer
+        // over the bloom threshold; the other order blooms first and then
+        // grades the glow.
+        let mut half_cube = "LUT_3D_SIZE 2\n".to_string();
+        for b in [0., 0.5] {
+            for g in [0., 0.5] {
+                for r in [0., 0.5] {
+                    half_cube += &format!("{r} {g} {b}\n");
+                }
+            }
+        }
+        let mut image = filled(9, 9, Matrix([[0., 0., 0., 1.]]));
+        image.set(4, 4, Matrix([[4., 4., 4., 1.]]));
+
+        let mut chain = PostChain::new();
+        chain.add_lut(&half_cube).unwrap();
+        chain.add_bloom(0.5, 1., 1.);
+        chain.add_vignette(0.5, 0.5);
+        assert_eq!(chain.len(), 3);
+
+        let mut out = image.clone();
+        chain.apply(&mut out, 0);
+        assert_eq!(out.get(4, 4).x(), 0.5);
+        assert_eq!(out.get(5, 4).x(), 0.);
+
+        chain.clear();
+        chain.add_bloom(0.5, 1., 1.);
+        chain.add_lut(&half_cube).unwrap();
+        let mut out = image.clone();
+        chain.apply(&mut out, 0);
+        assert_eq!(out.get(4, 4).x(), 0.5);
+        assert!(out.get(5, 4).x() > 0.);
+    }
+}
@@ -0,0 +1,693 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    bitmap::{Bitmap, Color},
+    blend::{BlendMode, premultiply, unpremultiply},
+    cross_product,
+    depth::DepthBuffer,
+    inside_triangle,
+    matrix::Matrix,
+    matrix_3d::{Point2D, screen},
+    shader::{Shader, Varying},
+};
+
+/// How the rasterizer smooths triangle edges.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AntialiasKind {
+    /// Pixels are covered or not by their centre.
+    #[default]
+    None,
+    /// Multisampling with 2, 4 or 8 depth tested samples per pixel, shaded
+    /// once per pixel and averaged.
+    Msaa2,
+    Msaa4,
+    Msaa8,
+    /// Opaque meshes cover pixels by the exact area of them their
+    /// triangles overlap.
+    Coverage,
+}
+
+impl AntialiasKind {
+    /// Samples per pixel for multisampling, `None` otherwise.
+    pub fn samples(self) -> Option<u32> {
+        match self {
+            AntialiasKind::Msaa2 => Some(2),
+            AntialiasKind::Msaa4 => Some(4),
+            AntialiasKind::Msaa8 => Some(8),
+            AntialiasKind::None | AntialiasKind::Coverage => None,
+        }
+    }
+}
+
+pub const MAX_SAMPLES: usize = 8;
+
+const PATTERN_1: [(f32, f32); 1] = [(0.5, 0.5)];
+// The standard Direct3D patterns, in sixteenths of a pixel from its centre.
+const PATTERN_2: [(f32, f32); 2] = [(4., 4.), (-4., -4.)];
+const PATTERN_4: [(f32, f32); 4] = [(-2., -6.), (6., -2.), (-6., 2.), (2., 6.)];
+const PATTERN_8: [(f32, f32); 8] = [
+    (1., -3.),
+    (-1., 3.),
+    (5., 1.),
+    (-3., -5.),
+    (-5., 5.),
+    (-7., -1.),
+    (3., 7.),
+    (7., -7.),
+];
+
+/// Where in a pixel each of `count` samples lies, from 0 to 1 across it.
+/// Counts other than 2, 4 and 8 get a single sample in the centre.
+pub fn sample_pattern(count: u32) -> Vec<(f32, f32)> {
+    let offsets: &[(f32, f32)] = match count {
+        2 => &PATTERN_2,
+        4 => &PATTERN_4,
+        8 => &PATTERN_8,
+        _ => return PATTERN_1.to_vec(),
+    };
+    offsets
+        .iter()
+        .map(|(x, y)| (0.5 + x / 16., 0.5 + y / 16.))
+        .collect()
+}
+
+/// Which points of a pixel `scan` tests a triangle against.
+pub enum Sampling<'a> {
+    /// The centre alone.
+    Center,
+    /// Each of these positions, as `sample_pattern` gives them.
+    Pattern(&'a [(f32, f32)]),
+    /// The whole pixel, by the area the triangle covers.
+    Coverage,
+}
+
+/// A pixel a triangle covers some of, shaded once.
+pub struct Fragment {
+    pub x: usize,
+    pub y: usize,
+    pub color: Color,
+    /// Clip w where the fragment was shaded, which is the distance from the
+    /// camera under perspective.
+    pub w: f32,
+    /// Fraction of the pixel covered.
+    pub coverage: f32,
+    /// Bit `i` is set when sample `i` is covered and passed the test.
+    pub mask: u32,
+    /// Normalized device depth at each sample, or at the shaded point for
+    /// the other kinds of sampling.
+    pub depth: [f32; MAX_SAMPLES],
+}
+
+/// Scan converts one triangle over a `width` by `height` target, running
+/// `shader`'s fragment stage once for each pixel it covers. Fragments are
+/// shaded at the pixel centre when the triangle covers it and at a covered
+/// point inside the pixel otherwise, so varyings never extrapolate past the
+/// triangle. `test` gets the pixel, sample index and depth of each covered
+/// sample, and pixels where it passes none are skipped.
+///
+/// The triangle is first cut at the near plane, `z = -w`, since vertices
+/// behind the camera would otherwise come out mirrored across the screen.
+#[allow(clippy::too_many_arguments)]
+pub fn scan<S: Shader>(
+    width: u32,
+    height: u32,
+    shader: &S,
+    clip: [Matrix<1, 4>; 3],
+    varyings: [S::Varying; 3],
+    sampling: &Sampling,
+    test: impl Fn(usize, usize, usize, f32) -> bool,
+    mut emit: impl FnMut(Fragment),
+) {
+    let distance = clip.map(|p| p.z() + p.w());
+    if distance.iter().all(|&d| d >= 0.) {
+        scan_triangle(
+            width, height, shader, clip, varyings, sampling, &test, &mut emit,
+        );
+        return;
+    }
+
+    // Sutherland–Hodgman against the near plane, with varyings blended
+    // linearly along the cut edges as they are in clip space.
+    let mut polygon = Vec::with_capacity(4);
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+        let (di, dj) = (distance[i], distance[j]);
+        if di >= 0. {
+            polygon.push((clip[i], varyings[i]));
+        }
+        if (di >= 0.) != (dj >= 0.) {
+            let t = di / (di - dj);
+            polygon.push((
+                clip[i] + (clip[j] - clip[i]) * t,
+                S::Varying::blend(varyings[i], varyings[j], varyings[j], [1. - t, t, 0.]),
+            ));
+        }
+    }
+
+    for k in 2..polygon.len() {
+        let [a, b, c] = [polygon[0], polygon[k - 1], polygon[k]];
+        scan_triangle(
+            width,
+            height,
+            shader,
+            [a.0, b.0, c.0],
+            [a.1, b.1, c.1],
+            sampling,
+            &test,
+            &mut emit,
+        );
+    }
+}
+
+/// `scan` for a triangle entirely in front of the near plane.
+#[allow(clippy::too_many_arguments)]
+fn scan_triangle<S: Shader>(
+    width: u32,
+    height: u32,
+    shader: &S,
+    clip: [Matrix<1, 4>; 3],
+    varyings: [S::Varying; 3],
+    sampling: &Sampling,
+    test: &impl Fn(usize, usize, usize, f32) -> bool,
+    emit: &mut impl FnMut(Fragment),
+) {
+    let [p0, p1, p2] = clip;
+
+    let s0 = screen(p0, width as f32, height as f32);
+    let s1 = screen(p1, width as f32, height as f32);
+    let s2 = screen(p2, width as f32, height as f32);
+
+    let min_x = s0.x().min(s1.x()).min(s2.x()).max(0.) as usize;
+    let max_x = (s0.x().max(s1.x()).max(s2.x()).ceil().max(0.) as usize).min(width as usize);
+    let min_y = s0.y().min(s1.y()).min(s2.y()).max(0.) as usize;
+    let max_y = (s0.y().max(s1.y()).max(s2.y()).ceil().max(0.) as usize).min(height as usize);
+
+    let area = cross_product(s0, s1, s2);
+    // Degenerate triangles cover nothing, and would interpolate NaNs.
+    if area == 0. {
+        return;
+    }
+
+    // Normalized device depth is affine in screen space, so the plain
+    // screen barycentrics interpolate it.
+    let weights = |p: Point2D| {
+        [
+            cross_product(s1, s2, p) / area,
+            cross_product(s2, s0, p) / area,
+            cross_product(s0, s1, p) / area,
+        ]
+    };
+    let depth_at = |p: Point2D| {
+        let [b0, b1, b2] = weights(p);
+        b0 * p0.z() / p0.w() + b1 * p1.z() / p1.w() + b2 * p2.z() / p2.w()
+    };
+    let inside = |p: Point2D| inside_triangle(s0, s1, s2, p);
+
+    for x in min_x..max_x {
+        for y in min_y..max_y {
+            let center = Matrix([[x as f32 + 0.5, y as f32 + 0.5]]);
+            let mut depth = [0.; MAX_SAMPLES];
+
+            let (at, coverage, mask) = match sampling {
+                Sampling::Center => {
+                    depth[0] = depth_at(center);
+                    if !inside(center) || !test(x, y, 0, depth[0]) {
+                        continue;
+                    }
+                    (center, 1., 1)
+                }
+                Sampling::Pattern(pattern) => {
+                    let mut mask: u32 = 0;
+                    let mut first = None;
+                    for (i, (dx, dy)) in pattern.iter().enumerate().take(MAX_SAMPLES) {
+                        let p = Matrix([[x as f32 + dx, y as f32 + dy]]);
+                        if inside(p) {
+                            depth[i] = depth_at(p);
+                            if test(x, y, i, depth[i]) {
+                                mask |= 1 << i;
+                                first.get_or_insert(p);
+                            }
+                        }
+                    }
+                    let Some(first) = first else {
+                        continue;
+                    };
+                    let at = if inside(center) { center } else { first };
+                    (at, mask.count_ones() as f32 / pattern.len() as f32, mask)
+                }
+                Sampling::Coverage => {
+                    let (coverage, centroid) = coverage(s0, s1, s2, x as f32, y as f32);
+                    if coverage <= 0. {
+                        continue;
+                    }
+                    let at = if inside(center) { center } else { centroid };
+                    depth[0] = depth_at(at);
+                    if !test(x, y, 0, depth[0]) {
+                        continue;
+                    }
+                    (at, coverage, 1)
+                }
+            };
+
+            // Perspective correct weights divide by each vertex's clip w.
+            let [b0, b1, b2] = weights(at);
+            let (b0, b1, b2) = (b0 / p0.w(), b1 / p1.w(), b2 / p2.w());
+            let sum = b0 + b1 + b2;
+            let varying = S::Varying::blend(
+                varyings[0],
+                varyings[1],
+                varyings[2],
+                [b0 / sum, b1 / sum, b2 / sum],
+            );
+
+            if let Some(color) = shader.fragment(varying) {
+                emit(Fragment {
+                    x,
+                    y,
+                    color,
+                    w: 1. / sum,
+                    coverage,
+                    mask,
+                    depth,
+                });
+            }
+        }
+    }
+}
+
+/// Fraction of the pixel with its top left corner at `(x, y)` that the
+/// screen space triangle covers, and the centroid of the covered part.
+/// Triangles facing away cover nothing, as with `inside_triangle`.
+pub fn coverage(s0: Point2D, s1: Point2D, s2: Point2D, x: f32, y: f32) -> (f32, Point2D) {
+    let mut polygon = vec![
+        Matrix([[x, y]]),
+        Matrix([[x + 1., y]]),
+        Matrix([[x + 1., y + 1.]]),
+        Matrix([[x, y + 1.]]),
+    ];
+
+    // Sutherland–Hodgman against the inner side of each edge.
+    for (a, b) in [(s1, s0), (s0, s2), (s2, s1)] {
+        let side = |p: Point2D| cross_product(a, b, p);
+        let mut clipped = Vec::with_capacity(polygon.len() + 1);
+        for (i, &p) in polygon.iter().enumerate() {
+            let q = polygon[(i + 1) % polygon.len()];
+            let (sp, sq) = (side(p), side(q));
+            if sp >= 0. {
+                clipped.push(p);
+            }
+            if (sp >= 0.) != (sq >= 0.) {
+                clipped.push(p + (q - p) * (sp / (sp - sq)));
+            }
+        }
+        polygon = clipped;
+        if polygon.len() < 3 {
+            return (0., Matrix([[x + 0.5, y + 0.5]]));
+        }
+    }
+
+    let mut area = 0.;
+    let mut centroid = Matrix([[0., 0.]]);
+    for (i, &p) in polygon.iter().enumerate() {
+        let q = polygon[(i + 1) % polygon.len()];
+        let cross = p.x() * q.y() - q.x() * p.y();
+        area += cross;
+        centroid = centroid + (p + q) * cross;
+    }
+    if area.abs() < 1e-9 {
+        return (0., Matrix([[x + 0.5, y + 0.5]]));
+    }
+    (area.abs() / 2., centroid / (3. * area))
+}
+
+/// Color and depth for every sample of every pixel, for multisample
+/// antialiasing. Triangles are depth tested per sample, so edges and
+/// intersections are smoothed alike. Colors are premultiplied.
+pub struct MultisampleBuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pattern: Vec<(f32, f32)>,
+    pub colors: Vec<Matrix<1, 4>>,
+    pub depth: Vec<f32>,
+}
+
+impl MultisampleBuffer {
+    pub fn new(width: u32, height: u32, samples: u32) -> MultisampleBuffer {
+        let pattern = sample_pattern(samples);
+        let size = width as usize * height as usize * pattern.len();
+        MultisampleBuffer {
+            width,
+            height,
+            pattern,
+            colors: vec![Matrix::default(); size],
+            depth: vec![f32::INFINITY; size],
+        }
+    }
+
+    fn index(&self, x: usize, y: usize, sample: usize) -> usize {
+        (y * self.width as usize + x) * self.pattern.len() + sample
+    }
+
+    /// Draws an opaque triangle, keeping the nearer surface at each sample.
+    pub fn render_shader<S: Shader>(&mut self, shader: &S, vertices: [&S::Vertex; 3]) {
+        self.render(shader, vertices, BlendMode::Over, true);
+    }
+
+    /// Composites a triangle with `mode` over the samples it's in front
+    /// of, leaving their depth be so transparent surfaces don't hide each
+    /// other.
+    pub fn render_shader_blended<S: Shader>(
+        &mut self,
+        shader: &S,
+        vertices: [&S::Vertex; 3],
+        mode: BlendMode,
+    ) {
+        self.render(shader, vertices, mode, false);
+    }
+
+    fn render<S: Shader>(
+        &mut self,
+        shader: &S,
+        vertices: [&S::Vertex; 3],
+        mode: BlendMode,
+        write_depth: bool,
+    ) {
+        let [a, b, c] = vertices.map(|vertex| shader.vertex(vertex));
+        let mut fragments = vec![];
+        scan(
+            self.width,
+            self.height,
+            shader,
+            [a.0, b.0, c.0],
+            [a.1, b.1, c.1],
+            &Sampling::Pattern(&self.pattern),
+            |x, y, sample, depth| depth <= self.depth[self.index(x, y, sample)],
+            |fragment| fragments.push(fragment),
+        );
+
+        for fragment in fragments.iter() {
+            let color = premultiply(fragment.color);
+            for sample in 0..self.pattern.len() {
+                if fragment.mask & (1 << sample) == 0 {
+                    continue;
+                }
+                let i = self.index(fragment.x, fragment.y, sample);
+                self.colors[i] = mode.blend(color, self.colors[i]);
+                if write_depth {
+                    self.depth[i] = fragment.depth[sample];
+                }
+            }
+        }
+    }
+
+    /// Farthest sample depth of each pixel, for single sampled passes that
+    /// come after the resolve.
+    pub fn depth_buffer(&self) -> DepthBuffer {
+        let mut buffer = DepthBuffer::new(self.width, self.height);
+        for (pixel, depth) in buffer.depth.iter_mut().enumerate() {
+            let samples = pixel * self.pattern.len()..(pixel + 1) * self.pattern.len();
+            *depth = self.depth[samples]
+                .iter()
+                .copied()
+                .fold(f32::NEG_INFINITY, f32::max);
+        }
+        buffer
+    }
+
+    /// Averages each pixel's samples and composites the result over `bmp`.
+    pub fn resolve(&self, bmp: &mut Bitmap) {
+        let samples = self.pattern.len();
+        for y in 0..self.height.min(bmp.height) as usize {
+            for x in 0..self.width.min(bmp.width) as usize {
+                let start = self.index(x, y, 0);
+                let sum = self.colors[start..start + samples]
+                    .iter()
+                    .fold(Matrix::default(), |sum, color| sum + *color);
+                if sum.w() <= 0. {
+                    continue;
+                }
+                let pixel = &mut bmp.rows[y][x];
+                *pixel =
+                    unpremultiply(BlendMode::Over.blend(sum / samples as f32, premultiply(*pixel)));
+            }
+        }
+    }
+}
+
+/// Analytic antialiasing. Each triangle's fragments are kept, weighted by
+/// the area of each pixel they cover, until the layer is resolved, when
+/// every pixel takes them nearest first until it is covered. The triangles
+/// either side of an edge inside a mesh sum to the whole pixel and leave no
+/// seam, faces hidden behind nearer ones stay hidden whatever order they
+/// were drawn in, and outlines blend with what's behind.
+pub struct CoverageLayer {
+    pub width: u32,
+    pub height: u32,
+    /// Pixel index, depth, premultiplied color and coverage of each fragment
+    /// since the last resolve.
+    fragments: Vec<(usize, f32, Matrix<1, 4>, f32)>,
+}
+
+impl CoverageLayer {
+    pub fn new(width: u32, height: u32) -> CoverageLayer {
+        CoverageLayer {
+            width,
+            height,
+            fragments: vec![],
+        }
+    }
+
+    pub fn render_shader<S: Shader>(&mut self, shader: &S, vertices: [&S::Vertex; 3]) {
+        let [a, b, c] = vertices.map(|vertex| shader.vertex(vertex));
+        let width = self.width as usize;
+        scan(
+            self.width,
+            self.height,
+            shader,
+            [a.0, b.0, c.0],
+            [a.1, b.1, c.1],
+            &Sampling::Coverage,
+            |_, _, _, _| true,
+            |fragment| {
+                self.fragments.push((
+                    fragment.y * width + fragment.x,
+                    fragment.depth[0],
+                    premultiply(fragment.color),
+                    fragment.coverage,
+                ));
+            },
+        );
+    }
+
+    /// Composites what was drawn since the last resolve over `bmp` and
+    /// clears it. Each pixel sums its fragments nearest first, each one only
+    /// up to what the nearer ones left uncovered.
+    pub fn resolve(&mut self, bmp: &mut Bitmap) {
+        let mut fragments = std::mem::take(&mut self.fragments);
+        fragments.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+
+        let width = self.width as usize;
+        for pixel_fragments in fragments.chunk_by(|a, b| a.0 == b.0) {
+            let (x, y) = (pixel_fragments[0].0 % width, pixel_fragments[0].0 / width);
+            if x >= bmp.width as usize || y >= bmp.height as usize {
+                continue;
+            }
+
+            let mut sum = Matrix::default();
+            let mut covered = 0.;
+            for &(_, _, color, coverage) in pixel_fragments {
+                let take = coverage.min(1. - covered);
+                if take <= 0. {
+                    break;
+                }
+                sum = sum + color * take;
+                covered += take;
+            }
+
+            if sum.w() <= 0. {
+                continue;
+            }
+            let pixel = &mut bmp.rows[y][x];
+            *pixel = unpremultiply(BlendMode::Over.blend(sum, premultiply(*pixel)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::Solid;
+
+    /// Clip space halves of a 4 by 4 bitmap, split along the diagonal from
+    /// its bottom left to its top right corner.
+    fn halves(z: f32) -> [[Matrix<1, 4>; 3]; 2] {
+        let corner = |x: f32, y: f32| Matrix([[x, y, z, 1.]]);
+        [
+            [corner(-1., -1.), corner(1., -1.), corner(1., 1.)],
+            [corner(-1., -1.), corner(1., 1.), corner(-1., 1.)],
+        ]
+    }
+
+    fn rgba(color: Color) -> [u8; 4] {
+        [color.r, color.g, color.b, color.a]
+    }
+
+    #[test]
+    fn test_sample_pattern() {
+        for count in [2, 4, 8] {
+            let pattern = sample_pattern(count);
+            assert_eq!(pattern.len(), count as usize);
+            assert!(
+                pattern
+                    .iter()
+                    .all(|&(x, y)| (0. ..1.).contains(&x) && (0. ..1.).contains(&y))
+            );
+            // Balanced about the centre.
+            let (x, y) = pattern
+                .iter()
+                .fold((0., 0.), |(sx, sy), (x, y)| (sx + x, sy + y));
+            assert_eq!((x / count as f32, y / count as f32), (0.5, 0.5));
+        }
+        assert_eq!(sample_pattern(1), vec![(0.5, 0.5)]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let s0 = Matrix([[-10., 10.]]);
+        let s1 = Matrix([[10., 10.]]);
+        let s2 = Matrix([[10., -10.]]);
+
+        // The hypotenuse runs corner to corner through this pixel.
+        let (area, centroid) = coverage(s0, s1, s2, 0., -1.);
+        assert!((area - 0.5).abs() < 1e-5);
+        assert!((centroid.x() - 2. / 3.).abs() < 1e-5);
+        assert!((centroid.y() + 1. / 3.).abs() < 1e-5);
+
+        assert!((coverage(s0, s1, s2, 2., 2.).0 - 1.).abs() < 1e-5);
+        assert_eq!(coverage(s0, s1, s2, -3., -3.).0, 0.);
+        // Facing away.
+        assert_eq!(coverage(s0, s2, s1, 2., 2.).0, 0.);
+    }
+
+    #[test]
+    fn test_near_clipping() {
+        let covered = |clip: [Matrix<1, 4>; 3]| {
+            let mut pixels = vec![];
+            let red = Solid(Color::new(255, 0, 0, 255));
+            scan(
+                8,
+                8,
+                &red,
+                clip,
+                [(); 3],
+                &Sampling::Center,
+                |_, _, _, _| true,
+                |fragment| pixels.push((fragment.x, fragment.y)),
+            );
+            pixels
+        };
+
+        // Straddling the camera, only the part in front shows, along the
+        // bottom edge.
+        let straddling = covered([
+            Matrix([[-1., -1., 0., 1.]]),
+            Matrix([[1., -1., 0., 1.]]),
+            Matrix([[0., 2., -3., -1.]]),
+        ]);
+        assert_eq!(straddling.len(), 8);
+        assert!(straddling.iter().all(|&(_, y)| y == 7));
+
+        // Entirely behind it, nothing shows rather than a mirror image.
+        let behind = covered([
+            Matrix([[-1., -1., 0., -1.]]),
+            Matrix([[1., -1., 0., -1.]]),
+            Matrix([[1., 1., 0., -1.]]),
+        ]);
+        assert!(behind.is_empty());
+    }
+
+    #[test]
+    fn test_multisample() {
+        let [[a, b, c], _] = halves(0.);
+        let [[d, e, f], [g, h, i]] = halves(0.5);
+        let red = Solid(Color::new(255, 0, 0, 255));
+        let green = Solid(Color::new(0, 255, 0, 255));
+
+        let mut buffer = MultisampleBuffer::new(4, 4, 4);
+        buffer.render_shader(&red, [&a, &b, &c]);
+        let mut bmp = Bitmap::new(4, 4);
+        buffer.resolve(&mut bmp);
+        // Two of the four samples of a pixel on the diagonal are covered.
+        assert_eq!(rgba(bmp.rows[2][1]), [255, 0, 0, 128]);
+        assert_eq!(rgba(bmp.rows[3][3]), [255, 0, 0, 255]);
+        assert_eq!(rgba(bmp.rows[0][0]), [0, 0, 0, 0]);
+
+        // Behind the red half, green only fills the samples left empty.
+        buffer.render_shader(&green, [&d, &e, &f]);
+        buffer.render_shader(&green, [&g, &h, &i]);
+        let mut bmp = Bitmap::new(4, 4);
+        buffer.resolve(&mut bmp);
+        assert_eq!(rgba(bmp.rows[2][1]), [128, 128, 0, 255]);
+        assert_eq!(rgba(bmp.rows[3][3]), [255, 0, 0, 255]);
+        assert_eq!(rgba(bmp.rows[0][0]), [0, 255, 0, 255]);
+
+        let depth = buffer.depth_buffer();
+        assert_eq!(depth.get(1, 2), 0.5);
+        assert_eq!(depth.get(3, 3), 0.);
+    }
+
+    #[test]
+    fn test_coverage_layer() {
+        let [[a, b, c], [d, e, f]] = halves(0.);
+        let red = Solid(Color::new(255, 0, 0, 255));
+
+        // One half alone leaves the diagonal half covered.
+        let mut layer = CoverageLayer::new(4, 4);
+        layer.render_shader(&red, [&a, &b, &c]);
+        let mut bmp = Bitmap::new(4, 4);
+        layer.resolve(&mut bmp);
+        assert_eq!(rgba(bmp.rows[2][1]), [255, 0, 0, 128]);
+        assert_eq!(rgba(bmp.rows[1][1]), [0, 0, 0, 0]);
+
+        // Both halves together leave no seam.
+        layer.render_shader(&red, [&a, &b, &c]);
+        layer.render_shader(&red, [&d, &e, &f]);
+        let mut bmp = Bitmap::new(4, 4);
+        layer.resolve(&mut bmp);
+        assert!(
+            bmp.rows
+                .iter()
+                .flatten()
+                .all(|&color| rgba(color) == [255, 0, 0, 255])
+        );
+    }
+
+    #[test]
+    fn test_coverage_layer_hidden() {
+        let near = halves(0.);
+        let far = halves(0.5);
+        let red = Solid(Color::new(255, 0, 0, 255));
+        let green = Solid(Color::new(0, 255, 0, 255));
+
+        // Two faces of one mesh over the same pixels show only the nearer
+        // one, whichever is drawn first.
+        let faces = [(&red, near), (&green, far)];
+        for order in [[0, 1], [1, 0]] {
+            let mut layer = CoverageLayer::new(4, 4);
+            for (shader, halves) in order.map(|i| faces[i]) {
+                for [a, b, c] in &halves {
+                    layer.render_shader(shader, [a, b, c]);
+                }
+            }
+            let mut bmp = Bitmap::new(4, 4);
+            layer.resolve(&mut bmp);
+            assert!(
+                bmp.rows
+                    .iter()
+                    .flatten()
+                    .all(|&color| rgba(color) == [255, 0, 0, 255])
+            );
+        }
+    }
+}
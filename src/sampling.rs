@@ -0,0 +1,436 @@
+use core::f32;
+
+use crate::{matrix::Matrix, matrix_3d::Point2D};
+
+/// PCG32 random number generator (O'Neill 2014). Small and deterministic,
+/// so renders are reproducible for a given seed.
+#[derive(Clone, Copy, Debug)]
+pub struct Rng {
+    state: u64,
+    increment: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng {
+            state: 0,
+            increment: (seed << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(0x853c49e6748fea9b ^ seed);
+        rng.next_u32();
+        rng
+    }
+
+    /// Generator seeded from pixel coordinates, so every pixel gets an
+    /// independent but repeatable stream.
+    pub fn for_pixel(x: usize, y: usize, seed: u32) -> Self {
+        Rng::new(((y as u64) << 32 | x as u64) ^ ((seed as u64) << 48))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(self.increment);
+
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1 << 24) as f32
+    }
+
+    pub fn next_2d(&mut self) -> Point2D {
+        Matrix([[self.next_f32(), self.next_f32()]])
+    }
+
+    /// Uniform in [0, n).
+    pub fn next_below(&mut self, n: u32) -> u32 {
+        ((self.next_u32() as u64 * n as u64) >> 32) as u32
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.next_below(i as u32 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// `n` jittered samples in [0, 1), one per stratum, in random order.
+pub fn stratified_1d(rng: &mut Rng, n: usize) -> Vec<f32> {
+    let mut samples: Vec<f32> = (0..n)
+        .map(|i| (i as f32 + rng.next_f32()) / n as f32)
+        .collect();
+
+    rng.shuffle(&mut samples);
+    samples
+}
+
+/// `n` jittered samples in the unit square, one per cell of a grid that is
+/// as close to square as possible. Cells are shuffled, so taking the first
+/// `n` of an oversized grid does not leave a hole in one corner.
+pub fn stratified_2d(rng: &mut Rng, n: usize) -> Vec<Point2D> {
+    let columns = (n as f32).sqrt().ceil().max(1.) as usize;
+    let rows = n.div_ceil(columns).max(1);
+
+    let mut samples = Vec::with_capacity(columns * rows);
+
+    for y in 0..rows {
+        for x in 0..columns {
+            samples.push(Matrix([[
+                (x as f32 + rng.next_f32()) / columns as f32,
+                (y as f32 + rng.next_f32()) / rows as f32,
+            ]]));
+        }
+    }
+
+    rng.shuffle(&mut samples);
+    samples.truncate(n);
+    samples
+}
+
+/// Maps the unit square onto the unit disc, keeping strata compact
+/// (Shirley and Chiu's concentric mapping).
+pub fn concentric_disc(u: Point2D) -> Point2D {
+    let a = 2. * u.x() - 1.;
+    let b = 2. * u.y() - 1.;
+
+    if a == 0. && b == 0. {
+        return Matrix([[0., 0.]]);
+    }
+
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (a / b))
+    };
+
+    Matrix([[r * theta.cos(), r * theta.sin()]])
+}
+
+/// Uniformly samples a regular polygon with `sides` vertices inscribed in the
+/// unit circle, rotated by `rotation` radians.
+pub fn regular_polygon(u: Point2D, sides: u32, rotation: f32) -> Point2D {
+    // Pick one of the triangles fanning out from the center, then reuse the
+    // remainder of `u.x` to place the point inside it.
+    let scaled = u.x() * sides as f32;
+    let wedge = (scaled as u32).min(sides - 1);
+    let remainder = scaled - wedge as f32;
+
+    let step = 2. * f32::consts::PI / sides as f32;
+    let a0 = rotation + wedge as f32 * step;
+    let a1 = a0 + step;
+
+    let s = remainder.sqrt();
+    let b1 = s * (1. - u.y());
+    let b2 = s * u.y();
+
+    Matrix([[b1 * a0.cos() + b2 * a1.cos(), b1 * a0.sin() + b2 * a1.sin()]])
+}
+
+/// Cosine weighted direction on the hemisphere around +z, returned as a
+/// direction in the local frame. The pdf is `z / PI`.
+pub fn cosine_hemisphere(u: Point2D) -> Matrix<1, 4> {
+    let d = concentric_disc(u);
+    let z = (1. - d.x() * d.x() - d.y() * d.y()).max(0.).sqrt();
+    Matrix([[d.x(), d.y(), z, 0.]])
+}
+
+/// Uniform direction on the unit sphere, with pdf `1 / (4 PI)`.
+pub fn uniform_sphere(u: Point2D) -> Matrix<1, 4> {
+    let z = 1. - 2. * u.x();
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * f32::consts::PI * u.y();
+    Matrix([[r * phi.cos(), r * phi.sin(), z, 0.]])
+}
+
+/// Rotates a direction from the local frame around +z into the frame around
+/// `normal` (Duff et al. 2017).
+pub fn to_world(local: Matrix<1, 4>, normal: Matrix<1, 4>) -> Matrix<1, 4> {
+    let sign = 1f32.copysign(normal.z());
+    let a = -1. / (sign + normal.z());
+    let b = normal.x() * normal.y() * a;
+
+    let tangent = Matrix([[
+        1. + sign * normal.x() * normal.x() * a,
+        sign * b,
+        -sign * normal.x(),
+        0.,
+    ]]);
+    let bitangent = Matrix([[b, sign + normal.y() * normal.y() * a, -normal.y(), 0.]]);
+
+    tangent * local.x() + bitangent * local.y() + normal * local.z()
+}
+
+/// Veach's power heuristic with exponent 2, for a sample drawn from the
+/// strategy with pdf `f` while another strategy had pdf `g`.
+pub fn power_heuristic(f: f32, g: f32) -> f32 {
+    let (f, g) = (f * f, g * g);
+    if f + g == 0. { 0. } else { f / (f + g) }
+}
+
+/// Piecewise constant distribution over [0, 1), sampled by inverting its
+/// cumulative distribution.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    pub integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Self {
+        let n = func.len() as f32;
+        let mut cdf = vec![0.; func.len() + 1];
+        for i in 0..func.len() {
+            cdf[i + 1] = cdf[i] + func[i].abs() / n;
+        }
+
+        let integral = cdf[func.len()];
+        for (i, value) in cdf.iter_mut().enumerate() {
+            // An all zero function falls back to uniform.
+            *value = if integral > 0. {
+                *value / integral
+            } else {
+                i as f32 / n
+            };
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    /// Returns the sampled point, its pdf and the index of its segment.
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        let offset = self
+            .cdf
+            .partition_point(|c| *c <= u)
+            .saturating_sub(1)
+            .min(self.count() - 1);
+
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if width > 0. {
+            (u - self.cdf[offset]) / width
+        } else {
+            0.
+        };
+
+        (
+            ((offset as f32 + du) / self.count() as f32).min(1. - f32::EPSILON),
+            self.pdf_at(offset),
+            offset,
+        )
+    }
+
+    fn pdf_at(&self, offset: usize) -> f32 {
+        if self.integral > 0. {
+            self.func[offset].abs() / self.integral
+        } else {
+            1.
+        }
+    }
+
+    pub fn pdf(&self, x: f32) -> f32 {
+        let offset = ((x * self.count() as f32) as usize).min(self.count() - 1);
+        self.pdf_at(offset)
+    }
+}
+
+/// Piecewise constant distribution over the unit square, given as `height`
+/// rows of `width` values. Rows are picked from the marginal distribution,
+/// then a column from that row.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Self {
+        let rows: Vec<Distribution1D> = func
+            .chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral).collect());
+
+        Distribution2D { rows, marginal }
+    }
+
+    /// Returns a point in the unit square and its pdf.
+    pub fn sample(&self, u: Point2D) -> (Point2D, f32) {
+        let (y, pdf_y, row) = self.marginal.sample(u.y());
+        let (x, pdf_x, _) = self.rows[row].sample(u.x());
+        (Matrix([[x, y]]), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, p: Point2D) -> f32 {
+        let row = ((p.y() * self.rows.len() as f32) as usize).min(self.rows.len() - 1);
+        self.marginal.pdf(p.y()) * self.rows[row].pdf(p.x())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_range() {
+        let mut rng = Rng::new(7);
+        let mut sum = 0.;
+
+        for _ in 0..10000 {
+            let x = rng.next_f32();
+            assert!((0. ..1.).contains(&x));
+            sum += x;
+        }
+
+        assert!((sum / 10000. - 0.5).abs() < 0.02);
+        assert_ne!(
+            Rng::for_pixel(0, 1, 0).next_u32(),
+            Rng::for_pixel(1, 0, 0).next_u32()
+        );
+    }
+
+    #[test]
+    fn test_stratified() {
+        let mut rng = Rng::new(1);
+        let samples = stratified_2d(&mut rng, 16);
+
+        assert_eq!(samples.len(), 16);
+
+        // Exactly one sample per cell of the 4x4 grid.
+        let mut cells = [0; 16];
+        for sample in samples.iter() {
+            let cell = (sample.y() * 4.) as usize * 4 + (sample.x() * 4.) as usize;
+            cells[cell] += 1;
+        }
+        assert!(cells.iter().all(|c| *c == 1));
+
+        assert_eq!(stratified_2d(&mut rng, 5).len(), 5);
+
+        let mut strata = stratified_1d(&mut rng, 8)
+            .iter()
+            .map(|u| (u * 8.) as usize)
+            .collect::<Vec<_>>();
+        strata.sort();
+        assert_eq!(strata, (0..8).collect::<Vec<_>>());
+        assert_eq!(stratified_2d(&mut rng, 1).len(), 1);
+    }
+
+    #[test]
+    fn test_disc() {
+        let mut rng = Rng::new(3);
+
+        for _ in 0..1000 {
+            let p = concentric_disc(rng.next_2d());
+            assert!(p.x() * p.x() + p.y() * p.y() <= 1. + 1e-6);
+        }
+
+        assert_eq!(concentric_disc(Matrix([[0.5, 0.5]])), Matrix([[0., 0.]]));
+        assert_eq!(
+            concentric_disc(Matrix([[1., 0.5]])).round(5),
+            Matrix([[1., 0.]])
+        );
+    }
+
+    #[test]
+    fn test_polygon() {
+        let mut rng = Rng::new(5);
+        let sides = 6;
+        // Apothem of a hexagon inscribed in the unit circle.
+        let apothem = (f32::consts::PI / sides as f32).cos();
+
+        for _ in 0..1000 {
+            let p = regular_polygon(rng.next_2d(), sides, 0.);
+
+            // Inside every edge's half plane.
+            for k in 0..sides {
+                let angle = (k as f32 + 0.5) * 2. * f32::consts::PI / sides as f32;
+                assert!(p.x() * angle.cos() + p.y() * angle.sin() <= apothem + 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn test_hemisphere() {
+        let mut rng = Rng::new(9);
+        let normal = Matrix([[0.3, -0.8, 0.2, 0.]]).normalize();
+
+        let mut mean_cos = 0.;
+        for _ in 0..10000 {
+            let local = cosine_hemisphere(rng.next_2d());
+            let world = to_world(local, normal);
+
+            assert!((world.dot(world.transpose()).x() - 1.).abs() < 1e-4);
+            let cos = world.dot(normal.transpose()).x();
+            assert!((cos - local.z()).abs() < 1e-4);
+            mean_cos += cos / 10000.;
+        }
+        // E[cos] under a cosine distribution is 2/3.
+        assert!((mean_cos - 2. / 3.).abs() < 0.01);
+
+        let d = uniform_sphere(rng.next_2d());
+        assert!((d.dot(d.transpose()).x() - 1.).abs() < 1e-5);
+        assert_eq!(power_heuristic(1., 1.), 0.5);
+        assert_eq!(power_heuristic(0., 0.), 0.);
+    }
+
+    #[test]
+    fn test_distribution_1d() {
+        let distribution = Distribution1D::new(vec![1., 3., 0., 4.]);
+        assert_eq!(distribution.integral, 2.);
+
+        let mut counts = [0; 4];
+        let mut rng = Rng::new(11);
+        for _ in 0..8000 {
+            let (x, pdf, offset) = distribution.sample(rng.next_f32());
+            assert!((0. ..1.).contains(&x));
+            assert_eq!(offset, (x * 4.) as usize);
+            assert_eq!(pdf, distribution.pdf(x));
+            counts[offset] += 1;
+        }
+
+        assert_eq!(counts[2], 0);
+        assert!((counts[3] as f32 / 8000. - 0.5).abs() < 0.02);
+        assert!((counts[0] as f32 / 8000. - 0.125).abs() < 0.02);
+
+        let uniform = Distribution1D::new(vec![0., 0.]);
+        assert_eq!(uniform.sample(0.75).0, 0.75);
+    }
+
+    #[test]
+    fn test_distribution_2d() {
+        let func = [0., 1., 2., 3., 4., 5.];
+        let distribution = Distribution2D::new(&func, 3, 2);
+
+        // The pdf integrates to one over the unit square.
+        let mut integral = 0.;
+        for y in 0..20 {
+            for x in 0..30 {
+                let p = Matrix([[(x as f32 + 0.5) / 30., (y as f32 + 0.5) / 20.]]);
+                integral += distribution.pdf(p) / 600.;
+            }
+        }
+        assert!((integral - 1.).abs() < 1e-4);
+
+        let mut rng = Rng::new(2);
+        for _ in 0..1000 {
+            let (p, pdf) = distribution.sample(rng.next_2d());
+            assert!((pdf - distribution.pdf(p)).abs() < 1e-5);
+            assert!(p.x() >= 1. / 3. || p.y() >= 0.5);
+        }
+    }
+}
@@ -0,0 +1,487 @@
+use crate::{
+    aabb::{Aabb, inverse_direction},
+    matrix::Matrix,
+    matrix_3d::{Point, RaycastHit, dot3, length3},
+};
+
+/// Stand-in for infinity in the bounds of unbounded shapes. Frustum tests
+/// multiply bounds by zero normal components, which turns a real infinity
+/// into NaN.
+const UNBOUNDED: f32 = 1e18;
+
+const MAX_STEPS: u32 = 256;
+const MAX_DISTANCE: f32 = 1000.;
+
+/// Signed distance field: negative inside, positive outside, and never more
+/// than the distance to the surface, so sphere tracing can step by it.
+/// Primitives are centred on the origin unless they say otherwise.
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {
+        radius: f32,
+    },
+    /// Axis-aligned box.
+    Cuboid {
+        half_size: Matrix<1, 4>,
+    },
+    /// Ring around the y axis.
+    Torus {
+        major: f32,
+        minor: f32,
+    },
+    /// Segment from `a` to `b` swept by a sphere.
+    Capsule {
+        a: Point,
+        b: Point,
+        radius: f32,
+    },
+    /// Half space below `dot(p, normal) = offset`.
+    Plane {
+        normal: Matrix<1, 4>,
+        offset: f32,
+    },
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    /// The first shape with the second carved out.
+    Subtraction(Box<Sdf>, Box<Sdf>),
+    /// Blends shapes over roughly `k` world units with a polynomial minimum.
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32),
+    SmoothIntersection(Box<Sdf>, Box<Sdf>, f32),
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, f32),
+    /// Infinite copies every `period` along each axis with a positive
+    /// period. Exact only while the shape fits in its cell.
+    Repeat {
+        period: Matrix<1, 4>,
+        inner: Box<Sdf>,
+    },
+    /// Rotates around the y axis by `rate` radians per unit of height.
+    Twist {
+        rate: f32,
+        inner: Box<Sdf>,
+    },
+    /// Object-to-world transform. Exact for rigid motion and uniform scale;
+    /// other scales use the smallest axis scale to stay a lower bound.
+    Transform {
+        transform: Matrix<4, 4>,
+        inverse: Matrix<4, 4>,
+        scale: f32,
+        inner: Box<Sdf>,
+    },
+}
+
+impl Sdf {
+    pub fn sphere(radius: f32) -> Sdf {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cuboid(x: f32, y: f32, z: f32) -> Sdf {
+        Sdf::Cuboid {
+            half_size: Matrix([[x, y, z, 0.]]),
+        }
+    }
+
+    pub fn torus(major: f32, minor: f32) -> Sdf {
+        Sdf::Torus { major, minor }
+    }
+
+    pub fn capsule(a: Point, b: Point, radius: f32) -> Sdf {
+        Sdf::Capsule { a, b, radius }
+    }
+
+    pub fn plane(normal: Matrix<1, 4>, offset: f32) -> Sdf {
+        Sdf::Plane {
+            normal: normal.normalize(),
+            offset,
+        }
+    }
+
+    pub fn union(self, other: Sdf) -> Sdf {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Sdf) -> Sdf {
+        Sdf::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn subtract(self, other: Sdf) -> Sdf {
+        Sdf::Subtraction(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: Sdf, k: f32) -> Sdf {
+        Sdf::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn smooth_intersection(self, other: Sdf, k: f32) -> Sdf {
+        Sdf::SmoothIntersection(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn smooth_subtract(self, other: Sdf, k: f32) -> Sdf {
+        Sdf::SmoothSubtraction(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn repeat(self, x: f32, y: f32, z: f32) -> Sdf {
+        Sdf::Repeat {
+            period: Matrix([[x, y, z, 0.]]),
+            inner: Box::new(self),
+        }
+    }
+
+    pub fn twist(self, rate: f32) -> Sdf {
+        Sdf::Twist {
+            rate,
+            inner: Box::new(self),
+        }
+    }
+
+    pub fn transform(self, transform: Matrix<4, 4>) -> Sdf {
+        let axis = |i: usize| {
+            (transform[i][0].powi(2) + transform[i][1].powi(2) + transform[i][2].powi(2)).sqrt()
+        };
+
+        Sdf::Transform {
+            transform,
+            inverse: transform.inv(),
+            scale: axis(0).min(axis(1)).min(axis(2)),
+            inner: Box::new(self),
+        }
+    }
+
+    pub fn distance(&self, p: Point) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => length3(p) - radius,
+            Sdf::Cuboid { half_size } => {
+                let q = p.abs() - *half_size;
+                let outside = Matrix([[q.x().max(0.), q.y().max(0.), q.z().max(0.), 0.]]);
+                length3(outside) + q.x().max(q.y()).max(q.z()).min(0.)
+            }
+            Sdf::Torus { major, minor } => {
+                let ring = (p.x() * p.x() + p.z() * p.z()).sqrt() - major;
+                (ring * ring + p.y() * p.y()).sqrt() - minor
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let pa = vector(p - *a);
+                let ba = vector(*b - *a);
+                let h = (dot3(pa, ba) / dot3(ba, ba)).clamp(0., 1.);
+                length3(pa - ba * h) - radius
+            }
+            Sdf::Plane { normal, offset } => dot3(vector(p), *normal) - offset,
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::Subtraction(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion(a, b, k) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = (0.5 + 0.5 * (b - a) / k).clamp(0., 1.);
+                b + (a - b) * h - k * h * (1. - h)
+            }
+            Sdf::SmoothIntersection(a, b, k) => {
+                let (a, b) = (a.distance(p), b.distance(p));
+                let h = (0.5 - 0.5 * (b - a) / k).clamp(0., 1.);
+                b + (a - b) * h + k * h * (1. - h)
+            }
+            Sdf::SmoothSubtraction(a, b, k) => {
+                let (a, b) = (a.distance(p), -b.distance(p));
+                let h = (0.5 - 0.5 * (b - a) / k).clamp(0., 1.);
+                b + (a - b) * h + k * h * (1. - h)
+            }
+            Sdf::Repeat { period, inner } => {
+                let mut q = p;
+                for i in 0..3 {
+                    if period[0][i] > 0. {
+                        q[0][i] -= period[0][i] * (p[0][i] / period[0][i]).round();
+                    }
+                }
+                inner.distance(q)
+            }
+            Sdf::Twist { rate, inner } => {
+                let (s, c) = (rate * p.y()).sin_cos();
+                let q = Matrix([[c * p.x() - s * p.z(), p.y(), s * p.x() + c * p.z(), 1.]]);
+
+                // Twisting stretches space by up to this much at this radius,
+                // so the inner distance overestimates by the same factor.
+                let radius = (p.x() * p.x() + p.z() * p.z()).sqrt();
+                inner.distance(q) / (1. + (rate * radius).powi(2)).sqrt()
+            }
+            Sdf::Transform {
+                inverse,
+                scale,
+                inner,
+                ..
+            } => inner.distance(p(*inverse)) * scale,
+        }
+    }
+
+    /// Outward surface normal from the gradient, using Inigo Quilez's
+    /// tetrahedron of four samples.
+    pub fn normal(&self, p: Point) -> Matrix<1, 4> {
+        let h = 1e-4 * (1. + length3(vector(p)));
+        let mut gradient = Matrix::default();
+
+        for k in [[1., -1., -1.], [-1., -1., 1.], [-1., 1., -1.], [1., 1., 1.]] {
+            let k = Matrix([[k[0], k[1], k[2], 0.]]);
+            gradient = gradient + k * self.distance(p + k * h);
+        }
+
+        gradient.normalize()
+    }
+
+    /// Box containing the surface, padded for smooth blends. Unbounded
+    /// shapes stretch to `UNBOUNDED` along the axes they're infinite in.
+    pub fn bounds(&self) -> Aabb {
+        let symmetric =
+            |x: f32, y: f32, z: f32| Aabb::new(Matrix([[-x, -y, -z, 1.]]), Matrix([[x, y, z, 1.]]));
+        let pad = |aabb: Aabb, k: f32| {
+            let k = Matrix([[k, k, k, 0.]]);
+            Aabb::new(aabb.min - k, aabb.max + k)
+        };
+
+        match self {
+            Sdf::Sphere { radius } => symmetric(*radius, *radius, *radius),
+            Sdf::Cuboid { half_size } => symmetric(half_size.x(), half_size.y(), half_size.z()),
+            Sdf::Torus { major, minor } => symmetric(major + minor, *minor, major + minor),
+            Sdf::Capsule { a, b, radius } => pad(Aabb::from_points(&[*a, *b]), *radius),
+            Sdf::Plane { .. } => symmetric(UNBOUNDED, UNBOUNDED, UNBOUNDED),
+            Sdf::Union(a, b) => a.bounds().union(b.bounds()),
+            Sdf::Intersection(a, b) | Sdf::SmoothIntersection(a, b, _) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                let mut out = a;
+                for i in 0..3 {
+                    out.min[0][i] = a.min[0][i].max(b.min[0][i]);
+                    out.max[0][i] = a.max[0][i].min(b.max[0][i]);
+                }
+                out
+            }
+            Sdf::Subtraction(a, _) | Sdf::SmoothSubtraction(a, _, _) => a.bounds(),
+            // The polynomial minimum sits at most k / 4 below either input.
+            Sdf::SmoothUnion(a, b, k) => pad(a.bounds().union(b.bounds()), k / 4.),
+            Sdf::Repeat { period, inner } => {
+                let mut out = inner.bounds();
+                for i in 0..3 {
+                    if period[0][i] > 0. {
+                        out.min[0][i] = -UNBOUNDED;
+                        out.max[0][i] = UNBOUNDED;
+                    }
+                }
+                out
+            }
+            Sdf::Twist { inner, .. } => {
+                let inner = inner.bounds();
+                let radius = inner
+                    .corners()
+                    .iter()
+                    .map(|c| (c.x() * c.x() + c.z() * c.z()).sqrt())
+                    .fold(0., f32::max);
+                Aabb::new(
+                    Matrix([[-radius, inner.min.y(), -radius, 1.]]),
+                    Matrix([[radius, inner.max.y(), radius, 1.]]),
+                )
+            }
+            Sdf::Transform {
+                transform, inner, ..
+            } => inner.bounds().transform(*transform),
+        }
+    }
+
+    /// Sphere traces from `origin`, returning the first hit within the
+    /// bounds.
+    pub fn nearest_hit(&self, origin: Point, direction: Matrix<1, 4>) -> Option<RaycastHit> {
+        // March in unit steps along a normalized direction, but report `t`
+        // along the original one like the triangle intersector does.
+        let scale = length3(direction);
+        let unit = direction / scale;
+
+        let (t_enter, t_exit) =
+            self.bounds()
+                .ray_intersects(origin, inverse_direction(unit), MAX_DISTANCE)?;
+
+        let mut t = t_enter;
+        for _ in 0..MAX_STEPS {
+            if t > t_exit {
+                return None;
+            }
+
+            let p = origin + unit * t;
+            let d = self.distance(p);
+            let epsilon = 1e-4 * (1. + t);
+
+            if d.abs() < epsilon {
+                // The surface is only located to within `epsilon`, which
+                // has to count as position error so secondary rays start
+                // clear of it.
+                let error = epsilon * 4. + length3(vector(p)) * 1e-6;
+                let normal = self.normal(p);
+                return Some(RaycastHit {
+                    t: t / scale,
+                    u: 0.,
+                    v: 0.,
+                    normal,
+                    shading_normal: normal,
+                    position: p,
+                    object_position: p,
+                    position_error: Matrix([[error, error, error, 0.]]),
+                    color: None,
+                });
+            }
+
+            t += d.abs();
+        }
+
+        None
+    }
+}
+
+fn vector(p: Point) -> Matrix<1, 4> {
+    Matrix([[p.x(), p.y(), p.z(), 0.]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix_3d::{Culling, Model, Motion, cube, rotate_y, translate};
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    #[test]
+    fn test_primitives() {
+        let sphere = Sdf::sphere(2.);
+        assert_eq!(sphere.distance(point(0., 3., 0.)), 1.);
+        assert_eq!(sphere.distance(point(0., 0., 0.)), -2.);
+
+        let cuboid = Sdf::cuboid(1., 2., 3.);
+        assert_eq!(cuboid.distance(point(3., 0., 0.)), 2.);
+        assert_eq!(cuboid.distance(point(0., 0., 0.)), -1.);
+        assert!((cuboid.distance(point(4., 6., 3.)) - 5.).abs() < 1e-6);
+
+        let torus = Sdf::torus(2., 0.5);
+        assert_eq!(torus.distance(point(2., 0., 0.)), -0.5);
+        assert_eq!(torus.distance(point(0., 0., 0.)), 1.5);
+
+        let capsule = Sdf::capsule(point(0., -1., 0.), point(0., 1., 0.), 0.5);
+        assert_eq!(capsule.distance(point(0., 3., 0.)), 1.5);
+        assert_eq!(capsule.distance(point(2., 0., 0.)), 1.5);
+
+        let plane = Sdf::plane(Matrix([[0., 2., 0., 0.]]), -1.);
+        assert_eq!(plane.distance(point(5., 1., 5.)), 2.);
+    }
+
+    #[test]
+    fn test_operators() {
+        let a = || Sdf::sphere(1.);
+        let b = || Sdf::sphere(1.).transform(translate(1.5, 0., 0.));
+        let p = point(0.75, 0., 0.);
+
+        assert_eq!(a().union(b()).distance(point(-2., 0., 0.)), 1.);
+        assert!(a().intersection(b()).distance(p) < 0.);
+        assert!(a().subtract(b()).distance(p) > 0.);
+        assert!(a().subtract(b()).distance(point(-0.5, 0., 0.)) < 0.);
+
+        // Smooth operators agree with the hard ones away from the seam and
+        // round it off near it.
+        let far = point(-3., 0., 0.);
+        assert_eq!(a().smooth_union(b(), 0.5).distance(far), 2.);
+        let seam = point(0.75, 0.7, 0.);
+        assert!(a().smooth_union(b(), 0.5).distance(seam) < a().union(b()).distance(seam));
+        assert!(a().smooth_intersection(b(), 0.5).distance(p) > a().intersection(b()).distance(p));
+        assert!(a().smooth_subtract(b(), 0.5).distance(p) > 0.);
+    }
+
+    #[test]
+    fn test_domain_operators() {
+        let repeated = Sdf::sphere(0.5).repeat(2., 0., 2.);
+        assert_eq!(repeated.distance(point(4., 0., -6.)), -0.5);
+        assert_eq!(repeated.distance(point(5., 0., 0.)), 0.5);
+        assert_eq!(repeated.distance(point(0., 3., 0.)), 2.5);
+
+        // Near the top the box has turned almost a quarter, swapping its x
+        // and z sizes.
+        let twisted = Sdf::cuboid(1., 1., 0.2).twist(std::f32::consts::FRAC_PI_2);
+        assert!(twisted.distance(point(0.9, 0., 0.)) < 0.);
+        assert!(twisted.distance(point(0., 0.9, 0.9)) < 0.);
+        assert!(twisted.distance(point(0.9, 0.9, 0.)) > 0.);
+
+        let moved = Sdf::sphere(1.).transform(translate(3., 0., 0.)(rotate_y(1.)));
+        assert!((moved.distance(point(0., 0., 0.)) - 2.).abs() < 1e-5);
+
+        let scaled = Sdf::sphere(1.).transform(Matrix([
+            [2., 0., 0., 0.],
+            [0., 2., 0., 0.],
+            [0., 0., 2., 0.],
+            [0., 0., 0., 1.],
+        ]));
+        assert!((scaled.distance(point(0., 5., 0.)) - 3.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_normals() {
+        let sphere = Sdf::sphere(1.);
+        let n = sphere.normal(point(0.6, 0.8, 0.));
+        assert!(
+            (n - Matrix([[0.6, 0.8, 0., 0.]])).abs().0[0]
+                .iter()
+                .all(|d| *d < 1e-3)
+        );
+
+        let n = Sdf::cuboid(1., 1., 1.).normal(point(1., 0.2, 0.3));
+        assert!((n.x() - 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_sphere_tracing() {
+        let sdf = Sdf::sphere(1.).transform(translate(0., 0., 5.));
+        let hit = sdf
+            .nearest_hit(point(0., 0., 0.), Matrix([[0., 0., 2., 0.]]))
+            .unwrap();
+        assert!((hit.t - 2.).abs() < 1e-3);
+        assert!((hit.normal.z() + 1.).abs() < 1e-3);
+
+        // Secondary rays leave from outside the surface.
+        let origin = hit.spawn_origin(Matrix([[0., 0., -1., 0.]]));
+        assert!(sdf.distance(origin) > 0.);
+
+        assert!(
+            sdf.nearest_hit(point(0., 1.5, 0.), Matrix([[0., 0., 1., 0.]]))
+                .is_none()
+        );
+
+        // Unbounded shapes are still found.
+        let floor = Sdf::plane(Matrix([[0., 1., 0., 0.]]), -1.);
+        let hit = floor
+            .nearest_hit(point(0., 0., 0.), Matrix([[0., -1., 1., 0.]]).normalize())
+            .unwrap();
+        assert!((hit.position.y() + 1.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_model() {
+        // A mesh cube at z = 3 and a sphere at z = 6 in the same model, moved
+        // along x over time.
+        let model = Model {
+            color: Matrix([[1., 1., 1., 1.]]),
+            reflect: 0.,
+            mesh: cube().apply(translate(0., 0., 3.)),
+            motion: Some(Motion(Box::new(|time| translate(time, 0., 0.)))),
+            sdf: Some(Sdf::sphere(1.).transform(translate(0., 0., 6.))),
+            ..Default::default()
+        };
+
+        let hit = |x: f32, time: f32| {
+            model
+                .nearest_hit(
+                    point(x, 0., 0.),
+                    Matrix([[0., 0., 1., 0.]]),
+                    time,
+                    Culling::None,
+                )
+                .map(|hit| (hit.t * 100.).round() / 100.)
+        };
+        assert_eq!(hit(0., 0.), Some(2.5));
+        assert_eq!(hit(0.8, 0.), Some(5.4));
+        assert_eq!(hit(2.8, 2.), Some(5.4));
+        assert_eq!(hit(2., 0.), None);
+
+        let bounds = model.bounds();
+        assert_eq!(bounds.min.z(), 2.5);
+        assert_eq!(bounds.max.z(), 7.);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{
+    bitmap::Color,
+    isosurface::IndexedMesh,
+    light::AMBIENT,
+    matrix::Matrix,
+    matrix_3d::{Point, dot3},
+    texture::Texture,
+};
+
+/// Values the vertex stage hands to the fragment stage, blended across
+/// the triangle.
+pub trait Varying: Copy {
+    /// `a * weights[0] + b * weights[1] + c * weights[2]`, with weights
+    /// summing to one.
+    fn blend(a: Self, b: Self, c: Self, weights: [f32; 3]) -> Self;
+}
+
+impl Varying for () {
+    fn blend(_: Self, _: Self, _: Self, _: [f32; 3]) -> Self {}
+}
+
+impl Varying for f32 {
+    fn blend(a: Self, b: Self, c: Self, weights: [f32; 3]) -> Self {
+        a * weights[0] + b * weights[1] + c * weights[2]
+    }
+}
+
+impl<const H: usize, const W: usize> Varying for Matrix<H, W> {
+    fn blend(a: Self, b: Self, c: Self, weights: [f32; 3]) -> Self {
+        a * weights[0] + b * weights[1] + c * weights[2]
+    }
+}
+
+impl<A: Varying, B: Varying> Varying for (A, B) {
+    fn blend(a: Self, b: Self, c: Self, weights: [f32; 3]) -> Self {
+        (
+            A::blend(a.0, b.0, c.0, weights),
+            B::blend(a.1, b.1, c.1, weights),
+        )
+    }
+}
+
+/// Programmable stages for `Bitmap::render_shader`. The vertex stage runs
+/// once per vertex, the fragment stage once per covered pixel with the
+/// varyings interpolated perspective-correctly.
+pub trait Shader {
+    type Vertex;
+    type Varying: Varying;
+
+    /// Clip space position of `vertex` and the varyings it carries.
+    fn vertex(&self, vertex: &Self::Vertex) -> (Matrix<1, 4>, Self::Varying);
+
+    /// Color of a pixel, or `None` to discard it and leave the pixel as is.
+    fn fragment(&self, varying: Self::Varying) -> Option<Color>;
+}
+
+/// Vertex of an `IndexedMesh`, for the shaders below.
+#[derive(Clone, Copy, Debug)]
+pub struct Vertex {
+    pub position: Point,
+    pub normal: Matrix<1, 4>,
+}
+
+pub fn vertices(mesh: &IndexedMesh) -> Vec<Vertex> {
+    mesh.positions
+        .iter()
+        .zip(mesh.normals.iter())
+        .map(|(position, normal)| Vertex {
+            position: *position,
+            normal: *normal,
+        })
+        .collect()
+}
+
+/// Object to world and world to clip space, with the matrix that takes
+/// normals to world space.
+#[derive(Clone, Copy, Debug)]
+pub struct Transforms {
+    pub model: Matrix<4, 4>,
+    pub view_projection: Matrix<4, 4>,
+    normal: Matrix<4, 4>,
+}
+
+impl Transforms {
+    pub fn new(model: Matrix<4, 4>, view_projection: Matrix<4, 4>) -> Transforms {
+        Transforms {
+            model,
+            view_projection,
+            normal: model.inv().transpose(),
+        }
+    }
+
+    pub fn world(&self, p: Point) -> Point {
+        p(self.model)
+    }
+
+    pub fn clip(&self, p: Point) -> Matrix<1, 4> {
+        p(self.model)(self.view_projection)
+    }
+
+    pub fn world_normal(&self, n: Matrix<1, 4>) -> Matrix<1, 4> {
+        let mut n = n(self.normal);
+        n[0][3] = 0.;
+        n.normalize()
+    }
+}
+
+/// Ambient plus Lambertian diffuse from a directional light.
+fn lambert(normal: Matrix<1, 4>, light: Matrix<1, 4>) -> f32 {
+    AMBIENT + (1. - AMBIENT) * dot3(normal, light).max(0.)
+}
+
+/// `color` scaled by `light`, keeping its alpha and saturating at white.
+fn lit(color: Matrix<1, 4>, light: f32, specular: f32) -> Color {
+    let channel = |v: f32| ((v * light + specular).clamp(0., 1.) * 255.) as u8;
+    Color::new(
+        channel(color.x()),
+        channel(color.y()),
+        channel(color.z()),
+        (color.w().clamp(0., 1.) * 255.) as u8,
+    )
+}
+
+/// One color across the whole surface, as `Bitmap::render_trig` draws.
+pub struct Flat {
+    pub transforms: Transforms,
+    pub color: Color,
+}
+
+impl Shader for Flat {
+    type Vertex = Vertex;
+    type Varying = ();
+
+    fn vertex(&self, vertex: &Vertex) -> (Matrix<1, 4>, ()) {
+        (self.transforms.clip(vertex.position), ())
+    }
+
+    fn fragment(&self, _: ()) -> Option<Color> {
+        Some(self.color)
+    }
+}
+
+/// One color, with vertices already in clip space, for testing the
+/// rasterizer on its own.
+#[cfg(test)]
+pub struct Solid(pub Color);
+
+#[cfg(test)]
+impl Shader for Solid {
+    type Vertex = Point;
+    type Varying = ();
+
+    fn vertex(&self, vertex: &Point) -> (Matrix<1, 4>, ()) {
+        (*vertex, ())
+    }
+
+    fn fragment(&self, _: ()) -> Option<Color> {
+        Some(self.0)
+    }
+}
+
+/// Diffuse lighting evaluated at the vertices and interpolated. `light`
+/// is the world space direction towards a directional light.
+pub struct Gouraud {
+    pub transforms: Transforms,
+    pub color: Matrix<1, 4>,
+    pub light: Matrix<1, 4>,
+}
+
+impl Shader for Gouraud {
+    type Vertex = Vertex;
+    type Varying = f32;
+
+    fn vertex(&self, vertex: &Vertex) -> (Matrix<1, 4>, f32) {
+        let normal = self.transforms.world_normal(vertex.normal);
+        (
+            self.transforms.clip(vertex.position),
+            lambert(normal, self.light),
+        )
+    }
+
+    fn fragment(&self, intensity: f32) -> Option<Color> {
+        Some(lit(self.color, intensity, 0.))
+    }
+}
+
+/// Blinn-Phong lighting per pixel from interpolated world space normals
+/// and positions, seen from `eye`.
+pub struct Phong {
+    pub transforms: Transforms,
+    pub color: Matrix<1, 4>,
+    pub light: Matrix<1, 4>,
+    pub eye: Point,
+    pub shininess: f32,
+}
+
+impl Shader for Phong {
+    type Vertex = Vertex;
+    type Varying = (Matrix<1, 4>, Point);
+
+    fn vertex(&self, vertex: &Vertex) -> (Matrix<1, 4>, Self::Varying) {
+        (
+            self.transforms.clip(vertex.position),
+            (
+                self.transforms.world_normal(vertex.normal),
+                self.transforms.world(vertex.position),
+            ),
+        )
+    }
+
+    fn fragment(&self, (normal, position): Self::Varying) -> Option<Color> {
+        let mut normal = normal;
+        normal[0][3] = 0.;
+        let normal = normal.normalize();
+        let mut view = self.eye - position;
+        view[0][3] = 0.;
+        let half = (view.normalize() + self.light).normalize();
+
+        let diffuse = lambert(normal, self.light);
+        // No highlights on the side facing away from the light.
+        let specular = if diffuse > AMBIENT {
+            normal
+                .dot(half.transpose())
+                .x()
+                .max(0.)
+                .powf(self.shininess)
+        } else {
+            0.
+        };
+
+        Some(lit(self.color, diffuse, specular))
+    }
+}
+
+/// Solid texture looked up at the interpolated object space position.
+/// Texels with alpha under one half are discarded, so textures can cut
+/// holes into surfaces.
+pub struct Textured<'a> {
+    pub transforms: Transforms,
+    pub texture: &'a Texture,
+}
+
+impl Shader for Textured<'_> {
+    type Vertex = Vertex;
+    type Varying = Point;
+
+    fn vertex(&self, vertex: &Vertex) -> (Matrix<1, 4>, Point) {
+        (self.transforms.clip(vertex.position), vertex.position)
+    }
+
+    fn fragment(&self, position: Point) -> Option<Color> {
+        let color = self.texture.color(position);
+        (color.w() >= 0.5).then(|| color.to_color())
+    }
+}
+
+/// Cel shading: diffuse light cut into `bands` flat steps.
+pub struct Toon {
+    pub transforms: Transforms,
+    pub color: Matrix<1, 4>,
+    pub light: Matrix<1, 4>,
+    pub bands: u32,
+}
+
+impl Shader for Toon {
+    type Vertex = Vertex;
+    type Varying = Matrix<1, 4>;
+
+    fn vertex(&self, vertex: &Vertex) -> (Matrix<1, 4>, Matrix<1, 4>) {
+        (
+            self.transforms.clip(vertex.position),
+            self.transforms.world_normal(vertex.normal),
+        )
+    }
+
+    fn fragment(&self, normal: Matrix<1, 4>) -> Option<Color> {
+        let mut normal = normal;
+        normal[0][3] = 0.;
+        let intensity = lambert(normal.normalize(), self.light);
+        let bands = self.bands as f32;
+        Some(lit(self.color, (intensity * bands).ceil() / bands, 0.))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::HashSet};
+
+    use super::*;
+    use crate::{
+        RenderSettings,
+        bitmap::Bitmap,
+        isosurface::{ScalarGrid, marching_cubes},
+        matrix_3d::screen,
+        sdf::Sdf,
+    };
+
+    const SIZE: u32 = 48;
+
+    fn view_projection() -> Matrix<4, 4> {
+        RenderSettings::default()
+            .camera(0.)
+            .view_projection(1.)
+            .unwrap()
+    }
+
+    fn sphere() -> IndexedMesh {
+        marching_cubes(&ScalarGrid::from_sdf(&Sdf::sphere(1.5), 0.1), 0.)
+    }
+
+    fn rgb(r: f32, g: f32, b: f32) -> Matrix<1, 4> {
+        Matrix([[r, g, b, 1.]])
+    }
+
+    /// Records the object space point behind every pixel it shades.
+    struct Positions {
+        view_projection: Matrix<4, 4>,
+        seen: RefCell<Vec<Point>>,
+    }
+
+    impl Shader for Positions {
+        type Vertex = Point;
+        type Varying = Point;
+
+        fn vertex(&self, vertex: &Point) -> (Matrix<1, 4>, Point) {
+            (vertex(self.view_projection), *vertex)
+        }
+
+        fn fragment(&self, point: Point) -> Option<Color> {
+            self.seen.borrow_mut().push(point);
+            Some(Color::new(255, 255, 255, 255))
+        }
+    }
+
+    #[test]
+    fn test_perspective_correct() {
+        let shader = Positions {
+            view_projection: view_projection(),
+            seen: RefCell::new(vec![]),
+        };
+        // A floor running steeply away from the camera.
+        let near = [Matrix([[-2., -1., -3., 1.]]), Matrix([[2., -1., -3., 1.]])];
+        let far = [Matrix([[-2., -1., 20., 1.]]), Matrix([[2., -1., 20., 1.]])];
+
+        let mut bmp = Bitmap::new(SIZE, SIZE);
+        bmp.render_shader(&shader, [&near[0], &far[0], &near[1]]);
+        bmp.render_shader(&shader, [&near[1], &far[0], &far[1]]);
+
+        let seen = shader.seen.into_inner();
+        assert!(seen.len() > 100);
+        // Every interpolated point projects back onto its pixel's centre.
+        for point in seen {
+            let s = screen(point(view_projection()), SIZE as f32, SIZE as f32);
+            assert!((s.x().fract() - 0.5).abs() < 1e-2, "{s:?}");
+            assert!((s.y().fract() - 0.5).abs() < 1e-2, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn test_discard() {
+        let texture = Texture::Stripes {
+            a: rgb(1., 0., 0.),
+            b: Matrix([[0., 1., 0., 0.]]),
+            width: 0.5,
+        };
+        let shader = Textured {
+            transforms: Transforms::new(Matrix::identity(), view_projection()),
+            texture: &texture,
+        };
+
+        let mut bmp = Bitmap::new(SIZE, SIZE);
+        bmp.render_mesh(&shader, &sphere());
+
+        let pixels: Vec<Color> = bmp.rows.iter().flatten().copied().collect();
+        let red = pixels.iter().filter(|c| c.r == 255 && c.a == 255).count();
+        // The transparent stripes leave the background showing.
+        assert!(pixels.iter().all(|c| c.g == 0));
+        assert!(red > 50);
+        assert!(red < pixels.iter().filter(|c| c.a == 0).count());
+    }
+
+    #[test]
+    fn test_lighting() {
+        let transforms = Transforms::new(Matrix::identity(), view_projection());
+        let red = rgb(1., 0., 0.);
+        // From the camera's side.
+        let light = Matrix([[0., 0., -1., 0.]]);
+        let mesh = sphere();
+        let centre = (SIZE / 2) as usize;
+
+        let mut gouraud = Bitmap::new(SIZE, SIZE);
+        gouraud.render_mesh(
+            &Gouraud {
+                transforms,
+                color: red,
+                light,
+            },
+            &mesh,
+        );
+        let c = gouraud.rows[centre][centre];
+        assert!(c.r > 240 && c.g == 0);
+
+        let mut phong = Bitmap::new(SIZE, SIZE);
+        phong.render_mesh(
+            &Phong {
+                transforms,
+                color: red,
+                light,
+                eye: Matrix([[0., 0., -5., 1.]]),
+                shininess: 32.,
+            },
+            &mesh,
+        );
+        // The highlight sits where the normal faces both light and eye.
+        let c = phong.rows[centre][centre];
+        assert!(c.r == 255 && c.g > 200 && c.b > 200);
+
+        let mut toon = Bitmap::new(SIZE, SIZE);
+        toon.render_mesh(
+            &Toon {
+                transforms,
+                color: red,
+                light,
+                bands: 3,
+            },
+            &mesh,
+        );
+        let shades: HashSet<u8> = toon
+            .rows
+            .iter()
+            .flatten()
+            .filter(|c| c.a > 0)
+            .map(|c| c.r)
+            .collect();
+        assert!((2..=3).contains(&shades.len()), "{shades:?}");
+    }
+}
@@ -0,0 +1,433 @@
+use crate::{
+    aabb::Aabb,
+    bitmap::Color,
+    depth::DepthBuffer,
+    light::{AMBIENT, Light},
+    matrix::Matrix,
+    matrix_3d::{Mesh, Point, dot3, length3, screen},
+    shader::{Shader, Transforms, Vertex},
+    texture::Texture,
+};
+
+/// Something casting shadows: an object space mesh and its object to
+/// world transform.
+pub type Caster<'a> = (&'a Mesh, Matrix<4, 4>);
+
+/// How shadow maps are rendered and looked up.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowSettings {
+    /// Width and height of each map in texels.
+    pub size: u32,
+    /// Maps a directional light's shadows are split across.
+    pub cascades: u32,
+    /// How far from the camera directional lights cast shadows.
+    pub distance: f32,
+    /// Percentage-closer filtering looks at the `(2 * pcf + 1)²` texels
+    /// around each lookup.
+    pub pcf: u32,
+    /// Depth offset per texel of slope, in normalized device depth.
+    pub slope_bias: f32,
+    /// Depth offset everywhere, in normalized device depth.
+    pub constant_bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        ShadowSettings {
+            size: 512,
+            cascades: 3,
+            distance: 20.,
+            pcf: 1,
+            slope_bias: 1.5,
+            constant_bias: 1e-4,
+        }
+    }
+}
+
+/// Depth of the nearest caster as seen from a light.
+pub struct ShadowMap {
+    pub depth: DepthBuffer,
+    pub view_projection: Matrix<4, 4>,
+}
+
+impl ShadowMap {
+    pub fn render(
+        view_projection: Matrix<4, 4>,
+        casters: &[Caster],
+        settings: &ShadowSettings,
+    ) -> ShadowMap {
+        let mut depth = DepthBuffer::new(settings.size, settings.size);
+        for (mesh, transform) in casters.iter() {
+            let model_view_projection = transform(view_projection);
+            for trig in mesh.0.iter() {
+                depth.render_trig_offset(
+                    *trig,
+                    model_view_projection,
+                    settings.slope_bias,
+                    settings.constant_bias,
+                );
+            }
+        }
+        ShadowMap {
+            depth,
+            view_projection,
+        }
+    }
+
+    /// Fraction of the texels around world space `p` whose caster is no
+    /// nearer the light than `p`, or `None` if `p` is off the map.
+    pub fn visibility(&self, p: Point, pcf: u32) -> Option<f32> {
+        let clip = p(self.view_projection);
+        if clip.w() <= 0. {
+            return None;
+        }
+        let depth = clip.z() / clip.w();
+        let (x, y) = (clip.x() / clip.w(), clip.y() / clip.w());
+        if !(-1. ..=1.).contains(&x) || !(-1. ..=1.).contains(&y) || depth > 1. {
+            return None;
+        }
+
+        let size = self.depth.width as f32;
+        let texel = screen(clip, size, size);
+        let (tx, ty) = (texel.x().floor() as i32, texel.y().floor() as i32);
+        let pcf = pcf as i32;
+
+        let mut lit = 0;
+        for dy in -pcf..=pcf {
+            for dx in -pcf..=pcf {
+                lit += (depth <= self.depth.get(tx + dx, ty + dy)) as u32;
+            }
+        }
+        Some(lit as f32 / ((2 * pcf + 1) * (2 * pcf + 1)) as f32)
+    }
+}
+
+/// A directional light's shadow maps, each covering a slice of the view
+/// further from the camera than the last. Near slices are small, so the
+/// shadows close by get the most texels.
+pub struct CascadedShadowMap {
+    /// Each map with the view depth its slice reaches.
+    pub cascades: Vec<(f32, ShadowMap)>,
+    view: Matrix<4, 4>,
+}
+
+impl CascadedShadowMap {
+    /// `view` and `view_projection` are the camera's, which sees from
+    /// `near` onwards. Slices are spaced halfway between evenly and
+    /// logarithmically out to `settings.distance`.
+    pub fn render(
+        light: &Light,
+        view: Matrix<4, 4>,
+        view_projection: Matrix<4, 4>,
+        near: f32,
+        casters: &[Caster],
+        settings: &ShadowSettings,
+    ) -> Option<CascadedShadowMap> {
+        let mut bounds = Aabb::empty();
+        for (mesh, transform) in casters.iter() {
+            bounds = bounds.union(mesh.bounds().transform(*transform));
+        }
+        if bounds.is_empty() {
+            return None;
+        }
+        let casters_corners = bounds.corners();
+
+        // Frustum edges from the near to the far plane, with the view depth
+        // at either end.
+        let inverse = view_projection.inv();
+        let unproject = |x: f32, y: f32, z: f32| {
+            let p = Matrix([[x, y, z, 1.]])(inverse);
+            p / p.w()
+        };
+        let depth = |p: Point| -p(view).z();
+        let edges: Vec<(Point, Point)> = [(-1., -1.), (1., -1.), (-1., 1.), (1., 1.)]
+            .iter()
+            .map(|(x, y)| (unproject(*x, *y, -1.), unproject(*x, *y, 1.)))
+            .collect();
+        let along = |(a, b): (Point, Point), d: f32| {
+            let t = (d - depth(a)) / (depth(b) - depth(a));
+            a + (b - a) * t.clamp(0., 1.)
+        };
+
+        let count = settings.cascades.max(1);
+        let far = settings.distance.max(near);
+        let split = |i: u32| {
+            let f = i as f32 / count as f32;
+            let uniform = near + (far - near) * f;
+            let logarithmic = near * (far / near).powf(f);
+            (uniform + logarithmic) / 2.
+        };
+
+        let mut cascades = vec![];
+        for i in 0..count {
+            let (from, to) = (split(i), split(i + 1));
+            let slice: Vec<Point> = edges
+                .iter()
+                .flat_map(|edge| [along(*edge, from), along(*edge, to)])
+                .collect();
+            let view_projection = light.directional_view_projection(&slice, &casters_corners)?;
+            cascades.push((to, ShadowMap::render(view_projection, casters, settings)));
+        }
+
+        Some(CascadedShadowMap { cascades, view })
+    }
+
+    /// Visibility from the first cascade covering world space `p`. Points
+    /// beyond the last cascade are lit.
+    pub fn visibility(&self, p: Point, pcf: u32) -> f32 {
+        let depth = -p(self.view).z();
+        self.cascades
+            .iter()
+            .filter(|(far, _)| depth <= *far)
+            .find_map(|(_, map)| map.visibility(p, pcf))
+            .unwrap_or(1.)
+    }
+}
+
+/// Shadows cast from one light.
+pub enum Shadow {
+    Map(ShadowMap),
+    Cascades(CascadedShadowMap),
+}
+
+/// A light with the shadows it casts.
+pub struct ShadowedLight {
+    pub light: Light,
+    pub shadow: Option<Shadow>,
+    pub pcf: u32,
+}
+
+impl ShadowedLight {
+    /// Renders `light`'s shadow maps for a camera with `view` and
+    /// `view_projection` that sees from `near` onwards. Spot lights get one
+    /// map reaching the furthest caster, directional lights cascades.
+    pub fn render(
+        light: Light,
+        view: Matrix<4, 4>,
+        view_projection: Matrix<4, 4>,
+        near: f32,
+        casters: &[Caster],
+        settings: &ShadowSettings,
+    ) -> ShadowedLight {
+        let shadow = match light {
+            Light::Directional { .. } => {
+                CascadedShadowMap::render(&light, view, view_projection, near, casters, settings)
+                    .map(Shadow::Cascades)
+            }
+            Light::Spot { position, .. } => {
+                let far = casters
+                    .iter()
+                    .flat_map(|(mesh, transform)| mesh.bounds().transform(*transform).corners())
+                    .map(|corner| length3(corner - position))
+                    .fold(0., f32::max);
+                light
+                    .spot_view_projection(0.05, far.max(1.))
+                    .map(|view_projection| {
+                        Shadow::Map(ShadowMap::render(view_projection, casters, settings))
+                    })
+            }
+        };
+        ShadowedLight {
+            light,
+            shadow,
+            pcf: settings.pcf,
+        }
+    }
+
+    /// Fraction of the light reaching world space `p`.
+    pub fn visibility(&self, p: Point) -> f32 {
+        match &self.shadow {
+            Some(Shadow::Map(map)) => map.visibility(p, self.pcf).unwrap_or(1.),
+            Some(Shadow::Cascades(cascades)) => cascades.visibility(p, self.pcf),
+            None => 1.,
+        }
+    }
+}
+
+/// Diffuse lighting per pixel from shadowed lights, over the model's
+/// color or solid texture. Normals are flat per triangle and turned to
+/// face `eye`.
+pub struct Lit<'a> {
+    pub transforms: Transforms,
+    pub color: Matrix<1, 4>,
+    pub texture: Option<&'a Texture>,
+    pub lights: &'a [ShadowedLight],
+    pub eye: Point,
+}
+
+impl Shader for Lit<'_> {
+    type Vertex = Vertex;
+    /// Object space position, world space position and normal.
+    type Varying = (Point, (Point, Matrix<1, 4>));
+
+    fn vertex(&self, vertex: &Vertex) -> (Matrix<1, 4>, Self::Varying) {
+        (
+            self.transforms.clip(vertex.position),
+            (
+                vertex.position,
+                (
+                    self.transforms.world(vertex.position),
+                    self.transforms.world_normal(vertex.normal),
+                ),
+            ),
+        )
+    }
+
+    fn fragment(&self, (object, (world, normal)): Self::Varying) -> Option<Color> {
+        let albedo = self
+            .texture
+            .map_or(self.color, |texture| texture.color(object));
+
+        let mut normal = normal;
+        normal[0][3] = 0.;
+        let view = self.eye - world;
+        if dot3(normal, view) < 0. {
+            normal = -normal;
+        }
+        let normal = normal.normalize();
+
+        let mut light = Matrix([[AMBIENT, AMBIENT, AMBIENT, 1.]]);
+        for shadowed in self.lights.iter() {
+            let (to_light, _, color) = shadowed.light.incident(world);
+            let cos = dot3(normal, to_light);
+            if cos > 0. {
+                light = light + color * (cos * shadowed.visibility(world));
+            }
+        }
+
+        let channel = |v: f32| (v.clamp(0., 1.) * 255.) as u8;
+        let c = albedo * light;
+        Some(Color::new(
+            channel(c.x()),
+            channel(c.y()),
+            channel(c.z()),
+            channel(albedo.w()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        RenderSettings,
+        matrix_3d::{quad, rotate_x, scale, translate},
+    };
+
+    /// A floor at y = 0 and a square one unit above its centre.
+    fn scene() -> (Mesh, Mesh) {
+        let square = |size: f32, height: f32| {
+            quad()
+                .apply(translate(-0.5, -0.5, 0.))
+                .apply(scale(size, size, 1.))
+                .apply(rotate_x(std::f32::consts::PI / 2.))
+                .apply(translate(0., height, 0.))
+        };
+        (square(8., 0.), square(1., 1.))
+    }
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    #[test]
+    fn test_shadow_map() {
+        let (floor, blocker) = scene();
+        let identity = translate(0., 0., 0.);
+        let casters = [(&floor, identity), (&blocker, identity)];
+        let sun = Light::Directional {
+            direction: Matrix([[0., -1., 0., 0.]]),
+            color: Matrix([[1., 1., 1., 1.]]),
+        };
+        let corners = [floor.bounds().corners(), blocker.bounds().corners()].concat();
+        let view_projection = sun.directional_view_projection(&corners, &[]).unwrap();
+        let settings = ShadowSettings {
+            size: 64,
+            ..Default::default()
+        };
+        let map = ShadowMap::render(view_projection, &casters, &settings);
+
+        // Under the square is dark, beside it lit, and neither the floor
+        // nor the square shadows itself.
+        assert_eq!(map.visibility(point(0., 0., 0.), 1), Some(0.));
+        assert_eq!(map.visibility(point(2., 0., 2.), 1), Some(1.));
+        assert_eq!(map.visibility(point(0.2, 1., 0.1), 1), Some(1.));
+        assert_eq!(map.visibility(point(20., 0., 0.), 1), None);
+
+        // Filtering softens the edge of the shadow.
+        let edge = map.visibility(point(0.5, 0., 0.), 1).unwrap();
+        assert!(edge > 0. && edge < 1., "{edge}");
+        assert_eq!(
+            map.visibility(point(0.5, 0., 0.), 0).map(|v| v.fract()),
+            Some(0.)
+        );
+    }
+
+    #[test]
+    fn test_cascades() {
+        let (floor, blocker) = scene();
+        let identity = translate(0., 0., 0.);
+        let casters = [(&floor, identity), (&blocker, identity)];
+        let camera = RenderSettings::default().camera(0.);
+        let view_projection = camera.view_projection(1.).unwrap();
+        let sun = Light::Directional {
+            direction: Matrix([[0.3, -1., 0.2, 0.]]),
+            color: Matrix([[1., 1., 1., 1.]]),
+        };
+        let settings = ShadowSettings {
+            size: 64,
+            distance: 12.,
+            ..Default::default()
+        };
+
+        let shadowed = ShadowedLight::render(
+            sun,
+            camera.view(),
+            view_projection,
+            0.1,
+            &casters,
+            &settings,
+        );
+        let Some(Shadow::Cascades(cascades)) = &shadowed.shadow else {
+            panic!("directional lights cascade");
+        };
+        let ends: Vec<f32> = cascades.cascades.iter().map(|(far, _)| *far).collect();
+        assert_eq!(ends.len(), 3);
+        assert!(ends.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((ends[2] - 12.).abs() < 1e-3);
+
+        // The shadow falls along the light, and nothing is shadowed past
+        // the last cascade.
+        assert_eq!(shadowed.visibility(point(0.3, 0., 0.2)), 0.);
+        assert_eq!(shadowed.visibility(point(-0.4, 0., -0.4)), 1.);
+        assert_eq!(shadowed.visibility(point(0., -1., 30.)), 1.);
+    }
+
+    #[test]
+    fn test_spot_shadow() {
+        let (floor, blocker) = scene();
+        let identity = translate(0., 0., 0.);
+        let casters = [(&floor, identity), (&blocker, identity)];
+        let camera = RenderSettings::default().camera(0.);
+        let spot = Light::Spot {
+            position: point(0., 3., 0.),
+            direction: Matrix([[0., -1., 0., 0.]]),
+            angle: 0.8,
+            color: Matrix([[1., 1., 1., 1.]]),
+        };
+        let shadowed = ShadowedLight::render(
+            spot,
+            camera.view(),
+            camera.view_projection(1.).unwrap(),
+            0.1,
+            &casters,
+            &ShadowSettings::default(),
+        );
+        assert!(matches!(shadowed.shadow, Some(Shadow::Map(_))));
+
+        // The square's shadow spreads out from under it.
+        assert_eq!(shadowed.visibility(point(0.7, 0., 0.)), 0.);
+        assert_eq!(shadowed.visibility(point(1.2, 0., 0.)), 1.);
+        assert_eq!(shadowed.visibility(point(0., 1., 0.2)), 1.);
+    }
+}
@@ -0,0 +1,414 @@
+use crate::{
+    aabb::{Aabb, inverse_direction},
+    bitmap::{Bitmap, FloatImage},
+    environment::luminance,
+    matrix::Matrix,
+    matrix_3d::{Culling, Mesh, Point, RaycastHit, Triangle, length3, ray_intersects_triangle},
+};
+
+/// Grid of heights over the xz plane. Sample `(x, z)` is the point
+/// `origin + (x * spacing, height, z * spacing)`, and each cell between four
+/// samples is split into two triangles.
+pub struct Heightfield {
+    /// Samples along x and z, at least two each.
+    pub size: [usize; 2],
+    pub origin: Point,
+    pub spacing: f32,
+    heights: Vec<f32>,
+    /// Lowest and highest height in each cell, then over 2x2 blocks of the
+    /// level below, up to a single node covering everything.
+    min_max: Vec<MinMaxLevel>,
+}
+
+struct MinMaxLevel {
+    width: usize,
+    depth: usize,
+    ranges: Vec<(f32, f32)>,
+}
+
+impl Heightfield {
+    /// Heights row by row, x varying fastest.
+    pub fn new(size: [usize; 2], spacing: f32, heights: Vec<f32>) -> Self {
+        assert!(size[0] >= 2 && size[1] >= 2);
+        assert_eq!(heights.len(), size[0] * size[1]);
+
+        let mut field = Heightfield {
+            size,
+            origin: Matrix([[0., 0., 0., 1.]]),
+            spacing,
+            heights,
+            min_max: Vec::new(),
+        };
+        field.build_min_max();
+        field
+    }
+
+    /// Heights from `height(x, z)`, in the field's own units.
+    pub fn from_fn(size: [usize; 2], spacing: f32, height: impl Fn(f32, f32) -> f32) -> Self {
+        let mut heights = Vec::with_capacity(size[0] * size[1]);
+        for z in 0..size[1] {
+            for x in 0..size[0] {
+                heights.push(height(x as f32 * spacing, z as f32 * spacing));
+            }
+        }
+        Heightfield::new(size, spacing, heights)
+    }
+
+    /// Luminance of each pixel scaled by `scale`, with image rows running
+    /// along +z.
+    pub fn from_bitmap(bmp: &Bitmap, spacing: f32, scale: f32) -> Self {
+        let image = FloatImage::from_bitmap(bmp);
+        let heights = image.pixels.iter().map(|c| luminance(*c) * scale).collect();
+        Heightfield::new([bmp.width as usize, bmp.height as usize], spacing, heights)
+    }
+
+    pub fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[z * self.size[0] + x]
+    }
+
+    fn point(&self, x: usize, z: usize) -> Point {
+        self.origin
+            + Matrix([[
+                x as f32 * self.spacing,
+                self.height(x, z),
+                z as f32 * self.spacing,
+                0.,
+            ]])
+    }
+
+    fn build_min_max(&mut self) {
+        let (width, depth) = (self.size[0] - 1, self.size[1] - 1);
+        let mut ranges = Vec::with_capacity(width * depth);
+        for z in 0..depth {
+            for x in 0..width {
+                let corners = [
+                    self.height(x, z),
+                    self.height(x + 1, z),
+                    self.height(x, z + 1),
+                    self.height(x + 1, z + 1),
+                ];
+                ranges.push((
+                    corners.iter().copied().fold(f32::INFINITY, f32::min),
+                    corners.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+                ));
+            }
+        }
+
+        let mut levels = vec![MinMaxLevel {
+            width,
+            depth,
+            ranges,
+        }];
+
+        while let Some(below) = levels.last()
+            && (below.width > 1 || below.depth > 1)
+        {
+            let (width, depth) = (below.width.div_ceil(2), below.depth.div_ceil(2));
+            let mut ranges = Vec::with_capacity(width * depth);
+            for z in 0..depth {
+                for x in 0..width {
+                    let mut range = (f32::INFINITY, f32::NEG_INFINITY);
+                    for (cx, cz) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (cx, cz) = (x * 2 + cx, z * 2 + cz);
+                        if cx < below.width && cz < below.depth {
+                            let child = below.ranges[cz * below.width + cx];
+                            range = (range.0.min(child.0), range.1.max(child.1));
+                        }
+                    }
+                    ranges.push(range);
+                }
+            }
+            levels.push(MinMaxLevel {
+                width,
+                depth,
+                ranges,
+            });
+        }
+
+        self.min_max = levels;
+    }
+
+    /// Box around the cells of node `(x, z)` on `level`.
+    fn node_bounds(&self, level: usize, x: usize, z: usize) -> Aabb {
+        let (min, max) = self.min_max[level].ranges[z * self.min_max[level].width + x];
+        let cells = |i: usize, axis: usize| ((i << level).min(self.size[axis] - 1)) as f32;
+
+        Aabb::new(
+            self.origin
+                + Matrix([[
+                    cells(x, 0) * self.spacing,
+                    min,
+                    cells(z, 1) * self.spacing,
+                    0.,
+                ]]),
+            self.origin
+                + Matrix([[
+                    cells(x + 1, 0) * self.spacing,
+                    max,
+                    cells(z + 1, 1) * self.spacing,
+                    0.,
+                ]]),
+        )
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.node_bounds(self.min_max.len() - 1, 0, 0)
+    }
+
+    /// The two triangles of a cell, wound counter-clockwise seen from above.
+    fn cell(&self, x: usize, z: usize) -> [Triangle; 2] {
+        let (p00, p10) = (self.point(x, z), self.point(x + 1, z));
+        let (p01, p11) = (self.point(x, z + 1), self.point(x + 1, z + 1));
+        [Triangle(p00, p01, p10), Triangle(p10, p01, p11)]
+    }
+
+    /// Every cell at full resolution.
+    pub fn mesh(&self) -> Mesh {
+        let mut mesh = Mesh(Vec::new());
+        for z in 0..self.size[1] - 1 {
+            for x in 0..self.size[0] - 1 {
+                mesh.0.extend(self.cell(x, z));
+            }
+        }
+        mesh
+    }
+
+    /// Meshes in square chunks of `chunk` cells, a power of two. Each chunk
+    /// halves its resolution every time its distance from `eye` doubles past
+    /// `lod_distance`. Neighbours at different resolutions would leave
+    /// cracks between them, so every chunk hangs a skirt one coarse step
+    /// deep from its edges to cover them.
+    pub fn chunks(&self, chunk: usize, eye: Point, lod_distance: f32) -> Vec<Mesh> {
+        assert!(chunk.is_power_of_two());
+        let (width, depth) = (self.size[0] - 1, self.size[1] - 1);
+        let mut meshes = Vec::new();
+
+        for cz in (0..depth).step_by(chunk) {
+            for cx in (0..width).step_by(chunk) {
+                let (end_x, end_z) = ((cx + chunk).min(width), (cz + chunk).min(depth));
+                let centre = (self.point(cx, cz) + self.point(end_x, end_z)) * 0.5;
+                let distance = length3(centre - eye);
+
+                let level = (distance / lod_distance).max(1.).log2().floor() as u32;
+                let step = 1usize << level.min(chunk.trailing_zeros());
+
+                // Sample columns and rows, always ending on the chunk edge.
+                let along = |start: usize, end: usize| {
+                    let mut samples: Vec<usize> = (start..end).step_by(step).collect();
+                    samples.push(end);
+                    samples
+                };
+                let (xs, zs) = (along(cx, end_x), along(cz, end_z));
+
+                let mut mesh = Mesh(Vec::new());
+                for j in 0..zs.len() - 1 {
+                    for i in 0..xs.len() - 1 {
+                        let (p00, p10) = (self.point(xs[i], zs[j]), self.point(xs[i + 1], zs[j]));
+                        let (p01, p11) = (
+                            self.point(xs[i], zs[j + 1]),
+                            self.point(xs[i + 1], zs[j + 1]),
+                        );
+                        mesh.0.push(Triangle(p00, p01, p10));
+                        mesh.0.push(Triangle(p10, p01, p11));
+                    }
+                }
+
+                let drop = Matrix([[0., step as f32 * self.spacing, 0., 0.]]);
+                let mut skirt = |a: Point, b: Point| {
+                    mesh.0.push(Triangle(a, b - drop, b));
+                    mesh.0.push(Triangle(a, a - drop, b - drop));
+                };
+                // Around the edge with the chunk on the left, so the skirt
+                // faces outwards.
+                for i in 0..xs.len() - 1 {
+                    skirt(self.point(xs[i + 1], cz), self.point(xs[i], cz));
+                    skirt(self.point(xs[i], end_z), self.point(xs[i + 1], end_z));
+                }
+                for j in 0..zs.len() - 1 {
+                    skirt(self.point(cx, zs[j]), self.point(cx, zs[j + 1]));
+                    skirt(self.point(end_x, zs[j + 1]), self.point(end_x, zs[j]));
+                }
+
+                meshes.push(mesh);
+            }
+        }
+
+        meshes
+    }
+
+    /// Maximum mipmap traversal (Tevs et al. 2008): walks the min/max
+    /// quadtree front to back, skipping every node whose box the ray misses
+    /// or only reaches beyond the nearest hit so far, and intersects the
+    /// triangles of the cells it reaches.
+    pub fn nearest_hit(&self, origin: Point, direction: Matrix<1, 4>) -> Option<RaycastHit> {
+        let inverse = inverse_direction(direction);
+        let top = self.min_max.len() - 1;
+        let mut nearest: Option<RaycastHit> = None;
+
+        let (t, _) = self
+            .node_bounds(top, 0, 0)
+            .ray_intersects(origin, inverse, f32::INFINITY)?;
+        let mut stack = vec![(t, top, 0, 0)];
+
+        while let Some((t, level, x, z)) = stack.pop() {
+            if nearest.is_some_and(|hit| hit.t < t) {
+                continue;
+            }
+
+            if level == 0 {
+                for trig in self.cell(x, z) {
+                    if let Some(hit) =
+                        ray_intersects_triangle(origin, direction, trig, Culling::None)
+                        && nearest.is_none_or(|other| hit.t < other.t)
+                    {
+                        nearest = Some(hit);
+                    }
+                }
+                continue;
+            }
+
+            let below = &self.min_max[level - 1];
+            let t_max = nearest.map_or(f32::INFINITY, |hit| hit.t);
+            let mut children = Vec::with_capacity(4);
+            for (cx, cz) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let (cx, cz) = (x * 2 + cx, z * 2 + cz);
+                if cx < below.width
+                    && cz < below.depth
+                    && let Some((t, _)) = self
+                        .node_bounds(level - 1, cx, cz)
+                        .ray_intersects(origin, inverse, t_max)
+                {
+                    children.push((t, level - 1, cx, cz));
+                }
+            }
+
+            // Nearest child on top of the stack.
+            children.sort_by(|a, b| b.0.total_cmp(&a.0));
+            stack.extend(children);
+        }
+
+        nearest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bitmap::Color,
+        noise::{Fractal, simplex},
+        sampling::Rng,
+    };
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    fn hills() -> Heightfield {
+        let fractal = Fractal::default();
+        let mut field = Heightfield::from_fn([45, 37], 0.5, |x, z| {
+            fractal.fbm(simplex, point(x * 0.2, 0., z * 0.2)) * 3.
+        });
+        field.origin = point(-10., -1., -5.);
+        field
+    }
+
+    #[test]
+    fn test_min_max() {
+        let field = hills();
+        let levels: Vec<(usize, usize)> = field
+            .min_max
+            .iter()
+            .map(|level| (level.width, level.depth))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![(44, 36), (22, 18), (11, 9), (6, 5), (3, 3), (2, 2), (1, 1)]
+        );
+
+        let bounds = field.bounds();
+        let lowest = field.heights.iter().copied().fold(f32::INFINITY, f32::min);
+        let highest = field
+            .heights
+            .iter()
+            .copied()
+            .fold(f32::NEG_INFINITY, f32::max);
+        assert_eq!(bounds.min, point(-10., lowest - 1., -5.));
+        assert_eq!(bounds.max, point(12., highest - 1., 13.));
+    }
+
+    #[test]
+    fn test_ray_matches_mesh() {
+        let field = hills();
+        let mesh = field.mesh();
+        let mut rng = Rng::new(4);
+
+        for _ in 0..300 {
+            let origin = point(rng.next_f32() * 30. - 15., 5., rng.next_f32() * 30. - 10.);
+            let target = point(rng.next_f32() * 22. - 10., -1., rng.next_f32() * 18. - 5.);
+            let direction = (target - origin).normalize();
+
+            let brute = mesh
+                .0
+                .iter()
+                .filter_map(|trig| ray_intersects_triangle(origin, direction, *trig, Culling::None))
+                .map(|hit| hit.t)
+                .min_by(|a, b| a.total_cmp(b));
+            let fast = field.nearest_hit(origin, direction).map(|hit| hit.t);
+            assert_eq!(brute, fast);
+        }
+
+        // Straight down onto a sample.
+        let hit = field
+            .nearest_hit(point(-9., 10., -4.), Matrix([[0., -1., 0., 0.]]))
+            .unwrap();
+        assert!((hit.position.y() - (field.height(2, 2) - 1.)).abs() < 1e-5);
+        assert!(hit.normal.y() > 0.);
+
+        assert!(
+            field
+                .nearest_hit(point(-9., 10., -4.), Matrix([[0., 1., 0., 0.]]))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_chunks() {
+        let field = Heightfield::from_fn([33, 33], 1., |_, _| 0.);
+
+        // Up close everything is at full resolution.
+        let near = field.chunks(8, point(16., 0., 16.), 100.);
+        assert_eq!(near.len(), 16);
+        for mesh in near.iter() {
+            // 64 cells, plus a skirt of 8 quads on each side.
+            assert_eq!(mesh.0.len(), 64 * 2 + 4 * 8 * 2);
+        }
+
+        // Far away, chunks drop to a single cell.
+        let far = field.chunks(8, point(16., 1000., 16.), 10.);
+        for mesh in far.iter() {
+            assert_eq!(mesh.0.len(), 2 + 4 * 2);
+        }
+
+        // Flat ground faces up and skirts face out of their chunk.
+        for trig in near[0].0.iter() {
+            let n = trig.normal();
+            let centre = (trig.0 + trig.1 + trig.2) / 3.;
+            if n.y() > 0.5 {
+                continue;
+            }
+            let out = centre - point(4., 0., 4.);
+            assert!(n.dot(out.transpose()).x() > 0., "{n}");
+        }
+    }
+
+    #[test]
+    fn test_from_bitmap() {
+        let mut bmp = Bitmap::new(3, 2);
+        bmp.rows[1][2] = Color::new(255, 255, 255, 255);
+        let field = Heightfield::from_bitmap(&bmp, 1., 4.);
+        assert_eq!(field.size, [3, 2]);
+        assert!((field.height(2, 1) - 4.).abs() < 1e-5);
+        assert_eq!(field.height(0, 0), 0.);
+    }
+}
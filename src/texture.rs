@@ -0,0 +1,193 @@
+use crate::{
+    matrix::Matrix,
+    matrix_3d::{Point, Point2D},
+    noise::{Fractal, perlin},
+};
+
+/// Procedural solid texture, a color at every point of space, so models
+/// can be carved out of it without UVs. Colors are RGBA like `Model::color`.
+#[derive(Clone, Debug)]
+pub enum Texture {
+    /// Alternating cubes with sides of `size`.
+    Checker {
+        a: Matrix<1, 4>,
+        b: Matrix<1, 4>,
+        size: f32,
+    },
+    /// Bands across x, each `width` wide.
+    Stripes {
+        a: Matrix<1, 4>,
+        b: Matrix<1, 4>,
+        width: f32,
+    },
+    /// Veins of `b` through `a` along x, `scale` per unit, pushed around by
+    /// `turbulence`.
+    Marble {
+        a: Matrix<1, 4>,
+        b: Matrix<1, 4>,
+        scale: f32,
+        turbulence: f32,
+    },
+    /// Growth rings around the y axis, `rings` per unit, from light `a` to
+    /// dark `b` across each ring. `noise` wobbles them.
+    Wood {
+        a: Matrix<1, 4>,
+        b: Matrix<1, 4>,
+        rings: f32,
+        noise: f32,
+    },
+    /// Running bond of bricks with sides `size`, every other course shifted
+    /// half a brick along both x and z so the pattern wraps around corners.
+    /// `joint` is the width of the mortar between them.
+    Brick {
+        brick: Matrix<1, 4>,
+        mortar: Matrix<1, 4>,
+        size: Matrix<1, 4>,
+        joint: f32,
+    },
+}
+
+impl Texture {
+    pub fn color(&self, p: Point) -> Matrix<1, 4> {
+        match self {
+            Texture::Checker { a, b, size } => {
+                let cells =
+                    (p.x() / size).floor() + (p.y() / size).floor() + (p.z() / size).floor();
+                if cells.rem_euclid(2.) < 1. { *a } else { *b }
+            }
+            Texture::Stripes { a, b, width } => {
+                if (p.x() / width).floor().rem_euclid(2.) < 1. {
+                    *a
+                } else {
+                    *b
+                }
+            }
+            Texture::Marble {
+                a,
+                b,
+                scale,
+                turbulence,
+            } => {
+                let p = p * *scale;
+                let t = Fractal::default().turbulence(perlin, p);
+                let vein = 0.5 + 0.5 * (p.x() + turbulence * t * 8.).sin();
+                mix(*a, *b, vein * vein)
+            }
+            Texture::Wood { a, b, rings, noise } => {
+                let wobble = perlin(Matrix([[p.x(), p.y() * 0.2, p.z(), 1.]]) * 2.) * noise;
+                let radius = (p.x() * p.x() + p.z() * p.z()).sqrt();
+                let ring = (radius * rings + wobble).rem_euclid(1.);
+                // Early wood is wide and pale, late wood a thin dark band.
+                mix(*a, *b, ring.powi(4))
+            }
+            Texture::Brick {
+                brick,
+                mortar,
+                size,
+                joint,
+            } => {
+                let course = (p.y() / size.y()).floor();
+                let shift = course.rem_euclid(2.) * 0.5;
+                let within = |v: f32, length: f32| {
+                    let along = (v / length + shift).rem_euclid(1.) * length;
+                    along.min(length - along)
+                };
+                let y = (p.y() / size.y()).rem_euclid(1.) * size.y();
+                let gap = within(p.x(), size.x())
+                    .min(within(p.z(), size.z()))
+                    .min(y.min(size.y() - y));
+                if gap < joint * 0.5 { *mortar } else { *brick }
+            }
+        }
+    }
+
+    /// The texture on the z = 0 plane, for surfaces with texture coordinates.
+    pub fn color_uv(&self, uv: Point2D) -> Matrix<1, 4> {
+        self.color(Matrix([[uv.x(), uv.y(), 0., 1.]]))
+    }
+}
+
+fn mix(a: Matrix<1, 4>, b: Matrix<1, 4>, t: f32) -> Matrix<1, 4> {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Matrix<1, 4> = Matrix([[1., 1., 1., 1.]]);
+    const B: Matrix<1, 4> = Matrix([[0., 0., 0., 1.]]);
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    #[test]
+    fn test_checker_and_stripes() {
+        let checker = Texture::Checker {
+            a: A,
+            b: B,
+            size: 0.5,
+        };
+        assert_eq!(checker.color(point(0.1, 0.1, 0.1)), A);
+        assert_eq!(checker.color(point(0.6, 0.1, 0.1)), B);
+        assert_eq!(checker.color(point(0.6, 0.6, 0.1)), A);
+        assert_eq!(checker.color(point(-0.1, 0.1, 0.1)), B);
+        assert_eq!(checker.color_uv(Matrix([[0.6, 0.1]])), B);
+
+        let stripes = Texture::Stripes {
+            a: A,
+            b: B,
+            width: 2.,
+        };
+        assert_eq!(stripes.color(point(1., 5., -3.)), A);
+        assert_eq!(stripes.color(point(3., 5., -3.)), B);
+        assert_eq!(stripes.color(point(-1., 5., -3.)), B);
+    }
+
+    #[test]
+    fn test_brick() {
+        let brick = Texture::Brick {
+            brick: A,
+            mortar: B,
+            size: Matrix([[0.4, 0.1, 0.4, 0.]]),
+            joint: 0.02,
+        };
+        // Middle of a brick, then on the joints below it and beside it.
+        assert_eq!(brick.color(point(0.2, 0.05, 0.2)), A);
+        assert_eq!(brick.color(point(0.2, 0.002, 0.2)), B);
+        assert_eq!(brick.color(point(0.401, 0.05, 0.2)), B);
+        // The next course is shifted by half a brick.
+        assert_eq!(brick.color(point(0.401, 0.15, 0.1)), A);
+        assert_eq!(brick.color(point(0.2, 0.15, 0.1)), B);
+    }
+
+    #[test]
+    fn test_natural() {
+        let marble = Texture::Marble {
+            a: A,
+            b: B,
+            scale: 3.,
+            turbulence: 1.,
+        };
+        let wood = Texture::Wood {
+            a: A,
+            b: B,
+            rings: 8.,
+            noise: 0.5,
+        };
+
+        for texture in [marble, wood] {
+            let colors: Vec<f32> = (0..200)
+                .map(|i| {
+                    let i = i as f32 * 0.013;
+                    texture.color(point(i, i * 0.5, -i)).x()
+                })
+                .collect();
+            assert!(colors.iter().all(|c| (0. ..=1.).contains(c)));
+            // Both colors show up.
+            assert!(colors.iter().any(|c| *c > 0.8));
+            assert!(colors.iter().any(|c| *c < 0.2));
+        }
+    }
+}
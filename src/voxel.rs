@@ -0,0 +1,698 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    aabb::{Aabb, inverse_direction},
+    matrix::Matrix,
+    matrix_3d::{Mesh, Point, RaycastHit, Triangle, gamma},
+};
+
+/// Side of the cubic chunks a sparse grid allocates.
+pub const CHUNK: u32 = 16;
+const CHUNK_VOLUME: usize = (CHUNK * CHUNK * CHUNK) as usize;
+
+enum Storage {
+    Dense(Vec<u8>),
+    /// Chunks with no voxels set are never allocated.
+    Sparse(HashMap<[u32; 3], Box<[u8; CHUNK_VOLUME]>>),
+}
+
+/// Grid of palette indices, zero meaning empty. Voxel `(x, y, z)` fills the
+/// cube from `origin + (x, y, z) * voxel_size` to one voxel further along
+/// each axis.
+pub struct VoxelGrid {
+    pub size: [u32; 3],
+    storage: Storage,
+    /// Colors by index. Index zero is never drawn.
+    pub palette: Vec<Matrix<1, 4>>,
+    pub origin: Point,
+    pub voxel_size: f32,
+}
+
+impl VoxelGrid {
+    pub fn dense(size: [u32; 3]) -> VoxelGrid {
+        let len = size[0] as usize * size[1] as usize * size[2] as usize;
+        VoxelGrid::with_storage(size, Storage::Dense(vec![0; len]))
+    }
+
+    pub fn sparse(size: [u32; 3]) -> VoxelGrid {
+        VoxelGrid::with_storage(size, Storage::Sparse(HashMap::new()))
+    }
+
+    fn with_storage(size: [u32; 3], storage: Storage) -> VoxelGrid {
+        VoxelGrid {
+            size,
+            storage,
+            palette: default_palette(),
+            origin: Matrix([[0., 0., 0., 1.]]),
+            voxel_size: 1.,
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.storage, Storage::Sparse(_))
+    }
+
+    /// Allocated chunks, or zero for a dense grid.
+    pub fn chunk_count(&self) -> usize {
+        match &self.storage {
+            Storage::Dense(_) => 0,
+            Storage::Sparse(chunks) => chunks.len(),
+        }
+    }
+
+    fn contains(&self, [x, y, z]: [u32; 3]) -> bool {
+        x < self.size[0] && y < self.size[1] && z < self.size[2]
+    }
+
+    /// Palette index at a voxel. Outside the grid is empty.
+    pub fn get(&self, voxel: [u32; 3]) -> u8 {
+        if !self.contains(voxel) {
+            return 0;
+        }
+        let [x, y, z] = voxel;
+
+        match &self.storage {
+            Storage::Dense(voxels) => voxels[((z * self.size[1] + y) * self.size[0] + x) as usize],
+            Storage::Sparse(chunks) => chunks
+                .get(&[x / CHUNK, y / CHUNK, z / CHUNK])
+                .map_or(0, |chunk| chunk[chunk_offset(voxel)]),
+        }
+    }
+
+    /// Sets a voxel. Writes outside the grid are ignored.
+    pub fn set(&mut self, voxel: [u32; 3], index: u8) {
+        if !self.contains(voxel) {
+            return;
+        }
+        let [x, y, z] = voxel;
+
+        match &mut self.storage {
+            Storage::Dense(voxels) => {
+                voxels[((z * self.size[1] + y) * self.size[0] + x) as usize] = index;
+            }
+            Storage::Sparse(chunks) => {
+                let key = [x / CHUNK, y / CHUNK, z / CHUNK];
+                if index == 0 && !chunks.contains_key(&key) {
+                    return;
+                }
+                chunks
+                    .entry(key)
+                    .or_insert_with(|| Box::new([0; CHUNK_VOLUME]))[chunk_offset(voxel)] = index;
+            }
+        }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let size = Matrix([[
+            self.size[0] as f32,
+            self.size[1] as f32,
+            self.size[2] as f32,
+            0.,
+        ]]);
+        Aabb::new(self.origin, self.origin + size * self.voxel_size)
+    }
+
+    /// Amanatides & Woo's traversal: steps voxel by voxel along the ray,
+    /// always across whichever boundary is nearest, until it reaches a
+    /// filled voxel or leaves the grid.
+    pub fn nearest_hit(&self, origin: Point, direction: Matrix<1, 4>) -> Option<RaycastHit> {
+        let inverse = inverse_direction(direction);
+        let (t_enter, t_exit) = self
+            .bounds()
+            .ray_intersects(origin, inverse, f32::INFINITY)?;
+
+        // Grid coordinates where voxels are unit cubes.
+        let local = (origin - self.origin) / self.voxel_size;
+        let entry = local + direction * (t_enter / self.voxel_size);
+
+        let mut voxel = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut t_max = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+
+        for i in 0..3 {
+            voxel[i] = (entry[0][i].floor() as i64).clamp(0, self.size[i] as i64 - 1);
+            let d = direction[0][i];
+
+            if d > 0. {
+                step[i] = 1;
+                t_max[i] = ((voxel[i] + 1) as f32 - local[0][i]) * self.voxel_size / d;
+            } else if d < 0. {
+                step[i] = -1;
+                t_max[i] = (voxel[i] as f32 - local[0][i]) * self.voxel_size / d;
+            }
+            if d != 0. {
+                t_delta[i] = self.voxel_size / d.abs();
+            }
+        }
+
+        // The face the ray entered the first voxel through is on the slab
+        // it crossed last.
+        let mut axis = (0..3)
+            .max_by(|a, b| {
+                let near = |i: usize| {
+                    if step[i] == 0 {
+                        f32::NEG_INFINITY
+                    } else {
+                        t_max[i] - t_delta[i]
+                    }
+                };
+                near(*a).total_cmp(&near(*b))
+            })
+            .unwrap();
+        let mut t = t_enter;
+
+        loop {
+            let index = self.get(voxel.map(|v| v as u32));
+            if index != 0 {
+                let mut normal = Matrix::default();
+                normal[0][axis] = -step[axis] as f32;
+                let position = origin + direction * t;
+
+                let mut position_error = position.abs() * gamma(7);
+                position_error[0][3] = 0.;
+
+                return Some(RaycastHit {
+                    t,
+                    u: 0.,
+                    v: 0.,
+                    normal,
+                    shading_normal: normal,
+                    position,
+                    object_position: position,
+                    position_error,
+                    color: Some(self.palette[index as usize]),
+                });
+            }
+
+            axis = if t_max[0] < t_max[1] {
+                if t_max[0] < t_max[2] { 0 } else { 2 }
+            } else if t_max[1] < t_max[2] {
+                1
+            } else {
+                2
+            };
+
+            t = t_max[axis];
+            if t > t_exit {
+                return None;
+            }
+            voxel[axis] += step[axis];
+            if voxel[axis] < 0 || voxel[axis] >= self.size[axis] as i64 {
+                return None;
+            }
+            t_max[axis] += t_delta[axis];
+        }
+    }
+
+    /// Meshes the surface with greedy meshing (Lysenko 2012): on every slice
+    /// through the grid, exposed faces of the same color are merged into as
+    /// few rectangles as possible. Returns one mesh per palette index, with
+    /// triangles wound counter-clockwise seen from outside.
+    pub fn greedy_mesh(&self) -> Vec<(u8, Mesh)> {
+        let mut meshes: HashMap<u8, Mesh> = HashMap::new();
+
+        for axis in 0..3 {
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let (width, height) = (self.size[u] as usize, self.size[v] as usize);
+
+            for forward in [true, false] {
+                for slice in 0..self.size[axis] {
+                    // Colors of the faces on this slice that point along
+                    // `axis` (or against it) into empty space.
+                    let mut mask = vec![0u8; width * height];
+                    for j in 0..height {
+                        for i in 0..width {
+                            let mut voxel = [0; 3];
+                            voxel[axis] = slice;
+                            voxel[u] = i as u32;
+                            voxel[v] = j as u32;
+                            let index = self.get(voxel);
+                            if index == 0 {
+                                continue;
+                            }
+
+                            let mut neighbour = voxel;
+                            if forward {
+                                neighbour[axis] += 1;
+                            } else if slice > 0 {
+                                neighbour[axis] -= 1;
+                            }
+                            if (!forward && slice == 0) || self.get(neighbour) == 0 {
+                                mask[j * width + i] = index;
+                            }
+                        }
+                    }
+
+                    for j in 0..height {
+                        let mut i = 0;
+                        while i < width {
+                            let index = mask[j * width + i];
+                            if index == 0 {
+                                i += 1;
+                                continue;
+                            }
+
+                            let mut w = 1;
+                            while i + w < width && mask[j * width + i + w] == index {
+                                w += 1;
+                            }
+                            let mut h = 1;
+                            while j + h < height
+                                && (i..i + w).all(|k| mask[(j + h) * width + k] == index)
+                            {
+                                h += 1;
+                            }
+                            for row in j..j + h {
+                                mask[row * width + i..row * width + i + w].fill(0);
+                            }
+
+                            let plane = slice as f32 + if forward { 1. } else { 0. };
+                            let corner = |a: usize, b: usize| {
+                                let mut p = [0.; 3];
+                                p[axis] = plane;
+                                p[u] = a as f32;
+                                p[v] = b as f32;
+                                self.origin + Matrix([[p[0], p[1], p[2], 0.]]) * self.voxel_size
+                            };
+                            let (p0, p1, p2, p3) = (
+                                corner(i, j),
+                                corner(i + w, j),
+                                corner(i + w, j + h),
+                                corner(i, j + h),
+                            );
+
+                            // u × v points along +axis, so counter-clockwise
+                            // in (u, v) faces forward.
+                            let quad = if forward {
+                                [Triangle(p0, p1, p2), Triangle(p0, p2, p3)]
+                            } else {
+                                [Triangle(p0, p2, p1), Triangle(p0, p3, p2)]
+                            };
+                            meshes
+                                .entry(index)
+                                .or_insert_with(|| Mesh(Vec::new()))
+                                .0
+                                .extend(quad);
+
+                            i += w;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut meshes: Vec<(u8, Mesh)> = meshes.into_iter().collect();
+        meshes.sort_by_key(|(index, _)| *index);
+        meshes
+    }
+}
+
+fn chunk_offset([x, y, z]: [u32; 3]) -> usize {
+    (((z % CHUNK) * CHUNK + y % CHUNK) * CHUNK + x % CHUNK) as usize
+}
+
+/// MagicaVoxel's built-in palette, used by files without an `RGBA` chunk: a
+/// 6x6x6 color cube from white down, then ten-step ramps of red, green, blue
+/// and grey.
+pub fn default_palette() -> Vec<Matrix<1, 4>> {
+    let mut palette = vec![Matrix([[0., 0., 0., 0.]])];
+    let rgb =
+        |r: u8, g: u8, b: u8| Matrix([[r as f32 / 255., g as f32 / 255., b as f32 / 255., 1.]]);
+
+    for r in (0..6).rev() {
+        for g in (0..6).rev() {
+            for b in (0..6).rev() {
+                if r + g + b > 0 {
+                    palette.push(rgb(r * 0x33, g * 0x33, b * 0x33));
+                }
+            }
+        }
+    }
+
+    let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+    for channel in 0..4 {
+        for v in ramp {
+            palette.push(match channel {
+                0 => rgb(v, 0, 0),
+                1 => rgb(0, v, 0),
+                2 => rgb(0, 0, v),
+                _ => rgb(v, v, v),
+            });
+        }
+    }
+
+    palette
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VoxError {
+    /// Doesn't start with `VOX `.
+    InvalidHeader,
+    UnexpectedEof,
+    /// A chunk's size runs past its parent.
+    InvalidChunk,
+    /// An `XYZI` chunk without a `SIZE` before it.
+    MissingSize,
+    NoModels,
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::InvalidHeader => f.write_str("not a MagicaVoxel file"),
+            VoxError::UnexpectedEof => f.write_str("unexpected end of .vox data"),
+            VoxError::InvalidChunk => f.write_str("chunk size exceeds its parent"),
+            VoxError::MissingSize => f.write_str("XYZI chunk without a SIZE chunk"),
+            VoxError::NoModels => f.write_str(".vox file contains no models"),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {}
+
+fn read_u32(data: &[u8], at: usize) -> Result<u32, VoxError> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(VoxError::UnexpectedEof)
+}
+
+/// Reads every model in a MagicaVoxel `.vox` file as a sparse grid, ignoring
+/// the scene graph. MagicaVoxel is z-up, so its z becomes y here and its y
+/// becomes -z, keeping the models right-handed.
+pub fn load_vox(data: &[u8]) -> Result<Vec<VoxelGrid>, VoxError> {
+    if data.get(0..4) != Some(b"VOX ") {
+        return Err(VoxError::InvalidHeader);
+    }
+
+    let mut sizes: Vec<[u32; 3]> = Vec::new();
+    let mut models: Vec<Vec<[u8; 4]>> = Vec::new();
+    let mut palette: Option<Vec<Matrix<1, 4>>> = None;
+
+    // Chunks are flat inside MAIN, so walking them in order is enough. Each
+    // chunk's children follow its content and must end within its parent.
+    let mut at = 8;
+    let mut limit = data.len();
+    while at < limit {
+        let id = data.get(at..at + 4).ok_or(VoxError::UnexpectedEof)?;
+        let content = read_u32(data, at + 4)? as usize;
+        let children = read_u32(data, at + 8)? as usize;
+        let body = at + 12;
+        let end = body.checked_add(content).ok_or(VoxError::InvalidChunk)?;
+        let children_end = end.checked_add(children).ok_or(VoxError::InvalidChunk)?;
+        if children_end > limit {
+            return Err(VoxError::InvalidChunk);
+        }
+
+        match id {
+            b"MAIN" => {
+                // Descend into the children.
+                limit = children_end;
+                at = end;
+                continue;
+            }
+            b"SIZE" => {
+                sizes.push([
+                    read_u32(data, body)?,
+                    read_u32(data, body + 4)?,
+                    read_u32(data, body + 8)?,
+                ]);
+            }
+            b"XYZI" => {
+                if sizes.len() != models.len() + 1 {
+                    return Err(VoxError::MissingSize);
+                }
+                let count = read_u32(data, body)? as usize;
+                let voxels_end = count
+                    .checked_mul(4)
+                    .and_then(|bytes| (body + 4).checked_add(bytes))
+                    .ok_or(VoxError::InvalidChunk)?;
+                let voxels = data
+                    .get(body + 4..voxels_end)
+                    .ok_or(VoxError::UnexpectedEof)?;
+                models.push(voxels.chunks(4).map(|v| [v[0], v[1], v[2], v[3]]).collect());
+            }
+            b"RGBA" => {
+                let colors = data.get(body..body + 1024).ok_or(VoxError::UnexpectedEof)?;
+                // Entry i holds the color of index i + 1.
+                let mut out = vec![Matrix([[0., 0., 0., 0.]])];
+                out.extend(colors.chunks(4).take(255).map(|c| {
+                    Matrix([[c[0] as f32, c[1] as f32, c[2] as f32, c[3] as f32]]) / 255.
+                }));
+                palette = Some(out);
+            }
+            _ => {}
+        }
+
+        at = children_end;
+    }
+
+    if models.is_empty() {
+        return Err(VoxError::NoModels);
+    }
+
+    let palette = palette.unwrap_or_else(default_palette);
+    let grids = models
+        .into_iter()
+        .zip(sizes)
+        .map(|(voxels, [sx, sy, sz])| {
+            let mut grid = VoxelGrid::sparse([sx, sz, sy]);
+            grid.palette = palette.clone();
+            for [x, y, z, index] in voxels {
+                if (y as u32) < sy {
+                    grid.set([x as u32, z as u32, sy - 1 - y as u32], index);
+                }
+            }
+            grid
+        })
+        .collect();
+
+    Ok(grids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matrix_3d::Culling;
+    use crate::matrix_3d::ray_intersects_triangle;
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Matrix([[x, y, z, 1.]])
+    }
+
+    #[test]
+    fn test_storage() {
+        for mut grid in [
+            VoxelGrid::dense([40, 20, 10]),
+            VoxelGrid::sparse([40, 20, 10]),
+        ] {
+            grid.set([0, 0, 0], 1);
+            grid.set([39, 19, 9], 2);
+            grid.set([17, 3, 5], 3);
+            grid.set([40, 0, 0], 4);
+
+            assert_eq!(grid.get([0, 0, 0]), 1);
+            assert_eq!(grid.get([39, 19, 9]), 2);
+            assert_eq!(grid.get([17, 3, 5]), 3);
+            assert_eq!(grid.get([16, 3, 5]), 0);
+            assert_eq!(grid.get([40, 0, 0]), 0);
+        }
+
+        // Only chunks with something in them are allocated.
+        let mut grid = VoxelGrid::sparse([1000, 1000, 1000]);
+        grid.set([500, 500, 500], 1);
+        grid.set([999, 0, 0], 1);
+        grid.set([0, 0, 999], 0);
+        assert_eq!(grid.chunk_count(), 2);
+    }
+
+    #[test]
+    fn test_dda() {
+        let mut grid = VoxelGrid::dense([8, 8, 8]);
+        grid.origin = point(-2., -2., -2.);
+        grid.voxel_size = 0.5;
+        grid.set([4, 4, 6], 5);
+        grid.set([0, 0, 0], 7);
+
+        // Straight down z into the back voxel at z = 1.
+        let hit = grid
+            .nearest_hit(point(0.1, 0.1, -10.), Matrix([[0., 0., 1., 0.]]))
+            .unwrap();
+        assert!((hit.t - 11.).abs() < 1e-5);
+        assert_eq!(hit.normal, Matrix([[0., 0., -1., 0.]]));
+        assert_eq!(hit.color, Some(grid.palette[5]));
+
+        // Diagonally into the corner voxel, through its x face.
+        let hit = grid
+            .nearest_hit(point(-3., -1.9, -1.9), Matrix([[1., 0., 0.01, 0.]]))
+            .unwrap();
+        assert!((hit.t - 1.).abs() < 1e-5);
+        assert_eq!(hit.normal, Matrix([[-1., 0., 0., 0.]]));
+
+        // Starting inside the grid and leaving it.
+        let hit = grid.nearest_hit(point(0.1, 0.1, 0.1), Matrix([[0., 0., -1., 0.]]));
+        assert!(hit.is_none());
+        let hit = grid
+            .nearest_hit(point(0.1, 0.1, 0.1), Matrix([[0., 0., 1., 0.]]))
+            .unwrap();
+        assert!((hit.t - 0.9).abs() < 1e-5);
+
+        assert!(
+            grid.nearest_hit(point(5., 0., 0.), Matrix([[0., 1., 0., 0.]]))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_dda_matches_brute_force() {
+        let mut grid = VoxelGrid::sparse([24, 24, 24]);
+        let mut rng = crate::sampling::Rng::new(9);
+        for _ in 0..300 {
+            let v = [rng.next_below(24), rng.next_below(24), rng.next_below(24)];
+            grid.set(v, 1 + rng.next_below(8) as u8);
+        }
+
+        let meshes = grid.greedy_mesh();
+        for _ in 0..200 {
+            let origin = point(rng.next_f32() * 40. - 8., rng.next_f32() * 40. - 8., -10.);
+            let target = point(
+                rng.next_f32() * 24.,
+                rng.next_f32() * 24.,
+                rng.next_f32() * 24.,
+            );
+            let direction = (target - origin).normalize();
+
+            let brute = meshes
+                .iter()
+                .flat_map(|(index, mesh)| mesh.0.iter().map(move |trig| (*index, *trig)))
+                .filter_map(|(index, trig)| {
+                    ray_intersects_triangle(origin, direction, trig, Culling::None)
+                        .map(|hit| (hit.t, index))
+                })
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            let dda = grid
+                .nearest_hit(origin, direction)
+                .map(|hit| (hit.t, hit.color));
+
+            match (brute, dda) {
+                (Some((t, index)), Some((dda_t, color))) => {
+                    assert!((t - dda_t).abs() < 1e-3, "{t} {dda_t}");
+                    assert_eq!(color, Some(grid.palette[index as usize]));
+                }
+                (None, None) => {}
+                other => panic!("{other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_greedy_mesh() {
+        // A solid 4x2x3 block meshes to one quad per side.
+        let mut grid = VoxelGrid::dense([6, 6, 6]);
+        for x in 1..5 {
+            for y in 0..2 {
+                for z in 2..5 {
+                    grid.set([x, y, z], 3);
+                }
+            }
+        }
+        let meshes = grid.greedy_mesh();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].0, 3);
+        assert_eq!(meshes[0].1.0.len(), 12);
+
+        // Every face points away from the block's centre.
+        let centre = point(3., 1., 3.5);
+        for trig in meshes[0].1.0.iter() {
+            let to_face = (trig.0 + trig.1 + trig.2) / 3. - centre;
+            assert!(trig.normal().dot(to_face.transpose()).x() > 0.);
+        }
+
+        // Different colors stay apart, and shared faces aren't meshed.
+        grid.set([1, 0, 2], 4);
+        let meshes = grid.greedy_mesh();
+        assert_eq!(
+            meshes.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert_eq!(meshes[1].1.0.len(), 6);
+    }
+
+    fn chunk(id: &[u8; 4], content: &[u8], children: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((content.len() as u32).to_le_bytes());
+        out.extend((children.len() as u32).to_le_bytes());
+        out.extend(content);
+        out.extend(children);
+        out
+    }
+
+    #[test]
+    fn test_load_vox() {
+        let words = |w: &[u32]| w.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        let mut xyzi = words(&[2]);
+        xyzi.extend([0, 0, 0, 1, 2, 1, 3, 255]);
+        let mut rgba = vec![0u8; 1024];
+        rgba[0..4].copy_from_slice(&[255, 0, 0, 255]);
+        rgba[254 * 4..255 * 4].copy_from_slice(&[0, 0, 255, 255]);
+
+        let mut children = chunk(b"SIZE", &words(&[3, 2, 4]), &[]);
+        children.extend(chunk(b"XYZI", &xyzi, &[]));
+        children.extend(chunk(b"nTRN", &[0; 8], &[]));
+        children.extend(chunk(b"RGBA", &rgba, &[]));
+
+        let mut file = b"VOX ".to_vec();
+        file.extend(150u32.to_le_bytes());
+        file.extend(chunk(b"MAIN", &[], &children));
+
+        let grids = load_vox(&file).unwrap();
+        assert_eq!(grids.len(), 1);
+        let grid = &grids[0];
+        assert_eq!(grid.size, [3, 4, 2]);
+        assert_eq!(grid.get([0, 0, 1]), 1);
+        assert_eq!(grid.get([2, 3, 0]), 255);
+        assert_eq!(grid.palette[1], Matrix([[1., 0., 0., 1.]]));
+        assert_eq!(grid.palette[255], Matrix([[0., 0., 1., 1.]]));
+
+        assert_eq!(load_vox(b"PNG ").err(), Some(VoxError::InvalidHeader));
+        assert_eq!(
+            load_vox(&file[..file.len() - 10]).err(),
+            Some(VoxError::InvalidChunk)
+        );
+        let mut no_size = b"VOX ".to_vec();
+        no_size.extend(150u32.to_le_bytes());
+        no_size.extend(chunk(b"MAIN", &[], &chunk(b"XYZI", &xyzi, &[])));
+        assert_eq!(load_vox(&no_size).err(), Some(VoxError::MissingSize));
+
+        // Children claiming to run past their parent, or far enough to wrap
+        // the offset, are rejected.
+        let last = file.len() - 12 - rgba.len();
+        for children in [1, u32::MAX] {
+            let mut huge = file.clone();
+            huge[last + 8..last + 12].copy_from_slice(&children.to_le_bytes());
+            assert_eq!(load_vox(&huge).err(), Some(VoxError::InvalidChunk));
+        }
+
+        // Truncated anywhere, loading fails cleanly.
+        for len in 0..file.len() {
+            let _ = load_vox(&file[..len]);
+        }
+    }
+
+    #[test]
+    fn test_default_palette() {
+        let palette = default_palette();
+        assert_eq!(palette.len(), 256);
+        assert_eq!(palette[1], Matrix([[1., 1., 1., 1.]]));
+        assert_eq!(palette[215], Matrix([[0., 0., 0.2, 1.]]));
+        assert_eq!(
+            palette[255],
+            Matrix([[
+                0x11 as f32 / 255.,
+                0x11 as f32 / 255.,
+                0x11 as f32 / 255.,
+                1.
+            ]])
+        );
+    }
+}
@@ -1,5 +1,15 @@
-import init from "./pkg/wasm_3d.js";
-const { render } = await init();
+import init, {
+  render,
+  export_image,
+  RenderSettings,
+  ProjectionKind,
+  ImageFormat,
+  Environment,
+  PostChain,
+  AovKind,
+  SceneKind,
+} from "./pkg/wasm_3d.js";
+await init();
 /**
  * @returns {never}
  */
@@ -8,11 +18,141 @@ function fail() {
 }
 
 const canvas = /** @type {HTMLCanvasElement} */ (document.getElementById("canvas") ?? fail());
+const scene = /** @type {HTMLSelectElement} */ (document.getElementById("scene") ?? fail());
+const projection = /** @type {HTMLSelectElement} */ (document.getElementById("projection") ?? fail());
+const aov = /** @type {HTMLSelectElement} */ (document.getElementById("aov") ?? fail());
+const format = /** @type {HTMLSelectElement} */ (document.getElementById("format") ?? fail());
+const exportButton = /** @type {HTMLButtonElement} */ (document.getElementById("export") ?? fail());
+const environmentSelect = /** @type {HTMLSelectElement} */ (document.getElementById("environment") ?? fail());
+const environmentFile = /** @type {HTMLInputElement} */ (document.getElementById("environment-file") ?? fail());
+const pathTracing = /** @type {HTMLInputElement} */ (document.getElementById("path-tracing") ?? fail());
+const denoise = /** @type {HTMLInputElement} */ (document.getElementById("denoise") ?? fail());
+const overlays = /** @type {HTMLInputElement} */ (document.getElementById("overlays") ?? fail());
+const postControls = /** @type {HTMLFieldSetElement} */ (document.getElementById("post") ?? fail());
+const lutFile = /** @type {HTMLInputElement} */ (document.getElementById("lut-file") ?? fail());
 
 const ctx = canvas.getContext("2d") ?? fail();
 
 const downscale = 3;
 
+const settings = new RenderSettings();
+
+for (const [name, value] of Object.entries(SceneKind)) {
+  if (typeof value !== "number") continue;
+  scene.add(new Option(name, String(value)));
+}
+scene.value = String(settings.scene);
+scene.addEventListener("change", () => {
+  settings.scene = Number(scene.value);
+});
+
+for (const [name, value] of Object.entries(ProjectionKind)) {
+  if (typeof value !== "number") continue;
+  projection.add(new Option(name, String(value)));
+}
+projection.value = String(settings.projection);
+projection.addEventListener("change", () => {
+  settings.projection = Number(projection.value);
+});
+
+for (const [name, value] of Object.entries(AovKind)) {
+  if (typeof value !== "number") continue;
+  aov.add(new Option(name, String(value)));
+}
+aov.value = String(settings.aov);
+aov.addEventListener("change", () => {
+  settings.aov = Number(aov.value);
+});
+
+let environment = Environment.constant(0, 0, 0);
+
+/**
+ * @param {Environment} next
+ */
+function setEnvironment(next) {
+  environment.free();
+  environment = next;
+}
+
+environmentSelect.addEventListener("change", () => {
+  if (environmentSelect.value === "file") {
+    environmentFile.click();
+  } else if (environmentSelect.value === "sky") {
+    setEnvironment(Environment.sky(0.5, 0.8, 3));
+  } else {
+    setEnvironment(Environment.constant(0, 0, 0));
+  }
+});
+environmentFile.addEventListener("change", async () => {
+  const file = environmentFile.files?.[0];
+  if (!file) return;
+  setEnvironment(Environment.load(new Uint8Array(await file.arrayBuffer())));
+});
+
+pathTracing.addEventListener("change", () => {
+  settings.path_tracing = pathTracing.checked;
+});
+denoise.addEventListener("change", () => {
+  settings.denoise = denoise.checked;
+});
+overlays.addEventListener("change", () => {
+  settings.overlays = overlays.checked;
+});
+
+const post = new PostChain();
+/** @type {string | null} */
+let cube = null;
+
+/**
+ * @param {string} name
+ */
+function enabled(name) {
+  const input = /** @type {HTMLInputElement | null} */ (postControls.elements.namedItem(name));
+  return input?.checked ?? false;
+}
+
+// Rebuilds the chain from the checkboxes, in a fixed order: antialiasing
+// first, then lens effects, grading and grain last.
+function updatePost() {
+  post.clear();
+  if (enabled("fxaa")) post.add_fxaa();
+  if (enabled("sharpen")) post.add_sharpen(0.5);
+  if (enabled("bloom")) post.add_bloom(0.8, 0.6, 3);
+  if (enabled("aberration")) post.add_chromatic_aberration(0.01);
+  if (enabled("vignette")) post.add_vignette(0.5, 0.4);
+  if (enabled("lut") && cube !== null) post.add_lut(cube);
+  if (enabled("grain")) post.add_grain(0.03);
+}
+
+postControls.addEventListener("change", (event) => {
+  const target = /** @type {HTMLInputElement} */ (event.target);
+  if (target.name === "lut" && target.checked && cube === null) {
+    lutFile.click();
+    return;
+  }
+  updatePost();
+});
+lutFile.addEventListener("change", async () => {
+  const file = lutFile.files?.[0];
+  if (!file) return;
+  cube = await file.text();
+  updatePost();
+});
+
+for (const [name, value] of Object.entries(ImageFormat)) {
+  if (typeof value !== "number") continue;
+  format.add(new Option(name, String(value)));
+}
+exportButton.addEventListener("click", () => {
+  const bytes = export_image(width, height, performance.now(), settings, environment, post, Number(format.value));
+  const name = format.options[format.selectedIndex].text.toLowerCase();
+  const link = document.createElement("a");
+  link.href = URL.createObjectURL(new Blob([bytes]));
+  link.download = `render.${name}`;
+  link.click();
+  URL.revokeObjectURL(link.href);
+});
+
 let width = 0;
 let height = 0;
 
@@ -22,7 +162,7 @@ const observer = new ResizeObserver((entries) => {
     height = entry.contentRect.height / downscale;
     canvas.width = width;
     canvas.height = height;
-    render(ctx, width, height, performance.now());
+    render(ctx, width, height, performance.now(), settings, environment, post);
   }
 });
 observer.observe(document.body);
@@ -32,7 +172,7 @@ observer.observe(document.body);
  * @param {number} t
  */
 function loop(t) {
-  if (width !== 0 && height !== 0) render(ctx, width, height, t);
+  if (width !== 0 && height !== 0) render(ctx, width, height, t, settings, environment, post);
   requestAnimationFrame(loop);
 }
 